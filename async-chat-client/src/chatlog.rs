@@ -0,0 +1,185 @@
+use chrono::{DateTime, Local, Utc};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::crypt::Cipher;
+
+/// The room the server puts us in when we join.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// A log is rotated once it grows past this many bytes.
+const MAX_SIZE: u64 = 1024 * 1024;
+/// Number of rotated logs kept per room, as `<room>.log.1` (the newest) and up.
+const KEEP: usize = 3;
+
+/// The user's own copy of the chat, one file per server and room:
+/// `<root>/<server>/<room>.log`, each line prefixed with the local time.
+pub struct ChatLog {
+    root: PathBuf,
+    /// The directory of the server we are connected to.
+    dir: PathBuf,
+    files: HashMap<String, File>,
+    /// Encrypts every line, if the user asked for that.
+    cipher: Option<Cipher>,
+}
+
+impl ChatLog {
+    /// Where logs go by default: `$XDG_DATA_HOME/simple-chat/logs`, or
+    /// `~/.local/share/simple-chat/logs`.
+    pub fn default_root() -> Option<PathBuf> {
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/share")))?;
+        Some(data.join("simple-chat").join("logs"))
+    }
+
+    /// Logs the chat on `server` (its `host:port`) under `root`.
+    pub fn new(root: PathBuf, server: &str) -> Self {
+        ChatLog {
+            dir: root.join(dir_name(server)),
+            root,
+            files: HashMap::new(),
+            cipher: None,
+        }
+    }
+
+    /// Encrypts every line logged from now on with `cipher`.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Logs to another server's directory from now on, after `/connect`.
+    pub fn switch_server(&mut self, server: &str) {
+        self.dir = self.root.join(dir_name(server));
+        self.files.clear();
+    }
+
+    /// The directory of the current server's logs.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Appends a line to a room's log, rotating it first if it has grown too big.
+    pub fn write(&mut self, room: &str, line: &str, at: DateTime<Utc>) -> io::Result<()> {
+        let path = self.dir.join(format!("{}.log", dir_name(room)));
+        if fs::metadata(&path).is_ok_and(|meta| meta.len() >= MAX_SIZE) {
+            self.files.remove(room);
+            rotate(&path)?;
+        }
+        let file = match self.files.entry(room.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                fs::create_dir_all(&self.dir)?;
+                entry.insert(OpenOptions::new().create(true).append(true).open(&path)?)
+            }
+        };
+        let line = stamp(line, at);
+        match &self.cipher {
+            Some(cipher) => writeln!(file, "{}", cipher.encrypt(&line)),
+            None => writeln!(file, "{line}"),
+        }
+    }
+
+    /// Every line logged for the current server, oldest first, room by room.
+    pub fn history(&self) -> io::Result<Vec<String>> {
+        let mut logs: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        logs.sort();
+        let mut lines = Vec::new();
+        let read = |text: String| -> Vec<String> {
+            match &self.cipher {
+                Some(cipher) => text
+                    .lines()
+                    .filter_map(|line| cipher.decrypt(line))
+                    .collect(),
+                None => text.lines().map(String::from).collect(),
+            }
+        };
+        for path in logs {
+            for n in (1..=KEEP).rev() {
+                if let Ok(text) = fs::read_to_string(rotated(&path, n)) {
+                    lines.extend(read(text));
+                }
+            }
+            lines.extend(read(fs::read_to_string(&path)?));
+        }
+        Ok(lines)
+    }
+}
+
+/// Prefixes a line with the local time it was sent at, as it is logged.
+pub fn stamp(line: &str, at: DateTime<Utc>) -> String {
+    let at = at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+    format!("{at} {line}")
+}
+
+/// The path of a log's `n`th rotation.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{n}", path.display()))
+}
+
+/// Shifts `<room>.log` to `<room>.log.1`, `.1` to `.2` and so on, dropping the oldest.
+fn rotate(path: &Path) -> io::Result<()> {
+    for n in (1..KEEP).rev() {
+        if rotated(path, n).exists() {
+            fs::rename(rotated(path, n), rotated(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+/// Makes a server address or room name safe to use as a file name.
+pub fn dir_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logs_rotate() {
+        let root = std::env::temp_dir().join(format!("chatlog-{}", std::process::id()));
+        let mut log = ChatLog::new(root.clone(), "127.0.0.1:12345");
+        assert_eq!(log.dir(), root.join("127.0.0.1_12345"));
+
+        log.write(DEFAULT_ROOM, "[bob]: hi", Utc::now()).unwrap();
+        let path = log.dir().join("lobby.log");
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with(" [bob]: hi\n"), "{text}");
+
+        // Past the limit, the next line starts a new log
+        fs::write(&path, vec![b'x'; MAX_SIZE as usize]).unwrap();
+        log.write(DEFAULT_ROOM, "[bob]: again", Utc::now()).unwrap();
+        let rotated = log.dir().join("lobby.log.1");
+        assert_eq!(fs::metadata(&rotated).unwrap().len(), MAX_SIZE);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with(" [bob]: again\n"));
+
+        // The history covers rotated logs too, oldest first
+        let history = log.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].starts_with("xxx"));
+        assert!(history[1].ends_with(" [bob]: again"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,1457 @@
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook_mio::v1_0::Signals;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::net::Shutdown;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::chatlog::{self, ChatLog};
+use crate::commands::{self, Command};
+use crate::connection::ConnectionState;
+use crate::direct::{self, Direct};
+use crate::dnd::{Dnd, Missed};
+use crate::i18n::t;
+use crate::input::{self, InputEvent};
+use crate::keyring::Entry;
+#[cfg(feature = "noise")]
+use crate::noise::Noise;
+use crate::notify::{self, Rules};
+use crate::once::Once;
+use crate::outbound::OutboundBuffer;
+use crate::output;
+use crate::pow;
+#[cfg(feature = "quic")]
+use crate::quic::Bridge;
+use crate::recorder::{Direction, Recorder};
+use crate::search::Search;
+use crate::sequence::{Received, Sequence};
+use crate::socks;
+use crate::spans;
+use crate::store::Store;
+use crate::tags::{self, Tags};
+use crate::throttle::{Throttle, Tick};
+use crate::version;
+
+// Constants for the server, input (stdin and proof-of-work threads) and signal events.
+const SERVER: Token = Token(0);
+const INPUT: Token = Token(1);
+const SIGNALS: Token = Token(2);
+
+/// How much is read from the server at a time, unless `--read-buffer` says otherwise.
+pub const READ_BUFFER: usize = 8192;
+/// Sent in place of a username to join as a guest; the server answers with
+/// `/name <name>`, the name it picked.
+pub const GUEST: &str = "/guest";
+
+/// What the server answers a wrong password with, before closing the connection.
+const WRONG_PASSWORD: &str = "Wrong password";
+/// What we tell the server we understand, ahead of the username, for its `/whois`:
+/// message ids and acks, formatting, `/fetch`, resuming sessions, direct
+/// connections and proofs of work.
+const CAPABILITIES: &str = "acks,spans,fetch,resume,direct,pow";
+
+/// How long leaving may take to get our last messages and `/leave` out.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// A second Ctrl-C within this long of the first one quits.
+const QUIT_WINDOW: Duration = Duration::from_secs(2);
+/// Number of chat messages kept in memory for `/search` when they aren't logged.
+const SCROLLBACK: usize = 1000;
+/// Number of kept messages shown on joining a room again.
+const SHOWN_ON_JOINING: usize = 20;
+
+/// A frame queued for the server, as it goes on the wire.
+enum Outgoing {
+    /// The username, or a request to resume our session, sent first on every (re)connect,
+    /// and what we support, sent ahead of it.
+    Username(Vec<u8>),
+    /// A chat message or command typed by the user. The text is kept so the message
+    /// can be sent again, and re-encrypted, after a reconnect.
+    Message { text: String, wire: Vec<u8> },
+    /// A solution to the server's proof-of-work challenge, only valid for this connection.
+    Proof(Vec<u8>),
+    /// The account's password, sent when the server asks for it. Never recorded.
+    Password(Vec<u8>),
+    /// A Noise handshake message, only valid for this connection.
+    #[cfg(feature = "noise")]
+    Handshake(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Outgoing {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Outgoing::Username(wire)
+            | Outgoing::Message { wire, .. }
+            | Outgoing::Proof(wire)
+            | Outgoing::Password(wire) => wire,
+            #[cfg(feature = "noise")]
+            Outgoing::Handshake(wire) => wire,
+        }
+    }
+}
+
+/// How connections to the server are made.
+pub enum Dialer {
+    Tcp,
+    /// Over QUIC, through a local bridge.
+    #[cfg(feature = "quic")]
+    Quic(Bridge),
+    /// Through a SOCKS5 proxy such as Tor, through a local bridge. The proxy resolves
+    /// the server's host name.
+    Socks(socks::Bridge),
+}
+
+impl Dialer {
+    /// Initiates a connection to `address` (`host:port`) and registers it for polling.
+    fn dial(&self, poll: &Poll, address: &str) -> io::Result<TcpStream> {
+        match self {
+            Dialer::Tcp => open(poll, resolve(address)?),
+            #[cfg(feature = "quic")]
+            Dialer::Quic(bridge) => {
+                bridge.retarget(resolve(address)?);
+                open(poll, bridge.local_addr())
+            }
+            Dialer::Socks(bridge) => {
+                bridge.retarget(address);
+                open(poll, bridge.local_addr())
+            }
+        }
+    }
+
+    /// Returns true if connections go through a proxy, which anything connecting
+    /// on its own would bypass.
+    fn is_proxied(&self) -> bool {
+        matches!(self, Dialer::Socks(_))
+    }
+}
+
+/// The chat client: owns the connection to the server and the event loop driving it.
+pub struct Client {
+    poll: Poll,
+    /// Wakes the event loop for lines typed and proofs of work solved. We hold on to
+    /// it: if a thread exits and drops the last handle, the wake-up it just issued
+    /// would be lost along with the underlying file descriptor.
+    waker: Arc<Waker>,
+    dialer: Dialer,
+    stream: TcpStream,
+    /// The server's `host:port`.
+    address: String,
+    username: String,
+    /// The password of the username's account, if it is registered.
+    password: Option<String>,
+    /// Where the password is kept in the keyring once the server takes it.
+    keyring: Option<Entry>,
+    /// Whether the server has yet to say if the password we sent is right: the next
+    /// line it sends does.
+    password_sent: bool,
+    /// Whether the next line typed is the password the server asked for, typed
+    /// without being shown.
+    asking_password: bool,
+    /// Reused for every read from the server.
+    read_buffer: Vec<u8>,
+    /// The token the server gave us to resume our session after a reconnect.
+    resume_token: Option<String>,
+    /// The name the server offered us because ours was taken, until we take it or
+    /// type another.
+    suggested_name: Option<String>,
+    /// The server's version and features, as it announced them.
+    server_version: Option<String>,
+    /// The proof-of-work challenge being solved for the current connection.
+    challenge: Option<String>,
+    /// Where the proof-of-work thread sends the nonces it found, for their challenges.
+    solved_tx: Sender<(String, u64)>,
+    solved: Receiver<(String, u64)>,
+    state: ConnectionState,
+    /// Frames accepted for the current connection but not yet (fully) written.
+    outbound: OutboundBuffer<Outgoing>,
+    /// Messages typed while disconnected, flushed in order once we reconnect.
+    pending: VecDeque<String>,
+    /// Chat messages queued on this connection that the server hasn't acknowledged
+    /// yet. They are sent again after a reconnect, and their ids keep the server from
+    /// posting them twice.
+    unacked: VecDeque<String>,
+    /// Holds back our room messages while the server rate limits us.
+    throttle: Throttle,
+    /// When the user is notified of incoming messages.
+    rules: Rules,
+    /// Keeps notifications quiet while the user doesn't want to be disturbed.
+    dnd: Dnd,
+    /// Whether to tell the server we are away while do not disturb is on.
+    dnd_away: bool,
+    /// A message with a code block being typed, line by line, until the block is
+    /// closed.
+    code_block: Option<String>,
+    /// Data received from the server that doesn't make up a complete line yet.
+    inbound: Vec<u8>,
+    /// Whether the connection is currently registered for `WRITABLE` events.
+    writable_interest: bool,
+    recorder: Option<Recorder>,
+    /// The user's local copy of the chat, unless turned off.
+    log: Option<ChatLog>,
+    /// The room messages received, kept for the next time we join, unless turned off.
+    store: Option<Store>,
+    /// Recent chat messages, searched instead of the log if there is none.
+    scrollback: VecDeque<String>,
+    /// The last `/search`, for `/next` and `/prev`.
+    search: Option<Search>,
+    /// The room we are in, as the server told us with `/room <name>`.
+    room: String,
+    /// The room's sequence numbers, to notice missed messages.
+    sequence: Sequence,
+    /// Direct connections to other users' clients, for private messages.
+    direct: Direct,
+    /// Encrypts the connection, if the user asked for Noise.
+    #[cfg(feature = "noise")]
+    noise: Option<Noise>,
+    /// What a one-shot run sends, instead of what the user types.
+    once: Option<Once>,
+}
+
+impl Client {
+    /// Initiates a connection to the server at `address`.
+    pub fn connect(
+        address: String,
+        username: String,
+        recorder: Option<Recorder>,
+        dialer: Dialer,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), INPUT)?);
+        let stream = dialer.dial(&poll, &address)?;
+        let (solved_tx, solved) = mpsc::channel();
+        Ok(Client {
+            poll,
+            waker,
+            dialer,
+            stream,
+            address,
+            direct: Direct::new(username.clone()),
+            username,
+            password: None,
+            keyring: None,
+            password_sent: false,
+            asking_password: false,
+            read_buffer: vec![0; READ_BUFFER],
+            resume_token: None,
+            suggested_name: None,
+            server_version: None,
+            challenge: None,
+            solved_tx,
+            solved,
+            state: ConnectionState::Connecting { attempt: 0 },
+            outbound: OutboundBuffer::new(),
+            pending: VecDeque::new(),
+            unacked: VecDeque::new(),
+            throttle: Throttle::default(),
+            rules: Rules::default(),
+            dnd: Dnd::default(),
+            dnd_away: false,
+            code_block: None,
+            inbound: Vec::new(),
+            writable_interest: true,
+            recorder,
+            log: None,
+            store: None,
+            scrollback: VecDeque::new(),
+            search: None,
+            room: chatlog::DEFAULT_ROOM.to_string(),
+            sequence: Sequence::default(),
+            #[cfg(feature = "noise")]
+            noise: None,
+            once: None,
+        })
+    }
+
+    /// Logs in with a password when the server asks for one.
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Keeps the password in the keyring once the server takes it, and removes it
+    /// from there if the server turns it down.
+    pub fn with_keyring(mut self, entry: Entry) -> Self {
+        self.keyring = Some(entry);
+        self
+    }
+
+    /// Reads up to `size` bytes from the server at a time.
+    pub fn with_read_buffer(mut self, size: usize) -> Self {
+        self.read_buffer = vec![0; size];
+        self
+    }
+
+    /// Notifies the user of incoming messages as `rules` say.
+    pub fn with_notifications(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Tells the server we are away while do not disturb is on.
+    pub fn with_dnd_away(mut self) -> Self {
+        self.dnd_away = true;
+        self
+    }
+
+    /// Keeps a local copy of the chat in `log`.
+    pub fn with_log(mut self, log: ChatLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Keeps the room messages received in `store`.
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Encrypts every connection with Noise.
+    #[cfg(feature = "noise")]
+    pub fn with_noise(mut self, noise: Noise) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// Runs the event loop until the user leaves.
+    pub fn run(&mut self) -> io::Result<()> {
+        // Stdin is read on a dedicated thread which wakes us up for every complete line
+        let input = input::spawn(Arc::clone(&self.waker));
+        self.event_loop(Some(input))
+    }
+
+    /// Runs the event loop for a one-shot run, without reading stdin: sends what
+    /// `once` says, shows the server's answers, and leaves once they are in. Fails if
+    /// they don't come, or a message doesn't get through.
+    pub fn run_once(&mut self, once: Once) -> io::Result<()> {
+        self.once = Some(once);
+        self.event_loop(None)
+    }
+
+    fn event_loop(&mut self, input: Option<Receiver<InputEvent>>) -> io::Result<()> {
+        // Ctrl-C clears the line being typed, a second one quits, as do SIGTERM and a
+        // terminal hangup. Either way we leave properly rather than just dying.
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+        self.poll
+            .registry()
+            .register(&mut signals, SIGNALS, Interest::READABLE)?;
+        let mut interrupted_at: Option<Instant> = None;
+
+        let mut events = Events::with_capacity(128);
+
+        // Main event loop
+        loop {
+            let now = Instant::now();
+            let timeout = [
+                self.state.poll_timeout(now),
+                self.direct.poll_timeout(now),
+                self.throttle.poll_timeout(now),
+                self.dnd.poll_timeout(now),
+                self.once.as_ref().map(|once| once.poll_timeout(now)),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            match self.poll.poll(&mut events, timeout) {
+                // A signal arrived, it is picked up on the next round
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+            self.direct.expire(self.poll.registry(), Instant::now())?;
+            self.count_down(Instant::now())?;
+            if let Some(missed) = self.dnd.expire(Instant::now()) {
+                self.dnd_over(missed)?;
+            }
+
+            if self.state.start_attempt(Instant::now()) {
+                output::info(&t!("reconnecting", address = self.address));
+                match self.dialer.dial(&self.poll, &self.address) {
+                    Ok(stream) => {
+                        self.stream = stream;
+                        self.writable_interest = true;
+                    }
+                    Err(e) => {
+                        output::error(&t!("connect-failed", error = e));
+                        self.state.connection_lost(Instant::now());
+                    }
+                }
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    SERVER => self.handle_server_event(event)?,
+
+                    INPUT => {
+                        self.send_proofs()?;
+                        // One-shot runs don't read stdin
+                        let Some(input) = &input else { continue };
+                        loop {
+                            // A single wake-up may cover several lines
+                            let line = match input.try_recv() {
+                                Ok(InputEvent::Line(line)) => line,
+                                Ok(InputEvent::Eof) | Err(TryRecvError::Disconnected) => {
+                                    return self.shutdown();
+                                }
+                                Err(TryRecvError::Empty) => break,
+                            };
+                            if !self.handle_input(&line)? {
+                                return self.shutdown();
+                            }
+                        }
+                    }
+
+                    SIGNALS => {
+                        for signal in signals.pending() {
+                            let again = interrupted_at.is_some_and(|at| at.elapsed() < QUIT_WINDOW);
+                            if signal != SIGINT || again || self.once.is_some() {
+                                return self.shutdown();
+                            }
+                            input::clear_line();
+                            self.code_block = None;
+                            // Get off the line the terminal echoed ^C on
+                            if !output::is_plain() {
+                                println!();
+                            }
+                            output::info(&t!("input-cleared"));
+                            interrupted_at = Some(Instant::now());
+                        }
+                    }
+
+                    token if self.direct.owns(token) => {
+                        self.direct.handle_event(self.poll.registry(), event)?
+                    }
+
+                    _token => output::info(&t!("spurious-event")),
+                }
+            }
+
+            let settled = self.unacked.is_empty() && !self.throttle.is_active();
+            let outcome =
+                (self.once.as_ref()).and_then(|once| once.outcome(settled, Instant::now()));
+            if let Some(outcome) = outcome {
+                self.shutdown()?;
+                return outcome.map_err(io::Error::other);
+            }
+        }
+    }
+
+    /// Answers the proof-of-work challenge of the current connection, once the thread
+    /// solving it is done. Solutions to the challenges of earlier connections are
+    /// dropped.
+    fn send_proofs(&mut self) -> io::Result<()> {
+        while let Ok((challenge, nonce)) = self.solved.try_recv() {
+            if self.challenge.as_deref() != Some(challenge.as_str()) {
+                continue;
+            }
+            self.challenge = None;
+            let proof = self.encode(&format!("/pow {nonce}\n"))?;
+            self.outbound.push(Outgoing::Proof(proof));
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Handles a line typed by the user. Returns false once the user leaves.
+    fn handle_input(&mut self, line: &str) -> io::Result<bool> {
+        if std::mem::take(&mut self.asking_password) {
+            input::hide_typing(false);
+            // The enter that ended the password wasn't shown either
+            println!();
+            self.password = Some(line.to_string());
+            let wire = self.encode(&format!("/password {line}\n"))?;
+            self.outbound.push(Outgoing::Password(wire));
+            self.password_sent = true;
+            self.flush()?;
+            return Ok(true);
+        }
+        // The lines of a code block keep their indentation
+        let input = match self.code_block.take() {
+            Some(mut block) => {
+                block.push('\n');
+                block.push_str(line.trim_end());
+                if spans::opens_block(&block) {
+                    self.code_block = Some(block);
+                    return Ok(true);
+                }
+                block
+            }
+            None => line.trim().to_string(),
+        };
+        let input = input.as_str();
+        if let Some(suggested) = self.suggested_name.take() {
+            // Enter takes the name the server offered, anything else is the one to try
+            let name = if input.is_empty() {
+                suggested
+            } else {
+                input.to_string()
+            };
+            self.rename(&name);
+            let wire = self.encode(&format!("{name}\n"))?;
+            self.outbound.push(Outgoing::Username(wire));
+            self.flush()?;
+            return Ok(true);
+        }
+        if input.is_empty() {
+            return Ok(true);
+        }
+        if !input.starts_with('/') && spans::opens_block(input) {
+            self.code_block = Some(input.to_string());
+            output::info(&t!("code-block"));
+            return Ok(true);
+        }
+        match commands::parse(input) {
+            Ok(Command::Send(message)) => {
+                // The server doesn't echo our own messages back
+                self.log(&format!("[{}]: {message}", self.username), Utc::now());
+                // Formatting goes beside the text, so clients that don't know it
+                // show the text without markers
+                let id = Uuid::new_v4();
+                let (text, spans) = spans::markup(&message);
+                if spans.is_empty() {
+                    self.send_message(&format!("@id={id} {message}"))?
+                } else {
+                    self.send_message(&format!("@id={id};fmt={} {text}", spans::tag(&spans)))?
+                }
+            }
+            Ok(Command::Server(message)) => self.send_message(&message)?,
+            Ok(Command::Msg { to, text }) => {
+                // Prefer the direct connection to the user, if there is one
+                if !self.direct.send(self.poll.registry(), &to, &text)? {
+                    self.send_message(&format!("/msg {to} {text}"))?
+                }
+            }
+            Ok(Command::Direct(to)) => self.open_direct(&to)?,
+            Ok(Command::Version) => {
+                let features = version::features();
+                output::info(&version::describe(
+                    "async-chat-client",
+                    version::VERSION,
+                    &features,
+                ));
+                match &self.server_version {
+                    Some(server) => output::info(server),
+                    None => output::info(&t!("version-unknown")),
+                }
+            }
+            Ok(Command::Raw) => match output::toggle_raw() {
+                true => output::info(&t!("raw-on")),
+                false => output::info(&t!("raw-off")),
+            },
+            Ok(Command::Search(query)) => self.search(&query),
+            Ok(Command::Dnd(duration)) => {
+                // At most a year, which a clock that can't hold leaves on until /dnd off
+                let until = duration.map(|duration| Instant::now().checked_add(duration));
+                self.dnd.start(until.flatten());
+                match duration.and_then(|duration| chrono::Duration::from_std(duration).ok()) {
+                    Some(duration) => {
+                        let until = (Local::now() + duration).format("%H:%M");
+                        output::info(&t!("dnd-on-until", time = until));
+                    }
+                    None => output::info(&t!("dnd-on")),
+                }
+                if self.dnd_away {
+                    self.send_message(&format!("/away {}", t!("dnd-away")))?;
+                }
+            }
+            Ok(Command::DndOff) => match self.dnd.stop() {
+                Some(missed) => self.dnd_over(missed)?,
+                None => output::info(&t!("dnd-not-on")),
+            },
+            Ok(Command::Next) => self.step_search(Search::next),
+            Ok(Command::Prev) => self.step_search(Search::prev),
+            Ok(Command::Connect(address)) => self.switch_server(&address)?,
+            Ok(Command::Help(None)) => {
+                output::info(&commands::help(None));
+                // The server lists the commands it accepts from us
+                if self.is_ready() {
+                    self.send_message("/help")?;
+                }
+            }
+            Ok(Command::Help(Some(topic))) => output::info(&commands::help(Some(&topic))),
+            Ok(Command::Quit) => return Ok(false),
+            Err(e) => output::error(&e),
+        }
+        Ok(true)
+    }
+
+    /// Searches the chat history, the logs of the current server or, without them,
+    /// this session's messages, and shows the newest match.
+    fn search(&mut self, query: &str) {
+        let lines = match &self.log {
+            Some(log) => match log.history() {
+                Ok(lines) => lines,
+                Err(e) => {
+                    output::error(&t!("search-failed", error = e));
+                    return;
+                }
+            },
+            None => self.scrollback.iter().cloned().collect(),
+        };
+        let search = Search::new(query, lines);
+        if search.is_empty() {
+            output::info(&t!("search-none", query = query));
+            self.search = None;
+            return;
+        }
+        show_match(&search);
+        self.search = Some(search);
+    }
+
+    /// Moves the last search to another match with `step`, and shows it.
+    fn step_search(&mut self, step: fn(&mut Search) -> bool) {
+        let Some(search) = self.search.as_mut() else {
+            output::info(&t!("search-nothing"));
+            return;
+        };
+        if step(search) {
+            show_match(search);
+        } else {
+            output::info(&t!("search-no-more", query = search.query()));
+        }
+    }
+
+    /// Opens a direct connection to a user, or asks the server to pass our offer on.
+    fn open_direct(&mut self, to: &str) -> io::Result<()> {
+        if to == self.username {
+            output::info(&t!("direct-self"));
+            return Ok(());
+        }
+        if self.dialer.is_proxied() {
+            output::info(&t!("direct-proxied"));
+            return Ok(());
+        }
+        // Offers are only valid while we are online, so they are never held back
+        if !self.is_ready() && !self.direct.is_invited_by(to) {
+            output::info(&t!("direct-offline"));
+            return Ok(());
+        }
+        match self.direct.open(self.poll.registry(), to)? {
+            Some(offer) => self.send_message(&offer),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops the current connection and connects to the server at `address` instead.
+    /// Messages that have not been sent yet go to the new server.
+    fn switch_server(&mut self, address: &str) -> io::Result<()> {
+        let stream = match self.dialer.dial(&self.poll, address) {
+            Ok(stream) => stream,
+            Err(e) => {
+                output::error(&t!("connect-address-failed", address = address, error = e));
+                return Ok(());
+            }
+        };
+        if !matches!(self.state, ConnectionState::Waiting { .. }) {
+            self.poll.registry().deregister(&mut self.stream)?;
+            self.requeue_unsent();
+        }
+
+        output::info(&t!("connecting-to", address = address));
+        self.address = address.to_string();
+        if let Some(log) = self.log.as_mut() {
+            log.switch_server(address);
+        }
+        if let Some(store) = self.store.as_mut() {
+            store.switch_server(address);
+        }
+        // Sessions don't carry over to another server, nor do keyring entries
+        self.resume_token = None;
+        self.keyring = None;
+        self.server_version = None;
+        self.room = chatlog::DEFAULT_ROOM.to_string();
+        self.sequence.reset();
+        self.stream = stream;
+        self.state = ConnectionState::Connecting { attempt: 0 };
+        self.writable_interest = true;
+        Ok(())
+    }
+
+    /// Queues a chat message, or holds on to it until the connection is back, or
+    /// until the server lets us send again.
+    fn send_message(&mut self, message: &str) -> io::Result<()> {
+        if !self.is_ready() {
+            output::info(&t!("pending", message = tags::split(message).1));
+            self.pending.push_back(format!("{message}\n"));
+            return Ok(());
+        }
+        let (tags, text) = tags::split(message);
+        if self.throttle.is_active() && tags.id.is_some() {
+            output::info(&t!("held", message = text));
+            self.throttle.hold(format!("{message}\n"));
+            return Ok(());
+        }
+        self.queue(format!("{message}\n"))?;
+        // Write as soon as the input is received rather than waiting for the next
+        // writable event, whatever doesn't fit is drained once the socket is writable again.
+        self.flush()
+    }
+
+    /// Shows how long until the server lets us send again, once a second, and sends
+    /// the messages held back when the time is up.
+    fn count_down(&mut self, now: Instant) -> io::Result<()> {
+        match self.throttle.tick(now) {
+            Tick::Wait => Ok(()),
+            Tick::Countdown(seconds) => {
+                output::info(&t!("throttle-countdown", seconds = seconds));
+                Ok(())
+            }
+            Tick::Release(held) => {
+                if held.is_empty() {
+                    return Ok(());
+                }
+                if !self.is_ready() {
+                    self.pending.extend(held);
+                    return Ok(());
+                }
+                output::info(&t!("throttle-released", count = held.len()));
+                for message in held {
+                    self.queue(message)?;
+                }
+                self.flush()
+            }
+        }
+    }
+
+    /// Queues a line typed by the user for the current connection, keeping track of
+    /// chat messages until the server acknowledges them.
+    fn queue(&mut self, text: String) -> io::Result<()> {
+        if tags::split(&text).0.id.is_some() {
+            self.unacked.push_back(text.clone());
+        }
+        let wire = self.encode(&text)?;
+        if carries_password(&text) {
+            // Kept out of recordings, and not sent again after a reconnect
+            self.outbound.push(Outgoing::Password(wire));
+            return Ok(());
+        }
+        self.outbound.push(Outgoing::Message { text, wire });
+        Ok(())
+    }
+
+    fn handle_server_event(&mut self, event: &Event) -> io::Result<()> {
+        match self.state {
+            ConnectionState::Waiting { .. } => return Ok(()),
+            ConnectionState::Connecting { .. } => {
+                // The non-blocking connect may still be in flight, or may have failed
+                match connect_result(&self.stream) {
+                    Ok(true) => self.on_connected()?,
+                    Ok(false) => return Ok(()),
+                    Err(e) => {
+                        output::error(&t!("connect-failed", error = e));
+                        return self.disconnect();
+                    }
+                }
+            }
+            ConnectionState::Connected => {}
+        }
+
+        if event.is_readable() {
+            self.read_from_server()?;
+        }
+        // Reading may have queued a reply, such as a proof-of-work solution
+        if (event.is_writable() || !self.outbound.is_empty()) && self.state.is_connected() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Starts the Noise handshake if there is one, or joins right away.
+    fn on_connected(&mut self) -> io::Result<()> {
+        self.state = ConnectionState::Connected;
+        self.inbound.clear();
+        #[cfg(feature = "noise")]
+        if let Some(noise) = self.noise.as_mut() {
+            // We join once the handshake completes
+            let hello = noise.start()?;
+            self.outbound.push(Outgoing::Handshake(hello));
+            return Ok(());
+        }
+        self.join()
+    }
+
+    /// Goes by another name from now on.
+    fn rename(&mut self, name: &str) {
+        self.username = name.to_string();
+        self.direct.rename(name);
+    }
+
+    /// Sends what we support and the username, or asks to resume our session if we
+    /// had one, and then everything typed while we were disconnected. If the session
+    /// can't be resumed, the server puts us back in our room and sends the messages
+    /// after the last one we saw instead.
+    fn join(&mut self) -> io::Result<()> {
+        let first = match (&self.resume_token, self.sequence.last()) {
+            (Some(token), Some(seq)) => {
+                format!("/resume {} {token} {seq} {}\n", self.username, self.room)
+            }
+            (Some(token), None) => format!("/resume {} {token}\n", self.username),
+            (None, _) => format!("{}\n", self.username),
+        };
+        let capabilities = self.encode(&format!("/caps {CAPABILITIES}\n"))?;
+        self.outbound.push(Outgoing::Username(capabilities));
+        let username = self.encode(&first)?;
+        self.outbound.push(Outgoing::Username(username));
+        if !self.pending.is_empty() {
+            output::info(&t!("sending-pending", count = self.pending.len()));
+        }
+        while let Some(text) = self.pending.pop_front() {
+            self.queue(text)?;
+        }
+        Ok(())
+    }
+
+    /// Whether messages can be sent: we are connected, and done with the Noise
+    /// handshake if there is one.
+    fn is_ready(&self) -> bool {
+        #[cfg(feature = "noise")]
+        if let Some(noise) = &self.noise {
+            return self.state.is_connected() && noise.is_ready();
+        }
+        self.state.is_connected()
+    }
+
+    /// Encodes a frame for the wire, encrypting it if the connection uses Noise.
+    fn encode(&mut self, text: &str) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "noise")]
+        if let Some(noise) = self.noise.as_mut() {
+            // Recording the encrypted bytes would be of no use, record what they carry.
+            // Passwords don't belong in a recording.
+            if let Some(recorder) = self.recorder.as_mut().filter(|_| !carries_password(text)) {
+                recorder.record(Direction::Outbound, text.as_bytes())?;
+            }
+            return noise.seal(text.as_bytes());
+        }
+        Ok(text.as_bytes().to_vec())
+    }
+
+    /// Decrypts data received over a Noise connection, answering the server's
+    /// handshake message along the way. Returns the plaintext.
+    #[cfg(feature = "noise")]
+    fn decrypt(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let Some(noise) = self.noise.as_mut() else {
+            return Ok(data.to_vec());
+        };
+        let mut plaintext = Vec::new();
+        if let Some(reply) = noise.receive(data, &mut plaintext)? {
+            self.outbound.push(Outgoing::Handshake(reply));
+            self.join()?;
+        }
+        Ok(plaintext)
+    }
+
+    /// Reads until the socket would block, as readiness events are edge-triggered.
+    fn read_from_server(&mut self) -> io::Result<()> {
+        // Taken out while reading, so handling what was read can borrow the client
+        let mut server_buffer = std::mem::take(&mut self.read_buffer);
+        let result = self.read_into(&mut server_buffer);
+        self.read_buffer = server_buffer;
+        result
+    }
+
+    fn read_into(&mut self, server_buffer: &mut [u8]) -> io::Result<()> {
+        loop {
+            match self.stream.read(server_buffer) {
+                Ok(0) => {
+                    output::info(&t!("closed-by-server"));
+                    return self.disconnect();
+                }
+                Ok(n) => {
+                    #[cfg(feature = "noise")]
+                    let data = &self.decrypt(&server_buffer[..n])?;
+                    #[cfg(not(feature = "noise"))]
+                    let data = &server_buffer[..n];
+                    if let Some(recorder) = self.recorder.as_mut().filter(|_| !data.is_empty()) {
+                        recorder.record(Direction::Inbound, data)?;
+                    }
+                    self.inbound.extend_from_slice(data);
+                    self.handle_lines()?;
+                }
+                Err(ref err) if would_block(err) => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    output::error(&t!("read-error", error = e));
+                    return self.disconnect();
+                }
+            }
+        }
+    }
+
+    /// Displays every complete line received from the server, answering any
+    /// proof-of-work challenge or password prompt along the way. The server's version,
+    /// session tokens and direct connection offers are taken note of instead of
+    /// displayed.
+    fn handle_lines(&mut self) -> io::Result<()> {
+        while let Some(end) = self.inbound.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.inbound.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let (tags, text) = tags::split(line.trim());
+            if std::mem::take(&mut self.password_sent) {
+                match text == WRONG_PASSWORD {
+                    true => self.forget_password(),
+                    false => self.keep_password(),
+                }
+            }
+            if let Some((challenge, bits)) = pow::parse_challenge(text) {
+                if bits > pow::MAX_BITS {
+                    // Left unanswered, the server gives up on us
+                    let error = t!("pow-too-hard", bits = bits, max = pow::MAX_BITS);
+                    output::error(&error);
+                    if let Some(once) = self.once.as_mut() {
+                        once.fail(error);
+                    }
+                    continue;
+                }
+                output::info(&t!("solving-pow", bits = bits));
+                self.challenge = Some(challenge.to_string());
+                let (solved, waker) = (self.solved_tx.clone(), Arc::clone(&self.waker));
+                pow::solve_in_background(challenge.to_string(), bits, solved, waker);
+            } else if let Some((server, features)) = version::parse_announcement(text) {
+                if !version::compatible(server) {
+                    output::info(&t!(
+                        "version-mismatch",
+                        server = server,
+                        client = version::VERSION
+                    ));
+                }
+                self.server_version = Some(version::describe("chat-server", server, &features));
+            } else if let Some((seq, id)) = parse_ack(text) {
+                // The number our own message got
+                let index = (self.unacked.iter())
+                    .position(|message| tags::split(message).0.id.as_deref() == id);
+                if let Some(message) = index.and_then(|index| self.unacked.remove(index)) {
+                    let line = own_line(&message, &self.username, seq);
+                    self.keep(seq, &line);
+                }
+                self.note_seq(seq)?;
+            } else if let Some((quota, limit, id)) = parse_quota(text) {
+                // Turned away for good, sending it again won't help
+                if id.is_some() {
+                    self.unacked
+                        .retain(|message| tags::split(message).0.id.as_deref() != id);
+                    if let Some(once) = self.once.as_mut() {
+                        once.fail(t!("once-not-sent"));
+                    }
+                }
+                match quota {
+                    "messages_per_day" => output::error(&t!("quota-messages", limit = limit)),
+                    "bytes_per_day" => output::error(&t!("quota-bytes", limit = limit)),
+                    "offline_lines" => output::info(&t!("quota-offline", limit = limit)),
+                    _ => output::error(&t!("quota-other", quota = quota, limit = limit)),
+                }
+            } else if let Some((limit, retry_after, id)) = parse_throttled(text) {
+                // A room message turned away is held back, along with those typed
+                // meanwhile, and sent again once we may
+                let index = id.and_then(|id| {
+                    (self.unacked.iter())
+                        .position(|message| tags::split(message).0.id.as_deref() == Some(id))
+                });
+                match index.and_then(|index| self.unacked.remove(index)) {
+                    Some(message) if limit == "messages" => {
+                        // Messages sent right after it are turned away too, once is enough
+                        let shown = self.throttle.is_active();
+                        let seconds =
+                            self.throttle
+                                .throttled(Some(message), retry_after, Instant::now());
+                        if !shown {
+                            output::info(&t!("throttled", seconds = seconds));
+                        }
+                    }
+                    _ => {
+                        let seconds = retry_after.as_millis().div_ceil(1000);
+                        output::error(&t!("throttled-other", limit = limit, seconds = seconds));
+                    }
+                }
+            } else if let Some(room) = text.strip_prefix("/room ") {
+                // Messages are numbered per room
+                if room != self.room {
+                    self.room = room.to_string();
+                    self.sequence.reset();
+                    output::info(&t!("room-entered", room = room));
+                }
+            } else if let Some(latest) = text.strip_prefix("/seq ").and_then(|seq| seq.parse().ok())
+            {
+                self.joined(latest)?;
+                let typed = (self.once.as_mut()).map(|once| once.joined(&self.room));
+                for line in typed.unwrap_or_default() {
+                    self.handle_input(&line)?;
+                }
+            } else if let Some(typed) =
+                (self.once.as_mut()).and_then(|once| once.pong(text, &self.room))
+            {
+                for line in typed {
+                    self.handle_input(&line)?;
+                }
+            } else if let Some(name) = text.strip_prefix("/name ") {
+                // We joined as a guest, or the server spells our name its own way
+                if self.username == GUEST {
+                    output::info(&t!("guest-name", username = name));
+                } else {
+                    output::info(&t!("name-given", username = name));
+                }
+                self.rename(name);
+            } else if let Some(name) = text.strip_prefix("/suggest ") {
+                // Ours is taken; the server told us so just before
+                if self.once.is_some() {
+                    return Err(io::Error::other(t!(
+                        "once-name-taken",
+                        username = self.username
+                    )));
+                }
+                output::info(&t!("name-suggested", username = name));
+                self.suggested_name = Some(name.to_string());
+            } else if let Some(token) = text.strip_prefix("/session ") {
+                self.resume_token = Some(token.to_string());
+            } else if text == "/password" {
+                if self.password.is_none() {
+                    self.password = self.keyring.as_mut().and_then(Entry::lookup);
+                }
+                match self.password.clone() {
+                    Some(password) => {
+                        let wire = self.encode(&format!("/password {password}\n"))?;
+                        self.outbound.push(Outgoing::Password(wire));
+                        self.password_sent = true;
+                    }
+                    None if self.once.is_some() => {
+                        return Err(io::Error::other(t!(
+                            "password-needed",
+                            username = self.username
+                        )))
+                    }
+                    // Typed without being shown, as the next line
+                    None if input::hide_typing(true) => {
+                        output::prompt(&t!("password", username = self.username))?;
+                        self.asking_password = true;
+                    }
+                    None => output::info(&t!("password-needed", username = self.username)),
+                }
+            } else if let Some((from, address, token)) = direct::parse_offer(text) {
+                if self.dialer.is_proxied() {
+                    output::info(&t!("direct-offer-proxied", user = from));
+                    continue;
+                }
+                self.direct
+                    .invited(self.poll.registry(), from, address, token)?;
+            } else {
+                // A one-shot run shows the server's answers to it, and nothing else
+                if let Some(once) = &self.once {
+                    if once.shows() && !text.starts_with('[') {
+                        show(&tags, text, Utc::now());
+                    }
+                    continue;
+                }
+                if let Some(seq) = tags.seq {
+                    if !self.note_seq(seq)? {
+                        continue;
+                    }
+                    self.keep(seq, line.trim());
+                }
+                let at = tags.time.unwrap_or_else(Utc::now);
+                self.alert(text);
+                if tags.action {
+                    self.log(&output::action_line(text), at);
+                } else if text.starts_with('[') {
+                    self.log(&spans::render_line(text, &tags.spans), at);
+                }
+                show(&tags, text, at);
+            }
+        }
+        Ok(())
+    }
+
+    /// Notifies the user of a chat message that the rules say calls for their
+    /// attention, or counts it while they don't want to be disturbed.
+    fn alert(&mut self, line: &str) {
+        let Some(from) = notify::sender(line) else {
+            return;
+        };
+        let alert = self.rules.alert(line, &self.username, &self.room);
+        if self.dnd.is_on() {
+            self.dnd.note(from, alert);
+        } else if alert.is_some() {
+            self.rules.notify(line);
+        }
+    }
+
+    /// Sums up what came in while do not disturb was on, now that it is over.
+    fn dnd_over(&mut self, missed: Missed) -> io::Result<()> {
+        let private: usize = missed.private.values().sum();
+        output::info(&t!(
+            "dnd-over",
+            messages = missed.messages,
+            mentions = missed.mentions,
+            private = private
+        ));
+        if private > 0 {
+            output::info(&t!("dnd-private-from", users = missed.senders()));
+        }
+        if self.dnd_away {
+            self.send_message("/away")?;
+        }
+        Ok(())
+    }
+
+    /// Takes note of the number of the latest message in the room we joined. If we
+    /// weren't in it already, the messages kept from last time are shown, and those
+    /// that came in since are asked for.
+    fn joined(&mut self, latest: u64) -> io::Result<()> {
+        let fresh = self.sequence.last().is_none();
+        self.sequence.joined(latest);
+        let Some(store) = self.store.as_mut().filter(|_| fresh) else {
+            return Ok(());
+        };
+        let result = store.last(&self.room).and_then(|last| match last {
+            // The server restarted, and numbers messages from 1 again
+            Some(last) if last > latest => store.clear(&self.room).map(|_| (Vec::new(), None)),
+            Some(last) => {
+                let recent = store.recent(&self.room, SHOWN_ON_JOINING)?;
+                let missing = store.missing(&self.room, last + 1..=latest)?;
+                Ok((recent, missing.first().cloned()))
+            }
+            None => Ok((Vec::new(), None)),
+        });
+        let (recent, missing) = match result {
+            Ok(found) => found,
+            Err(e) => {
+                output::error(&t!("store-failed", path = store.dir().display(), error = e));
+                self.store = None;
+                return Ok(());
+            }
+        };
+        if !recent.is_empty() {
+            output::info(&t!("history-earlier", room = self.room));
+            for line in &recent {
+                let (tags, text) = tags::split(line);
+                show(&tags, text, tags.time.unwrap_or_else(Utc::now));
+            }
+        }
+        if let Some(missing) = missing {
+            let count = missing.end() - missing.start() + 1;
+            output::info(&t!("history-fetching", count = count));
+            self.sequence.fetch(missing.clone());
+            self.send_message(&format!("/fetch {} {}", missing.start(), missing.end()))?;
+        }
+        Ok(())
+    }
+
+    /// Keeps a room message, as received with its tags. If that fails, keeping them
+    /// is turned off rather than complaining about every message.
+    fn keep(&mut self, seq: u64, line: &str) {
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        if let Err(e) = store.insert(&self.room, seq, line) {
+            output::error(&t!("store-failed", path = store.dir().display(), error = e));
+            self.store = None;
+        }
+    }
+
+    /// Takes note of a room message's sequence number, asking the server again for
+    /// any messages missed before it. Returns false if it is a duplicate.
+    fn note_seq(&mut self, seq: u64) -> io::Result<bool> {
+        match self.sequence.receive(seq) {
+            Received::Show => Ok(true),
+            Received::Duplicate => Ok(false),
+            Received::Gap(missed) => {
+                let count = missed.end() - missed.start() + 1;
+                output::info(&t!("missed-messages", count = count));
+                self.send_message(&format!("/fetch {} {}", missed.start(), missed.end()))?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Appends a chat message to the local log. If that fails, logging is turned off
+    /// rather than complaining about every message.
+    fn log(&mut self, line: &str, at: DateTime<Utc>) {
+        // Entries are a line each, even for messages of several
+        let line = &line.replace('\n', " ");
+        let Some(log) = self.log.as_mut() else {
+            if self.scrollback.len() == SCROLLBACK {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(chatlog::stamp(line, at));
+            return;
+        };
+        if let Err(e) = log.write(&self.room, line, at) {
+            output::error(&t!("log-failed", path = log.dir().display(), error = e));
+            self.log = None;
+        }
+    }
+
+    /// Drains the outbound buffer and keeps `WRITABLE` interest registered only while
+    /// there is data left to write.
+    fn flush(&mut self) -> io::Result<()> {
+        // Noise connections record frames as they are encrypted instead
+        #[cfg(feature = "noise")]
+        let mut recorder = self.recorder.as_mut().filter(|_| self.noise.is_none());
+        #[cfg(not(feature = "noise"))]
+        let mut recorder = self.recorder.as_mut();
+        let written = self
+            .outbound
+            .write_to(&mut self.stream, |frame, chunk| match recorder {
+                Some(_) if matches!(frame, Outgoing::Password(_)) => Ok(()),
+                Some(ref mut recorder) => recorder.record(Direction::Outbound, chunk),
+                None => Ok(()),
+            });
+        if let Err(e) = written {
+            output::error(&t!("write-error", error = e));
+            return self.disconnect();
+        }
+
+        let pending = !self.outbound.is_empty();
+        if pending != self.writable_interest {
+            let interest = if pending {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            self.poll
+                .registry()
+                .reregister(&mut self.stream, SERVER, interest)?;
+            self.writable_interest = pending;
+        }
+        Ok(())
+    }
+
+    /// Keeps the password the server took in the keyring, or says why it couldn't.
+    fn keep_password(&mut self) {
+        let (Some(entry), Some(password)) = (self.keyring.as_mut(), &self.password) else {
+            return;
+        };
+        if let Err(e) = entry.keep(password) {
+            let account = entry.account();
+            output::error(&t!("keyring-failed", account = account, error = e));
+        }
+    }
+
+    /// Drops the password the server turned down, from the keyring too, rather than
+    /// sending it again on every reconnect, which would only get us blocked.
+    fn forget_password(&mut self) {
+        self.password = None;
+        if let Some(entry) = self.keyring.as_mut() {
+            if let Err(e) = entry.forget() {
+                let account = entry.account();
+                output::error(&t!("keyring-clear-failed", account = account, error = e));
+            }
+        }
+    }
+
+    /// Stops polling a dropped connection and schedules a reconnect attempt. Messages
+    /// that did not make it out are kept for after the reconnect.
+    fn disconnect(&mut self) -> io::Result<()> {
+        self.poll.registry().deregister(&mut self.stream)?;
+        // The next connection gets a challenge of its own
+        self.challenge = None;
+        self.requeue_unsent();
+
+        self.state.connection_lost(Instant::now());
+        if let ConnectionState::Waiting { retry_at, .. } = self.state {
+            let delay = retry_at.saturating_duration_since(Instant::now());
+            let seconds = format!("{:.1}", delay.as_secs_f32());
+            output::info(&t!("retrying", seconds = seconds));
+        }
+        Ok(())
+    }
+
+    /// Leaves the chat: waits (up to [`SHUTDOWN_TIMEOUT`]) for the server to
+    /// acknowledge our messages, then tells it with `/leave`, so it ends our session
+    /// rather than keeping it for a resume, and closes the connection once that is
+    /// written.
+    fn shutdown(&mut self) -> io::Result<()> {
+        if self.asking_password {
+            input::hide_typing(false);
+        }
+        output::info(&t!("disconnecting"));
+        if self.is_ready() {
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            self.settle(deadline)?;
+            // The server closes the connection on `/leave`, so we stop reading
+            if self.state.is_connected() {
+                self.queue("/leave\n".to_string())?;
+                let mut events = Events::with_capacity(16);
+                loop {
+                    self.flush()?;
+                    if self.outbound.is_empty() || !self.state.is_connected() {
+                        break;
+                    }
+                    let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                        break;
+                    };
+                    match self.poll.poll(&mut events, Some(left)) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        result => result?,
+                    }
+                }
+            }
+            if self.state.is_connected() {
+                let _ = self.stream.shutdown(Shutdown::Write);
+            }
+        }
+        self.requeue_unsent();
+        // Our own `/leave` doesn't count
+        let unsent = self
+            .pending
+            .iter()
+            .filter(|m| m.as_str() != "/leave\n")
+            .count();
+        if unsent > 0 {
+            output::info(&t!("unsent", count = unsent));
+        }
+        Ok(())
+    }
+
+    /// Writes what is queued and reads what comes in until every chat message has
+    /// been written and acknowledged, the connection is lost or `deadline` passes.
+    fn settle(&mut self, deadline: Instant) -> io::Result<()> {
+        let mut events = Events::with_capacity(16);
+        loop {
+            self.flush()?;
+            let settled = self.outbound.is_empty() && self.unacked.is_empty();
+            if settled || !self.state.is_connected() {
+                return Ok(());
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(());
+            };
+            match self.poll.poll(&mut events, Some(left)) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+            if self.state.is_connected() {
+                self.read_from_server()?;
+            }
+        }
+    }
+
+    /// Moves messages that did not make it out, or weren't acknowledged, back to the
+    /// front of the pending queue.
+    fn requeue_unsent(&mut self) {
+        // Chat messages are among the unacknowledged ones, whether they were written
+        // or not
+        let unsent: Vec<String> = self
+            .outbound
+            .take_unsent()
+            .filter_map(|frame| match frame {
+                Outgoing::Message { text, .. } if tags::split(&text).0.id.is_none() => Some(text),
+                _ => None,
+            })
+            .collect();
+        let unacked = std::mem::take(&mut self.unacked);
+        let held: Vec<String> = self.throttle.take().collect();
+        for message in held.into_iter().chain(unacked).chain(unsent).rev() {
+            self.pending.push_front(message);
+        }
+    }
+}
+
+/// Whether a line sent to the server carries a password: the answer to `/password`,
+/// or `/register <password>` typed by the user.
+fn carries_password(text: &str) -> bool {
+    text.starts_with("/password ") || text.starts_with("/register ")
+}
+
+/// Shows a chat line received from the server, without its tags, as they say.
+fn show(tags: &Tags, text: &str, at: DateTime<Utc>) {
+    if tags.action {
+        output::action(text, at);
+    } else if tags.whisper {
+        output::whisper(text, &tags.spans, at);
+    } else {
+        output::display(text, &tags.spans, at);
+    }
+}
+
+/// One of our room messages as others received it, with the number the server
+/// acknowledged it with, to keep it with theirs: `@id=<uuid> hi` becomes
+/// `@time=...;seq=7;id=<uuid> [alice]: hi`.
+fn own_line(message: &str, username: &str, seq: u64) -> String {
+    let message = message.trim_end();
+    let (raw, text) = (message.strip_prefix('@'))
+        .and_then(|message| message.split_once(' '))
+        .unwrap_or(("", message));
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let tags = [format!("time={time};seq={seq}"), raw.to_string()];
+    let tags: Vec<&str> = (tags.iter().map(String::as_str))
+        .filter(|tags| !tags.is_empty())
+        .collect();
+    format!("@{} [{username}]: {text}", tags.join(";"))
+}
+
+/// Parses the server's `/ack <seq> [id]` for one of our room messages.
+fn parse_ack(line: &str) -> Option<(u64, Option<&str>)> {
+    let mut parts = line.strip_prefix("/ack ")?.split_whitespace();
+    let seq = parts.next()?.parse().ok()?;
+    Some((seq, parts.next()))
+}
+
+/// Parses `/quota <quota> <limit> [id]`, telling us we reached a quota, and which of
+/// our messages was turned away over it.
+fn parse_quota(line: &str) -> Option<(&str, u64, Option<&str>)> {
+    let mut parts = line.strip_prefix("/quota ")?.split_whitespace();
+    let quota = parts.next()?;
+    let limit = parts.next()?.parse().ok()?;
+    Some((quota, limit, parts.next()))
+}
+
+/// Parses `/throttled <limit> <retry-after> [id]`, telling us we are doing something
+/// faster than the server's rate limit allows, how long until we may again (in
+/// milliseconds), and which of our room messages was turned away.
+fn parse_throttled(line: &str) -> Option<(&str, Duration, Option<&str>)> {
+    let mut parts = line.strip_prefix("/throttled ")?.split_whitespace();
+    let limit = parts.next()?;
+    let retry_after = Duration::from_millis(parts.next()?.parse().ok()?);
+    Some((limit, retry_after, parts.next()))
+}
+
+/// Shows the current match of a search.
+fn show_match(search: &Search) {
+    let (position, count) = search.position();
+    output::info(&t!(
+        "search-match",
+        position = position,
+        count = count,
+        query = search.query()
+    ));
+    output::info(&search.render());
+}
+
+/// Resolves a server's `host:port`.
+pub fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Could not resolve {address}"),
+        )
+    })
+}
+
+/// Initiates a non-blocking connection to the server and registers it for polling.
+fn open(poll: &Poll, address: SocketAddr) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(address)?;
+    poll.registry()
+        .register(&mut stream, SERVER, Interest::READABLE | Interest::WRITABLE)?;
+    Ok(stream)
+}
+
+/// Checks whether a non-blocking connect has completed (`Ok(true)`), is still in
+/// progress (`Ok(false)`) or has failed.
+pub fn connect_result(stream: &TcpStream) -> io::Result<bool> {
+    if let Some(e) = stream.take_error()? {
+        return Err(e);
+    }
+    match stream.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn would_block(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
@@ -0,0 +1,237 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::dnd;
+use crate::i18n::{self, t};
+
+/// A line typed at the prompt, parsed and validated.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Show help for every command, or for a single one.
+    Help(Option<String>),
+    /// Leave the chat and exit.
+    Quit,
+    /// Show the client's and the server's versions.
+    Version,
+    /// Connect to a different server (`host:port`).
+    Connect(String),
+    /// Send a private message to a single user.
+    Msg { to: String, text: String },
+    /// Connect straight to a user's client, for private messages that skip the server.
+    Direct(String),
+    /// Switch between showing formatted messages styled and as typed.
+    Raw,
+    /// Search the chat history for some text.
+    Search(String),
+    /// Show the next older match of the last search.
+    Next,
+    /// Show the next newer match of the last search.
+    Prev,
+    /// Turn do not disturb on, for a while or until it is turned off.
+    Dnd(Option<Duration>),
+    /// Turn do not disturb off.
+    DndOff,
+    /// Send a chat message. Anything that isn't a command is a message.
+    Send(String),
+    /// Any other `/command`, forwarded to the server as typed.
+    Server(String),
+}
+
+/// Description of a client command, used both for parsing and to generate `/help`.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    parse: fn(&str) -> Result<Command, String>,
+}
+
+impl CommandSpec {
+    /// What the command does, in the user's language.
+    pub fn summary(&self) -> String {
+        i18n::text(&format!("command-{}", self.name), &[])
+    }
+}
+
+/// Registry of the commands handled by the client. Their summaries are in the
+/// locale files, as `command-<name>`.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        usage: "/help [command]",
+        parse: |args| Ok(Command::Help(optional_arg(args))),
+    },
+    CommandSpec {
+        name: "quit",
+        usage: "/quit",
+        parse: |args| no_args(args).map(|_| Command::Quit),
+    },
+    CommandSpec {
+        name: "version",
+        usage: "/version",
+        parse: |args| no_args(args).map(|_| Command::Version),
+    },
+    CommandSpec {
+        name: "connect",
+        usage: "/connect <host:port>",
+        parse: |args| match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [address] if address.contains(':') => Ok(Command::Connect(address.to_string())),
+            _ => Err(t!("args-address")),
+        },
+    },
+    CommandSpec {
+        name: "msg",
+        usage: "/msg <user> <message>",
+        parse: |args| match args.split_once(' ') {
+            Some((to, text)) if !text.trim().is_empty() => Ok(Command::Msg {
+                to: to.to_string(),
+                text: text.trim().to_string(),
+            }),
+            _ => Err(t!("args-message")),
+        },
+    },
+    CommandSpec {
+        name: "direct",
+        usage: "/direct <user>",
+        parse: |args| match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [user] => Ok(Command::Direct(user.to_string())),
+            _ => Err(t!("args-user")),
+        },
+    },
+    CommandSpec {
+        name: "raw",
+        usage: "/raw",
+        parse: |args| no_args(args).map(|_| Command::Raw),
+    },
+    CommandSpec {
+        name: "search",
+        usage: "/search <text>",
+        parse: |args| match optional_arg(args) {
+            Some(text) => Ok(Command::Search(text)),
+            None => Err(t!("args-text")),
+        },
+    },
+    CommandSpec {
+        name: "next",
+        usage: "/next",
+        parse: |args| no_args(args).map(|_| Command::Next),
+    },
+    CommandSpec {
+        name: "prev",
+        usage: "/prev",
+        parse: |args| no_args(args).map(|_| Command::Prev),
+    },
+    CommandSpec {
+        name: "dnd",
+        usage: "/dnd [duration|off]",
+        parse: |args| match args {
+            "" => Ok(Command::Dnd(None)),
+            "off" => Ok(Command::DndOff),
+            duration => (dnd::parse_duration(duration))
+                .map(|duration| Command::Dnd(Some(duration)))
+                .ok_or_else(|| t!("args-duration")),
+        },
+    },
+];
+
+/// Parses a line of user input. Bare text is sent as a chat message, `/name args`
+/// is looked up in [`COMMANDS`] and anything else starting with `/` goes to the server.
+pub fn parse(input: &str) -> Result<Command, String> {
+    let Some(command) = input.strip_prefix('/') else {
+        return Ok(Command::Send(input.to_string()));
+    };
+    let (name, args) = command
+        .split_once(' ')
+        .map(|(name, args)| (name, args.trim()))
+        .unwrap_or((command, ""));
+    match find(name) {
+        Some(spec) => {
+            (spec.parse)(args).map_err(|e| t!("usage-error", error = e, usage = spec.usage))
+        }
+        None => Ok(Command::Server(input.to_string())),
+    }
+}
+
+/// Renders the help text for every command, or for `topic` alone.
+pub fn help(topic: Option<&str>) -> String {
+    let mut out = String::new();
+    match topic.map(|topic| topic.trim_start_matches('/')) {
+        Some(name) => match find(name) {
+            Some(spec) => {
+                let _ = write!(out, "{} - {}", spec.usage, spec.summary());
+            }
+            None => {
+                out.push_str(&t!("help-unknown", name = name));
+            }
+        },
+        None => {
+            out.push_str(&t!("help-intro"));
+            for spec in COMMANDS {
+                let _ = write!(out, "\n  {:<24} {}", spec.usage, spec.summary());
+            }
+            let _ = write!(out, "\n{}", t!("help-outro"));
+        }
+    }
+    out
+}
+
+fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+fn optional_arg(args: &str) -> Option<String> {
+    (!args.is_empty()).then(|| args.to_string())
+}
+
+fn no_args(args: &str) -> Result<(), String> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err(t!("args-none"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bare_text_is_a_message() {
+        assert_eq!(
+            parse("hello there"),
+            Ok(Command::Send("hello there".into()))
+        );
+    }
+
+    #[test]
+    fn test_command_arguments_are_validated() {
+        assert_eq!(
+            parse("/msg bob hi bob"),
+            Ok(Command::Msg {
+                to: "bob".into(),
+                text: "hi bob".into()
+            })
+        );
+        assert!(parse("/msg bob").is_err());
+        assert!(parse("/quit now").is_err());
+        assert!(parse("/connect").is_err());
+        assert!(parse("/direct bob carol").is_err());
+        assert!(parse("/search").is_err());
+        assert_eq!(
+            parse("/dnd 1h"),
+            Ok(Command::Dnd(Some(Duration::from_secs(3600))))
+        );
+        assert!(parse("/dnd later").is_err());
+    }
+
+    #[test]
+    fn test_unknown_commands_are_forwarded() {
+        assert_eq!(parse("/who"), Ok(Command::Server("/who".into())));
+    }
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let help = help(None);
+        for spec in COMMANDS {
+            assert!(help.contains(spec.usage));
+        }
+    }
+}
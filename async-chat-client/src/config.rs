@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::notify::Rules;
+
+/// The client's config file, in TOML. It says when the user is notified of incoming
+/// messages, and who they may be on which servers:
+///
+/// ```toml
+/// [notifications]
+/// mentions = true
+/// private = true
+/// keywords = ["deploy", "outage"]
+/// senders = ["bob"]
+/// rooms = ["alerts"]
+/// muted_rooms = ["random"]
+/// bell = true
+/// desktop = false
+///
+/// [profiles.work]
+/// host = "chat.example.com"
+/// port = 12345
+/// username = "alice.smith"
+/// auth = "password"
+/// noise_key = "/home/alice/.config/simple-chat/work.key"
+/// server_key = "3b6a27bc..."
+///
+/// [profiles.lan]
+/// auth = "guest"
+/// ```
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub notifications: Rules,
+    /// Named identities, picked with `--profile <name>`.
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named identity: who to be on which server, and how to prove it. What the
+/// command line or the environment says goes first.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub auth: Auth,
+    /// The file with the Noise key pair, for builds with the `noise` feature.
+    pub noise_key: Option<PathBuf>,
+    /// The Noise server's public key (hex encoded).
+    pub server_key: Option<String>,
+}
+
+/// How a profile logs in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    /// With the username alone.
+    #[default]
+    None,
+    /// With the password of the username's account, kept in the desktop's keyring as
+    /// `password:<profile>`, and asked for the first time.
+    Password,
+    /// As a guest, under a name the server picks.
+    Guest,
+}
+
+impl Config {
+    /// Where the config file is looked for by default: `$XDG_CONFIG_HOME/simple-chat/
+    /// client.toml`, or `~/.config/simple-chat/client.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+        Some(config.join("simple-chat").join("client.toml"))
+    }
+
+    /// Reads the config file at `path`, or the default one if there is one. Without
+    /// a file, everything is as if it were empty.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|e| format!("{}: {e}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {e}", path.display())),
+        }
+    }
+
+    /// The profile called `name`.
+    pub fn profile(&self, name: &str) -> Result<&Profile, String> {
+        self.profiles.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            match names.is_empty() {
+                true => format!("No profile {name}: the config file has none"),
+                false => format!("No profile {name} (there are {})", names.join(", ")),
+            }
+        })
+    }
+
+    fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| e.message().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_fills_in_defaults() {
+        let config = Config::parse("[notifications]\nkeywords = [\"deploy\"]").unwrap();
+        assert_eq!(config.notifications.keywords, ["deploy"]);
+        assert!(config.notifications.mentions && config.notifications.bell);
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("[notifications]\nsound = true").is_err());
+    }
+
+    #[test]
+    fn test_profiles_are_picked_by_name() {
+        let config = Config::parse(
+            "[profiles.work]\nhost = \"chat.example.com\"\nusername = \"alice\"\nauth = \"password\"\n\
+             [profiles.lan]\nauth = \"guest\"",
+        )
+        .unwrap();
+        let work = config.profile("work").unwrap();
+        assert_eq!(work.host.as_deref(), Some("chat.example.com"));
+        assert_eq!((work.port, work.auth), (None, Auth::Password));
+        assert_eq!(config.profile("lan").unwrap().auth, Auth::Guest);
+        assert_eq!(
+            config.profile("home").unwrap_err(),
+            "No profile home (there are lan, work)"
+        );
+        assert!(Config::parse("[profiles.x]\nauth = \"token\"").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+/// Delay before the first reconnect attempt. Doubled on every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound on the delay between two reconnect attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Reconnect state machine for the connection to the chat server.
+///
+/// `Connecting` -> `Connected` once the username has been sent. A lost connection (or a
+/// failed attempt) moves to `Waiting`, which becomes `Connecting` again once the backoff
+/// delay has elapsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection attempt is in flight; `attempt` is 0 for the initial connect.
+    Connecting { attempt: u32 },
+    /// Connected and registered with the server.
+    Connected,
+    /// Disconnected, waiting until `retry_at` before attempting to reconnect.
+    Waiting { attempt: u32, retry_at: Instant },
+}
+
+impl ConnectionState {
+    /// Returns true if messages can be written to the server right away.
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::Connected)
+    }
+
+    /// Transitions into `Waiting` after the connection dropped or an attempt failed.
+    pub fn connection_lost(&mut self, now: Instant) {
+        let attempt = match *self {
+            ConnectionState::Connecting { attempt } => attempt + 1,
+            ConnectionState::Connected => 0,
+            ConnectionState::Waiting { .. } => return,
+        };
+        *self = ConnectionState::Waiting {
+            attempt,
+            retry_at: now + backoff(attempt),
+        };
+    }
+
+    /// Returns true (and moves to `Connecting`) if a reconnect attempt is due.
+    pub fn start_attempt(&mut self, now: Instant) -> bool {
+        match *self {
+            ConnectionState::Waiting { attempt, retry_at } if now >= retry_at => {
+                *self = ConnectionState::Connecting { attempt };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How long the event loop may block before the next reconnect attempt is due.
+    pub fn poll_timeout(&self, now: Instant) -> Option<Duration> {
+        match *self {
+            ConnectionState::Waiting { retry_at, .. } => {
+                Some(retry_at.saturating_duration_since(now))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Exponential backoff for the given attempt, capped at [`MAX_BACKOFF`].
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(1), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(20), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_reconnect_transitions() {
+        let now = Instant::now();
+        let mut state = ConnectionState::Connected;
+
+        state.connection_lost(now);
+        assert!(!state.is_connected());
+        assert!(!state.start_attempt(now));
+        assert!(state.start_attempt(now + INITIAL_BACKOFF));
+        assert_eq!(state, ConnectionState::Connecting { attempt: 0 });
+
+        // A failed attempt backs off further
+        state.connection_lost(now);
+        assert_eq!(state.poll_timeout(now), Some(INITIAL_BACKOFF * 2));
+    }
+}
@@ -0,0 +1,218 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::chatlog::ChatLog;
+
+/// What encrypted lines start with, telling them apart from those written before
+/// encryption was turned on (and encrypted then, see [`encrypt_existing`]).
+const MARKER: char = '~';
+/// Encrypted into the key file, to tell whether a passphrase is the right one.
+const CHECK: &str = "simple-chat";
+/// Bytes of salt the key is derived with.
+const SALT_SIZE: usize = 16;
+/// Bytes of a ChaCha20-Poly1305 nonce.
+const NONCE_SIZE: usize = 12;
+
+/// Encrypts the chat kept on disk, the logs and the local history, line by line, so
+/// files stay append-only. The key is derived from a passphrase with Argon2id.
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, String> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(Cipher {
+            cipher: ChaCha20Poly1305::new(&key),
+        })
+    }
+
+    /// Encrypts a line, with a nonce of its own: `~<hex of nonce and ciphertext>`.
+    pub fn encrypt(&self, line: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = (self.cipher.encrypt(&nonce, line.as_bytes()))
+            .expect("a line fits in ChaCha20-Poly1305");
+        let mut out = String::from(MARKER);
+        out.push_str(&hex(&nonce));
+        out.push_str(&hex(&sealed));
+        out
+    }
+
+    /// Decrypts a line written by [`Cipher::encrypt`]. Lines that don't decrypt, e.g.
+    /// written with another key, don't come back, and neither do unencrypted ones:
+    /// anyone who can write to the files could have slipped them in.
+    pub fn decrypt(&self, line: &str) -> Option<String> {
+        let sealed = unhex(line.strip_prefix(MARKER)?)?;
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_SIZE);
+        let plain = (self.cipher)
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .ok()?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+/// Where the key file is by default: `$XDG_DATA_HOME/simple-chat/key`, or
+/// `~/.local/share/simple-chat/key`. It holds the salt the key is derived with, and
+/// a check that the passphrase is right, but not the key.
+pub fn default_key_path() -> Option<PathBuf> {
+    Some(ChatLog::default_root()?.with_file_name("key"))
+}
+
+/// Sets up encryption with a new passphrase, writing the key file.
+pub fn create(path: &Path, passphrase: &str) -> Result<Cipher, String> {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = Cipher::derive(passphrase, &salt)?;
+    let text = format!("{}\n{}\n", hex(&salt), cipher.encrypt(CHECK));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, text).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(cipher)
+}
+
+/// Derives the key from `passphrase` and the key file, if the passphrase is right.
+pub fn unlock(path: &Path, passphrase: &str) -> Result<Cipher, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut lines = text.lines();
+    let (Some(salt), Some(check)) = (lines.next().and_then(unhex), lines.next()) else {
+        return Err(format!("{}: not a key file", path.display()));
+    };
+    let cipher = Cipher::derive(passphrase, &salt)?;
+    match cipher.decrypt(check) {
+        Some(check) if check == CHECK => Ok(cipher),
+        _ => Err("Wrong passphrase".to_string()),
+    }
+}
+
+/// Encrypts what was kept before encryption was turned on: the unencrypted lines
+/// of every file in the directories (one per server) under `root`, a root of the
+/// logs or the history.
+pub fn encrypt_existing(root: &Path, cipher: &Cipher) -> io::Result<()> {
+    let servers = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for server in servers {
+        let server = server?.path();
+        if !server.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(&server)? {
+            let path = file?.path();
+            if path.is_file() {
+                encrypt_file(&path, cipher)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encrypts a file's unencrypted lines, replacing it once they all are.
+fn encrypt_file(path: &Path, cipher: &Cipher) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    if text.lines().all(|line| line.starts_with(MARKER)) {
+        return Ok(());
+    }
+    let mut out = String::new();
+    for line in text.lines() {
+        match line.starts_with(MARKER) {
+            true => out.push_str(line),
+            false => out.push_str(&cipher.encrypt(line)),
+        }
+        out.push('\n');
+    }
+    let temp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&temp, out)?;
+    fs::rename(&temp, path)
+}
+
+/// A random 128-bit token from the operating system's generator, hex encoded, for
+/// secrets others mustn't guess.
+pub fn random_token() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_tokens_differ() {
+        let token = random_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, random_token());
+    }
+
+    #[test]
+    fn test_lines_need_the_right_passphrase() {
+        let path = std::env::temp_dir().join(format!("key-{}", std::process::id()));
+        let cipher = create(&path, "correct horse").unwrap();
+        let line = cipher.encrypt("2026-10-15 09:00:00 [bob]: hi");
+        assert!(line.starts_with(MARKER) && !line.contains("bob"));
+        assert_ne!(cipher.encrypt("[bob]: hi"), cipher.encrypt("[bob]: hi"));
+
+        let unlocked = unlock(&path, "correct horse").unwrap();
+        assert_eq!(
+            unlocked.decrypt(&line).as_deref(),
+            Some("2026-10-15 09:00:00 [bob]: hi")
+        );
+        assert_eq!(unlocked.decrypt("[bob]: plain"), None);
+        assert_eq!(unlocked.decrypt("~00ff").as_deref(), None);
+        assert_eq!(
+            unlock(&path, "wrong horse").err().as_deref(),
+            Some("Wrong passphrase")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_existing_lines_are_encrypted() {
+        let root = std::env::temp_dir().join(format!("crypt-{}", std::process::id()));
+        let path = root.join("127.0.0.1_12345").join("lobby.log");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let key = root.join("key");
+        let cipher = create(&key, "correct horse").unwrap();
+        let sealed = cipher.encrypt("[carol]: later");
+        fs::write(&path, format!("[bob]: before\n{sealed}\n")).unwrap();
+
+        encrypt_existing(&root, &cipher).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("bob") && text.ends_with(&format!("{sealed}\n")));
+        let lines: Vec<_> = text
+            .lines()
+            .filter_map(|line| cipher.decrypt(line))
+            .collect();
+        assert_eq!(lines, ["[bob]: before", "[carol]: later"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use mio::event::Event;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Interest, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::client::{connect_result, would_block};
+use crate::crypt;
+use crate::i18n::t;
+use crate::outbound::OutboundBuffer;
+use crate::output;
+
+/// Token of the listener peers connect to. Peer connections use the tokens after it,
+/// the ones before belong to the client's server connection, stdin and signals.
+pub const LISTENER: Token = Token(3);
+
+/// How long a peer gets to connect, or to say who it is, before private messages
+/// fall back to the server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an offer waits for the other user to accept it.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+const BUF_SIZE: usize = 512;
+
+/// An offer we made to another user, waiting for their client to connect.
+struct Offer {
+    token: String,
+    expires: Instant,
+}
+
+/// An offer another user made to us, waiting for our user to accept it.
+struct Invitation {
+    address: SocketAddr,
+    token: String,
+}
+
+enum PeerState {
+    /// We are connecting to the peer's listener.
+    Connecting {
+        token: String,
+        deadline: Instant,
+    },
+    /// The peer connected to our listener and hasn't said who it is yet.
+    Hello {
+        deadline: Instant,
+    },
+    Connected,
+}
+
+/// A direct connection to another user's client.
+struct Peer {
+    /// The peer's username, empty until an incoming peer has said who it is.
+    name: String,
+    stream: TcpStream,
+    state: PeerState,
+    outbound: OutboundBuffer<Vec<u8>>,
+    /// Data received from the peer that doesn't make up a complete line yet.
+    inbound: Vec<u8>,
+}
+
+/// Direct connections between clients, for private messages that don't go through
+/// the server.
+///
+/// The server only brokers them: `/direct <user>` opens a listener and sends
+/// `/direct <user> <port> <token>` to the server, which passes it on to that user
+/// along with the address it sees us connect from. Once they accept, their client
+/// connects to ours and opens with `<token> <username>`. Every line after that is a
+/// private message. When the peer can't be reached, e.g. because of NAT, private
+/// messages simply keep going through the server.
+pub struct Direct {
+    username: String,
+    listener: Option<TcpListener>,
+    /// Offers we made, by the username they were made to.
+    offers: HashMap<String, Offer>,
+    /// Offers made to us, by the username they came from.
+    invitations: HashMap<String, Invitation>,
+    peers: HashMap<Token, Peer>,
+    next_token: usize,
+}
+
+impl Direct {
+    pub fn new(username: String) -> Self {
+        Direct {
+            username,
+            listener: None,
+            offers: HashMap::new(),
+            invitations: HashMap::new(),
+            peers: HashMap::new(),
+            next_token: LISTENER.0 + 1,
+        }
+    }
+
+    /// Introduces us to peers under another name, e.g. the one the server gave a guest.
+    pub fn rename(&mut self, username: &str) {
+        self.username = username.to_string();
+    }
+
+    /// Returns true if `token` belongs to the listener or a peer connection.
+    pub fn owns(&self, token: Token) -> bool {
+        token == LISTENER || self.peers.contains_key(&token)
+    }
+
+    /// Returns true if `from` offered us a direct connection.
+    pub fn is_invited_by(&self, from: &str) -> bool {
+        self.invitations.contains_key(from)
+    }
+
+    /// Opens a direct connection to `to`: accepts their offer if they made one, or
+    /// makes one. Returns the line to send to the server for a new offer.
+    pub fn open(&mut self, registry: &Registry, to: &str) -> io::Result<Option<String>> {
+        if self.peer(to).is_some() {
+            output::info(&t!("direct-already", user = to));
+            return Ok(None);
+        }
+        if let Some(invitation) = self.invitations.remove(to) {
+            self.connect(registry, to, invitation)?;
+            return Ok(None);
+        }
+
+        if self.listener.is_none() {
+            let mut listener = TcpListener::bind("0.0.0.0:0".parse().unwrap())?;
+            registry.register(&mut listener, LISTENER, Interest::READABLE)?;
+            self.listener = Some(listener);
+        }
+        let port = self.listener.as_ref().unwrap().local_addr()?.port();
+        // Only the user the offer was made to may use it
+        let token = crypt::random_token();
+        let offer = Offer {
+            token: token.clone(),
+            expires: Instant::now() + OFFER_TIMEOUT,
+        };
+        self.offers.insert(to.to_string(), offer);
+        output::info(&t!("direct-asked", user = to));
+        Ok(Some(format!("/direct {to} {port} {token}")))
+    }
+
+    /// Takes note of an offer from `from`, relayed by the server. If we made one to
+    /// them as well, the user with the smaller name connects to the other.
+    pub fn invited(
+        &mut self,
+        registry: &Registry,
+        from: &str,
+        address: SocketAddr,
+        token: &str,
+    ) -> io::Result<()> {
+        let invitation = Invitation {
+            address,
+            token: token.to_string(),
+        };
+        if self.offers.contains_key(from) && self.username.as_str() < from {
+            self.offers.remove(from);
+            return self.connect(registry, from, invitation);
+        }
+        if !self.offers.contains_key(from) {
+            output::info(&t!("direct-offer", user = from));
+        }
+        self.invitations.insert(from.to_string(), invitation);
+        Ok(())
+    }
+
+    /// Sends a private message to `to` over the direct connection. Returns false if
+    /// there is no direct connection to them.
+    pub fn send(&mut self, registry: &Registry, to: &str, text: &str) -> io::Result<bool> {
+        let Some(token) = self.peer(to) else {
+            return Ok(false);
+        };
+        let peer = self.peers.get_mut(&token).expect("connected peer");
+        peer.outbound.push(format!("{text}\n").into_bytes());
+        self.flush(registry, token)?;
+        Ok(true)
+    }
+
+    /// Handles readiness of the listener or of a peer connection.
+    pub fn handle_event(&mut self, registry: &Registry, event: &Event) -> io::Result<()> {
+        let token = event.token();
+        if token == LISTENER {
+            return self.accept(registry);
+        }
+        let Some(peer) = self.peers.get_mut(&token) else {
+            return Ok(());
+        };
+        if let PeerState::Connecting { token: offer, .. } = &peer.state {
+            match connect_result(&peer.stream) {
+                Ok(true) => {
+                    let hello = format!("{offer} {}\n", self.username);
+                    peer.outbound.push(hello.into_bytes());
+                    peer.state = PeerState::Connected;
+                    output::info(&t!("direct-connected", user = peer.name));
+                }
+                Ok(false) => return Ok(()),
+                Err(e) => {
+                    let name = peer.name.clone();
+                    self.close(registry, token)?;
+                    output::info(&t!("direct-unreachable-error", user = name, error = e));
+                    return Ok(());
+                }
+            }
+        }
+        if event.is_readable() {
+            self.read(registry, token)?;
+        }
+        if self.peers.contains_key(&token) {
+            self.flush(registry, token)?;
+        }
+        Ok(())
+    }
+
+    /// Gives up on peers that are taking too long to connect, and on offers that
+    /// were never accepted.
+    pub fn expire(&mut self, registry: &Registry, now: Instant) -> io::Result<()> {
+        let late: Vec<Token> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.deadline().is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in late {
+            if let Some(peer) = self.close(registry, token)? {
+                if !peer.name.is_empty() {
+                    output::info(&t!("direct-unreachable", user = peer.name));
+                }
+            }
+        }
+        self.offers.retain(|to, offer| {
+            let open = offer.expires > now;
+            if !open {
+                output::info(&t!("direct-not-accepted", user = to));
+            }
+            open
+        });
+        Ok(())
+    }
+
+    /// How long the event loop may block before a peer or offer times out.
+    pub fn poll_timeout(&self, now: Instant) -> Option<Duration> {
+        let peers = self.peers.values().filter_map(Peer::deadline);
+        let offers = self.offers.values().map(|offer| offer.expires);
+        peers
+            .chain(offers)
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+
+    /// Finds the connection to `name`, if we are connected to them.
+    fn peer(&self, name: &str) -> Option<Token> {
+        self.peers
+            .iter()
+            .find(|(_, peer)| peer.name == name && matches!(peer.state, PeerState::Connected))
+            .map(|(token, _)| *token)
+    }
+
+    /// Starts connecting to the client that invited us.
+    fn connect(&mut self, registry: &Registry, to: &str, invitation: Invitation) -> io::Result<()> {
+        output::info(&t!(
+            "direct-connecting",
+            user = to,
+            address = invitation.address
+        ));
+        let stream = match TcpStream::connect(invitation.address) {
+            Ok(stream) => stream,
+            Err(e) => {
+                output::info(&t!("direct-unreachable-error", user = to, error = e));
+                return Ok(());
+            }
+        };
+        let state = PeerState::Connecting {
+            token: invitation.token,
+            deadline: Instant::now() + CONNECT_TIMEOUT,
+        };
+        self.add(registry, to.to_string(), stream, state)
+    }
+
+    /// Accepts every peer waiting on the listener.
+    fn accept(&mut self, registry: &Registry) -> io::Result<()> {
+        loop {
+            let Some(listener) = self.listener.as_ref() else {
+                return Ok(());
+            };
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let state = PeerState::Hello {
+                        deadline: Instant::now() + CONNECT_TIMEOUT,
+                    };
+                    self.add(registry, String::new(), stream, state)?;
+                }
+                Err(ref err) if would_block(err) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn add(
+        &mut self,
+        registry: &Registry,
+        name: String,
+        mut stream: TcpStream,
+        state: PeerState,
+    ) -> io::Result<()> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        // Readiness is edge-triggered, so staying registered for writes costs nothing
+        registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        let peer = Peer {
+            name,
+            stream,
+            state,
+            outbound: OutboundBuffer::new(),
+            inbound: Vec::new(),
+        };
+        self.peers.insert(token, peer);
+        Ok(())
+    }
+
+    /// Reads until the socket would block, handling every complete line.
+    fn read(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let mut buffer = [0; BUF_SIZE];
+        loop {
+            let peer = self.peers.get_mut(&token).expect("registered peer");
+            match peer.stream.read(&mut buffer) {
+                Ok(0) => {
+                    if let Some(peer) = self.close(registry, token)? {
+                        if matches!(peer.state, PeerState::Connected) {
+                            output::info(&t!("direct-closed", user = peer.name));
+                        }
+                    }
+                    return Ok(());
+                }
+                Ok(n) => {
+                    peer.inbound.extend_from_slice(&buffer[..n]);
+                    if !self.handle_lines(registry, token)? {
+                        return Ok(());
+                    }
+                }
+                Err(ref err) if would_block(err) => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if let Some(peer) = self.close(registry, token)? {
+                        output::info(&t!("direct-lost", user = peer.name, error = e));
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Checks who an incoming peer is, then displays their messages. Returns false
+    /// if the peer was turned away.
+    fn handle_lines(&mut self, registry: &Registry, token: Token) -> io::Result<bool> {
+        let peer = self.peers.get_mut(&token).expect("registered peer");
+        while let Some(end) = peer.inbound.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = peer.inbound.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if let PeerState::Connected = peer.state {
+                output::message(&t!(
+                    "direct-message",
+                    from = peer.name,
+                    to = self.username,
+                    text = line
+                ));
+                continue;
+            }
+            // Only the user we made the offer to knows its token
+            let (offer, name) = line.split_once(' ').unwrap_or((line, ""));
+            match self.offers.get(name) {
+                Some(expected) if expected.token == offer => {
+                    self.offers.remove(name);
+                    self.invitations.remove(name);
+                    peer.name = name.to_string();
+                    peer.state = PeerState::Connected;
+                    output::info(&t!("direct-peer-connected", user = name));
+                }
+                _ => {
+                    self.close(registry, token)?;
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn flush(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let peer = self.peers.get_mut(&token).expect("registered peer");
+        if matches!(peer.state, PeerState::Connecting { .. }) {
+            return Ok(());
+        }
+        if let Err(e) = peer.outbound.write_to(&mut peer.stream, |_, _| Ok(())) {
+            if let Some(peer) = self.close(registry, token)? {
+                output::info(&t!("direct-lost", user = peer.name, error = e));
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, registry: &Registry, token: Token) -> io::Result<Option<Peer>> {
+        let Some(mut peer) = self.peers.remove(&token) else {
+            return Ok(None);
+        };
+        registry.deregister(&mut peer.stream)?;
+        Ok(Some(peer))
+    }
+}
+
+impl Peer {
+    /// When the peer times out, if it hasn't finished connecting yet.
+    fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            PeerState::Connecting { deadline, .. } | PeerState::Hello { deadline } => {
+                Some(deadline)
+            }
+            PeerState::Connected => None,
+        }
+    }
+}
+
+/// Parses an offer relayed by the server, `/direct <from> <address> <token>`.
+pub fn parse_offer(line: &str) -> Option<(&str, SocketAddr, &str)> {
+    let mut parts = line.strip_prefix("/direct ")?.split(' ');
+    let (from, address, token) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((from, address.parse().ok()?, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_offer() {
+        assert_eq!(
+            parse_offer("/direct alice 192.0.2.1:4000 abc123"),
+            Some(("alice", "192.0.2.1:4000".parse().unwrap(), "abc123"))
+        );
+        assert_eq!(parse_offer("/direct alice nowhere abc123"), None);
+        assert_eq!(parse_offer("/direct alice 192.0.2.1:4000"), None);
+        assert_eq!(parse_offer("[bob]: /direct alice 192.0.2.1:4000 x"), None);
+    }
+}
@@ -0,0 +1,164 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::i18n::t;
+use crate::output;
+
+/// UDP port servers listen on for discovery probes.
+const DISCOVERY_PORT: u16 = 12346;
+const PROBE: &[u8] = b"SIMPLE-CHAT?";
+
+/// A server that answered a discovery probe.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Found {
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+impl Found {
+    /// A server on this machine answers both the broadcast and the loopback probe,
+    /// from different addresses.
+    fn is_same(&self, other: &Found) -> bool {
+        let loopback = self.address.ip().is_loopback() || other.address.ip().is_loopback();
+        self.address == other.address
+            || (loopback && self.address.port() == other.address.port() && self.name == other.name)
+    }
+}
+
+/// Parses a server's `SIMPLE-CHAT <port> <name>` beacon, received from `from`.
+fn parse_beacon(beacon: &str, from: SocketAddr) -> Option<Found> {
+    let (port, name) = beacon.strip_prefix("SIMPLE-CHAT ")?.split_once(' ')?;
+    Some(Found {
+        name: name.to_string(),
+        address: SocketAddr::new(from.ip(), port.parse().ok()?),
+    })
+}
+
+/// Broadcasts a probe on the LAN (and to this machine) and collects the servers that
+/// answer within `wait`. Servers advertised over mDNS are included too, when built
+/// with the `mdns` feature.
+pub fn discover(wait: Duration) -> io::Result<Vec<Found>> {
+    #[cfg(feature = "mdns")]
+    let mdns = std::thread::spawn(move || browse_mdns(wait));
+    let found = probe(wait)?;
+    #[cfg(feature = "mdns")]
+    let found = match mdns.join().expect("mDNS browser panicked") {
+        Ok(servers) => {
+            let mut found = found;
+            for server in servers {
+                if !found.iter().any(|known| known.is_same(&server)) {
+                    found.push(server);
+                }
+            }
+            found
+        }
+        Err(e) => {
+            output::error(&t!("mdns-failed", error = e));
+            found
+        }
+    };
+    Ok(found)
+}
+
+/// DNS-SD service type servers register under.
+#[cfg(feature = "mdns")]
+const SERVICE_TYPE: &str = "_simple-chat._tcp.local.";
+
+/// Collects the servers advertised over mDNS within `wait`.
+#[cfg(feature = "mdns")]
+fn browse_mdns(wait: Duration) -> io::Result<Vec<Found>> {
+    use std::collections::BTreeMap;
+    use std::net::IpAddr;
+
+    let daemon = mdns_sd::ServiceDaemon::new().map_err(io::Error::other)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(io::Error::other)?;
+    let deadline = Instant::now() + wait;
+    // A service may be resolved several times as its addresses come in
+    let mut services: BTreeMap<String, (u16, Vec<IpAddr>)> = BTreeMap::new();
+    while let Ok(event) = events.recv_deadline(deadline) {
+        if let mdns_sd::ServiceEvent::ServiceResolved(info) = event {
+            let name = info.get_fullname().trim_end_matches(SERVICE_TYPE);
+            let (port, addresses) = services
+                .entry(name.trim_end_matches('.').to_string())
+                .or_default();
+            *port = info.get_port();
+            addresses.extend(info.get_addresses());
+        }
+    }
+    let _ = daemon.shutdown();
+
+    // Servers usually listen on IPv4 only, and IPv6 link-local addresses would need
+    // a scope, so prefer IPv4
+    let found = services
+        .into_iter()
+        .filter_map(|(name, (port, addresses))| {
+            let ip = addresses
+                .iter()
+                .find(|ip| ip.is_ipv4())
+                .or(addresses.first())?;
+            Some(Found {
+                name,
+                address: SocketAddr::new(*ip, port),
+            })
+        })
+        .collect();
+    Ok(found)
+}
+
+/// Sends the UDP probes and collects the beacons that come back within `wait`.
+fn probe(wait: Duration) -> io::Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    // Broadcasts don't come back to this machine, so ask it separately
+    for target in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        if let Err(e) = socket.send_to(PROBE, (target, DISCOVERY_PORT)) {
+            output::error(&t!("probe-failed", target = target, error = e));
+        }
+    }
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<Found> = Vec::new();
+    let mut buf = [0; 512];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        let beacon = String::from_utf8_lossy(&buf[..len]);
+        if let Some(server) = parse_beacon(&beacon, from) {
+            if !found.iter().any(|known| known.is_same(&server)) {
+                found.push(server);
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_beacon() {
+        let from: SocketAddr = "192.168.1.20:12346".parse().unwrap();
+        assert_eq!(
+            parse_beacon("SIMPLE-CHAT 12345 The Lounge", from),
+            Some(Found {
+                name: "The Lounge".to_string(),
+                address: "192.168.1.20:12345".parse().unwrap(),
+            })
+        );
+        assert_eq!(parse_beacon("SIMPLE-CHAT nope Lounge", from), None);
+        assert_eq!(parse_beacon("HELLO", from), None);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::notify::Alert;
+
+/// What came in while do not disturb was on.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Missed {
+    pub messages: usize,
+    pub mentions: usize,
+    /// Who sent private messages, and how many each.
+    pub private: BTreeMap<String, usize>,
+}
+
+impl Missed {
+    /// The senders of private messages, with how many each sent if more than one:
+    /// `bob (3), carol`.
+    pub fn senders(&self) -> String {
+        let senders: Vec<String> = (self.private.iter())
+            .map(|(from, &count)| match count {
+                1 => from.clone(),
+                _ => format!("{from} ({count})"),
+            })
+            .collect();
+        senders.join(", ")
+    }
+}
+
+/// Do not disturb: while it is on, nothing rings the bell, and what would have is
+/// counted for a summary once it ends.
+#[derive(Debug, Default)]
+pub struct Dnd {
+    /// Whether it is on, and until when if it was given a duration.
+    on: Option<Option<Instant>>,
+    missed: Missed,
+}
+
+impl Dnd {
+    /// Turns do not disturb on, until `until` or until it is turned off. Turning it on
+    /// again only changes when it ends.
+    pub fn start(&mut self, until: Option<Instant>) {
+        self.on = Some(until);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.is_some()
+    }
+
+    /// Counts a chat message from `from` that came in while it is on, and whether it
+    /// would have alerted the user.
+    pub fn note(&mut self, from: &str, alert: Option<Alert>) {
+        match alert {
+            Some(Alert::Private) => *self.missed.private.entry(from.to_string()).or_default() += 1,
+            Some(Alert::Mention) => {
+                self.missed.messages += 1;
+                self.missed.mentions += 1;
+            }
+            _ => self.missed.messages += 1,
+        }
+    }
+
+    /// Turns do not disturb off, giving what was missed meanwhile if it was on.
+    pub fn stop(&mut self) -> Option<Missed> {
+        self.on.take()?;
+        Some(std::mem::take(&mut self.missed))
+    }
+
+    /// How long until it ends by itself, if it does.
+    pub fn poll_timeout(&self, now: Instant) -> Option<Duration> {
+        Some(self.on.flatten()?.saturating_duration_since(now))
+    }
+
+    /// Turns do not disturb off if its time is up, giving what was missed.
+    pub fn expire(&mut self, now: Instant) -> Option<Missed> {
+        match self.on {
+            Some(Some(until)) if until <= now => self.stop(),
+            _ => None,
+        }
+    }
+}
+
+/// The longest do not disturb can be turned on for.
+const MAX: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parses a duration such as `30s`, `10m`, `2h` or `1d`, up to a year. A bare number
+/// is in minutes.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "m"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return None,
+    };
+    let seconds = value.parse::<u64>().ok()?.checked_mul(multiplier)?;
+    let duration = Duration::from_secs(seconds);
+    (seconds > 0 && duration <= MAX).then_some(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dnd_counts_what_was_missed_until_it_expires() {
+        let now = Instant::now();
+        let mut dnd = Dnd::default();
+        dnd.start(Some(now + Duration::from_secs(60)));
+        dnd.note("bob", None);
+        dnd.note("bob", Some(Alert::Mention));
+        dnd.note("carol", Some(Alert::Private));
+        dnd.note("bob", Some(Alert::Private));
+        dnd.note("bob", Some(Alert::Private));
+        assert_eq!(dnd.poll_timeout(now), Some(Duration::from_secs(60)));
+        assert_eq!(dnd.expire(now), None);
+
+        let missed = dnd.expire(now + Duration::from_secs(60)).unwrap();
+        assert_eq!((missed.messages, missed.mentions), (2, 1));
+        assert_eq!(missed.senders(), "bob (2), carol");
+        assert!(!dnd.is_on());
+        assert_eq!(dnd.stop(), None);
+
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("365d"), Some(MAX));
+        assert_eq!(parse_duration("366d"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+    }
+}
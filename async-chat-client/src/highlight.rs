@@ -0,0 +1,298 @@
+/// What a piece of code is, for showing it in colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Keyword,
+    String,
+    Comment,
+    Number,
+}
+
+impl Token {
+    /// The ANSI escape code (SGR) the token is shown with in a terminal.
+    pub fn sgr(self) -> &'static str {
+        match self {
+            Token::Keyword => "35",
+            Token::String => "32",
+            Token::Comment => "90",
+            Token::Number => "33",
+        }
+    }
+}
+
+/// What the highlighter needs to know about a language: enough to tell its keywords,
+/// strings, comments and numbers apart, not to parse it.
+struct Language {
+    /// The names a code block may be tagged with, e.g. `rust` or `rs`.
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    /// The characters strings are quoted with.
+    quotes: &'static [char],
+}
+
+const C_LIKE_COMMENT: Option<(&str, &str)> = Some(("/*", "*/"));
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        line_comment: Some("//"),
+        block_comment: C_LIKE_COMMENT,
+        // Not `'`, which also starts lifetimes
+        quotes: &['"'],
+    },
+    Language {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+            "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return",
+            "True", "try", "while", "with", "yield",
+        ],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+    Language {
+        names: &["javascript", "js", "jsx", "typescript", "ts", "tsx"],
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "export",
+            "extends",
+            "false",
+            "finally",
+            "for",
+            "function",
+            "if",
+            "import",
+            "in",
+            "instanceof",
+            "interface",
+            "let",
+            "new",
+            "null",
+            "return",
+            "super",
+            "switch",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "type",
+            "typeof",
+            "undefined",
+            "var",
+            "void",
+            "while",
+            "yield",
+        ],
+        line_comment: Some("//"),
+        block_comment: C_LIKE_COMMENT,
+        quotes: &['"', '\'', '`'],
+    },
+    Language {
+        names: &["go", "golang"],
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "fallthrough",
+            "false",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "nil",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "true",
+            "type",
+            "var",
+        ],
+        line_comment: Some("//"),
+        block_comment: C_LIKE_COMMENT,
+        quotes: &['"', '\'', '`'],
+    },
+    Language {
+        names: &["c", "h", "cpp", "c++", "hpp", "java", "cs", "c#"],
+        keywords: &[
+            "break",
+            "case",
+            "catch",
+            "char",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "do",
+            "double",
+            "else",
+            "enum",
+            "extern",
+            "false",
+            "final",
+            "float",
+            "for",
+            "if",
+            "import",
+            "int",
+            "long",
+            "namespace",
+            "new",
+            "null",
+            "nullptr",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "short",
+            "sizeof",
+            "static",
+            "struct",
+            "switch",
+            "this",
+            "throw",
+            "true",
+            "try",
+            "typedef",
+            "unsigned",
+            "using",
+            "void",
+            "volatile",
+            "while",
+        ],
+        line_comment: Some("//"),
+        block_comment: C_LIKE_COMMENT,
+        quotes: &['"', '\''],
+    },
+    Language {
+        names: &["sh", "bash", "shell", "zsh"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+    Language {
+        names: &["json"],
+        keywords: &["false", "null", "true"],
+        line_comment: None,
+        block_comment: None,
+        quotes: &['"'],
+    },
+    Language {
+        names: &["toml"],
+        keywords: &["false", "true"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: &['"', '\''],
+    },
+];
+
+/// Highlights `code` written in `language`, giving the kind of token each of its
+/// characters is part of, if it is part of one. Returns `None` for languages it
+/// doesn't know, whose code is shown without colours.
+pub fn highlight(code: &[char], language: &str) -> Option<Vec<Option<Token>>> {
+    let language = language.to_lowercase();
+    let language = LANGUAGES
+        .iter()
+        .find(|known| known.names.contains(&language.as_str()))?;
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = vec![None; code.len()];
+    let mut i = 0;
+    while i < code.len() {
+        let rest = &code[i..];
+        let after_word = i > 0 && is_word(code[i - 1]);
+        let (length, token) = if language.line_comment.is_some_and(|c| starts_with(rest, c)) {
+            let length = rest.iter().position(|&c| c == '\n');
+            (length.unwrap_or(rest.len()), Some(Token::Comment))
+        } else if let Some((open, close)) =
+            (language.block_comment).filter(|(open, _)| starts_with(rest, open))
+        {
+            let open = open.chars().count();
+            let length = (open..rest.len())
+                .find(|&j| starts_with(&rest[j..], close))
+                .map_or(rest.len(), |j| j + close.chars().count());
+            (length, Some(Token::Comment))
+        } else if language.quotes.contains(&rest[0]) {
+            // Up to the closing quote, or the end of the line for a string left open
+            let mut j = 1;
+            while j < rest.len() && rest[j] != rest[0] && rest[j] != '\n' {
+                j += if rest[j] == '\\' { 2 } else { 1 };
+            }
+            ((j + 1).min(rest.len()), Some(Token::String))
+        } else if rest[0].is_ascii_digit() && !after_word {
+            let length = rest.iter().take_while(|&&c| is_word(c) || c == '.').count();
+            (length, Some(Token::Number))
+        } else if is_word(rest[0]) && !after_word {
+            let length = rest.iter().take_while(|&&c| is_word(c)).count();
+            let word: String = rest[..length].iter().collect();
+            let keyword = language.keywords.contains(&word.as_str());
+            (length, keyword.then_some(Token::Keyword))
+        } else {
+            (1, None)
+        };
+        tokens[i..i + length].fill(token);
+        i += length;
+    }
+    Some(tokens)
+}
+
+fn starts_with(chars: &[char], prefix: &str) -> bool {
+    let mut chars = chars.iter();
+    prefix.chars().all(|c| chars.next() == Some(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_tells_tokens_apart() {
+        let code: Vec<char> = "let x = \"a\\\"b\"; // 42\nx2 + 0x1f".chars().collect();
+        let tokens = highlight(&code, "Rust").unwrap();
+        let kinds: String = (tokens.iter())
+            .map(|token| match token {
+                Some(Token::Keyword) => 'k',
+                Some(Token::String) => 's',
+                Some(Token::Comment) => 'c',
+                Some(Token::Number) => 'n',
+                None => '.',
+            })
+            .collect();
+        assert_eq!(kinds, "kkk.....ssssss..ccccc......nnnn");
+        assert_eq!(highlight(&code, "cobol"), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// The languages the client's texts are translated to, with their texts. English
+/// comes first, it is the fallback for texts missing from the others.
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.txt")),
+    ("de", include_str!("../locales/de.txt")),
+];
+
+/// The texts in use, English ones filling any gaps in the chosen language.
+static CATALOG: OnceLock<HashMap<&'static str, &'static str>> = OnceLock::new();
+
+/// Picks the language of the client's texts. Without `lang`, it comes from the
+/// environment's locale (`LC_ALL`, `LC_MESSAGES` or `LANG`), falling back to English
+/// if that isn't translated. An explicit `lang` that isn't is an error.
+pub fn init(lang: Option<&str>) -> Result<(), String> {
+    let code = match lang {
+        Some(lang) => {
+            let code = language_code(lang);
+            if !LOCALES.iter().any(|(locale, _)| *locale == code) {
+                let available: Vec<_> = LOCALES.iter().map(|(locale, _)| *locale).collect();
+                return Err(format!(
+                    "Unsupported language: {lang} (available: {})",
+                    available.join(", ")
+                ));
+            }
+            code
+        }
+        None => ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+            .map(|value| language_code(&value))
+            .unwrap_or_default(),
+    };
+    let _ = CATALOG.set(catalog(&code));
+    Ok(())
+}
+
+/// Turns a locale such as `de_DE.UTF-8` into its language code, `de`.
+fn language_code(locale: &str) -> String {
+    locale
+        .split(['_', '.', '@', '-'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Builds the catalog for a language: English, overridden by the language's texts.
+fn catalog(code: &str) -> HashMap<&'static str, &'static str> {
+    let mut texts = HashMap::new();
+    for (locale, source) in LOCALES {
+        if *locale == "en" || *locale == code {
+            texts.extend(parse(source));
+        }
+    }
+    texts
+}
+
+/// Parses `key = text` lines, skipping blank lines and `#` comments.
+fn parse(source: &'static str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(" = "))
+}
+
+/// Looks up a text, filling in its `{name}` placeholders from `args`. A key missing
+/// from every language comes out as is, so it shows up rather than disappearing.
+pub fn text(key: &str, args: &[(&str, &dyn std::fmt::Display)]) -> String {
+    let catalog = CATALOG.get_or_init(|| catalog("en"));
+    let mut text = catalog.get(key).copied().unwrap_or(key).to_string();
+    for (name, value) in args {
+        text = text.replace(&format!("{{{name}}}"), &value.to_string());
+    }
+    text
+}
+
+/// Looks up a text of the client in the user's language: `t!("key")`, or with
+/// placeholders, `t!("key", name = value, ...)`.
+macro_rules! t {
+    ($key:literal) => {
+        $crate::i18n::text($key, &[])
+    };
+    ($key:literal, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::text($key, &[$((stringify!($name), &$value)),+])
+    };
+}
+
+pub(crate) use t;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// The `{name}` placeholders of a text.
+    fn placeholders(text: &str) -> BTreeSet<&str> {
+        text.split('{')
+            .skip(1)
+            .filter_map(|rest| rest.split_once('}'))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn test_translations_match_english() {
+        let english: HashMap<_, _> = parse(LOCALES[0].1).collect();
+        for (locale, source) in &LOCALES[1..] {
+            for (key, text) in parse(source) {
+                let Some(original) = english.get(key) else {
+                    panic!("{locale} has a text for {key}, which English doesn't");
+                };
+                assert_eq!(
+                    placeholders(text),
+                    placeholders(original),
+                    "{locale} and English differ in the placeholders of {key}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_text_fills_in_placeholders() {
+        assert_eq!(language_code("de_DE.UTF-8"), "de");
+        assert_eq!(
+            t!(
+                "connecting-as",
+                address = "127.0.0.1:12345",
+                username = "alice"
+            ),
+            "Connecting to server at 127.0.0.1:12345 as alice"
+        );
+        assert_eq!(t!("no-such-key"), "no-such-key");
+    }
+}
@@ -0,0 +1,93 @@
+use mio::Waker;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+/// Input forwarded from the stdin thread to the event loop.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A complete line, without the trailing newline.
+    Line(String),
+    /// Stdin was closed (e.g. Ctrl-D or the end of piped input).
+    Eof,
+}
+
+/// Spawns a thread that reads complete lines from stdin and forwards them over a channel,
+/// waking the event loop through `waker` after each one.
+///
+/// Reading stdin on its own thread keeps a blocking `read_line` (or a terminal delivering a
+/// partial line) from stalling the `Poll` loop, and avoids registering stdin with mio, which
+/// does not work for every kind of stdin (e.g. regular files).
+pub fn spawn(waker: Arc<Waker>) -> Receiver<InputEvent> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(InputEvent::Line(line)).is_err() {
+                // The event loop has gone away
+                return;
+            }
+            let _ = waker.wake();
+        }
+        let _ = tx.send(InputEvent::Eof);
+        let _ = waker.wake();
+    });
+    rx
+}
+
+/// Reads a line from stdin without showing it as it is typed, e.g. a passphrase.
+/// Typing is only hidden if stdin is a terminal.
+pub fn read_hidden() -> io::Result<String> {
+    // SAFETY: tcgetattr fills in the termios it is given, which outlives the call, and
+    // fails harmlessly on anything that isn't a terminal.
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let terminal = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == 0;
+    if terminal {
+        let mut hidden = saved;
+        hidden.c_lflag &= !libc::ECHO;
+        // SAFETY: as above, with a termios that outlives the call.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) };
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    if terminal {
+        // SAFETY: as above.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        // The enter that ended the line wasn't shown either
+        println!();
+    }
+    match read? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
+/// Stops the terminal showing what is typed, or shows it again, while the stdin
+/// thread reads a password. Returns whether stdin is a terminal: if not, nothing
+/// can be hidden.
+pub fn hide_typing(hidden: bool) -> bool {
+    // SAFETY: as in `read_hidden`.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+        return false;
+    }
+    match hidden {
+        true => termios.c_lflag &= !libc::ECHO,
+        false => termios.c_lflag |= libc::ECHO,
+    }
+    // SAFETY: as above.
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+    true
+}
+
+/// Throws away what the user typed on the current line but hasn't sent yet, by
+/// discarding the terminal's unread input. Does nothing if stdin isn't a terminal.
+pub fn clear_line() {
+    // SAFETY: tcflush takes no pointers, and fails harmlessly on anything that
+    // isn't a terminal.
+    unsafe {
+        libc::tcflush(libc::STDIN_FILENO, libc::TCIFLUSH);
+    }
+}
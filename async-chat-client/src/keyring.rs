@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// Looks a secret up in the desktop's keyring (the Secret Service, with
+/// `secret-tool`), under `account`, e.g. `history` or `password:work`.
+pub fn lookup(account: &str) -> Option<String> {
+    let output = Command::new("secret-tool")
+        .args(["lookup", "application", "simple-chat", "account", account])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let secret = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !secret.is_empty()).then_some(secret)
+}
+
+/// Keeps a secret in the desktop's keyring under `account`, for [`lookup`], with
+/// `label` to tell it apart in the keyring's own tools.
+pub fn store(account: &str, label: &str, secret: &str) -> io::Result<()> {
+    let mut child = Command::new("secret-tool")
+        .args(["store", &format!("--label={label}")])
+        .args(["application", "simple-chat", "account", account])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(secret.as_bytes())?;
+    }
+    match child.wait()?.success() {
+        true => Ok(()),
+        false => Err(io::Error::other("secret-tool failed")),
+    }
+}
+
+/// Removes the secret kept under `account`, if there is one.
+pub fn clear(account: &str) -> io::Result<()> {
+    let status = Command::new("secret-tool")
+        .args(["clear", "application", "simple-chat", "account", account])
+        .stderr(Stdio::null())
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other("secret-tool failed")),
+    }
+}
+
+/// An account's password in the keyring: looked up before logging in, kept once the
+/// server takes it, and removed if the server turns it down.
+#[derive(Debug)]
+pub struct Entry {
+    account: String,
+    label: String,
+    /// Whether the keyring holds the password being used.
+    kept: bool,
+}
+
+impl Entry {
+    pub fn new(account: String, label: String) -> Self {
+        Entry {
+            account,
+            label,
+            kept: false,
+        }
+    }
+
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn lookup(&mut self) -> Option<String> {
+        let password = lookup(&self.account);
+        self.kept = password.is_some();
+        password
+    }
+
+    /// The server took `password`: keeps it, unless it is there already.
+    pub fn keep(&mut self, password: &str) -> io::Result<()> {
+        if !self.kept {
+            store(&self.account, &self.label, password)?;
+            self.kept = true;
+        }
+        Ok(())
+    }
+
+    /// The server turned the password down: removes it, if it was kept.
+    pub fn forget(&mut self) -> io::Result<()> {
+        if self.kept {
+            clear(&self.account)?;
+            self.kept = false;
+        }
+        Ok(())
+    }
+}
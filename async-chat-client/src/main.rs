@@ -1,167 +1,527 @@
-use clap::Parser;
-use mio::net::TcpStream;
-use mio::unix::SourceFd; // For handling `Stdin` on Unix-like systems
-use mio::{Events, Interest, Poll, Token};
+mod chatlog;
+mod client;
+mod commands;
+mod config;
+mod connection;
+mod crypt;
+mod direct;
+mod discovery;
+mod dnd;
+mod highlight;
+mod i18n;
+mod input;
+mod keyring;
+#[cfg(feature = "noise")]
+mod noise;
+mod notify;
+mod once;
+mod outbound;
+mod output;
+mod pow;
+#[cfg(feature = "quic")]
+mod quic;
+mod recorder;
+mod search;
+mod sequence;
+mod socks;
+mod spans;
+mod store;
+mod tags;
+mod throttle;
+mod timestamp;
+mod version;
+mod wrap;
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use std::env;
-use std::io::{self, Read, Write};
+use std::io;
 use std::net::SocketAddr;
-use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use chatlog::ChatLog;
+use client::{Client, Dialer};
+use config::{Auth, Config};
+use crypt::Cipher;
+use i18n::t;
+use once::Once;
+use recorder::{Direction, Recorder, Replay};
+use store::Store;
+use timestamp::TimeFormat;
 
-/// Command-line argument struct for configuring the chat application.
+/// Command-line argument struct for configuring the chat application. The options
+/// go before or after the subcommand.
 #[derive(Parser)]
 struct Args {
-    /// The host of the server (default: 127.0.0.1)
-    #[arg(long, default_value = "127.0.0.1")]
-    host: String,
+    /// What to do (default: connect)
+    #[command(subcommand)]
+    action: Option<Action>,
+
+    /// The host of the server (default: the profile's, or 127.0.0.1)
+    #[arg(long, global = true)]
+    host: Option<String>,
+
+    /// The port of the server (default: the profile's, or 12345)
+    #[arg(short, long, global = true)]
+    port: Option<String>,
+
+    /// The username used for identification, needed unless the profile has one or
+    /// USERNAME is set
+    #[arg(short, long, global = true)]
+    username: Option<String>,
+
+    /// Be who the config file's `[profiles.<name>]` says: server, username, how to
+    /// log in and Noise keys. The other options still go first
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Join as a guest, under a name the server picks
+    #[arg(long, conflicts_with = "username", global = true)]
+    guest: bool,
+
+    /// Don't look the account's password up in the desktop's keyring (with
+    /// secret-tool), nor keep it there once the server takes it
+    #[arg(long, global = true)]
+    no_keyring: bool,
+
+    /// Look for servers on the LAN instead of using --host and --port
+    #[arg(long, global = true)]
+    discover: bool,
+
+    /// Record every frame exchanged with the server to this file
+    #[arg(long, global = true)]
+    record: Option<PathBuf>,
+
+    /// Keep the local copy of the chat here instead of
+    /// ~/.local/share/simple-chat/logs (one directory per server, one file per room)
+    #[arg(long, global = true)]
+    log_dir: Option<PathBuf>,
+
+    /// Don't keep a local copy of the chat
+    #[arg(long, conflicts_with = "log_dir", global = true)]
+    no_log: bool,
+
+    /// Don't keep the room messages received, to show them again after a restart
+    /// and ask the server only for those missed meanwhile
+    #[arg(long, global = true)]
+    no_history: bool,
+
+    /// Encrypt the local logs and history with a key derived from a passphrase,
+    /// asked for on this first run and every start after it
+    #[arg(long, global = true)]
+    encrypt: bool,
 
-    /// The port of the server (default: 8080)
-    #[arg(short, long, default_value = "12345")]
-    port: String,
+    /// Keep the passphrase of --encrypt in the desktop's keyring (with secret-tool)
+    /// rather than asking for it at every start
+    #[arg(long, conflicts_with = "no_keyring", global = true)]
+    keyring: bool,
+
+    /// Tell the server we are away while /dnd is on, so private messages are
+    /// answered
+    #[arg(long, global = true)]
+    dnd_away: bool,
+
+    /// How many bytes to read from the server at a time
+    #[arg(long, default_value_t = client::READ_BUFFER, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(64..), global = true)]
+    read_buffer: usize,
+
+    /// Replay a session captured with `--record` instead of connecting to a server
+    #[arg(long, conflicts_with = "record", global = true)]
+    replay: Option<PathBuf>,
+
+    /// Connect through this SOCKS5 proxy, e.g. Tor's at 127.0.0.1:9050 to reach a
+    /// .onion server. The proxy resolves the server's host name
+    #[arg(long, global = true)]
+    proxy: Option<SocketAddr>,
+
+    /// Encrypt the connection with Noise, using (or creating) the key pair in this file
+    #[cfg(feature = "noise")]
+    #[arg(long, global = true)]
+    noise: Option<PathBuf>,
+
+    /// Only talk to a Noise server with this public key (hex encoded)
+    #[cfg(feature = "noise")]
+    #[arg(long, requires = "noise", global = true)]
+    server_key: Option<String>,
+
+    /// Connect over QUIC, trusting the server certificate in this file (the server's
+    /// quic-cert.der)
+    #[cfg(feature = "quic")]
+    #[arg(long, global = true)]
+    quic: Option<PathBuf>,
+
+    /// Plain line-oriented output for screen readers and scripts: every line starts
+    /// with what it is (MSG, SERVER, INFO, ERROR or PROMPT), and nothing is wrapped
+    #[arg(long, global = true)]
+    plain: bool,
+
+    /// Show the time in front of chat messages, in this strftime-style format, such as
+    /// "%H:%M"
+    #[arg(long, global = true)]
+    time_format: Option<String>,
+
+    /// The format of times before today, e.g. in a replay (default: the date, then
+    /// --time-format)
+    #[arg(long, requires = "time_format", global = true)]
+    older_time_format: Option<String>,
+
+    /// Show times in this time zone, e.g. Europe/Berlin, instead of the local one
+    #[arg(long, requires = "time_format", global = true)]
+    timezone: Option<String>,
+
+    /// The language of the client's texts, e.g. `de` (default: from the locale, LANG)
+    #[arg(long, global = true)]
+    lang: Option<String>,
+
+    /// The config file, with the notification rules and profiles (default:
+    /// ~/.config/simple-chat/client.toml, if there is one)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
 
-    /// The username used for identification
-    #[arg(short, long)]
-    username: String,
+/// What the client does once connected: chat, or something for a script.
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+enum Action {
+    /// Connect and chat, reading what to send from stdin
+    Connect,
+    /// Connect, send a message and exit once the server has it
+    Send {
+        /// The room to send it to (default: the lobby)
+        #[arg(long)]
+        room: Option<String>,
+        /// The message, as it would be typed: commands such as "/msg bob hi" or
+        /// "/me waves" work too
+        message: String,
+    },
+    /// Show who is in a room, the lobby by default, and exit
+    Who {
+        /// The room, e.g. dev or #dev
+        room: Option<String>,
+    },
 }
 
-// Constants for the server and stdin events.
-const SERVER: Token = Token(0);
-const STDIN: Token = Token(1);
+impl Action {
+    /// The one-shot run it is, if it isn't [`Action::Connect`].
+    fn once(&self) -> Option<Once> {
+        let now = Instant::now();
+        match self {
+            Action::Connect => None,
+            Action::Send { room, message } => {
+                Some(Once::new(vec![message.clone()], room.clone(), now))
+            }
+            Action::Who { room: None } => Some(Once::new(vec!["/who".to_string()], None, now)),
+            Action::Who { room: Some(room) } => {
+                let who = format!("/who #{}", room.trim_start_matches('#'));
+                Some(Once::new(vec![who], None, now))
+            }
+        }
+    }
+}
 
 /// Entry point of the chat application. Manages connection and polling of events.
 fn main() -> io::Result<()> {
     // Parse the command-line arguments
     let args = Args::parse();
+    output::set_plain(args.plain);
+    i18n::init(args.lang.as_deref()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if let Some(today) = &args.time_format {
+        let mut format = TimeFormat::new(today, args.older_time_format.as_deref());
+        if let Some(zone) = &args.timezone {
+            format = format.and_then(|format| format.in_zone(zone));
+        }
+        let format = format.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        output::set_time_format(format);
+    }
 
-    let host = env::var("HOST").unwrap_or(args.host);
-    let port = env::var("PORT").unwrap_or(args.port);
-    let username = env::var("USERNAME").unwrap_or(args.username);
+    let config = Config::load(args.config.as_deref())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    if let Some(path) = &args.replay {
+        return replay(path);
+    }
+
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let profile = match &args.profile {
+        Some(name) => config.profile(name).map_err(invalid)?.clone(),
+        None => Default::default(),
+    };
+    let host = (env::var("HOST").ok())
+        .or(args.host)
+        .or(profile.host)
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+    let port = (env::var("PORT").ok())
+        .or(args.port)
+        .or(profile.port.map(|port| port.to_string()))
+        .unwrap_or_else(|| "12345".to_string());
+    let guest = args.guest || (args.username.is_none() && profile.auth == Auth::Guest);
+    let username = if guest {
+        client::GUEST.to_string()
+    } else {
+        (env::var("USERNAME").ok())
+            .or(args.username)
+            .or(profile.username)
+            .ok_or_else(|| invalid(t!("username-missing")))?
+    };
+    // Never an option, as those show up in the process list and shell history
+    let mut password = env::var("PASSWORD").ok();
 
     // Create a stream socket and initiate a connection
-    let address = format!("{host}:{port}");
-    let username = format!("{username}\n");
-    let server_address: SocketAddr = address.parse().unwrap();
-    let mut stream = TcpStream::connect(server_address)?;
-    println!("Connecting to server at {} as {}", &address, &username);
-
-    // We'll need the raw file descriptor for the standard input stream
-    let stdin = io::stdin();
-    let stdin_fd = stdin.as_raw_fd();
-
-    // Set up polling to handle both stdin and the TCP stream
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(128);
-
-    // Register the connection with the Poll instance
-    poll.registry()
-        .register(&mut stream, SERVER, Interest::READABLE | Interest::WRITABLE)?;
-
-    // Register `Stdin` as a source for polling
-    poll.registry()
-        .register(&mut SourceFd(&stdin_fd), STDIN, Interest::READABLE)?;
-
-    const BUF_SIZE: usize = 512;
-    let mut input_buffer = Vec::new();
-    let mut server_buffer = [0; BUF_SIZE];
-    let mut bytes_to_send;
-    let mut bytes_written = 0;
-    let mut username_sent = false;
-
-    // Main event loop
-    loop {
-        poll.poll(&mut events, None)?;
-
-        for event in events.iter() {
-            match event.token() {
-                SERVER => {
-                    if event.is_readable() {
-                        match stream.read(&mut server_buffer) {
-                            Ok(0) => {
-                                println!("Connection closed by server.");
-                                return Ok(());
-                            }
-                            Ok(n) => {
-                                let msg = String::from_utf8_lossy(&server_buffer[..n]);
-                                println!("{}", msg.trim());
-                            }
-                            Err(ref err) if would_block(err) => {}
-                            Err(e) => {
-                                eprintln!("Error reading from server: {e}");
-                                return Err(e);
-                            }
-                        }
-                    }
-
-                    if event.is_writable() {
-                        if !username_sent {
-                            input_buffer.extend_from_slice(username.as_bytes());
-                            // In this simple chat app, we assume the username is short and will be sent in a single write.
-                            // Note: This assumption may not hold in all cases, as `stream.write` does NOT guarantee that
-                            // the entire buffer will be written at once. According to the documentation, we should loop
-                            // until either a `WouldBlock` error occurs or the entire data buffer is sent.
-                            let _ = stream.write(&input_buffer.as_slice());
-                            username_sent = true;
-                        }
-                    }
-                }
+    let address = if args.discover {
+        match pick_server()? {
+            Some(address) => address.to_string(),
+            None => return Ok(()),
+        }
+    } else {
+        format!("{host}:{port}")
+    };
+    // Account passwords are kept per profile, or per username and server
+    let mut entry = (!guest && !args.no_keyring).then(|| match &args.profile {
+        Some(name) => keyring::Entry::new(
+            format!("password:{name}"),
+            format!("simple-chat {username} ({name})"),
+        ),
+        None => keyring::Entry::new(
+            format!("password:{username}@{address}"),
+            format!("simple-chat {username} ({address})"),
+        ),
+    });
+    if password.is_none() && profile.auth == Auth::Password {
+        password = entry.as_mut().and_then(keyring::Entry::lookup);
+        if password.is_none() {
+            output::prompt(&t!("password", username = username))?;
+            password = Some(input::read_hidden()?);
+        }
+    }
+    // A one-shot run's output is the server's answer, for scripts to read
+    let one_shot = args
+        .action
+        .as_ref()
+        .is_some_and(|action| *action != Action::Connect);
+    output::set_quiet(one_shot);
+    if guest {
+        output::info(&t!("connecting-as-guest", address = address));
+    } else {
+        output::info(&t!("connecting-as", address = address, username = username));
+    }
+    output::info(&t!("welcome"));
 
-                STDIN => {
-                    // Handle input from `Stdin`
-                    let mut input = String::new();
-                    stdin.read_line(&mut input).expect("Failed to read input");
-                    input = input.trim().to_string();
-
-                    if let Some(stripped) = input.strip_prefix("send ") {
-                        let message = format!("{stripped}\n");
-                        let msg_len = message.len();
-                        input_buffer.clear();
-                        input_buffer.extend_from_slice(message.as_bytes());
-                        bytes_to_send = msg_len;
-                        // If we receive a write readiness event but skip writing due to `!input_buffer.is_empty()`
-                        // or an incomplete `input_buffer.extend_from_slice(message.as_bytes())` call, the code may
-                        // not write to the stream as expected since we may miss the SERVER token.
-
-                        // To handle this, we write to the stream as soon as user input is received from stdin.
-                        // Note: there are more robust solutions for handling this, but for a basic chat app,
-                        // this approach should be sufficient while maintaining asynchronous behavior.
-                        match stream.write(&input_buffer[bytes_written..bytes_to_send]) {
-                            // Continue writing until we hit a `WouldBlock`
-                            Ok(n) if n < bytes_to_send => {
-                                bytes_written += n;
-                                continue;
-                            }
-                            // Our data buffer has been exhausted i.e. we have sent everything we need to
-                            Ok(_v) => {
-                                input_buffer.clear();
-                                break;
-                            }
-                            // Encountered a `WouldBlock`, stop and poll again for readiness
-                            Err(ref err) if would_block(err) => {
-                                println!("{}", io::ErrorKind::WouldBlock);
-                                break;
-                            }
-                            Err(e) => {
-                                eprintln!("Error writing to server: {e}");
-                                return Err(e);
-                            }
-                        }
-                    } else if input == "leave" {
-                        println!("Disconnecting...");
-                        return Ok(());
-                    } else {
-                        println!("Invalid command. Use 'send <MSG>' or 'leave'");
-                    }
-                }
+    // Optionally capture the session for later replay
+    let recorder = args.record.as_deref().map(Recorder::create).transpose()?;
 
-                _token => {
-                    println!("Got a spurious event!")
+    let dialer = match args.proxy {
+        Some(proxy) => Dialer::Socks(socks::Bridge::start(proxy, &address)?),
+        None => Dialer::Tcp,
+    };
+    #[cfg(feature = "quic")]
+    let dialer = match &args.quic {
+        // Tor only carries TCP, and so do SOCKS5 proxies as far as we are concerned
+        Some(_) if args.proxy.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                t!("quic-with-proxy"),
+            ))
+        }
+        Some(cert) => Dialer::Quic(quic::Bridge::start(cert, client::resolve(&address)?)?),
+        None => dialer,
+    };
+
+    // One-shot runs keep neither logs nor history
+    let log_root = args.log_dir.or_else(ChatLog::default_root);
+    let store_root = Store::default_root();
+    let cipher = match (args.no_log && args.no_history) || one_shot {
+        true => None,
+        false => unlock(args.encrypt, args.keyring, [&log_root, &store_root])?,
+    };
+    let log = log_root
+        .filter(|_| !args.no_log && !one_shot)
+        .map(|root| ChatLog::new(root, &address));
+    let store = store_root
+        .filter(|_| !args.no_history && !one_shot)
+        .map(|root| Store::new(root, &address));
+    let (log, store) = match cipher {
+        Some(cipher) => (
+            log.map(|log| log.with_cipher(cipher.clone())),
+            store.map(|store| store.with_cipher(cipher)),
+        ),
+        None => (log, store),
+    };
+
+    let mut client = Client::connect(address, username, recorder, dialer)?;
+    if let Some(log) = log {
+        client = client.with_log(log);
+    }
+    if let Some(store) = store {
+        client = client.with_store(store);
+    }
+    if let Some(password) = password {
+        client = client.with_password(password);
+    }
+    if let Some(entry) = entry {
+        client = client.with_keyring(entry);
+    }
+    client = client
+        .with_read_buffer(args.read_buffer)
+        .with_notifications(config.notifications);
+    if args.dnd_away {
+        client = client.with_dnd_away();
+    }
+    #[cfg(feature = "noise")]
+    if let Some(path) = args.noise.or(profile.noise_key) {
+        let server_key = match args.server_key.or(profile.server_key).as_deref() {
+            Some(key) => Some(noise::from_hex(key).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, t!("invalid-server-key"))
+            })?),
+            None => None,
+        };
+        client = client.with_noise(noise::Noise::new(&path, server_key)?);
+    }
+    #[cfg(not(feature = "noise"))]
+    if profile.noise_key.is_some() || profile.server_key.is_some() {
+        return Err(invalid(t!("profile-noise")));
+    }
+    match args.action.as_ref().and_then(Action::once) {
+        Some(once) => client.run_once(once),
+        None => client.run(),
+    }
+}
+
+/// Looks for servers on the LAN and lets the user pick one if there are several.
+fn pick_server() -> io::Result<Option<SocketAddr>> {
+    output::info(&t!("looking-for-servers"));
+    let mut servers = discovery::discover(Duration::from_secs(2))?;
+    match servers.len() {
+        0 => {
+            output::info(&t!("no-servers"));
+            return Ok(None);
+        }
+        1 => return Ok(Some(servers.remove(0).address)),
+        _ => {}
+    }
+    for (index, server) in servers.iter().enumerate() {
+        output::info(&format!(
+            "  {}. {} ({})",
+            index + 1,
+            server.name,
+            server.address
+        ));
+    }
+    loop {
+        output::prompt(&t!("pick-server", count = servers.len()))?;
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice)? == 0 {
+            return Ok(None);
+        }
+        match choice.trim().parse::<usize>() {
+            Ok(n) if (1..=servers.len()).contains(&n) => {
+                return Ok(Some(servers.remove(n - 1).address))
+            }
+            _ => output::info(&t!("pick-invalid", count = servers.len())),
+        }
+    }
+}
+
+/// The key the logs and history are encrypted with. Once encryption is set up with
+/// `--encrypt`, it is used from then on: the passphrase comes from the keyring, if
+/// `keyring` and it is there, or is asked for. Setting it up encrypts what the
+/// `roots` of the logs and history already hold.
+fn unlock(
+    encrypt: bool,
+    keyring: bool,
+    roots: [&Option<PathBuf>; 2],
+) -> io::Result<Option<Cipher>> {
+    const ACCOUNT: &str = "history";
+    const LABEL: &str = "simple-chat history";
+    let Some(path) = crypt::default_key_path() else {
+        return Ok(None);
+    };
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    if !path.exists() {
+        if !encrypt {
+            return Ok(None);
+        }
+        let passphrase = loop {
+            output::prompt(&t!("passphrase-new"))?;
+            let passphrase = input::read_hidden()?;
+            if passphrase.is_empty() {
+                output::info(&t!("passphrase-empty"));
+                continue;
+            }
+            output::prompt(&t!("passphrase-again"))?;
+            if input::read_hidden()? == passphrase {
+                break passphrase;
+            }
+            output::info(&t!("passphrase-mismatch"));
+        };
+        let cipher = crypt::create(&path, &passphrase).map_err(invalid)?;
+        // Only encrypted lines are read from now on
+        for root in roots.into_iter().flatten() {
+            crypt::encrypt_existing(root, &cipher)?;
+        }
+        if keyring {
+            remember(ACCOUNT, LABEL, &passphrase);
+        }
+        return Ok(Some(cipher));
+    }
+    if let Some(passphrase) = keyring.then(|| keyring::lookup(ACCOUNT)).flatten() {
+        if let Ok(cipher) = crypt::unlock(&path, &passphrase) {
+            return Ok(Some(cipher));
+        }
+    }
+    for attempt in 1..=3 {
+        output::prompt(&t!("passphrase"))?;
+        let passphrase = input::read_hidden()?;
+        match crypt::unlock(&path, &passphrase) {
+            Ok(cipher) => {
+                if keyring {
+                    remember(ACCOUNT, LABEL, &passphrase);
                 }
+                return Ok(Some(cipher));
             }
+            Err(e) if attempt < 3 => output::error(&e),
+            Err(e) => return Err(invalid(e)),
         }
     }
+    unreachable!("the last attempt returns")
 }
 
-fn would_block(err: &io::Error) -> bool {
-    err.kind() == io::ErrorKind::WouldBlock
+/// Keeps a secret in the keyring, or says why it couldn't.
+fn remember(account: &str, label: &str, secret: &str) {
+    if let Err(e) = keyring::store(account, label, secret) {
+        output::error(&t!("keyring-failed", account = account, error = e));
+    }
+}
+
+/// Replays a recorded session without a live server, rendering inbound frames
+/// with their original timing and echoing outbound frames prefixed with `>`.
+fn replay(path: &Path) -> io::Result<()> {
+    let recording = Replay::open(path)?;
+    output::info(&t!("replay-start", time = recording.started_at));
+
+    let started_at = DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_secs(recording.started_at));
+    let start = Instant::now();
+    for frame in recording {
+        let frame = frame?;
+        if let Some(wait) = frame.offset.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+        match frame.direction {
+            Direction::Inbound => {
+                let received = started_at + frame.offset;
+                for line in String::from_utf8_lossy(&frame.data).lines() {
+                    let (tags, text) = tags::split(line.trim());
+                    output::display(text, &tags.spans, tags.time.unwrap_or(received));
+                }
+            }
+            Direction::Outbound => {
+                let data = String::from_utf8_lossy(&frame.data);
+                output::info(&format!("> {}", tags::split(data.trim()).1))
+            }
+        }
+    }
+    output::info(&t!("replay-end"));
+    Ok(())
 }
 
 #[cfg(test)]
@@ -183,9 +543,68 @@ mod tests {
         ]);
 
         // Assert: verify the parsed values match expected inputs
-        assert_eq!(args.host, "192.168.0.1");
-        assert_eq!(args.port, "9000");
-        assert_eq!(args.username, "testuser");
+        assert_eq!(args.host.as_deref(), Some("192.168.0.1"));
+        assert_eq!(args.port.as_deref(), Some("9000"));
+        assert_eq!(args.username.as_deref(), Some("testuser"));
+    }
+
+    #[test]
+    fn test_replay_does_not_require_username() {
+        let args = Args::parse_from(["test", "--replay", "session.bin"]);
+
+        assert!(args.username.is_none());
+        assert_eq!(args.replay, Some(PathBuf::from("session.bin")));
+    }
+
+    #[test]
+    fn test_guest_needs_no_username() {
+        let args = Args::parse_from(["test", "--guest"]);
+
+        assert!(args.guest && args.username.is_none());
+        assert!(Args::try_parse_from(["test", "--guest", "-u", "bob"]).is_err());
+    }
+
+    #[test]
+    fn test_password_is_not_an_option() {
+        assert!(Args::try_parse_from(["test", "-u", "bob", "--password", "secret"]).is_err());
+    }
+
+    #[test]
+    fn test_profile_needs_no_username() {
+        let args = Args::parse_from(["test", "--profile", "work", "--port", "9000"]);
+
+        assert_eq!(args.profile.as_deref(), Some("work"));
+        assert!(args.username.is_none() && args.host.is_none());
+    }
+
+    #[test]
+    fn test_subcommands_take_the_options_anywhere() {
+        let args = Args::parse_from([
+            "test",
+            "send",
+            "--room",
+            "dev",
+            "build is green",
+            "-u",
+            "ci",
+        ]);
+
+        assert_eq!(
+            args.action,
+            Some(Action::Send {
+                room: Some("dev".into()),
+                message: "build is green".into()
+            })
+        );
+        assert_eq!(args.username.as_deref(), Some("ci"));
+        let args = Args::parse_from(["test", "-u", "ci", "who", "#dev"]);
+        assert_eq!(
+            args.action,
+            Some(Action::Who {
+                room: Some("#dev".into())
+            })
+        );
+        assert!(Args::try_parse_from(["test", "send", "-u", "ci"]).is_err());
     }
 
     #[test]
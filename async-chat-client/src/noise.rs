@@ -0,0 +1,230 @@
+use snow::{Builder, HandshakeState, TransportState};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::i18n::t;
+use crate::output;
+
+/// Must match the server: Noise_XX transmits both static keys during the handshake.
+const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// The line that asks the server to switch the connection to Noise.
+const REQUEST: &[u8] = b"/noise\n";
+
+fn params() -> snow::params::NoiseParams {
+    PARAMS.parse().expect("valid Noise parameters")
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Prefixes a Noise message with its length.
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = (message.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    frame
+}
+
+enum State {
+    Handshake(Box<HandshakeState>),
+    Transport(Box<TransportState>),
+}
+
+/// The client side of a Noise encrypted connection.
+///
+/// Every message is sent as a 2-byte big-endian length followed by the Noise message.
+/// A fresh handshake is run for every connection, with the same long-term key pair.
+pub struct Noise {
+    private: Vec<u8>,
+    /// The server key to insist on, if the user pinned one.
+    server_key: Option<Vec<u8>>,
+    state: Option<State>,
+    /// Received data that doesn't make up a complete message yet.
+    frames: Vec<u8>,
+}
+
+impl Noise {
+    /// Loads the client's key pair from `path`, generating (and saving) one on first use.
+    /// The file holds the private and public key, hex encoded, one per line.
+    pub fn new(path: &Path, server_key: Option<Vec<u8>>) -> io::Result<Self> {
+        let private =
+            match fs::read_to_string(path) {
+                Ok(contents) => contents.lines().next().and_then(from_hex).ok_or_else(|| {
+                    invalid(format!("{} is not a valid key file", path.display()))
+                })?,
+                Err(_) => {
+                    let keypair = Builder::new(params()).generate_keypair().map_err(invalid)?;
+                    let contents = format!("{}\n{}\n", hex(&keypair.private), hex(&keypair.public));
+                    fs::write(path, contents)?;
+                    keypair.private
+                }
+            };
+        Ok(Noise {
+            private,
+            server_key,
+            state: None,
+            frames: Vec::new(),
+        })
+    }
+
+    /// Starts the handshake on a new connection. Returns what to send the server.
+    pub fn start(&mut self) -> io::Result<Vec<u8>> {
+        let mut handshake = Builder::new(params())
+            .local_private_key(&self.private)
+            .build_initiator()
+            .map_err(invalid)?;
+        // -> e
+        let mut message = vec![0; MAX_MESSAGE];
+        let len = handshake
+            .write_message(&[], &mut message)
+            .map_err(invalid)?;
+        self.state = Some(State::Handshake(Box::new(handshake)));
+        self.frames.clear();
+
+        let mut out = REQUEST.to_vec();
+        out.extend_from_slice(&frame(&message[..len]));
+        Ok(out)
+    }
+
+    /// Whether the handshake is done and messages can be sent.
+    pub fn is_ready(&self) -> bool {
+        matches!(self.state, Some(State::Transport(_)))
+    }
+
+    /// Processes data received from the server, appending what it decrypts to
+    /// `plaintext`. Returns the final handshake message to send once the server's
+    /// reply to the handshake arrives.
+    pub fn receive(&mut self, data: &[u8], plaintext: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        self.frames.extend_from_slice(data);
+        let mut reply = None;
+        while let Some(message) = self.next_message() {
+            let mut payload = vec![0; message.len()];
+            match self.state.take() {
+                Some(State::Handshake(mut handshake)) => {
+                    // <- e, ee, s, es
+                    handshake
+                        .read_message(&message, &mut payload)
+                        .map_err(invalid)?;
+                    let server_key = handshake.get_remote_static().unwrap_or_default();
+                    match &self.server_key {
+                        Some(pinned) if pinned != server_key => {
+                            return Err(invalid(format!(
+                                "the server's key {} doesn't match the pinned key",
+                                hex(server_key)
+                            )))
+                        }
+                        Some(_) => {}
+                        None => output::info(&t!("noise-connected", key = hex(server_key))),
+                    }
+                    // -> s, se
+                    let mut out = vec![0; MAX_MESSAGE];
+                    let len = handshake.write_message(&[], &mut out).map_err(invalid)?;
+                    reply = Some(frame(&out[..len]));
+                    let transport = handshake.into_transport_mode().map_err(invalid)?;
+                    self.state = Some(State::Transport(Box::new(transport)));
+                }
+                Some(State::Transport(mut transport)) => {
+                    let len = transport
+                        .read_message(&message, &mut payload)
+                        .map_err(invalid)?;
+                    plaintext.extend_from_slice(&payload[..len]);
+                    self.state = Some(State::Transport(transport));
+                }
+                None => return Err(invalid("unexpected data before the Noise handshake")),
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Pops the next complete message off the receive buffer.
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*self.frames.first()?, *self.frames.get(1)?]) as usize;
+        if self.frames.len() < 2 + len {
+            return None;
+        }
+        let message = self.frames[2..2 + len].to_vec();
+        self.frames.drain(..2 + len);
+        Some(message)
+    }
+
+    /// Encrypts data for the server, split over as many messages as it takes.
+    pub fn seal(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let Some(State::Transport(transport)) = self.state.as_mut() else {
+            return Err(invalid("the Noise handshake hasn't completed"));
+        };
+        let mut out = Vec::new();
+        let mut message = vec![0; MAX_MESSAGE];
+        for chunk in plaintext.chunks(MAX_MESSAGE - TAG_LEN) {
+            let len = transport
+                .write_message(chunk, &mut message)
+                .map_err(invalid)?;
+            out.extend_from_slice(&frame(&message[..len]));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_and_messages() {
+        let path = std::env::temp_dir().join(format!("noise-client-{}.key", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let server_keypair = Builder::new(params()).generate_keypair().unwrap();
+        let mut client = Noise::new(&path, Some(server_keypair.public.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut server = Builder::new(params())
+            .local_private_key(&server_keypair.private)
+            .build_responder()
+            .unwrap();
+        let mut buf = vec![0; MAX_MESSAGE];
+        let hello = client.start().unwrap();
+        let first = hello.strip_prefix(REQUEST).unwrap();
+        server.read_message(&first[2..], &mut buf).unwrap();
+        let len = server.write_message(&[], &mut buf).unwrap();
+        let reply = frame(&buf[..len]);
+
+        // Messages may arrive in pieces
+        let mut plaintext = Vec::new();
+        assert!(client
+            .receive(&reply[..10], &mut plaintext)
+            .unwrap()
+            .is_none());
+        assert!(!client.is_ready());
+        let last = client
+            .receive(&reply[10..], &mut plaintext)
+            .unwrap()
+            .unwrap();
+        assert!(client.is_ready());
+        server.read_message(&last[2..], &mut buf).unwrap();
+
+        let mut server = server.into_transport_mode().unwrap();
+        let sealed = client.seal(b"alice\n").unwrap();
+        let len = server.read_message(&sealed[2..], &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"alice\n");
+
+        let mut out = vec![0; MAX_MESSAGE];
+        let len = server.write_message(b"welcome\n", &mut out).unwrap();
+        client.receive(&frame(&out[..len]), &mut plaintext).unwrap();
+        assert_eq!(plaintext, b"welcome\n");
+    }
+}
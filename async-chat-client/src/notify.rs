@@ -0,0 +1,161 @@
+use serde::Deserialize;
+use std::process::Command;
+use std::thread;
+
+/// Why an incoming chat message calls for the user's attention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alert {
+    /// A private message or whisper to us.
+    Private,
+    /// A room message that mentions us by name.
+    Mention,
+    /// A room message with one of the keywords in it.
+    Keyword,
+    /// A room message from one of the senders.
+    Sender,
+    /// A message in one of the rooms.
+    Room,
+}
+
+/// When the user is notified of incoming messages, and how: the `[notifications]`
+/// table of the config file. Private messages and mentions ring the bell by default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// Notify of messages that mention us by name.
+    pub mentions: bool,
+    /// Notify of private messages and whispers.
+    pub private: bool,
+    /// Notify of room messages with any of these words in them, whatever the case.
+    pub keywords: Vec<String>,
+    /// Notify of room messages from these users.
+    pub senders: Vec<String>,
+    /// Notify of every message in these rooms.
+    pub rooms: Vec<String>,
+    /// Never notify of messages in these rooms, whatever the other rules say.
+    pub muted_rooms: Vec<String>,
+    /// Ring the terminal's bell.
+    pub bell: bool,
+    /// Show a desktop notification, with `notify-send`.
+    pub desktop: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            mentions: true,
+            private: true,
+            keywords: Vec::new(),
+            senders: Vec::new(),
+            rooms: Vec::new(),
+            muted_rooms: Vec::new(),
+            bell: true,
+            desktop: false,
+        }
+    }
+}
+
+impl Rules {
+    /// Whether `line`, a chat message as the server sends it (`[bob]: hi alice`) in
+    /// `room`, calls for the attention of `username`, and why. The rules are tried
+    /// in the order of [`Alert`].
+    pub fn alert(&self, line: &str, username: &str, room: &str) -> Option<Alert> {
+        let (header, text) = line.strip_prefix('[')?.split_once("]: ")?;
+        if header.contains(" -> ") || header.contains(" ~> ") {
+            return self.private.then_some(Alert::Private);
+        }
+        let room = room.trim_start_matches('#');
+        let listed = |rooms: &[String]| rooms.iter().any(|r| r.trim_start_matches('#') == room);
+        if listed(&self.muted_rooms) {
+            None
+        } else if self.mentions && mentions(text, username) {
+            Some(Alert::Mention)
+        } else if self.keywords.iter().any(|keyword| mentions(text, keyword)) {
+            Some(Alert::Keyword)
+        } else if self.senders.iter().any(|sender| sender == header) {
+            Some(Alert::Sender)
+        } else if listed(&self.rooms) {
+            Some(Alert::Room)
+        } else {
+            None
+        }
+    }
+
+    /// Notifies the user of `line`, the way the rules say.
+    pub fn notify(&self, line: &str) {
+        if self.bell {
+            crate::output::bell();
+        }
+        if self.desktop {
+            desktop(line);
+        }
+    }
+}
+
+/// Who sent `line`, a chat message as the server sends it: `bob` for `[bob]: hi` or
+/// `[bob -> alice]: hi`.
+pub fn sender(line: &str) -> Option<&str> {
+    let (header, _) = line.strip_prefix('[')?.split_once("]: ")?;
+    header.split(' ').next()
+}
+
+/// Whether `text` has `word` in it as a word of its own, e.g. `@alice` or `alice:`
+/// for `alice`, whatever the case.
+pub fn mentions(text: &str, word: &str) -> bool {
+    let word = word.to_lowercase();
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .any(|candidate| candidate.to_lowercase() == word)
+}
+
+/// Shows `line` as a desktop notification, on a thread of its own so a slow
+/// notification daemon doesn't hold up the chat. Without `notify-send`, nothing
+/// is shown.
+fn desktop(line: &str) {
+    let Some((header, text)) = line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once("]: "))
+    else {
+        return;
+    };
+    let (title, body) = (format!("simple-chat: {header}"), text.to_string());
+    thread::spawn(move || {
+        let _ = Command::new("notify-send").arg(title).arg(body).status();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_messages_and_mentions_alert() {
+        let rules = Rules::default();
+        let alert = |line| rules.alert(line, "alice", "lobby");
+        assert_eq!(alert("[bob -> alice]: hi"), Some(Alert::Private));
+        assert_eq!(alert("[bob ~> alice]: psst"), Some(Alert::Private));
+        assert_eq!(alert("[bob]: @Alice, look"), Some(Alert::Mention));
+        assert_eq!(alert("[bob]: alicetown is nice"), None);
+        assert_eq!(alert("[alice]: hi"), None);
+        assert_eq!(alert("Welcome, alice"), None);
+        assert_eq!(sender("[bob -> alice]: hi"), Some("bob"));
+    }
+
+    #[test]
+    fn test_rules_pick_keywords_senders_and_rooms() {
+        let rules = Rules {
+            mentions: false,
+            keywords: vec!["deploy".to_string()],
+            senders: vec!["carol".to_string()],
+            rooms: vec!["#alerts".to_string()],
+            muted_rooms: vec!["random".to_string()],
+            ..Rules::default()
+        };
+        let alert = |line, room| rules.alert(line, "alice", room);
+        assert_eq!(alert("[bob]: alice, look", "lobby"), None);
+        assert_eq!(alert("[bob]: Deploy done", "lobby"), Some(Alert::Keyword));
+        assert_eq!(alert("[carol]: hi", "lobby"), Some(Alert::Sender));
+        assert_eq!(alert("[bob]: hi", "alerts"), Some(Alert::Room));
+        assert_eq!(alert("[carol]: deploy", "random"), None);
+        assert_eq!(alert("[bob -> alice]: hi", "random"), Some(Alert::Private));
+    }
+}
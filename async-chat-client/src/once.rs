@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::i18n::t;
+
+/// How long a one-shot run may take, from connecting to the server's last answer.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A one-shot run, for scripts: once joined (and in the room asked for, if any), the
+/// lines are sent as if typed, followed by `/ping <token>`. The server handles them
+/// in order, so its answers to them are what comes before `/pong <token>`.
+#[derive(Debug)]
+pub struct Once {
+    lines: Vec<String>,
+    room: Option<String>,
+    token: String,
+    stage: Stage,
+    deadline: Instant,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Stage {
+    /// Waiting to join the server.
+    Joining,
+    /// `/join` sent for the room asked for.
+    Entering,
+    /// The lines are sent, their answers are coming in.
+    Sent,
+    /// The server answered them all.
+    Answered,
+    Failed(String),
+}
+
+impl Once {
+    /// Sends `lines`, in `room` if there is one (with or without its `#`).
+    pub fn new(lines: Vec<String>, room: Option<String>, now: Instant) -> Self {
+        Once {
+            lines,
+            room: room.map(|room| room.trim_start_matches('#').to_string()),
+            token: Uuid::new_v4().simple().to_string(),
+            stage: Stage::Joining,
+            deadline: now + TIMEOUT,
+        }
+    }
+
+    /// We joined `room`, on connecting or later. Returns the lines to type, if it is
+    /// time to.
+    pub fn joined(&mut self, room: &str) -> Vec<String> {
+        if self.stage != Stage::Joining {
+            return Vec::new();
+        }
+        match &self.room {
+            Some(wanted) if wanted != room => {
+                self.stage = Stage::Entering;
+                vec![format!("/join #{wanted}"), self.ping()]
+            }
+            _ => self.send(),
+        }
+    }
+
+    /// Takes note of `/pong <token>`, while we are in `room`. Returns None for any
+    /// other line, or the lines to type next.
+    pub fn pong(&mut self, line: &str, room: &str) -> Option<Vec<String>> {
+        if line.strip_prefix("/pong ") != Some(self.token.as_str()) {
+            return None;
+        }
+        match self.stage {
+            Stage::Entering if self.room.as_deref() == Some(room) => return Some(self.send()),
+            Stage::Entering => {
+                let wanted = self.room.as_deref().unwrap_or_default();
+                self.fail(t!("once-not-in-room", room = wanted));
+            }
+            Stage::Sent => self.stage = Stage::Answered,
+            _ => {}
+        }
+        Some(Vec::new())
+    }
+
+    /// Whether lines from the server are answers to ours, to show.
+    pub fn shows(&self) -> bool {
+        matches!(self.stage, Stage::Entering | Stage::Sent)
+    }
+
+    /// Gives up, saying why.
+    pub fn fail(&mut self, why: String) {
+        self.stage = Stage::Failed(why);
+    }
+
+    /// How it went, once it is over: answered, with our messages `settled` (all
+    /// acknowledged), failed or out of time.
+    pub fn outcome(&self, settled: bool, now: Instant) -> Option<Result<(), String>> {
+        match &self.stage {
+            Stage::Answered if settled => Some(Ok(())),
+            Stage::Failed(why) => Some(Err(why.clone())),
+            _ if now >= self.deadline => Some(Err(t!("once-timeout"))),
+            _ => None,
+        }
+    }
+
+    /// How long until the run is out of time.
+    pub fn poll_timeout(&self, now: Instant) -> Duration {
+        self.deadline.saturating_duration_since(now)
+    }
+
+    fn send(&mut self) -> Vec<String> {
+        self.stage = Stage::Sent;
+        let mut lines = std::mem::take(&mut self.lines);
+        lines.push(self.ping());
+        lines
+    }
+
+    fn ping(&self) -> String {
+        format!("/ping {}", self.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_go_out_in_the_room_asked_for() {
+        let now = Instant::now();
+        let mut once = Once::new(vec!["hi".into()], Some("#dev".into()), now);
+        let pong = format!("/pong {}", once.token);
+        assert_eq!(
+            once.joined("lobby"),
+            ["/join #dev".to_string(), once.ping()]
+        );
+        assert!(once.joined("dev").is_empty());
+        assert!(once.shows());
+        assert_eq!(once.pong("/pong other", "dev"), None);
+        assert_eq!(
+            once.pong(&pong, "dev"),
+            Some(vec!["hi".into(), once.ping()])
+        );
+        assert_eq!(once.outcome(true, now), None);
+        assert_eq!(once.pong(&pong, "dev"), Some(Vec::new()));
+        assert!(!once.shows());
+        assert_eq!(once.outcome(false, now), None);
+        assert_eq!(once.outcome(true, now), Some(Ok(())));
+    }
+
+    #[test]
+    fn test_a_room_that_can_not_be_entered_fails() {
+        let now = Instant::now();
+        let mut once = Once::new(vec!["hi".into()], Some("dev".into()), now);
+        let pong = format!("/pong {}", once.token);
+        once.joined("lobby");
+        assert_eq!(once.pong(&pong, "lobby"), Some(Vec::new()));
+        assert!(matches!(once.outcome(true, now), Some(Err(_))));
+        let once = Once::new(Vec::new(), None, now);
+        assert!(matches!(once.outcome(true, now + TIMEOUT), Some(Err(_))));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Frames waiting to be written to the server.
+///
+/// Writes to a non-blocking socket may be short or fail with `WouldBlock`, so frames are
+/// queued here and drained whenever the socket reports it is writable. `offset` tracks how
+/// much of the frame at the front of the queue has already been written.
+pub struct OutboundBuffer<T> {
+    frames: VecDeque<T>,
+    offset: usize,
+}
+
+impl<T: AsRef<[u8]>> OutboundBuffer<T> {
+    pub fn new() -> Self {
+        OutboundBuffer {
+            frames: VecDeque::new(),
+            offset: 0,
+        }
+    }
+
+    /// Appends a frame to the back of the buffer.
+    pub fn push(&mut self, frame: T) {
+        self.frames.push_back(frame);
+    }
+
+    /// Returns true if there is nothing left to write.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Writes as much of the buffer as `writer` accepts, stopping at the first `WouldBlock`.
+    ///
+    /// `on_write` is called with every chunk that was actually written, along with
+    /// the frame it belongs to.
+    pub fn write_to<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mut on_write: impl FnMut(&T, &[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        while let Some(frame) = self.frames.front() {
+            let remaining = &frame.as_ref()[self.offset..];
+            match writer.write(remaining) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    on_write(frame, &remaining[..n])?;
+                    self.offset += n;
+                    if self.offset == frame.as_ref().len() {
+                        self.frames.pop_front();
+                        self.offset = 0;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Empties the buffer, returning every frame that was not completely written
+    /// (including a partially written one) so it can be sent again on a new connection.
+    pub fn take_unsent(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.offset = 0;
+        self.frames.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer that accepts at most `limit` bytes before reporting `WouldBlock`.
+    struct Throttled {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.limit - self.written.len());
+            if n == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_partial_writes_resume_at_offset() {
+        let mut buffer = OutboundBuffer::new();
+        buffer.push("hello\n");
+        buffer.push("world\n");
+
+        let mut writer = Throttled {
+            written: Vec::new(),
+            limit: 8,
+        };
+        buffer.write_to(&mut writer, |_, _| Ok(())).unwrap();
+        assert!(!buffer.is_empty());
+        assert_eq!(writer.written, b"hello\nwo");
+
+        writer.limit = 64;
+        buffer.write_to(&mut writer, |_, _| Ok(())).unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(writer.written, b"hello\nworld\n");
+    }
+
+    #[test]
+    fn test_take_unsent_returns_partial_frames() {
+        let mut buffer = OutboundBuffer::new();
+        buffer.push("hello\n");
+        buffer.push("world\n");
+
+        let mut writer = Throttled {
+            written: Vec::new(),
+            limit: 3,
+        };
+        buffer.write_to(&mut writer, |_, _| Ok(())).unwrap();
+
+        let unsent: Vec<_> = buffer.take_unsent().collect();
+        assert_eq!(unsent, vec!["hello\n", "world\n"]);
+        assert!(buffer.is_empty());
+    }
+}
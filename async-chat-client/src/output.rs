@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::i18n::t;
+use crate::spans::{self, Span};
+use crate::timestamp::TimeFormat;
+use crate::wrap;
+
+/// Whether output is in `--plain` mode.
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Switches to plain output: every line starts with a prefix saying what it is, and
+/// nothing is wrapped or redrawn, for screen readers and scripts.
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+pub fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Whether the client's own notices are left out.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Leaves the client's own notices out from now on, for one-shot runs, whose output
+/// is what the server answered. Errors and prompts are still shown.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether formatted messages are shown as typed, with their markers, rather than
+/// styled.
+static RAW: AtomicBool = AtomicBool::new(false);
+
+/// Switches between showing formatted messages styled and as typed. Returns true if
+/// they are shown as typed from now on.
+pub fn toggle_raw() -> bool {
+    !RAW.fetch_xor(true, Ordering::Relaxed)
+}
+
+/// A chat line whose text is formatted with `spans`, styled with ANSI escape codes
+/// for a terminal. Plain output, output that isn't a terminal and raw mode get the
+/// markers the text was typed with instead.
+fn formatted(line: &str, spans: &[Span]) -> String {
+    if is_plain() || RAW.load(Ordering::Relaxed) || wrap::terminal_width().is_none() {
+        spans::render_line(line, spans)
+    } else {
+        spans::styled_line(line, spans)
+    }
+}
+
+/// How chat messages are timestamped, if they are.
+static TIME_FORMAT: OnceLock<TimeFormat> = OnceLock::new();
+
+/// Shows the time in front of every chat message from now on.
+pub fn set_time_format(format: TimeFormat) {
+    let _ = TIME_FORMAT.set(format);
+}
+
+/// Puts the time `at` in front of a chat message, if timestamps are on.
+fn stamp(text: &str, at: DateTime<Utc>) -> String {
+    match TIME_FORMAT.get() {
+        Some(format) => format!("{} {text}", format.format(at)),
+        None => text.to_string(),
+    }
+}
+
+/// What a line of output is, shown as its prefix in plain mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A chat message, from the room, a private message or a direct connection.
+    Msg,
+    /// A whisper, a private message from someone in the room.
+    Whisper,
+    /// Anything else the server sent, such as command replies and notices.
+    Server,
+    /// A notice from the client itself.
+    Info,
+    Error,
+    /// A question for the user, answered on the next input line.
+    Prompt,
+}
+
+impl Kind {
+    fn prefix(self) -> &'static str {
+        match self {
+            Kind::Msg => "MSG",
+            Kind::Whisper => "WHISPER",
+            Kind::Server => "SERVER",
+            Kind::Info => "INFO",
+            Kind::Error => "ERROR",
+            Kind::Prompt => "PROMPT",
+        }
+    }
+}
+
+/// Formats text for output. In plain mode, every line of it gets the prefix of its
+/// kind. Otherwise chat messages are wrapped to the terminal's width.
+fn format(kind: Kind, text: &str) -> String {
+    if is_plain() {
+        let prefix = kind.prefix();
+        return text
+            .lines()
+            .map(|line| format!("{prefix} {line}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    match wrap::terminal_width() {
+        Some(width) if matches!(kind, Kind::Msg | Kind::Whisper) => wrap::wrap(text, width),
+        _ => text.to_string(),
+    }
+}
+
+/// Prints a client notice.
+pub fn info(text: &str) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    println!("{}", format(Kind::Info, text));
+}
+
+/// Prints an error, on stderr.
+pub fn error(text: &str) {
+    eprintln!("{}", format(Kind::Error, text));
+}
+
+/// Prints a chat message.
+pub fn message(text: &str) {
+    println!("{}", format(Kind::Msg, &stamp(text, Utc::now())));
+}
+
+/// Prints a whisper received from the server, set apart from the room's messages by
+/// its prefix. `at` is when it was sent, `spans` how it is formatted.
+pub fn whisper(text: &str, spans: &[Span], at: DateTime<Utc>) {
+    let text = formatted(text, spans);
+    let text = if is_plain() {
+        text
+    } else {
+        t!("whisper", text = text)
+    };
+    println!("{}", format(Kind::Whisper, &stamp(&text, at)));
+}
+
+/// An action sent with `/me`, `[alice]: waves` as the server sends it, as it is
+/// shown: `* alice waves`.
+pub fn action_line(line: &str) -> String {
+    match line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once("]: "))
+    {
+        Some((from, action)) => format!("* {from} {action}"),
+        None => line.to_string(),
+    }
+}
+
+/// Prints an action sent with `/me`, in italics in a terminal. `at` is when it was
+/// sent.
+pub fn action(text: &str, at: DateTime<Utc>) {
+    let text = action_line(text);
+    let text = if is_plain() || wrap::terminal_width().is_none() {
+        text
+    } else {
+        format!("\x1b[3;35m{text}\x1b[0m")
+    };
+    println!("{}", format(Kind::Msg, &stamp(&text, at)));
+}
+
+/// Rings the terminal's bell, unless output isn't a terminal or is plain.
+pub fn bell() {
+    if !is_plain() && wrap::terminal_width().is_some() {
+        print!("\x07");
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Asks the user something. The answer is typed on the same line, except in plain
+/// mode where the prompt is a line of its own.
+pub fn prompt(text: &str) -> io::Result<()> {
+    if is_plain() {
+        println!("{}", format(Kind::Prompt, text));
+        return Ok(());
+    }
+    print!("{text} ");
+    io::stdout().flush()
+}
+
+/// Renders a line received from the server, without its tags: chat messages start
+/// with `[`, anything else is the server talking. `at` is when the message was sent,
+/// `spans` how it is formatted.
+pub fn display(text: &str, spans: &[Span], at: DateTime<Utc>) {
+    if text.starts_with('[') {
+        let text = formatted(text, spans);
+        println!("{}", format(Kind::Msg, &stamp(&text, at)));
+    } else {
+        println!("{}", format(Kind::Server, text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_output_prefixes_every_line() {
+        set_plain(true);
+        assert_eq!(format(Kind::Msg, "[bob]: hi"), "MSG [bob]: hi");
+        assert_eq!(
+            format(Kind::Server, "Available commands:\n  /help"),
+            "SERVER Available commands:\nSERVER   /help"
+        );
+        set_plain(false);
+    }
+
+    #[test]
+    fn test_actions_read_as_sentences() {
+        assert_eq!(action_line("[alice]: waves"), "* alice waves");
+        assert_eq!(action_line("waves"), "waves");
+    }
+}
@@ -0,0 +1,66 @@
+use mio::Waker;
+use sha2::{Digest, Sha256};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+/// The hardest challenge the client takes on, the most servers ask for. Each bit
+/// doubles the work, so a server asking for many more could keep us busy for ever.
+pub const MAX_BITS: u32 = 32;
+
+/// Solves a server's proof-of-work challenge: finds a nonce such that
+/// `SHA-256("<challenge>:<nonce>")` starts with at least `bits` zero bits.
+pub fn solve(challenge: &str, bits: u32) -> u64 {
+    (0..)
+        .find(|nonce| leading_zero_bits(challenge, *nonce) >= bits)
+        .expect("a solution within u64")
+}
+
+/// Solves a challenge on a thread of its own, so the client keeps going meanwhile.
+/// The challenge and its nonce are sent over `solved`, and the event loop woken
+/// through `waker`.
+pub fn solve_in_background(
+    challenge: String,
+    bits: u32,
+    solved: Sender<(String, u64)>,
+    waker: Arc<Waker>,
+) {
+    thread::spawn(move || {
+        let nonce = solve(&challenge, bits);
+        if solved.send((challenge, nonce)).is_ok() {
+            let _ = waker.wake();
+        }
+    });
+}
+
+fn leading_zero_bits(challenge: &str, nonce: u64) -> u32 {
+    let digest = Sha256::digest(format!("{challenge}:{nonce}"));
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Parses a `/pow <challenge> <bits>` line from the server.
+pub fn parse_challenge(line: &str) -> Option<(&str, u32)> {
+    let (challenge, bits) = line.strip_prefix("/pow ")?.split_once(' ')?;
+    Some((challenge, bits.trim().parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        let (challenge, bits) = parse_challenge("/pow 0123456789abcdef 12").unwrap();
+        let nonce = solve(challenge, bits);
+        assert!(leading_zero_bits(challenge, nonce) >= 12);
+        assert!((0..nonce).all(|n| leading_zero_bits(challenge, n) < 12));
+        assert!(parse_challenge("hello").is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::RootCertStore;
+use quinn::{ClientConfig, Endpoint};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+
+use crate::i18n::t;
+use crate::output;
+
+/// Name the server's certificate is issued for. The certificate itself is pinned, so
+/// this doesn't need to match the server's DNS name.
+const SERVER_NAME: &str = "simple-chat";
+
+/// Carries the client's connection over QUIC, which keeps working when the client's
+/// address changes and encrypts everything on the way.
+///
+/// The event loop only speaks TCP, so the bridge listens on a loopback port and
+/// forwards every connection made to it over a new QUIC connection to the server.
+/// When either side goes away, so does the other, and the client's reconnect logic
+/// takes it from there.
+pub struct Bridge {
+    local: SocketAddr,
+    endpoint: Endpoint,
+    runtime: Handle,
+    /// The server new connections are forwarded to.
+    target: Arc<Mutex<SocketAddr>>,
+}
+
+impl Bridge {
+    /// Starts the bridge on a thread of its own. Only a server presenting the
+    /// certificate in `cert_path` (the server's `quic-cert.der`) is trusted.
+    pub fn start(cert_path: &Path, target: SocketAddr) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(fs::read(cert_path)?))
+            .map_err(invalid)?;
+        let config = ClientConfig::with_root_certificates(Arc::new(roots)).map_err(invalid)?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let unspecified: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let (endpoint, listener) = runtime.block_on(async {
+            let mut endpoint = Endpoint::client(unspecified)?;
+            endpoint.set_default_client_config(config);
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            io::Result::Ok((endpoint, listener))
+        })?;
+
+        let bridge = Bridge {
+            local: listener.local_addr()?,
+            endpoint: endpoint.clone(),
+            runtime: runtime.handle().clone(),
+            target: Arc::new(Mutex::new(target)),
+        };
+        let target = Arc::clone(&bridge.target);
+        thread::spawn(move || {
+            runtime.block_on(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let target = *target.lock().unwrap();
+                    tokio::spawn(forward(stream, endpoint.clone(), target));
+                }
+            })
+        });
+        Ok(bridge)
+    }
+
+    /// The loopback address the client connects to instead of the server.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Forwards connections made from now on to another server.
+    pub fn retarget(&self, target: SocketAddr) {
+        *self.target.lock().unwrap() = target;
+    }
+}
+
+impl Drop for Bridge {
+    /// Tells the server we are leaving, rather than have it wait for the connection
+    /// to time out.
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+        self.runtime.block_on(self.endpoint.wait_idle());
+    }
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// Copies data both ways between a local connection and a QUIC stream to the server.
+async fn forward(mut local: TcpStream, endpoint: Endpoint, target: SocketAddr) {
+    let connection = match endpoint.connect(target, SERVER_NAME) {
+        Ok(connecting) => connecting.await,
+        Err(e) => {
+            output::error(&t!("quic-failed", target = target, error = e));
+            return;
+        }
+    };
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            output::error(&t!("quic-failed", target = target, error = e));
+            return;
+        }
+    };
+    let Ok((mut send, mut recv)) = connection.open_bi().await else {
+        return;
+    };
+
+    let (mut local_read, mut local_write) = local.split();
+    let upstream = async {
+        let _ = tokio::io::copy(&mut local_read, &mut send).await;
+        // Wait for the server to read everything before closing the connection
+        let _ = send.finish();
+        let _ = send.stopped().await;
+    };
+    let downstream = async {
+        let _ = tokio::io::copy(&mut recv, &mut local_write).await;
+        let _ = local_write.shutdown().await;
+    };
+    // Whichever side finishes first ends the session
+    tokio::select! {
+        _ = upstream => {}
+        _ = downstream => {}
+    }
+    connection.close(0u32.into(), b"");
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of every session recording.
+const MAGIC: &[u8; 8] = b"SCHATREC";
+/// The largest frame in a recording. Larger reads are recorded as several frames, and
+/// replaying a file with a larger one fails rather than allocating whatever it says.
+const MAX_FRAME: usize = 64 * 1024;
+
+/// Direction of a recorded frame, relative to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Bytes read from the server.
+    Inbound,
+    /// Bytes written to the server.
+    Outbound,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            _ => Err(invalid_data("unknown frame direction")),
+        }
+    }
+}
+
+/// A single frame read back from a recording.
+#[derive(Debug)]
+pub struct Frame {
+    pub direction: Direction,
+    /// Time elapsed between the start of the recording and this frame.
+    pub offset: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Appends every frame exchanged with the server to a recording file.
+///
+/// Each record is laid out as `direction (u8) | offset in µs (u64 LE) | length (u32 LE) | data`,
+/// following an 8 byte magic and the wall-clock start time of the session (u64 LE, seconds).
+pub struct Recorder {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Creates (or truncates) the recording at `path` and writes the header.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        out.write_all(MAGIC)?;
+        out.write_all(&unix_secs.to_le_bytes())?;
+        out.flush()?;
+        Ok(Recorder {
+            out,
+            started: Instant::now(),
+        })
+    }
+
+    /// Records a frame, split in frames of up to [`MAX_FRAME`] bytes if it is larger.
+    /// The file is flushed after every frame so a crashing client still leaves a
+    /// usable recording behind.
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let offset = self.started.elapsed().as_micros() as u64;
+        for chunk in data.chunks(MAX_FRAME) {
+            self.out.write_all(&[direction.to_byte()])?;
+            self.out.write_all(&offset.to_le_bytes())?;
+            self.out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+            self.out.write_all(chunk)?;
+        }
+        self.out.flush()
+    }
+}
+
+/// Reads frames back from a recording created by [`Recorder`].
+pub struct Replay {
+    input: BufReader<File>,
+    /// Wall-clock start time of the recorded session, in seconds since the Unix epoch.
+    pub started_at: u64,
+}
+
+impl Replay {
+    /// Opens the recording at `path` and validates its header.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a session recording"));
+        }
+        let mut started_at = [0; 8];
+        input.read_exact(&mut started_at)?;
+        Ok(Replay {
+            input,
+            started_at: u64::from_le_bytes(started_at),
+        })
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut direction = [0; 1];
+        match self.input.read_exact(&mut direction) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut offset = [0; 8];
+        let mut len = [0; 4];
+        self.input.read_exact(&mut offset)?;
+        self.input.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return Err(invalid_data("frame too large"));
+        }
+        let mut data = vec![0; len];
+        self.input.read_exact(&mut data)?;
+        Ok(Some(Frame {
+            direction: Direction::from_byte(direction[0])?,
+            offset: Duration::from_micros(u64::from_le_bytes(offset)),
+            data,
+        }))
+    }
+}
+
+impl Iterator for Replay {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay_roundtrip() {
+        let path = std::env::temp_dir().join(format!("simple-chat-{}.bin", std::process::id()));
+
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record(Direction::Outbound, b"testuser\n").unwrap();
+        recorder.record(Direction::Inbound, b"[bob]: hi\n").unwrap();
+        drop(recorder);
+
+        let frames: Vec<Frame> = Replay::open(&path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].direction, Direction::Outbound);
+        assert_eq!(frames[0].data, b"testuser\n");
+        assert_eq!(frames[1].direction, Direction::Inbound);
+        assert_eq!(frames[1].data, b"[bob]: hi\n");
+        assert!(frames[0].offset <= frames[1].offset);
+    }
+
+    #[test]
+    fn test_oversized_frames_are_split_and_refused() {
+        let path = std::env::temp_dir().join(format!("simple-chat-big-{}.bin", std::process::id()));
+
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder
+            .record(Direction::Inbound, &vec![b'x'; MAX_FRAME + 1])
+            .unwrap();
+        drop(recorder);
+        let sizes: Vec<usize> = (Replay::open(&path).unwrap())
+            .map(|frame| frame.unwrap().data.len())
+            .collect();
+        assert_eq!(sizes, [MAX_FRAME, 1]);
+
+        // A corrupt length is an error, not a 4 GiB allocation
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0; 9]).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+        let last = Replay::open(&path).unwrap().last().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(last.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
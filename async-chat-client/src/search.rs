@@ -0,0 +1,112 @@
+/// Lines shown on either side of a match.
+const CONTEXT: usize = 2;
+
+/// A search through the chat history, stepping from match to match with `/next`
+/// (older) and `/prev` (newer). The newest match is shown first.
+pub struct Search {
+    query: String,
+    lines: Vec<String>,
+    /// Indexes into `lines` of the lines matching the query, oldest first.
+    matches: Vec<usize>,
+    /// The match shown, counted from the newest.
+    current: usize,
+}
+
+impl Search {
+    /// Searches `lines`, oldest first, for `query`, ignoring case.
+    pub fn new(query: &str, lines: Vec<String>) -> Self {
+        let needle = query.to_lowercase();
+        let matches = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.to_lowercase().contains(&needle))
+            .map(|(index, _)| index)
+            .collect();
+        Search {
+            query: query.to_string(),
+            lines,
+            matches,
+            current: 0,
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    /// Moves to the next older match. Returns false if there is none.
+    pub fn next(&mut self) -> bool {
+        if self.current + 1 >= self.matches.len() {
+            return false;
+        }
+        self.current += 1;
+        true
+    }
+
+    /// Moves to the next newer match. Returns false if there is none.
+    pub fn prev(&mut self) -> bool {
+        if self.current == 0 {
+            return false;
+        }
+        self.current -= 1;
+        true
+    }
+
+    /// The match shown as `(position, count)`, where 1 is the newest.
+    pub fn position(&self) -> (usize, usize) {
+        (self.current + 1, self.matches.len())
+    }
+
+    /// The current match with the lines around it, the match marked with `>`.
+    pub fn render(&self) -> String {
+        let Some(&found) = self
+            .matches
+            .get(self.matches.len().wrapping_sub(self.current + 1))
+        else {
+            return String::new();
+        };
+        let start = found.saturating_sub(CONTEXT);
+        let end = (found + CONTEXT + 1).min(self.lines.len());
+        (start..end)
+            .map(|index| {
+                let marker = if index == found { '>' } else { ' ' };
+                format!("{marker} {}", self.lines[index])
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_steps_from_newest_to_oldest() {
+        let lines = [
+            "[bob]: Hello",
+            "[alice]: hi",
+            "[bob]: hello again",
+            "[carol]: yo",
+        ];
+        let mut search = Search::new("hello", lines.map(String::from).to_vec());
+        assert_eq!(search.position(), (1, 2));
+        assert_eq!(
+            search.render(),
+            "  [bob]: Hello\n  [alice]: hi\n> [bob]: hello again\n  [carol]: yo"
+        );
+
+        assert!(search.next());
+        assert_eq!(search.position(), (2, 2));
+        assert!(search.render().starts_with("> [bob]: Hello\n"));
+        assert!(!search.next());
+        assert!(search.prev());
+        assert!(!search.prev());
+
+        assert!(Search::new("nothing", lines.map(String::from).to_vec()).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use std::ops::RangeInclusive;
+
+/// What to do with a room message, going by its sequence number.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    /// The next message, or one we asked for again: show it.
+    Show,
+    /// Seen already: skip it.
+    Duplicate,
+    /// Messages were missed before this one: show it, and ask for these again.
+    Gap(RangeInclusive<u64>),
+}
+
+/// Keeps track of the room's sequence numbers, which the server counts up from 1
+/// without gaps, to notice messages that never arrived.
+#[derive(Debug, Default)]
+pub struct Sequence {
+    /// The highest number seen, including those of our own messages.
+    last: Option<u64>,
+    /// Missed messages we have asked the server for again.
+    fetching: Option<RangeInclusive<u64>>,
+}
+
+impl Sequence {
+    /// Takes note of a message's number, ours or someone else's.
+    pub fn receive(&mut self, seq: u64) -> Received {
+        if let Some(fetching) = &self.fetching {
+            if fetching.contains(&seq) {
+                if seq == *fetching.end() {
+                    self.fetching = None;
+                }
+                return Received::Show;
+            }
+        }
+        let Some(last) = self.last else {
+            // We can't know what came before we joined
+            self.last = Some(seq);
+            return Received::Show;
+        };
+        if seq <= last {
+            return Received::Duplicate;
+        }
+        self.last = Some(seq);
+        if seq == last + 1 {
+            return Received::Show;
+        }
+        let missed = last + 1..=seq - 1;
+        self.fetching = Some(missed.clone());
+        Received::Gap(missed)
+    }
+
+    /// Takes note of messages we asked the server for, which are shown when they come
+    /// in although they are older than the last one seen.
+    pub fn fetch(&mut self, missed: RangeInclusive<u64>) {
+        self.fetching = Some(missed);
+    }
+
+    /// The highest number seen.
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Takes note of the number of the room's latest message, which the server sends
+    /// when we join. If we saw a higher one, the server restarted and numbers messages
+    /// from 1 again.
+    pub fn joined(&mut self, latest: u64) {
+        if self.last.is_none_or(|last| last > latest) {
+            self.last = Some(latest);
+            self.fetching = None;
+        }
+    }
+
+    /// Starts afresh, e.g. on another server.
+    pub fn reset(&mut self) {
+        *self = Sequence::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaps_are_noticed_and_filled() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.receive(5), Received::Show);
+        assert_eq!(sequence.receive(6), Received::Show);
+        assert_eq!(sequence.receive(6), Received::Duplicate);
+        assert_eq!(sequence.receive(9), Received::Gap(7..=8));
+        // The missed messages are shown when they come in again, but only once
+        assert_eq!(sequence.receive(7), Received::Show);
+        assert_eq!(sequence.receive(8), Received::Show);
+        assert_eq!(sequence.receive(8), Received::Duplicate);
+        assert_eq!(sequence.receive(10), Received::Show);
+    }
+
+    #[test]
+    fn test_numbers_start_over_after_a_server_restart() {
+        let mut sequence = Sequence::default();
+        sequence.joined(0);
+        assert_eq!(sequence.receive(2), Received::Gap(1..=1));
+        sequence.joined(5);
+        assert_eq!(sequence.last(), Some(2));
+        sequence.joined(0);
+        assert_eq!(sequence.receive(1), Received::Show);
+    }
+
+    #[test]
+    fn test_fetched_messages_are_shown() {
+        let mut sequence = Sequence::default();
+        sequence.joined(9);
+        sequence.fetch(6..=9);
+        assert_eq!(sequence.receive(6), Received::Show);
+        assert_eq!(sequence.receive(9), Received::Show);
+        assert_eq!(sequence.receive(9), Received::Duplicate);
+    }
+}
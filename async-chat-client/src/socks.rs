@@ -0,0 +1,200 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::i18n::t;
+use crate::output;
+
+/// How long the proxy may take to connect to the server. Tor has to build a circuit
+/// first, and reaching an onion service takes several round trips across the
+/// network, so this is much longer than a direct connection would need.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long connecting to the proxy itself may take.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Carries the client's connection through a SOCKS5 proxy, such as Tor's.
+///
+/// The event loop only speaks non-blocking TCP, while the proxy has to be talked to
+/// before any chat data flows, so the bridge listens on a loopback port and opens a
+/// connection through the proxy for every connection made to it. The server's host
+/// name is resolved by the proxy, which is what makes `.onion` addresses work and
+/// keeps DNS lookups from leaking outside Tor.
+pub struct Bridge {
+    local: SocketAddr,
+    /// The server new connections are forwarded to, as `host:port`.
+    target: Arc<Mutex<String>>,
+}
+
+impl Bridge {
+    /// Starts the bridge on a thread of its own, forwarding through the proxy at `proxy`.
+    pub fn start(proxy: SocketAddr, target: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let bridge = Bridge {
+            local: listener.local_addr()?,
+            target: Arc::new(Mutex::new(target.to_string())),
+        };
+        let target = Arc::clone(&bridge.target);
+        thread::spawn(move || {
+            for local in listener.incoming().flatten() {
+                let target = target.lock().unwrap().clone();
+                thread::spawn(move || forward(local, proxy, &target));
+            }
+        });
+        Ok(bridge)
+    }
+
+    /// The loopback address the client connects to instead of the server.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Forwards connections made from now on to another server.
+    pub fn retarget(&self, target: &str) {
+        *self.target.lock().unwrap() = target.to_string();
+    }
+}
+
+/// Copies data both ways between a local connection and a connection through the proxy.
+fn forward(local: TcpStream, proxy: SocketAddr, target: &str) {
+    let remote = match connect(proxy, target) {
+        Ok(remote) => remote,
+        Err(e) => {
+            output::error(&t!("proxy-failed", target = target, error = e));
+            return;
+        }
+    };
+    let (Ok(mut local_read), Ok(mut remote_write)) = (local.try_clone(), remote.try_clone()) else {
+        return;
+    };
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut local_read, &mut remote_write);
+        let _ = remote_write.shutdown(Shutdown::Write);
+    });
+    let (mut remote_read, mut local_write) = (remote, local);
+    let _ = io::copy(&mut remote_read, &mut local_write);
+    // The server is gone, so is the client's connection
+    let _ = local_write.shutdown(Shutdown::Both);
+    let _ = upstream.join();
+}
+
+/// Opens a connection to `target` (`host:port`) through the SOCKS5 proxy at `proxy`.
+pub fn connect(proxy: SocketAddr, target: &str) -> io::Result<TcpStream> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| invalid(format!("Expected host:port, got {target}")))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut stream = TcpStream::connect_timeout(&proxy, PROXY_TIMEOUT)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    // Version 5, offering a single method: no authentication
+    stream.write_all(&[5, 1, 0])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        return Err(invalid("The proxy requires authentication".to_string()));
+    }
+
+    // CONNECT, to an IP address if that's what we have, or else a host name
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| invalid(format!("Host name too long: {host}")))?;
+            request.push(3);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            reply_error(reply[1]),
+        ));
+    }
+    // Skip the address the proxy bound, it is of no use to us
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        _ => return Err(invalid("Malformed reply from the proxy".to_string())),
+    };
+    let mut skipped = vec![0; bound + 2];
+    stream.read_exact(&mut skipped)?;
+
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+/// Describes a SOCKS5 reply code.
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general proxy failure",
+        2 => "connection not allowed by the proxy",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported by the proxy",
+        8 => "address type not supported by the proxy",
+        _ => "unknown proxy error",
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_sends_host_names_to_the_proxy() {
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = proxy.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = proxy.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let host = b"example.onion";
+            let mut request = vec![0; 5 + host.len() + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..5], [5, 1, 0, 3, host.len() as u8]);
+            assert_eq!(&request[5..5 + host.len()], host);
+            assert_eq!(request[5 + host.len()..], 12345u16.to_be_bytes());
+
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            stream.write_all(b"welcome\n").unwrap();
+        });
+
+        let mut stream = connect(address, "example.onion:12345").unwrap();
+        let mut welcome = String::new();
+        stream.read_to_string(&mut welcome).unwrap();
+        assert_eq!(welcome, "welcome\n");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+use crate::highlight;
+
+/// How a stretch of a message is formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Italic,
+    Code,
+    Spoiler,
+    /// A block of code, set apart on lines of its own.
+    Pre,
+    /// A web address, always shown in full so no one is led somewhere else.
+    Link,
+    /// A line break, sent as a space.
+    Break,
+    /// The excerpt a reply quotes, set apart on a line of its own.
+    Quote,
+}
+
+impl Style {
+    /// The ANSI escape code (SGR) the style is shown with in a terminal.
+    fn sgr(self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Italic => "3",
+            Style::Code | Style::Pre => "36",
+            // Black on black, selecting it shows it
+            Style::Spoiler => "30;40",
+            Style::Link => "4;34",
+            Style::Break => "",
+            Style::Quote => "2",
+        }
+    }
+}
+
+/// Reset to no style.
+const RESET: &str = "\x1b[0m";
+/// What code blocks are indented by.
+const PRE_INDENT: &str = "    ";
+/// What code blocks are typed between.
+const FENCE: &str = "```";
+/// Longest language name a code block may be tagged with.
+const MAX_LANGUAGE: usize = 16;
+
+/// The markers typed around text to format it, as in Discord's markdown, and the
+/// letter each style goes by in the `fmt` tag. Longest first, so `**` isn't read as
+/// two `*`; the first for each style is the one it is shown with. Links have no
+/// markers, they are told by their `http://` or `https://`, and line breaks are
+/// typed as such.
+const STYLES: &[(&str, char, Style)] = &[
+    (FENCE, 'p', Style::Pre),
+    ("||", 's', Style::Spoiler),
+    ("**", 'b', Style::Bold),
+    ("*", 'i', Style::Italic),
+    ("_", 'i', Style::Italic),
+    ("`", 'c', Style::Code),
+    ("", 'l', Style::Link),
+    ("", 'n', Style::Break),
+    ("", 'q', Style::Quote),
+];
+
+/// A formatted stretch of a message, from its `start`th character up to its `end`th.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub style: Style,
+    pub start: usize,
+    pub end: usize,
+    /// The language of a code block, e.g. `rust`, to highlight it in.
+    pub language: Option<String>,
+}
+
+/// Parses the server's `fmt` tag, e.g. `b0-4,s10-17` or `p0-30:rust`: a style letter
+/// and a range of characters for each span, and maybe the language of a code block.
+/// Spans the client doesn't know are left out.
+pub fn parse(value: &str) -> Vec<Span> {
+    (value.split(','))
+        .filter_map(|span| {
+            let mut chars = span.chars();
+            let letter = chars.next()?;
+            let (_, _, style) = STYLES.iter().find(|(_, l, _)| *l == letter)?;
+            let (range, language) = match chars.as_str().split_once(':') {
+                Some((range, language)) => (range, Some(language.to_string())),
+                None => (chars.as_str(), None),
+            };
+            let (start, end) = range.split_once('-')?;
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start < end).then_some(Span {
+                style: *style,
+                start,
+                end,
+                language,
+            })
+        })
+        .collect()
+}
+
+/// The `fmt` tag for `spans`.
+pub fn tag(spans: &[Span]) -> String {
+    let spans: Vec<String> = (spans.iter())
+        .map(|span| {
+            let language = span.language.as_ref().map(|l| format!(":{l}"));
+            let (letter, start, end) = (marker(span.style).1, span.start, span.end);
+            format!("{letter}{start}-{end}{}", language.unwrap_or_default())
+        })
+        .collect();
+    spans.join(",")
+}
+
+/// Whether `input` opens a code block it doesn't close, so the lines typed after it
+/// are part of the message, up to the one closing it.
+pub fn opens_block(input: &str) -> bool {
+    input.matches(FENCE).count() % 2 == 1
+}
+
+/// Takes the formatting markers out of a message typed by the user, e.g. `**bold**`,
+/// `*italic*` or `_italic_`, `` `code` ``, ```` ```code block``` ```` or
+/// `||spoiler||`, and returns the text without them and its spans. Markers count
+/// only around words, so `snake_case` stays as it is, and nothing is formatted inside
+/// code. A code block may start with its language, on the line of its opening
+/// marker. Web addresses become links, and `[docs](https://...)` becomes
+/// `docs (https://...)`, as links are never hidden behind their text. Line breaks
+/// become spaces, with a span of their own.
+pub fn markup(input: &str) -> (String, Vec<Span>) {
+    let chars: Vec<char> = input.chars().collect();
+    let mut text = Vec::with_capacity(chars.len());
+    let mut spans = Vec::new();
+    parse_markup(&chars, &mut text, &mut spans);
+    for (i, c) in text.iter_mut().enumerate() {
+        if *c == '\n' {
+            *c = ' ';
+            spans.push(span(Style::Break, i, i + 1));
+        }
+    }
+    spans.sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+    (text.into_iter().collect(), spans)
+}
+
+fn span(style: Style, start: usize, end: usize) -> Span {
+    Span {
+        style,
+        start,
+        end,
+        language: None,
+    }
+}
+
+fn parse_markup(chars: &[char], text: &mut Vec<char>, spans: &mut Vec<Span>) {
+    let fence: Vec<char> = FENCE.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let block = (chars[i..].starts_with(&fence) && (i == 0 || !chars[i - 1].is_alphanumeric()))
+            .then(|| (i + fence.len()..chars.len()).find(|&j| chars[j..].starts_with(&fence)))
+            .flatten()
+            .and_then(|close| code_block(&chars[i + fence.len()..close]).map(|b| (close, b)));
+        if let Some((close, (language, code))) = block {
+            let start = text.len();
+            text.extend_from_slice(code);
+            spans.push(Span {
+                language,
+                ..span(Style::Pre, start, text.len())
+            });
+            i = close + fence.len();
+            continue;
+        }
+        if let Some((length, label, url)) = link_at(chars, i) {
+            if !label.is_empty() {
+                text.extend_from_slice(label);
+                text.extend([' ', '(']);
+            }
+            let start = text.len();
+            text.extend_from_slice(url);
+            spans.push(span(Style::Link, start, text.len()));
+            if !label.is_empty() {
+                text.push(')');
+            }
+            i += length;
+            continue;
+        }
+        let opened = STYLES.iter().find_map(|&(marker, _, style)| {
+            let marker: Vec<char> = marker.chars().collect();
+            let inner = i + marker.len();
+            let opens = !marker.is_empty()
+                && style != Style::Pre
+                && chars[i..].starts_with(&marker)
+                && (i == 0 || !chars[i - 1].is_alphanumeric())
+                && chars.get(inner).is_some_and(|c| !c.is_whitespace());
+            let close = (inner + 1..chars.len())
+                .filter(|&j| chars[j..].starts_with(&marker) && !chars[j - 1].is_whitespace())
+                .find(|&j| (chars.get(j + marker.len())).is_none_or(|c| !c.is_alphanumeric()));
+            Some((style, inner, close.filter(|_| opens)?, marker.len()))
+        });
+        let Some((style, inner, close, length)) = opened else {
+            text.push(chars[i]);
+            i += 1;
+            continue;
+        };
+        let start = text.len();
+        if style == Style::Code {
+            text.extend_from_slice(&chars[inner..close]);
+        } else {
+            parse_markup(&chars[inner..close], text, spans);
+        }
+        spans.push(span(style, start, text.len()));
+        i = close + length;
+    }
+}
+
+/// Reads what is between the markers of a code block: its language, if the opening
+/// marker is followed by one on its own line, then its code, without the line
+/// breaks around it. Returns `None` if there is no code.
+fn code_block(inner: &[char]) -> Option<(Option<String>, &[char])> {
+    let valid = |c: &&char| c.is_ascii_alphanumeric() || "+#-".contains(**c);
+    let length = inner.iter().take_while(valid).count();
+    let (language, code) = match inner.get(length) {
+        Some('\n') if (1..=MAX_LANGUAGE).contains(&length) => {
+            (Some(inner[..length].iter().collect()), &inner[length..])
+        }
+        _ => (None, inner),
+    };
+    let code = code.strip_prefix(&['\n']).unwrap_or(code);
+    let code = code.strip_suffix(&['\n']).unwrap_or(code);
+    (!code.is_empty()).then_some((language, code))
+}
+
+/// Finds a link at `i`, a web address or `[label](address)`. Returns how many
+/// characters it takes up, its label, if it has one, and its address.
+fn link_at(chars: &[char], i: usize) -> Option<(usize, &[char], &[char])> {
+    let is_url = |url: &[char]| {
+        let url: String = url.iter().collect();
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"));
+        rest.is_some_and(|rest| !rest.is_empty() && !rest.contains(char::is_whitespace))
+    };
+    if chars[i] == '[' {
+        let close = i + chars[i..].iter().position(|&c| c == ']')?;
+        let end = close + chars[close..].iter().position(|&c| c == ')')?;
+        let (label, url) = (&chars[i + 1..close], chars.get(close + 2..end)?);
+        let label_ok = !label.is_empty() && !label.contains(&'[');
+        return (label_ok && chars[close + 1] == '(' && is_url(url)).then_some((
+            end + 1 - i,
+            label,
+            url,
+        ));
+    }
+    if i > 0 && chars[i - 1].is_alphanumeric() {
+        return None;
+    }
+    let mut end = i + chars[i..].iter().take_while(|c| !c.is_whitespace()).count();
+    // Punctuation after an address isn't part of it
+    while end > i && matches!(chars[end - 1], '.' | ',' | ';' | ':' | '!' | '?' | ')') {
+        end -= 1;
+    }
+    let url = &chars[i..end];
+    is_url(url).then_some((end - i, &[][..], url))
+}
+
+/// Puts the markers back into `text`, showing its formatting the way it is typed.
+/// Spans that don't fit the text are left out.
+pub fn render(text: &str, spans: &[Span]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let (breaks, spans) = split_breaks(&chars, spans);
+    // Code blocks with a language or several lines have their markers on lines of
+    // their own
+    let on_own_lines =
+        |span: &Span| span.language.is_some() || (span.start..span.end).any(|i| breaks[i]);
+    let mut out = String::with_capacity(text.len());
+    for i in 0..=chars.len() {
+        // Inner spans close first, outer ones open first
+        for span in spans.iter().rev().filter(|span| span.end == i) {
+            if span.style == Style::Pre && on_own_lines(span) {
+                out.push('\n');
+            }
+            out.push_str(marker(span.style).0);
+        }
+        for span in spans.iter().filter(|span| span.start == i) {
+            out.push_str(marker(span.style).0);
+            if span.style == Style::Pre && on_own_lines(span) {
+                out.push_str(span.language.as_deref().unwrap_or_default());
+                out.push('\n');
+            }
+        }
+        match chars.get(i) {
+            Some(_) if breaks[i] => out.push('\n'),
+            Some(&c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Shows `text` formatted with `spans` in a terminal, with ANSI escape codes. Code
+/// blocks and quotes go on lines of their own, indented, and code is highlighted if
+/// it is in a language the client knows.
+pub fn styled(text: &str, spans: &[Span]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let (breaks, spans) = split_breaks(&chars, spans);
+    // The colour of each character of highlighted code, in place of the code's
+    let mut colours = vec![None; chars.len()];
+    for span in spans.iter().filter(|span| span.style == Style::Pre) {
+        let code: Vec<char> = (span.start..span.end)
+            .map(|i| if breaks[i] { '\n' } else { chars[i] })
+            .collect();
+        let language = span.language.as_deref().unwrap_or_default();
+        if let Some(tokens) = highlight::highlight(&code, language) {
+            for (i, token) in tokens.into_iter().enumerate() {
+                // The terminal's own colour for anything else
+                colours[span.start + i] = Some(token.map_or("39", |token| token.sgr()));
+            }
+        }
+    }
+    let mut out = String::with_capacity(text.len() * 2);
+    let block = |span: &&&Span| matches!(span.style, Style::Pre | Style::Quote);
+    let block_ends = |i: usize| spans.iter().filter(block).any(|span| span.end == i);
+    let block_starts = |i: usize| spans.iter().filter(block).any(|span| span.start == i);
+    let in_block =
+        |i: usize| (spans.iter().filter(block)).any(|span| span.start <= i && i < span.end);
+    for (i, &c) in chars.iter().enumerate() {
+        if block_ends(i) {
+            out.push_str(RESET);
+            out.push('\n');
+        }
+        if block_starts(i) {
+            out.push('\n');
+            out.push_str(PRE_INDENT);
+        }
+        let recoloured = i > 0 && colours[i] != colours[i - 1];
+        if recoloured || spans.iter().any(|span| span.start == i || span.end == i) {
+            out.push_str(RESET);
+            for span in spans.iter().filter(|span| span.start <= i && i < span.end) {
+                out.push_str(&format!("\x1b[{}m", span.style.sgr()));
+            }
+            if let Some(colour) = colours[i] {
+                out.push_str(&format!("\x1b[{colour}m"));
+            }
+        }
+        // The line breaks around a block take the place of the spaces
+        if c == ' ' && (block_ends(i) || block_starts(i + 1)) {
+            continue;
+        }
+        if breaks[i] {
+            out.push('\n');
+            if in_block(i) {
+                out.push_str(PRE_INDENT);
+            }
+            continue;
+        }
+        out.push(c);
+    }
+    if spans.iter().any(|span| span.end == chars.len()) {
+        out.push_str(RESET);
+    }
+    out
+}
+
+/// Takes the line breaks out of `spans`, as a flag for each character of `text`,
+/// and leaves out the spans that don't fit it.
+fn split_breaks<'a>(text: &[char], spans: &'a [Span]) -> (Vec<bool>, Vec<&'a Span>) {
+    let mut breaks = vec![false; text.len()];
+    let mut rest = Vec::with_capacity(spans.len());
+    for span in spans.iter().filter(|span| span.end <= text.len()) {
+        match span.style {
+            Style::Break => breaks[span.start] = true,
+            _ => rest.push(span),
+        }
+    }
+    (breaks, rest)
+}
+
+/// Renders a chat line, `[bob]: text`, whose text is formatted with `spans`, with
+/// the markers the text was typed with.
+pub fn render_line(line: &str, spans: &[Span]) -> String {
+    map_text(line, spans, render)
+}
+
+/// Renders a chat line, `[bob]: text`, whose text is formatted with `spans`, for a
+/// terminal; see [`styled`].
+pub fn styled_line(line: &str, spans: &[Span]) -> String {
+    map_text(line, spans, styled)
+}
+
+fn map_text(line: &str, spans: &[Span], render: fn(&str, &[Span]) -> String) -> String {
+    match line.split_once("]: ") {
+        Some((from, text)) if line.starts_with('[') && !spans.is_empty() => {
+            format!("{from}]: {}", render(text, spans))
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// The marker a style is typed with, and its letter in the `fmt` tag.
+fn marker(style: Style) -> (&'static str, char) {
+    STYLES
+        .iter()
+        .find(|(_, _, s)| *s == style)
+        .map(|&(marker, letter, _)| (marker, letter))
+        .unwrap_or(("", '?'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markup_becomes_spans() {
+        let (text, spans) = markup("**bold** and ||_hidden_ joke||, run `rm *x*`");
+        assert_eq!(text, "bold and hidden joke, run rm *x*");
+        assert_eq!(tag(&spans), "b0-4,s9-20,i9-15,c26-32");
+        assert_eq!(parse(&tag(&spans)), spans);
+        assert_eq!(
+            render(&text, &spans),
+            "**bold** and ||*hidden* joke||, run `rm *x*`"
+        );
+
+        // Not markers
+        for plain in [
+            "snake_case_name",
+            "2 * 3 * 4",
+            "* not bold*",
+            "**",
+            "a_b c_",
+        ] {
+            assert_eq!(markup(plain), (plain.to_string(), Vec::new()), "{plain}");
+        }
+    }
+
+    #[test]
+    fn test_links_are_shown_in_full() {
+        let (text, spans) = markup("see [the docs](https://x.io/a), or https://y.io/b?c=d.");
+        assert_eq!(
+            text,
+            "see the docs (https://x.io/a), or https://y.io/b?c=d."
+        );
+        assert_eq!(tag(&spans), "l14-28,l34-52");
+        assert_eq!(render(&text, &spans), text);
+        for plain in ["[x](ftp://x)", "xhttps://y.io", "https://"] {
+            assert_eq!(markup(plain).1, Vec::new(), "{plain}");
+        }
+    }
+
+    #[test]
+    fn test_styled_uses_ansi_codes() {
+        let (text, spans) = markup("**hi** ```x = 1``` ok");
+        assert_eq!(
+            styled(&text, &spans),
+            "\x1b[0m\x1b[1mhi\x1b[0m\n    \x1b[0m\x1b[36mx = 1\x1b[0m\n\x1b[0mok"
+        );
+        assert_eq!(styled("plain", &[]), "plain");
+    }
+
+    #[test]
+    fn test_code_blocks_keep_their_lines() {
+        let typed = "look:\n```rust\nfn main() {\n    x();\n}\n```";
+        let (text, spans) = markup(typed);
+        assert_eq!(text, "look: fn main() {     x(); }");
+        assert_eq!(tag(&spans), "n5-6,p6-28:rust,n17-18,n26-27");
+        assert_eq!(parse(&tag(&spans)), spans);
+        assert_eq!(render(&text, &spans), typed);
+        let styled = styled(&text, &spans);
+        let plain: String = styled
+            .split('\x1b')
+            .map(|part| part.split_once('m').map_or(part, |(_, rest)| rest))
+            .collect();
+        assert_eq!(plain, "look:\n    fn main() {\n        x();\n    }");
+        assert!(styled.contains("\x1b[35mfn"));
+        assert!(opens_block("see ```rust") && !opens_block(typed));
+    }
+
+    #[test]
+    fn test_quotes_go_on_a_line_of_their_own() {
+        let text = "> bob: hi all me too";
+        let spans = parse("q0-13,n13-14");
+        assert_eq!(render(text, &spans), "> bob: hi all\nme too");
+        assert_eq!(
+            styled(text, &spans),
+            "\n    \x1b[0m\x1b[2m> bob: hi all\x1b[0m\n\x1b[0mme too"
+        );
+    }
+
+    #[test]
+    fn test_render_leaves_out_what_doesnt_fit() {
+        assert_eq!(render("hi", &parse("b0-2,i1-9,x0-1")), "**hi**");
+        assert_eq!(
+            render_line("[bob]: hi there", &parse("c0-2")),
+            "[bob]: `hi` there"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use crate::chatlog;
+use crate::crypt::Cipher;
+
+/// Most messages kept for each room. Older ones are dropped once the file holds
+/// twice as many.
+const ROOM_SIZE: usize = 1000;
+
+/// The room messages received from a server, as they were received, by room and
+/// sequence number: `<root>/<server>/<room>.history`, each line a message's number,
+/// then the message with its tags. Unlike the log, it is for the client to read
+/// back, so scrollback survives a restart and what was missed meanwhile can be
+/// worked out. Rooms are read in when first used.
+pub struct Store {
+    root: PathBuf,
+    /// The directory of the server we are connected to.
+    dir: PathBuf,
+    rooms: HashMap<String, Room>,
+    /// Encrypts every entry, if the user asked for that.
+    cipher: Option<Cipher>,
+}
+
+/// A room's stored messages, and the file they are appended to.
+struct Room {
+    messages: BTreeMap<u64, String>,
+    file: Option<File>,
+    /// Lines in the file, some of them perhaps dropped from `messages` since.
+    lines: usize,
+}
+
+impl Store {
+    /// Where stores go by default: `$XDG_DATA_HOME/simple-chat/history`, or
+    /// `~/.local/share/simple-chat/history`.
+    pub fn default_root() -> Option<PathBuf> {
+        Some(chatlog::ChatLog::default_root()?.with_file_name("history"))
+    }
+
+    /// Stores the messages of `server` (its `host:port`) under `root`.
+    pub fn new(root: PathBuf, server: &str) -> Self {
+        Store {
+            dir: root.join(chatlog::dir_name(server)),
+            root,
+            rooms: HashMap::new(),
+            cipher: None,
+        }
+    }
+
+    /// Encrypts every entry written from now on with `cipher`.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Stores another server's messages from now on, after `/connect`.
+    pub fn switch_server(&mut self, server: &str) {
+        self.dir = self.root.join(chatlog::dir_name(server));
+        self.rooms.clear();
+    }
+
+    /// The directory of the current server's messages.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Keeps message `seq` of `room`, as received with its tags, unless it is kept
+    /// already.
+    pub fn insert(&mut self, room: &str, seq: u64, line: &str) -> io::Result<()> {
+        let path = self.path(room);
+        let dir = self.dir.clone();
+        let cipher = self.cipher.clone();
+        let entry = |seq: u64, line: &str| {
+            let entry = format!("{seq} {line}");
+            match &cipher {
+                Some(cipher) => cipher.encrypt(&entry),
+                None => entry,
+            }
+        };
+        let room = self.room(room)?;
+        if room.messages.contains_key(&seq) {
+            return Ok(());
+        }
+        room.messages.insert(seq, line.to_string());
+        while room.messages.len() > ROOM_SIZE {
+            room.messages.pop_first();
+        }
+        if room.lines >= 2 * ROOM_SIZE {
+            // Rewritten with only what is kept
+            room.file = None;
+            let text: String = (room.messages.iter())
+                .map(|(&seq, line)| entry(seq, line) + "\n")
+                .collect();
+            fs::write(&path, text)?;
+            room.lines = room.messages.len();
+            return Ok(());
+        }
+        let file = match &mut room.file {
+            Some(file) => file,
+            file => {
+                fs::create_dir_all(&dir)?;
+                file.insert(OpenOptions::new().create(true).append(true).open(&path)?)
+            }
+        };
+        writeln!(file, "{}", entry(seq, line))?;
+        room.lines += 1;
+        Ok(())
+    }
+
+    /// The number of the newest message kept for `room`.
+    pub fn last(&mut self, room: &str) -> io::Result<Option<u64>> {
+        Ok(self.room(room)?.messages.keys().next_back().copied())
+    }
+
+    /// The newest `count` messages kept for `room`, oldest first.
+    pub fn recent(&mut self, room: &str, count: usize) -> io::Result<Vec<String>> {
+        let messages = &self.room(room)?.messages;
+        let skip = messages.len().saturating_sub(count);
+        Ok(messages.values().skip(skip).cloned().collect())
+    }
+
+    /// The messages of `range` that aren't kept for `room`, as ranges of numbers.
+    pub fn missing(
+        &mut self,
+        room: &str,
+        range: RangeInclusive<u64>,
+    ) -> io::Result<Vec<RangeInclusive<u64>>> {
+        let messages = &self.room(room)?.messages;
+        let mut missing = Vec::new();
+        if range.is_empty() {
+            return Ok(missing);
+        }
+        let mut next = *range.start();
+        for &seq in messages.range(range.clone()).map(|(seq, _)| seq) {
+            if seq > next {
+                missing.push(next..=seq - 1);
+            }
+            next = seq + 1;
+        }
+        if next <= *range.end() {
+            missing.push(next..=*range.end());
+        }
+        Ok(missing)
+    }
+
+    /// Forgets what is kept for `room`, e.g. once the server restarted and numbers
+    /// its messages from 1 again.
+    pub fn clear(&mut self, room: &str) -> io::Result<()> {
+        self.rooms.remove(room);
+        match fs::remove_file(self.path(room)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.history", chatlog::dir_name(room)))
+    }
+
+    /// A room's messages, read from its file the first time.
+    fn room(&mut self, room: &str) -> io::Result<&mut Room> {
+        if !self.rooms.contains_key(room) {
+            let text = match fs::read_to_string(self.path(room)) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e),
+            };
+            let cipher = self.cipher.as_ref();
+            let mut messages: BTreeMap<u64, String> = (text.lines())
+                .filter_map(|line| {
+                    let line = match cipher {
+                        Some(cipher) => cipher.decrypt(line)?,
+                        None => line.to_string(),
+                    };
+                    let (seq, line) = line.split_once(' ')?;
+                    Some((seq.parse().ok()?, line.to_string()))
+                })
+                .collect();
+            while messages.len() > ROOM_SIZE {
+                messages.pop_first();
+            }
+            let lines = text.lines().count();
+            let stored = Room {
+                messages,
+                file: None,
+                lines,
+            };
+            self.rooms.insert(room.to_string(), stored);
+        }
+        Ok(self.rooms.get_mut(room).expect("room just read in"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_survive_a_restart() {
+        let root = std::env::temp_dir().join(format!("store-{}", std::process::id()));
+        let mut store = Store::new(root.clone(), "127.0.0.1:12345");
+        for seq in [1, 2, 5, 3] {
+            let line = format!("@seq={seq} [bob]: message {seq}");
+            store.insert("lobby", seq, &line).unwrap();
+        }
+        store.insert("lobby", 2, "@seq=2 [bob]: again").unwrap();
+
+        let mut store = Store::new(root.clone(), "127.0.0.1:12345");
+        assert_eq!(store.last("lobby").unwrap(), Some(5));
+        assert_eq!(
+            store.recent("lobby", 2).unwrap(),
+            ["@seq=3 [bob]: message 3", "@seq=5 [bob]: message 5"]
+        );
+        assert_eq!(store.missing("lobby", 1..=7).unwrap(), [4..=4, 6..=7]);
+        assert_eq!(store.missing("lobby", 6..=5).unwrap(), []);
+        assert_eq!(store.last("rust").unwrap(), None);
+
+        store.clear("lobby").unwrap();
+        assert_eq!(store.last("lobby").unwrap(), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
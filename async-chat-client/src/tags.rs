@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+
+use crate::spans::{self, Span};
+
+/// Metadata the server puts in front of chat lines, IRCv3 style:
+/// `@key=value;key=value [bob]: hi`. Tags the client doesn't know are ignored.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tags {
+    /// When the server received the message.
+    pub time: Option<DateTime<Utc>>,
+    /// The message's number in the room.
+    pub seq: Option<u64>,
+    /// The id our client gave one of our messages.
+    pub id: Option<String>,
+    /// Whether the message is a whisper, a private message within the room.
+    pub whisper: bool,
+    /// Whether the message is an action, sent with `/me`.
+    pub action: bool,
+    /// How the message's text is formatted.
+    pub spans: Vec<Span>,
+}
+
+/// Takes the tags off the front of a line, if it has any.
+pub fn split(line: &str) -> (Tags, &str) {
+    let mut tags = Tags::default();
+    let Some((raw, rest)) = line
+        .strip_prefix('@')
+        .and_then(|tagged| tagged.split_once(' '))
+    else {
+        return (tags, line);
+    };
+    for (key, value) in raw.split(';').filter_map(|tag| tag.split_once('=')) {
+        match key {
+            "time" => {
+                tags.time = DateTime::parse_from_rfc3339(value)
+                    .ok()
+                    .map(|time| time.with_timezone(&Utc))
+            }
+            "seq" => tags.seq = value.parse().ok(),
+            "id" => tags.id = Some(value.to_string()),
+            "kind" => {
+                tags.whisper = value == "whisper";
+                tags.action = value == "action";
+            }
+            "fmt" => tags.spans = spans::parse(value),
+            _ => {}
+        }
+    }
+    (tags, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_takes_the_tags_off() {
+        let (tags, line) = split("@time=2001-09-09T01:46:40.250Z;seq=7;new=1 [bob]: hi");
+        assert_eq!(line, "[bob]: hi");
+        assert_eq!(
+            tags.time.map(|time| time.timestamp_millis()),
+            Some(1_000_000_000_250)
+        );
+        assert_eq!(tags.seq, Some(7));
+        assert!(split("@kind=whisper [bob ~> alice]: psst").0.whisper);
+        assert!(split("@kind=action [bob]: waves").0.action);
+        assert_eq!(
+            split("[bob]: @alice hi"),
+            (Tags::default(), "[bob]: @alice hi")
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// What the countdown comes to on a tick.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tick {
+    /// Nothing to show yet.
+    Wait,
+    /// A whole second went by: this many are left.
+    Countdown(u64),
+    /// Time is up: send these, in order.
+    Release(Vec<String>),
+}
+
+/// Holds back our room messages while the server rate limits us: those it turned
+/// away, and those typed since, go out once it said we may send again.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    /// When we may send again.
+    until: Option<Instant>,
+    /// Messages the server turned away, which were sent before any typed since.
+    turned_away: VecDeque<String>,
+    /// Messages typed while throttled.
+    typed: VecDeque<String>,
+    /// The seconds left shown last, so the countdown ticks once a second.
+    shown: Option<u64>,
+}
+
+impl Throttle {
+    /// The server turned `message` away, and lets us send again after `retry_after`.
+    /// Returns the seconds to count down from.
+    pub fn throttled(
+        &mut self,
+        message: Option<String>,
+        retry_after: Duration,
+        now: Instant,
+    ) -> u64 {
+        let until = now + retry_after;
+        self.until = Some(self.until.map_or(until, |held| held.max(until)));
+        self.turned_away.extend(message);
+        let left = self.left(now);
+        self.shown = Some(left);
+        left
+    }
+
+    /// Whether our room messages are held back.
+    pub fn is_active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    /// Holds back a message typed while throttled.
+    pub fn hold(&mut self, message: String) {
+        self.typed.push_back(message);
+    }
+
+    /// How long until the next tick, if we are throttled.
+    pub fn poll_timeout(&self, now: Instant) -> Option<Duration> {
+        let left = self.until?.saturating_duration_since(now);
+        // The next whole second, or the end
+        let fraction = Duration::from_nanos((left.as_nanos() % 1_000_000_000) as u64);
+        Some(if fraction.is_zero() {
+            left.min(Duration::from_secs(1))
+        } else {
+            fraction
+        })
+    }
+
+    /// Counts down, and hands the held messages back once the time is up.
+    pub fn tick(&mut self, now: Instant) -> Tick {
+        let Some(until) = self.until else {
+            return Tick::Wait;
+        };
+        if now >= until {
+            return Tick::Release(self.take().collect());
+        }
+        let left = self.left(now);
+        if self.shown == Some(left) {
+            return Tick::Wait;
+        }
+        self.shown = Some(left);
+        Tick::Countdown(left)
+    }
+
+    /// Stops holding back, handing the held messages back, e.g. to be sent after a
+    /// reconnect.
+    pub fn take(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.until = None;
+        self.shown = None;
+        self.turned_away.drain(..).chain(self.typed.drain(..))
+    }
+
+    /// The seconds left, rounded up.
+    fn left(&self, now: Instant) -> u64 {
+        let left = self
+            .until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        left.as_millis().div_ceil(1000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_held_messages_go_out_when_the_time_is_up() {
+        let start = Instant::now();
+        let mut throttle = Throttle::default();
+        assert_eq!(throttle.tick(start), Tick::Wait);
+
+        let left = throttle.throttled(
+            Some("one\n".to_string()),
+            Duration::from_millis(2500),
+            start,
+        );
+        assert_eq!(left, 3);
+        assert!(throttle.is_active());
+        throttle.hold("two\n".to_string());
+        assert_eq!(
+            throttle.poll_timeout(start),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            throttle.tick(start + Duration::from_millis(100)),
+            Tick::Wait
+        );
+        assert_eq!(
+            throttle.tick(start + Duration::from_millis(600)),
+            Tick::Countdown(2)
+        );
+        assert_eq!(
+            throttle.tick(start + Duration::from_millis(700)),
+            Tick::Wait
+        );
+
+        // A message turned away later doesn't bring the end forward, and goes out
+        // before those typed since
+        throttle.throttled(
+            Some("three\n".to_string()),
+            Duration::from_millis(100),
+            start,
+        );
+        assert_eq!(
+            throttle.tick(start + Duration::from_millis(2500)),
+            Tick::Release(vec!["one\n".into(), "three\n".into(), "two\n".into()])
+        );
+        assert!(!throttle.is_active());
+        assert_eq!(throttle.poll_timeout(start), None);
+    }
+}
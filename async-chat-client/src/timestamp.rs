@@ -0,0 +1,89 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// How the time messages were sent at is shown in front of them, in strftime-style
+/// formats: one for times of today and one, usually with the date, for older ones
+/// (such as in a replayed recording). Times are shown in the local time zone, or the
+/// one the user picked.
+#[derive(Debug, Clone)]
+pub struct TimeFormat {
+    today: String,
+    older: String,
+    zone: Option<Tz>,
+}
+
+impl TimeFormat {
+    /// Shows times in `today`'s format, or `older`'s before today. Without `older`,
+    /// older times are prefixed with their date.
+    pub fn new(today: &str, older: Option<&str>) -> Result<Self, String> {
+        let older = older.map_or_else(|| format!("%Y-%m-%d {today}"), str::to_string);
+        validate(today)?;
+        validate(&older)?;
+        Ok(TimeFormat {
+            today: today.to_string(),
+            older,
+            zone: None,
+        })
+    }
+
+    /// Shows times in `zone`, a name such as `Europe/Berlin`, rather than local time.
+    pub fn in_zone(mut self, zone: &str) -> Result<Self, String> {
+        self.zone = Some(
+            zone.parse()
+                .map_err(|_| format!("Unknown time zone: {zone}"))?,
+        );
+        Ok(self)
+    }
+
+    pub fn format(&self, at: DateTime<Utc>) -> String {
+        match self.zone {
+            Some(zone) => self.format_in(&zone, at),
+            None => self.format_in(&Local, at),
+        }
+    }
+
+    fn format_in<Z: TimeZone>(&self, zone: &Z, at: DateTime<Utc>) -> String
+    where
+        Z::Offset: std::fmt::Display,
+    {
+        let at = at.with_timezone(zone);
+        let today = Utc::now().with_timezone(zone).date_naive();
+        let format = if at.date_naive() == today {
+            &self.today
+        } else {
+            &self.older
+        };
+        at.format(format).to_string()
+    }
+}
+
+/// Checks a format up front, as chrono only fails once it is used.
+fn validate(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("Invalid time format: {format}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_older_times_show_the_date() {
+        let format = TimeFormat::new("[%H:%M]", None).unwrap();
+        let long_ago = DateTime::from_timestamp(1_000_000_000, 0).unwrap();
+        assert!(format.format(long_ago).starts_with("2001-09-"));
+        assert_eq!(format.format(Utc::now()).len(), "[12:34]".len());
+        assert!(TimeFormat::new("%Q", None).is_err());
+
+        // 01:46:40 UTC is 03:46:40 in Berlin's summer time
+        let format = format.in_zone("Europe/Berlin").unwrap();
+        assert_eq!(format.format(long_ago), "2001-09-09 [03:46]");
+        assert!(TimeFormat::new("%H", None)
+            .unwrap()
+            .in_zone("Mars/Olympus")
+            .is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::i18n::t;
+
+/// The client's version, as released.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The optional features the client was built with.
+pub fn features() -> Vec<&'static str> {
+    [
+        ("noise", cfg!(feature = "noise")),
+        ("quic", cfg!(feature = "quic")),
+        ("mdns", cfg!(feature = "mdns")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+/// The version and features of a program, for people to read.
+pub fn describe(program: &str, version: &str, features: &[&str]) -> String {
+    if features.is_empty() {
+        t!("version-no-features", program = program, version = version)
+    } else {
+        let features = features.join(", ");
+        t!(
+            "version",
+            program = program,
+            version = version,
+            features = features
+        )
+    }
+}
+
+/// Parses the server's `/server <version> <features>` announcement, where the
+/// features are separated by commas, or `-` if there are none.
+pub fn parse_announcement(line: &str) -> Option<(&str, Vec<&str>)> {
+    let rest = line.strip_prefix("/server ")?;
+    let (version, features) = rest.split_once(' ')?;
+    let features = match features {
+        "-" => Vec::new(),
+        features => features.split(',').collect(),
+    };
+    Some((version, features))
+}
+
+/// Returns true if a server of version `server` speaks the same protocol as we do.
+/// Versions follow semver: the major version has to match, and while it is 0 the
+/// minor version too.
+pub fn compatible(server: &str) -> bool {
+    let release = |version: &str| -> Option<(u64, u64)> {
+        let mut parts = version.split('.');
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    };
+    match (release(VERSION), release(server)) {
+        (Some((0, ours)), Some((0, theirs))) => ours == theirs,
+        (Some((ours, _)), Some((theirs, _))) => ours == theirs,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_announcement() {
+        assert_eq!(
+            parse_announcement("/server 0.1.0 noise,quic"),
+            Some(("0.1.0", vec!["noise", "quic"]))
+        );
+        assert_eq!(
+            parse_announcement("/server 0.1.0 -"),
+            Some(("0.1.0", vec![]))
+        );
+        assert_eq!(parse_announcement("/session abc"), None);
+        assert!(compatible(VERSION));
+        assert!(!compatible("99.0.0"));
+        assert!(!compatible("garbage"));
+    }
+}
@@ -0,0 +1,137 @@
+/// Returns the width of the terminal stdout is connected to, in columns, or `None` if
+/// it isn't a terminal (e.g. output piped to a file), which shouldn't be wrapped.
+///
+/// It is asked for every line, so lines printed after a resize fit the new width.
+pub fn terminal_width() -> Option<usize> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ fills in the winsize it is given, which outlives the call.
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(usize::from(size.ws_col))
+}
+
+/// Wraps a chat line to `width` columns, breaking between words. Continuation lines
+/// are indented past a leading `[username]: ` (or `[from -> to]: `) prefix, so the
+/// text lines up beneath the start of the message, or as far as the line itself is
+/// indented, e.g. in a code block. A line with several lines in it has each wrapped.
+///
+/// Columns are counted in characters, which is right for most text but not for wide
+/// characters such as CJK or emoji. ANSI escape codes take up none.
+pub fn wrap(line: &str, width: usize) -> String {
+    if line.contains('\n') {
+        let lines: Vec<String> = line.split('\n').map(|line| wrap(line, width)).collect();
+        return lines.join("\n");
+    }
+    if width == 0 || columns(line) <= width {
+        return line.to_string();
+    }
+    let leading = line.len() - line.trim_start_matches(' ').len();
+    let indent = match line.starts_with('[').then(|| line.find("]: ")).flatten() {
+        Some(end) => columns(&line[..end + 3]),
+        None => leading,
+    };
+    // An indent taking up most of the line would leave no room for the message
+    let indent = if indent <= width / 2 { indent } else { 0 };
+
+    let mut out = " ".repeat(leading);
+    let mut column = leading;
+    let line = &line[leading..];
+    let new_line = |out: &mut String, column: &mut usize| {
+        out.push('\n');
+        out.extend(std::iter::repeat_n(' ', indent));
+        *column = indent;
+    };
+    for (i, word) in line.split(' ').enumerate() {
+        let mut word = word;
+        let len = columns(word);
+        if i > 0 && column + 1 + len > width && indent + len <= width {
+            new_line(&mut out, &mut column);
+        } else if i > 0 {
+            out.push(' ');
+            column += 1;
+        }
+        // Words that don't fit on a line of their own are split at the edge
+        while column + columns(word) > width {
+            if column >= width {
+                new_line(&mut out, &mut column);
+                continue;
+            }
+            let split = split_at_column(word, width - column);
+            out.push_str(&word[..split]);
+            new_line(&mut out, &mut column);
+            word = &word[split..];
+        }
+        out.push_str(word);
+        column += columns(word);
+    }
+    out
+}
+
+/// How many columns `text` takes up, without its ANSI escape codes.
+fn columns(text: &str) -> usize {
+    let mut count = 0;
+    let mut escape = false;
+    for c in text.chars() {
+        match (escape, c) {
+            (false, '\x1b') => escape = true,
+            (false, _) => count += 1,
+            // Escape codes end with a letter, e.g. `\x1b[1m`
+            (true, c) => escape = !c.is_ascii_alphabetic(),
+        }
+    }
+    count
+}
+
+/// The byte index `text` is split at for its first part to take up `column` columns.
+fn split_at_column(text: &str, column: usize) -> usize {
+    let mut count = 0;
+    let mut escape = false;
+    for (index, c) in text.char_indices() {
+        match (escape, c) {
+            (false, '\x1b') => escape = true,
+            (false, _) if count == column => return index,
+            (false, _) => count += 1,
+            (true, c) => escape = !c.is_ascii_alphabetic(),
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_indents_past_the_username() {
+        assert_eq!(wrap("[bob]: short", 20), "[bob]: short");
+        assert_eq!(
+            wrap("[bob]: the quick brown fox jumps over", 20),
+            "[bob]: the quick\n       brown fox\n       jumps over"
+        );
+        // Without a prefix, continuation lines start at the edge
+        assert_eq!(wrap("the quick brown fox", 10), "the quick\nbrown fox");
+        // Words longer than a line are split
+        assert_eq!(
+            wrap("[bob]: abcdefghijklmnopq", 14),
+            "[bob]: abcdefg\n       hijklmn\n       opq"
+        );
+    }
+
+    #[test]
+    fn test_wrap_code_blocks_and_styles() {
+        // Escape codes take up no room
+        assert_eq!(
+            wrap("[bob]: \x1b[1mthe quick\x1b[0m brown", 16),
+            "[bob]: \x1b[1mthe quick\x1b[0m\n       brown"
+        );
+        // Every line of a code block is wrapped, past its indent
+        assert_eq!(
+            wrap("[bob]: look\n    let x = vec![1, 2, 3];", 20),
+            "[bob]: look\n    let x = vec![1,\n    2, 3];"
+        );
+    }
+}
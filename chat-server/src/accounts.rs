@@ -0,0 +1,278 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::names;
+use crate::profile::Profile;
+
+/// Argon2id memory cost, in KiB. Raising any of these costs makes every stored hash
+/// stale, and each one is rehashed the next time its user logs in.
+const M_COST: u32 = 19 * 1024;
+/// Argon2id number of passes.
+const T_COST: u32 = 2;
+/// Argon2id degree of parallelism.
+const P_COST: u32 = 1;
+
+/// A registered account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// The password, as an Argon2 PHC string carrying its own salt and parameters.
+    pub password_hash: String,
+    #[serde(default)]
+    pub profile: Profile,
+    /// Where missed-message digests go. Unlike the profile, only the server sees it.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Whether the user wants a digest of what they miss while offline.
+    #[serde(default)]
+    pub digest: bool,
+}
+
+/// Outcome of checking a password against an account.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Login {
+    Valid,
+    /// The password is right, but was hashed with other parameters than the current
+    /// ones and should be rehashed.
+    Stale,
+    Invalid,
+}
+
+/// Registered accounts, kept as JSON in a single file, and the names of those erased
+/// with `/forget` in `forgotten.json` beside it.
+///
+/// The file is rewritten as a whole on every change, so it shouldn't be edited (e.g.
+/// with `chat-server account`) while the server is running.
+pub struct Accounts {
+    path: PathBuf,
+    accounts: BTreeMap<String, Account>,
+    /// The [`names::skeleton`]s of erased accounts' names, which no one may take.
+    forgotten: BTreeSet<String>,
+}
+
+impl Accounts {
+    /// Opens the accounts stored at `path`. A missing file means there are none yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let accounts = read_json(path)?;
+        let forgotten = read_json(&path.with_file_name(FORGOTTEN_FILE))?;
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            accounts,
+            forgotten,
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(username)
+    }
+
+    /// The registered username that `username` would pass for, e.g. `alice` for
+    /// `Alice`, if there is one. See [`names::skeleton`].
+    pub fn find(&self, username: &str) -> Option<&str> {
+        if let Some((registered, _)) = self.accounts.get_key_value(username) {
+            return Some(registered);
+        }
+        let skeleton = names::skeleton(username);
+        (self.accounts.keys())
+            .find(|registered| names::skeleton(registered) == skeleton)
+            .map(String::as_str)
+    }
+
+    /// Every registered username, in order.
+    pub fn usernames(&self) -> impl Iterator<Item = &String> {
+        self.accounts.keys()
+    }
+
+    /// Creates an account, or replaces the password of an existing one.
+    pub fn set_password(&mut self, username: &str, password: &str) -> io::Result<()> {
+        let password_hash = hash(password)?;
+        match self.accounts.get_mut(username) {
+            Some(account) => account.password_hash = password_hash,
+            None => {
+                let account = Account {
+                    password_hash,
+                    profile: Profile::default(),
+                    email: None,
+                    digest: false,
+                };
+                self.accounts.insert(username.to_string(), account);
+            }
+        }
+        self.save()
+    }
+
+    /// Replaces the profile of an account. Returns false if there is no such account.
+    pub fn set_profile(&mut self, username: &str, profile: Profile) -> io::Result<bool> {
+        let Some(account) = self.accounts.get_mut(username) else {
+            return Ok(false);
+        };
+        account.profile = profile;
+        self.save().map(|_| true)
+    }
+
+    /// Sets where an account's digests go, and whether it wants them. Returns false if
+    /// there is no such account.
+    pub fn set_digest(
+        &mut self,
+        username: &str,
+        email: Option<String>,
+        digest: bool,
+    ) -> io::Result<bool> {
+        let Some(account) = self.accounts.get_mut(username) else {
+            return Ok(false);
+        };
+        account.email = email;
+        account.digest = digest;
+        self.save().map(|_| true)
+    }
+
+    /// Deletes an account. Returns false if there was no such account.
+    pub fn remove(&mut self, username: &str) -> io::Result<bool> {
+        if self.accounts.remove(username).is_none() {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Deletes an account for its user, who asked to be forgotten. Nothing of it is
+    /// kept but the skeleton of the name, so no one can take it over and pass for
+    /// them. Returns false if there was no such account.
+    pub fn forget(&mut self, username: &str) -> io::Result<bool> {
+        if !self.accounts.contains_key(username) {
+            return Ok(false);
+        }
+        // Reserved before the account goes, so a failure can't leave the name free
+        self.forgotten.insert(names::skeleton(username));
+        write_json(&self.path.with_file_name(FORGOTTEN_FILE), &self.forgotten)?;
+        self.remove(username)
+    }
+
+    /// Whether `username` passes for the name of an account that was forgotten.
+    pub fn is_forgotten(&self, username: &str) -> bool {
+        self.forgotten.contains(&names::skeleton(username))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        write_json(&self.path, &self.accounts)
+    }
+}
+
+/// The file the names of forgotten accounts are kept in, beside the accounts.
+const FORGOTTEN_FILE: &str = "forgotten.json";
+
+/// Reads a JSON file. A missing file is an empty one.
+fn read_json<T: Default + for<'de> Deserialize<'de>>(path: &Path) -> io::Result<T> {
+    match fs::read(path) {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a JSON file through a temporary file, so a crash can't leave a truncated
+/// file behind.
+fn write_json(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(value)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+fn hasher() -> Argon2<'static> {
+    let params = Params::new(M_COST, T_COST, P_COST, None).expect("valid Argon2 parameters");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Hashes a password with a fresh salt.
+pub fn hash(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(io::Error::other)?;
+    Ok(hash.to_string())
+}
+
+/// Checks a password against an account's hash. This is deliberately slow, so it
+/// shouldn't run while holding a lock others are waiting on.
+pub fn verify(account: &Account, password: &str) -> Login {
+    let Ok(hash) = PasswordHash::new(&account.password_hash) else {
+        return Login::Invalid;
+    };
+    // The hash says which algorithm and parameters it was made with
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_err()
+    {
+        return Login::Invalid;
+    }
+    let costs = Params::try_from(&hash)
+        .map(|params| (params.m_cost(), params.t_cost(), params.p_cost()))
+        .ok();
+    let stale = hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || costs != Some((M_COST, T_COST, P_COST));
+    if stale {
+        Login::Stale
+    } else {
+        Login::Valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_flags_old_parameters() {
+        let path = std::env::temp_dir().join(format!("accounts-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut accounts = Accounts::open(&path).unwrap();
+        accounts.set_password("alice", "hunter2").unwrap();
+        let accounts = Accounts::open(&path).unwrap();
+        assert_eq!(accounts.find("Al\u{456}ce"), Some("alice"));
+        assert_eq!(accounts.find("bob"), None);
+        let alice = accounts.get("alice").unwrap();
+        assert_eq!(verify(alice, "hunter2"), Login::Valid);
+        assert_eq!(verify(alice, "hunter3"), Login::Invalid);
+
+        // A hash made with cheaper parameters still logs in, but needs upgrading
+        let params = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let old = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"hunter2", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        let old = Account {
+            password_hash: old,
+            profile: Profile::default(),
+            email: None,
+            digest: false,
+        };
+        assert_eq!(verify(&old, "hunter2"), Login::Stale);
+        assert_eq!(verify(&old, "hunter3"), Login::Invalid);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forgotten_accounts_keep_only_their_name() {
+        let dir = std::env::temp_dir().join(format!("accounts-forget-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.json");
+
+        let mut accounts = Accounts::open(&path).unwrap();
+        accounts.set_password("alice", "hunter2").unwrap();
+        assert!(accounts.forget("alice").unwrap());
+        assert!(!accounts.forget("bob").unwrap());
+        let accounts = Accounts::open(&path).unwrap();
+        assert!(accounts.get("alice").is_none());
+        assert!(!fs::read_to_string(&path).unwrap().contains("argon2"));
+        assert!(accounts.is_forgotten("Al\u{456}ce"));
+        assert!(!accounts.is_forgotten("bob"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
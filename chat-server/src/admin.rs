@@ -0,0 +1,89 @@
+use clap::Subcommand;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::accounts::Accounts;
+use crate::names;
+use crate::server;
+
+/// Offline account maintenance, run against the data directory while the server is
+/// stopped (it rewrites the accounts file as it pleases).
+#[derive(Subcommand)]
+pub enum AccountCommand {
+    /// Register a username, reading its password from stdin
+    Create { username: String },
+    /// Set a new password for an account, reading it from stdin
+    Reset { username: String },
+    /// Delete an account
+    Delete { username: String },
+    /// List the registered usernames
+    List,
+}
+
+/// Runs an account command against `<data_dir>/accounts.json`. New accounts' names
+/// must follow `usernames`.
+pub fn run(command: AccountCommand, data_dir: &Path, usernames: &names::Policy) -> io::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    let mut accounts = Accounts::open(&data_dir.join("accounts.json"))?;
+    match command {
+        AccountCommand::Create { username } => {
+            let username = names::canonical(&username);
+            if let Err(error) = usernames.check(&username) {
+                return Err(invalid(format!("{error}: {username}")));
+            }
+            if server::is_guest_name(&username) {
+                return Err(invalid(format!("{username} is a guest name")));
+            }
+            match accounts.find(&username) {
+                Some(registered) if registered == username => {
+                    return Err(invalid(format!(
+                        "{username} is already registered, use `account reset` to change the password"
+                    )));
+                }
+                Some(registered) => {
+                    return Err(invalid(format!("{username} is too much like {registered}")));
+                }
+                None => {}
+            }
+            accounts.set_password(&username, &read_password(&username)?)?;
+            println!("Registered {username}");
+        }
+        AccountCommand::Reset { username } => {
+            if accounts.get(&username).is_none() {
+                return Err(invalid(format!("No such account: {username}")));
+            }
+            accounts.set_password(&username, &read_password(&username)?)?;
+            println!("Changed the password of {username}");
+        }
+        AccountCommand::Delete { username } => {
+            if !accounts.remove(&username)? {
+                return Err(invalid(format!("No such account: {username}")));
+            }
+            println!("Deleted {username}");
+        }
+        AccountCommand::List => {
+            for username in accounts.usernames() {
+                println!("{username}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a password from the first line of stdin, so it can be piped in as well.
+fn read_password(username: &str) -> io::Result<String> {
+    eprint!("Password for {username}: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    // The server trims what clients send as well
+    let password = password.trim();
+    if password.is_empty() {
+        return Err(invalid("The password can't be empty".to_string()));
+    }
+    Ok(password.to_string())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::cidr::Cidr;
+use crate::names;
+
+/// Who is on the allowlist: a username, or an address or network everyone connecting
+/// from may join.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    User(String),
+    Network(Cidr),
+}
+
+/// Anything that reads as an address or CIDR block is a network, the rest usernames.
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(' ') || s.starts_with('/') {
+            return Err(format!("Invalid username, address or network: {s}"));
+        }
+        Ok(s.parse()
+            .map_or_else(|_| Entry::User(s.to_string()), Entry::Network))
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Entry::User(username) => f.write_str(username),
+            Entry::Network(cidr) => write!(f, "{cidr}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    /// Whether only those on the list may join.
+    enforced: bool,
+    usernames: BTreeSet<String>,
+    networks: Vec<Cidr>,
+}
+
+/// The users and networks allowed in when the server is allowlist-only, e.g. a
+/// private family or team server. Kept as JSON in a single file, along with whether
+/// it is enforced, so admins can change both while the server runs.
+pub struct Allowlist {
+    path: PathBuf,
+    stored: Stored,
+}
+
+impl Allowlist {
+    /// Opens the allowlist stored at `path`. A missing file means an empty list that
+    /// isn't enforced.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let stored = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Allowlist {
+            path: path.to_path_buf(),
+            stored,
+        })
+    }
+
+    pub fn is_enforced(&self) -> bool {
+        self.stored.enforced
+    }
+
+    /// Whether `username`, connecting from `ip`, may join. Usernames on the list only
+    /// let in the `registered` account going by them, or by a name that looks the same.
+    pub fn admits(&self, username: &str, registered: bool, ip: IpAddr) -> bool {
+        !self.stored.enforced
+            || (registered && self.listed(username).is_some())
+            || self.stored.networks.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The username on the list that `username` can't be told apart from, if any.
+    fn listed(&self, username: &str) -> Option<&String> {
+        if let Some(listed) = self.stored.usernames.get(username) {
+            return Some(listed);
+        }
+        let skeleton = names::skeleton(username);
+        (self.stored.usernames.iter()).find(|listed| names::skeleton(listed) == skeleton)
+    }
+
+    /// Turns the allowlist on or off. Returns false if it already was.
+    pub fn enforce(&mut self, enforced: bool) -> io::Result<bool> {
+        if self.stored.enforced == enforced {
+            return Ok(false);
+        }
+        self.stored.enforced = enforced;
+        self.save().map(|_| true)
+    }
+
+    /// Adds someone to the list, a username in its canonical form. Returns false if
+    /// they, or a name that looks the same, were on it already.
+    pub fn add(&mut self, entry: Entry) -> io::Result<bool> {
+        let added = match entry {
+            Entry::User(username) if self.listed(&username).is_some() => false,
+            Entry::User(username) => self.stored.usernames.insert(names::canonical(&username)),
+            Entry::Network(cidr) if self.stored.networks.contains(&cidr) => false,
+            Entry::Network(cidr) => {
+                self.stored.networks.push(cidr);
+                true
+            }
+        };
+        if !added {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Takes someone off the list, or the username that looks the same as theirs.
+    /// Returns false if they weren't on it.
+    pub fn remove(&mut self, entry: &Entry) -> io::Result<bool> {
+        let removed = match entry {
+            Entry::User(username) => match self.listed(username).cloned() {
+                Some(listed) => self.stored.usernames.remove(&listed),
+                None => false,
+            },
+            Entry::Network(cidr) => {
+                let count = self.stored.networks.len();
+                self.stored.networks.retain(|network| network != cidr);
+                self.stored.networks.len() < count
+            }
+        };
+        if !removed {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Everyone on the list, usernames first.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        let usernames = self.stored.usernames.iter().cloned().map(Entry::User);
+        usernames.chain(self.stored.networks.iter().copied().map(Entry::Network))
+    }
+
+    /// Writes the list to a temporary file first, so a crash can't leave a truncated
+    /// file behind.
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.stored)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_those_on_the_list_get_in() {
+        let path = std::env::temp_dir().join(format!("allowlist-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let home: IpAddr = "192.168.1.20".parse().unwrap();
+        let away: IpAddr = "198.51.100.7".parse().unwrap();
+
+        let mut allowlist = Allowlist::open(&path).unwrap();
+        assert!(allowlist.admits("mallory", false, away));
+        allowlist.add("alice".parse().unwrap()).unwrap();
+        allowlist.add("192.168.1.0/24".parse().unwrap()).unwrap();
+        assert!(allowlist.enforce(true).unwrap());
+
+        let mut allowlist = Allowlist::open(&path).unwrap();
+        assert!(allowlist.admits("alice", true, away));
+        assert!(allowlist.admits("grandma", false, home));
+        assert!(!allowlist.admits("mallory", true, away));
+        // Names on the list are for their accounts only, in any spelling that looks alike
+        assert!(!allowlist.admits("alice", false, away));
+        assert!(allowlist.admits("ａlice", true, away));
+        assert!(!allowlist.add("ａlice".parse().unwrap()).unwrap());
+        assert_eq!(
+            allowlist
+                .entries()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>(),
+            ["alice", "192.168.1.0/24"]
+        );
+        assert!(allowlist.remove(&"ａlice".parse().unwrap()).unwrap());
+        assert!(!allowlist.admits("alice", true, away));
+        assert!("/alice".parse::<Entry>().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::token;
+
+/// An API token, as stored. Only a hash of the token itself is kept, so the file
+/// can't be used to post.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// SHA-256 of the token, hex encoded.
+    hash: String,
+    /// The rooms it may post to, or stream. Any room, if empty.
+    #[serde(default)]
+    pub rooms: BTreeSet<String>,
+    /// Whether it may only stream rooms' messages, not post to them.
+    #[serde(default)]
+    pub read_only: bool,
+    /// The admin who created it.
+    pub created_by: String,
+}
+
+impl ApiToken {
+    /// Whether it may post to `room`, or stream it if it is read-only.
+    pub fn covers(&self, room: &str) -> bool {
+        self.rooms.is_empty() || self.rooms.contains(room)
+    }
+}
+
+/// The tokens scripts post messages to rooms, or stream them, over HTTP with, by
+/// name. Messages are posted under the token's name. Kept as JSON in a single file, so admins can create
+/// and revoke tokens while the server runs.
+pub struct ApiTokens {
+    path: PathBuf,
+    tokens: BTreeMap<String, ApiToken>,
+}
+
+impl ApiTokens {
+    /// Opens the tokens stored at `path`. A missing file means there are none yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let tokens = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(ApiTokens {
+            path: path.to_path_buf(),
+            tokens,
+        })
+    }
+
+    /// Creates a token named `name` that may post to `rooms`, or any room if there are
+    /// none, or only stream them if it is `read_only`. Returns the token, which can't
+    /// be shown again, or `None` if the name is taken.
+    pub fn create(
+        &mut self,
+        name: &str,
+        rooms: BTreeSet<String>,
+        read_only: bool,
+        created_by: &str,
+    ) -> io::Result<Option<String>> {
+        if self.tokens.contains_key(name) {
+            return Ok(None);
+        }
+        let secret = token::generate();
+        let api_token = ApiToken {
+            hash: hash(&secret),
+            rooms,
+            read_only,
+            created_by: created_by.to_string(),
+        };
+        self.tokens.insert(name.to_string(), api_token);
+        self.save().map(|_| Some(secret))
+    }
+
+    /// Revokes a token. Returns false if there is none by that name.
+    pub fn revoke(&mut self, name: &str) -> io::Result<bool> {
+        if self.tokens.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// The name and details of the token `secret`, if it is one.
+    pub fn check(&self, secret: &str) -> Option<(&str, &ApiToken)> {
+        let hash = hash(secret);
+        (self.tokens.iter())
+            .find(|(_, token)| token.hash == hash)
+            .map(|(name, token)| (name.as_str(), token))
+    }
+
+    /// Every token, by name.
+    pub fn all(&self) -> impl Iterator<Item = (&String, &ApiToken)> {
+        self.tokens.iter()
+    }
+
+    /// Writes the tokens to a temporary file first, so a crash can't leave a truncated
+    /// file behind.
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.tokens)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_checked_and_revoked() {
+        let path = std::env::temp_dir().join(format!("api-tokens-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut tokens = ApiTokens::open(&path).unwrap();
+        let rooms = BTreeSet::from(["builds".to_string()]);
+        let secret = tokens.create("ci", rooms, false, "admin").unwrap().unwrap();
+        assert!(tokens
+            .create("ci", BTreeSet::new(), true, "admin")
+            .unwrap()
+            .is_none());
+
+        let mut tokens = ApiTokens::open(&path).unwrap();
+        let (name, token) = tokens.check(&secret).unwrap();
+        assert_eq!(name, "ci");
+        assert!(token.covers("builds"));
+        assert!(!token.covers("lobby"));
+        assert!(!token.read_only);
+        assert!(tokens.check("guess").is_none());
+        assert!(!fs::read_to_string(&path).unwrap().contains(&secret));
+
+        assert!(tokens.revoke("ci").unwrap());
+        assert!(tokens.check(&secret).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hash used as the `prev` link of the very first entry.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A privileged action recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Mute,
+    Unmute,
+    RoleChange,
+    Announce,
+    CloseReport,
+    ReloadRooms,
+    SetTopic,
+    Pin,
+    Unpin,
+    Kick,
+    Op,
+    Deop,
+    Ban,
+    Unban,
+    Allowlist,
+    ApiToken,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Mute => "mute",
+            Action::Unmute => "unmute",
+            Action::RoleChange => "role change",
+            Action::Announce => "announce",
+            Action::CloseReport => "close report",
+            Action::ReloadRooms => "reload rooms",
+            Action::SetTopic => "set topic",
+            Action::Pin => "pin",
+            Action::Unpin => "unpin",
+            Action::Kick => "kick",
+            Action::Op => "op",
+            Action::Deop => "deop",
+            Action::Ban => "ban",
+            Action::Unban => "unban",
+            Action::Allowlist => "allowlist",
+            Action::ApiToken => "api token",
+        })
+    }
+}
+
+/// One line of the audit log.
+///
+/// Entries form a hash chain: `hash` covers every other field, including `prev`, the
+/// hash of the entry before it. Editing or deleting an entry breaks the chain from
+/// that point on, which [`AuditLog::verify`] detects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    pub actor: String,
+    pub action: Action,
+    pub target: Option<String>,
+    /// Action specific details, e.g. the duration of a mute.
+    pub detail: String,
+    pub reason: String,
+    pub prev: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> String {
+        let fields = (
+            self.seq,
+            self.at,
+            &self.actor,
+            self.action,
+            &self.target,
+            &self.detail,
+            &self.reason,
+            &self.prev,
+        );
+        let json = serde_json::to_vec(&fields).expect("audit fields serialize");
+        Sha256::digest(json)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// Append-only, tamper-evident log of privileged actions, stored as JSON lines.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the log at `path`, picking up the chain where it left off.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut log = AuditLog {
+            path: path.to_path_buf(),
+            file,
+            next_seq: 1,
+            last_hash: GENESIS.to_string(),
+        };
+        if let Some(last) = log.entries()?.pop() {
+            log.next_seq = last.seq + 1;
+            log.last_hash = last.hash;
+        }
+        Ok(log)
+    }
+
+    /// Appends an entry to the log.
+    pub fn record(
+        &mut self,
+        actor: &str,
+        action: Action,
+        target: Option<&str>,
+        detail: &str,
+        reason: &str,
+    ) -> io::Result<()> {
+        let mut entry = AuditEntry {
+            seq: self.next_seq,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            actor: actor.to_string(),
+            action,
+            target: target.map(str::to_string),
+            detail: detail.to_string(),
+            reason: reason.to_string(),
+            prev: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.next_seq += 1;
+        self.last_hash = entry.hash;
+        Ok(())
+    }
+
+    /// Reads back every entry in the log, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<AuditEntry>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        reader
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    /// Walks the hash chain, returning the number of entries if it is intact or a
+    /// description of the first entry that was tampered with.
+    pub fn verify(&self) -> io::Result<Result<usize, String>> {
+        let entries = self.entries()?;
+        let mut prev = GENESIS.to_string();
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.seq != index as u64 + 1 {
+                return Ok(Err(format!("entry #{} is missing", index + 1)));
+            }
+            if entry.prev != prev || entry.hash != entry.digest() {
+                return Ok(Err(format!("entry #{} was modified", entry.seq)));
+            }
+            prev = entry.hash.clone();
+        }
+        Ok(Ok(entries.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tampering_breaks_the_chain() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = AuditLog::open(&path).unwrap();
+        log.record("alice", Action::Mute, Some("bob"), "10m", "spam")
+            .unwrap();
+        log.record("alice", Action::Unmute, Some("bob"), "", "")
+            .unwrap();
+        assert_eq!(log.verify().unwrap(), Ok(2));
+
+        // Reopening continues the chain
+        let mut log = AuditLog::open(&path).unwrap();
+        log.record("console", Action::RoleChange, Some("carol"), "admin", "")
+            .unwrap();
+        assert_eq!(log.verify().unwrap(), Ok(3));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replacen("spam", "nothing", 1)).unwrap();
+        assert_eq!(
+            log.verify().unwrap(),
+            Err("entry #1 was modified".to_string())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
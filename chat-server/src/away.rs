@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often a user who is away has each sender of a private message told so.
+const REPLY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A user's `/away` status: why they are away, and who was told when.
+pub struct Away {
+    reason: String,
+    /// When each sender of a private message was last told the user is away.
+    replied: HashMap<String, Instant>,
+}
+
+impl Away {
+    pub fn new(reason: String) -> Self {
+        Away {
+            reason,
+            replied: HashMap::new(),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The reason to tell `from`, who sent a private message at `now`, unless they
+    /// were told in the last 10 minutes.
+    pub fn reply_to(&mut self, from: &str, now: Instant) -> Option<&str> {
+        if let Some(&at) = self.replied.get(from) {
+            if now.saturating_duration_since(at) < REPLY_INTERVAL {
+                return None;
+            }
+        }
+        self.replied.insert(from.to_string(), now);
+        Some(&self.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_sender_is_told_once_per_interval() {
+        let mut away = Away::new("Lunch".to_string());
+        let now = Instant::now();
+        assert_eq!(away.reply_to("alice", now), Some("Lunch"));
+        assert_eq!(away.reply_to("alice", now + Duration::from_secs(60)), None);
+        assert_eq!(away.reply_to("bob", now), Some("Lunch"));
+        assert_eq!(away.reply_to("alice", now + REPLY_INTERVAL), Some("Lunch"));
+    }
+}
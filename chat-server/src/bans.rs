@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cidr::Cidr;
+
+/// A block of addresses that may not connect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub cidr: Cidr,
+    /// Seconds since the Unix epoch when the ban runs out, if it does.
+    pub until: Option<u64>,
+    pub by: String,
+    pub reason: String,
+}
+
+impl Ban {
+    /// How much longer the ban lasts at `now`, `None` if it is permanent.
+    pub fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let until = UNIX_EPOCH + Duration::from_secs(self.until?);
+        Some(until.duration_since(now).unwrap_or_default())
+    }
+
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.remaining(now)
+            .is_none_or(|remaining| !remaining.is_zero())
+    }
+}
+
+/// Banned addresses and networks, checked as connections are accepted and kept as
+/// JSON in a single file so they outlast restarts.
+pub struct Bans {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl Bans {
+    /// Opens the bans stored at `path`. A missing file means there are none yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let bans = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Bans {
+            path: path.to_path_buf(),
+            bans,
+        })
+    }
+
+    /// The ban covering `ip` at `now`, if there is one.
+    pub fn find(&self, ip: IpAddr, now: SystemTime) -> Option<&Ban> {
+        (self.bans.iter()).find(|ban| ban.is_active(now) && ban.cidr.contains(ip))
+    }
+
+    /// The bans in force at `now`.
+    pub fn active(&self, now: SystemTime) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(move |ban| ban.is_active(now))
+    }
+
+    /// Bans a block of addresses, replacing an earlier ban of the same block.
+    pub fn ban(&mut self, ban: Ban) -> io::Result<()> {
+        self.bans.retain(|banned| banned.cidr != ban.cidr);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lifts the ban of a block early. Returns false if it wasn't banned.
+    pub fn unban(&mut self, cidr: Cidr) -> io::Result<bool> {
+        let count = self.bans.len();
+        self.bans.retain(|ban| ban.cidr != cidr);
+        if self.bans.len() == count {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Forgets the bans that have run out by `now`.
+    pub fn expire(&mut self, now: SystemTime) -> io::Result<()> {
+        let count = self.bans.len();
+        self.bans.retain(|ban| ban.is_active(now));
+        if self.bans.len() == count {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// When the timed bans run out, as seconds since the Unix epoch.
+    pub fn expiries(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bans.iter().filter_map(|ban| ban.until)
+    }
+
+    /// Writes the bans to a temporary file first, so a crash can't leave a truncated
+    /// file behind.
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.bans)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans_are_kept_and_expire() {
+        let path = std::env::temp_dir().join(format!("bans-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ban = |cidr: &str, until| Ban {
+            cidr: cidr.parse().unwrap(),
+            until,
+            by: "mod".to_string(),
+            reason: String::new(),
+        };
+        let mut bans = Bans::open(&path).unwrap();
+        bans.ban(ban("203.0.113.0/24", None)).unwrap();
+        bans.ban(ban("198.51.100.7", Some(1_000_060))).unwrap();
+
+        let bans = Bans::open(&path).unwrap();
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert!(bans.find(ip("203.0.113.99"), now).is_some());
+        let timed = bans.find(ip("198.51.100.7"), now).unwrap();
+        assert_eq!(timed.remaining(now), Some(Duration::from_secs(60)));
+        assert!(bans.find(ip("198.51.100.8"), now).is_none());
+
+        let later = now + Duration::from_secs(60);
+        assert!(bans.find(ip("198.51.100.7"), later).is_none());
+        let mut bans = bans;
+        bans.expire(later).unwrap();
+        assert_eq!(bans.active(now).count(), 1);
+        assert!(bans.unban("203.0.113.0/24".parse().unwrap()).unwrap());
+        assert!(Bans::open(&path).unwrap().active(now).next().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
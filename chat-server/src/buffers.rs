@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::stats::Counter;
+
+/// Buffers kept for reuse at most; any more are freed.
+const MAX_FREE: usize = 1024;
+/// What new buffers start with, unless `--write-buffer` says otherwise.
+pub const WRITE_BUFFER: usize = 256;
+
+/// The buffers lines are written to clients from, reused rather than allocated for
+/// every line (a broadcast writes one to every member of the room).
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    /// The capacity new buffers get. Buffers that grew past a few times this, for an
+    /// unusually long line, are freed rather than kept.
+    size: AtomicUsize,
+    /// Buffers handed out from the pool.
+    pub hits: Counter,
+    /// Buffers that had to be allocated as the pool was empty.
+    pub misses: Counter,
+}
+
+/// Shared by every connection, which write from threads of their own.
+pub static BUFFERS: BufferPool = BufferPool::new(WRITE_BUFFER);
+
+impl BufferPool {
+    pub const fn new(size: usize) -> Self {
+        BufferPool {
+            free: Mutex::new(Vec::new()),
+            size: AtomicUsize::new(size),
+            hits: Counter::new(),
+            misses: Counter::new(),
+        }
+    }
+
+    /// Sets the capacity new buffers get, e.g. from `--write-buffer`.
+    pub fn configure(&self, size: usize) {
+        self.size.store(size, Ordering::Relaxed);
+        self.free.lock().unwrap().clear();
+    }
+
+    /// An empty buffer, from the pool if it has one.
+    pub fn take(&self) -> Vec<u8> {
+        if let Some(buffer) = self.free.lock().unwrap().pop() {
+            self.hits.bump();
+            return buffer;
+        }
+        self.misses.bump();
+        Vec::with_capacity(self.size.load(Ordering::Relaxed))
+    }
+
+    /// Returns a buffer to the pool, for the next line.
+    pub fn give_back(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 4 * self.size.load(Ordering::Relaxed) {
+            return;
+        }
+        buffer.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_FREE {
+            free.push(buffer);
+        }
+    }
+
+    /// The share of buffers that came from the pool, in percent.
+    pub fn hit_rate(&self) -> f64 {
+        let (hits, misses) = (self.hits.get(), self.misses.get());
+        100.0 * hits as f64 / (hits + misses).max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new(16);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"hello\n");
+        pool.give_back(buffer);
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!((pool.hits.get(), pool.misses.get()), (1, 1));
+        assert_eq!(pool.hit_rate(), 50.0);
+
+        // Buffers grown for a long line aren't kept
+        let mut long = buffer;
+        long.extend_from_slice(&[b'a'; 100]);
+        pool.give_back(long);
+        pool.take();
+        assert_eq!(pool.misses.get(), 2);
+    }
+}
@@ -0,0 +1,2829 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::allowlist::Entry;
+use crate::audit::{Action, AuditEntry};
+use crate::bans::Ban;
+use crate::buffers::BUFFERS;
+use crate::cidr::Cidr;
+use crate::digest::Missed;
+use crate::duration;
+use crate::events::Event;
+use crate::histogram;
+use crate::history::{self, Via};
+use crate::mail;
+use crate::polls;
+use crate::privacy;
+use crate::profile;
+use crate::push::{self, Device, Notification};
+use crate::ratelimit::Limit;
+use crate::rooms;
+use crate::server::{self, Role, Server, SessionId};
+use crate::spans::Spans;
+use crate::tags::Tags;
+use crate::traffic::Usage;
+use crate::transport::WRITES;
+use crate::version;
+
+/// Number of the reported user's recent messages attached to a report.
+const REPORT_CONTEXT: usize = 5;
+
+/// Number of audit log entries shown by `/audit`.
+const AUDIT_PAGE: usize = 20;
+
+/// Number of the heaviest users `/stats` names.
+const TRAFFIC_TOP: usize = 3;
+
+/// What the connection loop should do once a command has run.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Leave,
+}
+
+/// Who is running a command.
+#[derive(Clone, Copy)]
+pub enum Actor<'a> {
+    /// One of a connected user's sessions.
+    User(&'a Arc<String>, SessionId),
+    /// The operator, typing at the server's admin console.
+    Console,
+}
+
+/// The actor running a command, along with the state it runs against.
+pub struct Context<'a> {
+    pub server: &'a Server,
+    pub actor: Actor<'a>,
+    pub role: Role,
+}
+
+impl Context<'_> {
+    /// Name the actor is shown as to other users.
+    pub fn name(&self) -> &str {
+        match self.actor {
+            Actor::User(username, _) => username,
+            Actor::Console => "console",
+        }
+    }
+
+    /// Records a privileged action in the audit log.
+    pub fn audit(&self, action: Action, target: Option<&str>, detail: &str, reason: &str) {
+        let mut audit = self.server.audit.lock().unwrap();
+        if let Err(e) = audit.record(self.name(), action, target, detail, reason) {
+            eprintln!("Failed to write to the audit log: {e}");
+        }
+        drop(audit);
+        self.server.events.emit(Event::Moderated {
+            action,
+            by: self.name(),
+            target,
+            detail,
+            reason,
+        });
+    }
+
+    /// Sends a reply to the actor running the command, on the session it came from.
+    pub fn reply(&self, text: &str) {
+        match self.actor {
+            Actor::User(username, session) => {
+                self.server.send_to_session(username, session, text);
+            }
+            Actor::Console => println!("{text}"),
+        }
+    }
+}
+
+/// A `/command` understood by the server.
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    /// The minimum role allowed to run the command.
+    pub role: Role,
+    pub handler: fn(&Context, &str) -> Flow,
+}
+
+/// Optional packs of commands, each a table like [`COMMANDS`] in a module of its
+/// own, built in with the feature of the same name. Their commands are run and
+/// listed like the others.
+const PACKS: &[&[Command]] = &[
+    #[cfg(feature = "fun")]
+    crate::fun::COMMANDS,
+];
+
+/// Every command the server understands, those of the packs built in included.
+fn all() -> impl Iterator<Item = &'static Command> {
+    COMMANDS
+        .iter()
+        .chain(PACKS.iter().flat_map(|pack| pack.iter()))
+}
+
+/// The command router: every command the server understands.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help",
+        summary: "List the commands you may run",
+        role: Role::Guest,
+        handler: help,
+    },
+    Command {
+        name: "version",
+        usage: "/version",
+        summary: "Show the server's version and optional features",
+        role: Role::Guest,
+        handler: |ctx, _| {
+            ctx.reply(&version::describe());
+            Flow::Continue
+        },
+    },
+    Command {
+        name: "ping",
+        usage: "/ping [token]",
+        summary: "Get /pong back, once what you sent before has been handled",
+        role: Role::Guest,
+        handler: |ctx, args| {
+            ctx.reply(format!("/pong {args}").trim_end());
+            Flow::Continue
+        },
+    },
+    Command {
+        name: "msg",
+        usage: "/msg <user> <text>",
+        summary: "Send a private message to a user",
+        role: Role::User,
+        handler: msg,
+    },
+    Command {
+        name: "whisper",
+        usage: "/whisper <user> <text>",
+        summary: "Send a private message to a user in the same room as you",
+        role: Role::User,
+        handler: whisper,
+    },
+    Command {
+        name: "away",
+        usage: "/away [reason]",
+        summary: "Mark yourself away, so private messages are answered with the reason; without one, you are back",
+        role: Role::Guest,
+        handler: away,
+    },
+    Command {
+        name: "join",
+        usage: "/join <#room>",
+        summary: "Move to another room, opening it if there is none by that name",
+        role: Role::Guest,
+        handler: join,
+    },
+    Command {
+        name: "spectate",
+        usage: "/spectate <#room>",
+        summary: "Follow a room without taking part, e.g. an AMA",
+        role: Role::Guest,
+        handler: spectate,
+    },
+    Command {
+        name: "who",
+        usage: "/who [#room]",
+        summary: "List who is in your room, or another",
+        role: Role::Guest,
+        handler: who,
+    },
+    Command {
+        name: "list",
+        usage: "/list",
+        summary: "List the rooms, with how many are in each and their topics",
+        role: Role::Guest,
+        handler: list,
+    },
+    Command {
+        name: "direct",
+        usage: "/direct <user> <port> <token>",
+        summary: "Offer a user a direct connection to your client (sent by the client)",
+        role: Role::User,
+        handler: direct,
+    },
+    Command {
+        name: "fetch",
+        usage: "/fetch <from> [to]",
+        summary: "Send room messages again by number (sent by the client when it missed some)",
+        role: Role::Guest,
+        handler: fetch,
+    },
+    Command {
+        name: "register",
+        usage: "/register <password>",
+        summary: "Reserve your name: from then on it takes the password to use it",
+        role: Role::User,
+        handler: register,
+    },
+    Command {
+        name: "profile",
+        usage: "/profile [<user>|set <field> [value]]",
+        summary: "Show a profile, or set a field of yours (bio, pronouns, timezone, avatar)",
+        role: Role::User,
+        handler: profile,
+    },
+    Command {
+        name: "digest",
+        usage: "/digest [on|off|email [address]]",
+        summary: "Get an email digest of private messages and mentions you miss while offline",
+        role: Role::User,
+        handler: digest,
+    },
+    Command {
+        name: "push",
+        usage: "/push [add <name> <provider> <url> [token]|remove <name>|test]",
+        summary: "Register devices to get private messages and mentions pushed to while offline",
+        role: Role::User,
+        handler: push,
+    },
+    Command {
+        name: "whois",
+        usage: "/whois <user>",
+        summary: "Show who a user is and how they are connected",
+        role: Role::Guest,
+        handler: whois,
+    },
+    Command {
+        name: "report",
+        usage: "/report <user> <reason>",
+        summary: "Report a user to the moderators",
+        role: Role::User,
+        handler: report,
+    },
+    Command {
+        name: "mute",
+        usage: "/mute [#room] <user> <duration> [reason]",
+        summary:
+            "Reject a user's messages for a while (e.g. 30s, 10m, 2h), in one room or everywhere",
+        role: Role::User,
+        handler: mute,
+    },
+    Command {
+        name: "unmute",
+        usage: "/unmute [#room] <user> [reason]",
+        summary: "Lift a mute early",
+        role: Role::User,
+        handler: unmute,
+    },
+    Command {
+        name: "ban",
+        usage: "/ban ip <address|cidr> <duration|forever> [reason]",
+        summary: "Refuse connections from an address or network, e.g. 203.0.113.0/24",
+        role: Role::Moderator,
+        handler: ban,
+    },
+    Command {
+        name: "unban",
+        usage: "/unban ip <address|cidr> [reason]",
+        summary: "Lift a ban early",
+        role: Role::Moderator,
+        handler: unban,
+    },
+    Command {
+        name: "bans",
+        usage: "/bans",
+        summary: "List the banned addresses and networks",
+        role: Role::Moderator,
+        handler: bans,
+    },
+    Command {
+        name: "allowlist",
+        usage: "/allowlist [on|off|add <user|cidr>|remove <user|cidr>]",
+        summary: "Show or change who may join when the server is allowlist-only",
+        role: Role::Admin,
+        handler: allowlist,
+    },
+    Command {
+        name: "tokens",
+        usage: "/tokens [create <name> [read-only] [#room...]|revoke <name>]",
+        summary: "List, create or revoke the tokens scripts post to or stream rooms over HTTP with",
+        role: Role::Admin,
+        handler: tokens,
+    },
+    Command {
+        name: "broadcast",
+        usage: "/broadcast <#room,#room...> <text>",
+        summary:
+            "Send a message to several rooms at once (room ops of all of them, and moderators)",
+        role: Role::User,
+        handler: broadcast,
+    },
+    Command {
+        name: "forward",
+        usage: "/forward <seq> <#room|user>",
+        summary: "Send a message from your room on to another room or a user, by its number",
+        role: Role::User,
+        handler: forward,
+    },
+    Command {
+        name: "quote",
+        usage: "/quote <seq> <text>",
+        summary: "Reply to a message in your room by its number, quoting the start of it",
+        role: Role::User,
+        handler: quote,
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        summary: "Tell your room what you are doing, shown as \"* alice waves\"",
+        role: Role::User,
+        handler: me,
+    },
+    Command {
+        name: "poll",
+        usage: "/poll \"<question>\" <option> <option>...",
+        summary: "Ask your room a question, with options in quotes if they have spaces",
+        role: Role::User,
+        handler: poll,
+    },
+    Command {
+        name: "vote",
+        usage: "/vote <poll> <n>",
+        summary: "Answer a poll with the number of an option",
+        role: Role::User,
+        handler: vote,
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <#room> <user> [reason]",
+        summary: "Send a user back to the lobby (room ops and moderators)",
+        role: Role::User,
+        handler: kick,
+    },
+    Command {
+        name: "topic",
+        usage: "/topic <#room> [text]",
+        summary: "Set a room's topic, or clear it (room ops and moderators)",
+        role: Role::User,
+        handler: topic,
+    },
+    Command {
+        name: "pin",
+        usage: "/pin <seq>",
+        summary: "Pin a message in your room by its number (room ops and moderators)",
+        role: Role::User,
+        handler: pin,
+    },
+    Command {
+        name: "unpin",
+        usage: "/unpin <seq>",
+        summary: "Unpin a message in your room (room ops and moderators)",
+        role: Role::User,
+        handler: unpin,
+    },
+    Command {
+        name: "pins",
+        usage: "/pins",
+        summary: "List the messages pinned in your room",
+        role: Role::User,
+        handler: pins,
+    },
+    Command {
+        name: "op",
+        usage: "/op <#room> <user>",
+        summary: "Make a user a room op, who may kick, mute and set the topic there",
+        role: Role::User,
+        handler: op,
+    },
+    Command {
+        name: "deop",
+        usage: "/deop <#room> <user>",
+        summary: "Take a user's room op status away",
+        role: Role::User,
+        handler: deop,
+    },
+    Command {
+        name: "reports",
+        usage: "/reports [show|close <id>]",
+        summary: "Review open reports",
+        role: Role::Moderator,
+        handler: reports,
+    },
+    Command {
+        name: "announce",
+        usage: "/announce <text>",
+        summary: "Send an announcement to every connected user",
+        role: Role::Admin,
+        handler: announce,
+    },
+    Command {
+        name: "role",
+        usage: "/role <user> <role>",
+        summary: "Set a user's role (user, moderator or admin)",
+        role: Role::Admin,
+        handler: role,
+    },
+    Command {
+        name: "rooms",
+        usage: "/rooms reload",
+        summary: "Reload the permanent rooms from the config file",
+        role: Role::Admin,
+        handler: rooms,
+    },
+    Command {
+        name: "stats",
+        usage: "/stats",
+        summary: "Show server statistics",
+        role: Role::Admin,
+        handler: stats,
+    },
+    Command {
+        name: "audit",
+        usage: "/audit [verify|<user>|<count>]",
+        summary: "Show recent privileged actions, or verify the audit log",
+        role: Role::Admin,
+        handler: audit,
+    },
+    Command {
+        name: "export",
+        usage: "/export",
+        summary: "Get a copy of the data the server stores about you, as JSON",
+        role: Role::User,
+        handler: export,
+    },
+    Command {
+        name: "forget",
+        usage: "/forget confirm",
+        summary: "Leave and anonymize your messages and reports",
+        role: Role::User,
+        handler: forget,
+    },
+    Command {
+        name: "leave",
+        usage: "/leave",
+        summary: "Leave the chat",
+        role: Role::Guest,
+        handler: |_, _| Flow::Leave,
+    },
+];
+
+/// Routes a `/command` line to its handler. Commands above the user's role are
+/// treated as unknown so they don't show up for users who can't run them.
+pub fn dispatch(ctx: &Context, line: &str) -> Flow {
+    let line = line.trim_start_matches('/');
+    let (name, args) = line
+        .split_once(' ')
+        .map(|(name, args)| (name, args.trim()))
+        .unwrap_or((line, ""));
+    match all().find(|command| command.name == name) {
+        Some(command) if ctx.role >= command.role => (command.handler)(ctx, args),
+        _ => {
+            ctx.reply(&format!(
+                "Unknown command /{name}. Type /help for a list of commands"
+            ));
+            Flow::Continue
+        }
+    }
+}
+
+/// Notice sent back to a muted user whose message was rejected.
+pub fn muted_notice(remaining: Duration) -> String {
+    format!(
+        "You are muted, your message was not sent. The mute expires in {}",
+        duration::format(remaining)
+    )
+}
+
+/// Notice sent back to a user muted in their room whose message was rejected.
+pub fn room_muted_notice(remaining: Duration) -> String {
+    format!(
+        "You are muted in this room, your message was not sent. The mute expires in {}",
+        duration::format(remaining)
+    )
+}
+
+/// Notice sent back to a spectator whose message was rejected.
+pub fn spectating_notice(room: &str) -> String {
+    format!("You are spectating #{room}, your message was not sent. /join #{room} to take part")
+}
+
+/// The line telling a user they are doing something faster than `limit` allows, so
+/// it wasn't done: `/throttled <limit> <retry-after> [id]`, with how long until they
+/// may try again in milliseconds, and the id of the room message turned away, if it
+/// had one. Clients hold their messages back until then.
+pub fn throttled(limit: Limit, wait: Duration, id: Option<&str>) -> String {
+    // Rounded up, so the client doesn't come back a moment too soon
+    let retry_after = wait.as_nanos().div_ceil(1_000_000);
+    match id {
+        Some(id) => format!("/throttled {limit} {retry_after} {id}"),
+        None => format!("/throttled {limit} {retry_after}"),
+    }
+}
+
+/// Lists the commands available to `role`.
+pub fn help_text(role: Role) -> String {
+    let mut out = String::from("Available commands:");
+    for command in all().filter(|command| role >= command.role) {
+        let _ = write!(out, "\n  {:<24} {}", command.usage, command.summary);
+    }
+    out
+}
+
+fn help(ctx: &Context, _args: &str) -> Flow {
+    ctx.reply(&help_text(ctx.role));
+    Flow::Continue
+}
+
+/// Delivers a private message to a single user, or tells the sender that the
+/// recipient doesn't exist.
+fn msg(ctx: &Context, args: &str) -> Flow {
+    if let Actor::User(username, _) = ctx.actor {
+        if let Some(remaining) = ctx.server.muted_for(username) {
+            ctx.reply(&muted_notice(remaining));
+            return Flow::Continue;
+        }
+        if let Err(wait) = ctx.server.throttle(Limit::DirectMessages, username) {
+            ctx.reply(&throttled(Limit::DirectMessages, wait, None));
+            return Flow::Continue;
+        }
+    }
+    match args.split_once(' ') {
+        Some((to, text)) => {
+            let line = Tags::default().time(SystemTime::now()).apply(&format!(
+                "[{} -> {}]: {}",
+                ctx.name(),
+                to,
+                text
+            ));
+            send_private(ctx, to, &line, text);
+        }
+        None => ctx.reply("Usage: /msg <user> <text>"),
+    }
+    Flow::Continue
+}
+
+/// Sends `line`, the actor's private message of `text`, to `to`, or into their digest
+/// if they are offline and get one. The actor is told if `to` is away, offline or
+/// doesn't exist. Returns whether `to` got it.
+fn send_private(ctx: &Context, to: &str, line: &str, text: &str) -> bool {
+    let delivered = ctx.server.send_to(to, line);
+    let missed = Missed {
+        at: SystemTime::now(),
+        from: ctx.name().to_string(),
+        room: None,
+        text: text.to_string(),
+    };
+    let digested = ctx.server.miss(to, missed);
+    if delivered || digested {
+        ctx.server.stats.private_messages.bump();
+        ctx.server.events.emit(Event::PrivateMessage {
+            from: ctx.name(),
+            to,
+            whisper: false,
+        });
+    }
+    if delivered {
+        tell_away(ctx, to);
+    }
+    if !delivered && digested {
+        ctx.reply(&format!(
+            "{to} is offline, they'll be notified of your message"
+        ));
+    } else if !delivered {
+        ctx.reply(&format!("No such user: {to}"));
+    }
+    delivered
+}
+
+/// Sends a private message to a user in the same room, tagged as a whisper so
+/// clients can show it apart from other messages.
+fn whisper(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room, use /msg");
+        return Flow::Continue;
+    };
+    let Some((to, text)) = args.split_once(' ') else {
+        ctx.reply("Usage: /whisper <user> <text>");
+        return Flow::Continue;
+    };
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        ctx.reply(&muted_notice(remaining));
+        return Flow::Continue;
+    }
+    if let Err(wait) = ctx.server.throttle(Limit::DirectMessages, username) {
+        ctx.reply(&throttled(Limit::DirectMessages, wait, None));
+        return Flow::Continue;
+    }
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = rooms.room_of(username) else {
+        return Flow::Continue;
+    };
+    if rooms.room_of(to) != Some(room) {
+        ctx.reply(&format!(
+            "{to} isn't in #{room}. Whispers only reach users in the same room, use /msg"
+        ));
+        return Flow::Continue;
+    }
+    if let Some(remaining) = rooms
+        .current(username)
+        .and_then(|room| room.muted_for(username))
+    {
+        ctx.reply(&room_muted_notice(remaining));
+        return Flow::Continue;
+    }
+    let line = Tags::default()
+        .time(SystemTime::now())
+        .whisper()
+        .apply(&format!("[{username} ~> {to}]: {text}"));
+    if ctx.server.send_to(to, &line) {
+        ctx.server.stats.private_messages.bump();
+        ctx.server.events.emit(Event::PrivateMessage {
+            from: username,
+            to,
+            whisper: true,
+        });
+        tell_away(ctx, to);
+    }
+    Flow::Continue
+}
+
+/// Marks the user away with a reason, or back without one.
+fn away(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console can't be away");
+        return Flow::Continue;
+    };
+    let reason = (!args.is_empty()).then(|| args.to_string());
+    let was_away = ctx.server.set_away(username, reason.clone());
+    match reason {
+        Some(reason) => ctx.reply(&format!(
+            "You are away: {reason}. Private messages will be answered with that"
+        )),
+        None if was_away => ctx.reply("You are back"),
+        None => ctx.reply("Usage: /away <reason>, or /away alone once you are back"),
+    }
+    Flow::Continue
+}
+
+/// Tells the sender of a private message that its recipient is away, at most once
+/// every 10 minutes.
+fn tell_away(ctx: &Context, to: &str) {
+    if let Some(reason) = ctx.server.away_reply(to, ctx.name()) {
+        ctx.reply(&format!("{to} is away: {reason}"));
+    }
+}
+
+/// Moves the user to another room, if they are allowed in. A room that doesn't exist
+/// is opened as an ad hoc room, which closes when its last member leaves. Every
+/// session of the user is told with `/room <name>` and `/seq <latest>`, like on
+/// joining the chat.
+fn join(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let name = match rooms::parse_name(args) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let spectating = rooms
+        .get(name)
+        .is_some_and(|room| room.is_spectator(username));
+    if rooms.room_of(username) == Some(name) && !spectating {
+        ctx.reply(&format!("You are already in #{name}"));
+        return Flow::Continue;
+    }
+    if rooms
+        .get(name)
+        .is_some_and(|room| !room.access.admits(username, ctx.role))
+    {
+        ctx.reply(&format!("You may not join #{name}"));
+        return Flow::Continue;
+    }
+    if rooms.get(name).is_none() && ctx.role == Role::Guest {
+        ctx.reply(&format!(
+            "There is no room #{name}, and guests may not open rooms"
+        ));
+        return Flow::Continue;
+    }
+    if rooms.get(name).is_some_and(|room| room.is_full()) {
+        ctx.reply(&format!(
+            "#{name} is full. /spectate #{name} to follow it without taking part"
+        ));
+        return Flow::Continue;
+    }
+    if spectating {
+        rooms.enter(username, name);
+        ctx.reply(&format!("You now take part in #{name}"));
+        return Flow::Continue;
+    }
+    if let Err(wait) = ctx.server.throttle(Limit::Joins, username) {
+        ctx.reply(&throttled(Limit::Joins, wait, None));
+        return Flow::Continue;
+    }
+    let opened = rooms.enter(username, name);
+    if let Some(room) = rooms.current(username) {
+        ctx.server.send_to(username, &room.welcome(name).join("\n"));
+    }
+    if opened {
+        ctx.reply(&format!(
+            "You opened #{name}. It closes once everyone has left, and keeps no history"
+        ));
+    }
+    Flow::Continue
+}
+
+/// Moves the user into a room as a spectator, who gets its messages but may not send
+/// any and doesn't count against its member limit. Only rooms that are open may be
+/// spectated, and only by those who could join them.
+fn spectate(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let name = match rooms::parse_name(args) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = rooms.get(name) else {
+        ctx.reply(&format!("There is no room #{name}"));
+        return Flow::Continue;
+    };
+    if room.is_spectator(username) {
+        ctx.reply(&format!("You are already spectating #{name}"));
+        return Flow::Continue;
+    }
+    if !room.access.admits(username, ctx.role) {
+        ctx.reply(&format!("You may not join #{name}"));
+        return Flow::Continue;
+    }
+    let moved = rooms.room_of(username) != Some(name);
+    if moved {
+        if let Err(wait) = ctx.server.throttle(Limit::Joins, username) {
+            ctx.reply(&throttled(Limit::Joins, wait, None));
+            return Flow::Continue;
+        }
+    }
+    rooms.spectate(username, name);
+    if let Some(room) = rooms.get(name).filter(|_| moved) {
+        ctx.server.send_to(username, &room.welcome(name).join("\n"));
+    }
+    ctx.reply(&format!(
+        "You are spectating #{name}: you get its messages but may not send any. /join #{name} to take part"
+    ));
+    Flow::Continue
+}
+
+/// Lists who is in a room, the user's own unless they name one, with the spectators
+/// apart. Invite-only rooms are only shown to those allowed in them.
+fn who(ctx: &Context, args: &str) -> Flow {
+    let username = match ctx.actor {
+        Actor::User(username, _) => Some(username.as_str()),
+        Actor::Console => None,
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let name = match (args, username) {
+        ("", Some(username)) => rooms.room_of(username).unwrap_or(rooms::LOBBY),
+        ("", None) => {
+            ctx.reply("Usage: /who <#room>");
+            return Flow::Continue;
+        }
+        (name, _) => match rooms::parse_name(name) {
+            Ok(name) => name,
+            Err(e) => {
+                ctx.reply(&e);
+                return Flow::Continue;
+            }
+        },
+    };
+    let Some((_, room)) = (rooms.listed(username).into_iter()).find(|(listed, _)| *listed == name)
+    else {
+        ctx.reply(&format!("There is no room #{name}"));
+        return Flow::Continue;
+    };
+    let participants = room.participants();
+    let mut out = format!("In #{name} ({}", participants.len());
+    if let Some(max) = room.max_members {
+        let _ = write!(out, " of {max}");
+    }
+    let _ = write!(out, "): {}", participants.join(", "));
+    let spectators = room.spectators();
+    if !spectators.is_empty() {
+        let _ = write!(
+            out,
+            "\nSpectating ({}): {}",
+            spectators.len(),
+            spectators.join(", ")
+        );
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Lists the rooms with their member counts and topics. Invite-only rooms are only
+/// listed for those allowed in them.
+fn list(ctx: &Context, _args: &str) -> Flow {
+    let username = match ctx.actor {
+        Actor::User(username, _) => Some(username.as_str()),
+        Actor::Console => None,
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let mut out = String::from("Rooms:");
+    for (name, room) in rooms.listed(username) {
+        let _ = write!(
+            out,
+            "\n  #{name:<24} {:>3} member(s)",
+            room.participants().len()
+        );
+        let spectators = room.spectators().len();
+        if spectators > 0 {
+            let _ = write!(out, " (+{spectators} spectating)");
+        }
+        if let Some(topic) = &room.topic {
+            let _ = write!(out, "  {topic}");
+        }
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Reads the permanent rooms from the config file again. If it can't be read, the
+/// rooms stay as they are.
+fn rooms(ctx: &Context, args: &str) -> Flow {
+    if args != "reload" {
+        ctx.reply("Usage: /rooms reload");
+        return Flow::Continue;
+    }
+    let Some(path) = &ctx.server.config else {
+        ctx.reply("There is no config file, start the server with --config to declare rooms");
+        return Flow::Continue;
+    };
+    match ctx.server.load_config(path) {
+        Ok((declared, dropped)) => {
+            let detail = format!("{declared} declared, {dropped} no longer permanent");
+            ctx.audit(Action::ReloadRooms, None, &detail, "");
+            ctx.reply(&format!(
+                "Reloaded {}: {declared} permanent room(s), {dropped} room(s) no longer permanent",
+                path.display()
+            ));
+        }
+        Err(e) => ctx.reply(&format!("{e}. The rooms were left as they were")),
+    }
+    Flow::Continue
+}
+
+/// Sends the room messages numbered `from` to `to` (or the latest) again, as far as
+/// they are still in the history.
+fn fetch(ctx: &Context, args: &str) -> Flow {
+    let numbers: Result<Vec<u64>, _> = args.split_whitespace().map(str::parse).collect();
+    let seqs = match numbers.as_deref() {
+        Ok([from]) => *from..=u64::MAX,
+        Ok([from, to]) if from <= to => *from..=*to,
+        _ => {
+            ctx.reply("Usage: /fetch <from> [to]");
+            return Flow::Continue;
+        }
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = rooms.current(ctx.name()) else {
+        ctx.reply("You aren't in a room");
+        return Flow::Continue;
+    };
+    let history = &room.history;
+    let mut lines = Vec::new();
+    match history.first_seq() {
+        Some(first) if first > *seqs.start() => {
+            lines.push(format!("Messages before #{first} are no longer kept"))
+        }
+        None => lines.push("There are no messages to send".to_string()),
+        _ => {}
+    }
+    lines.extend(history.range(seqs).iter().map(|message| message.line()));
+    ctx.reply(&lines.join("\n"));
+    Flow::Continue
+}
+
+/// Brokers a direct connection: passes the user's offer on to `to`, along with the
+/// address the user connected from, so `to`'s client can connect straight to theirs.
+/// If it can't, private messages keep going through the server.
+fn direct(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, session) = ctx.actor else {
+        ctx.reply("The console can't make direct connections");
+        return Flow::Continue;
+    };
+    // Muted users can't get around the mute by talking to others directly
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        ctx.reply(&muted_notice(remaining));
+        return Flow::Continue;
+    }
+    let [to, port, token] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+        ctx.reply("Usage: /direct <user> <port> <token>");
+        return Flow::Continue;
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        ctx.reply(&format!("Invalid port: {port}"));
+        return Flow::Continue;
+    };
+    if to == username.as_str() {
+        ctx.reply("You can't connect to yourself");
+        return Flow::Continue;
+    }
+    let Some(ip) = ctx.server.ip(username, session) else {
+        return Flow::Continue;
+    };
+    let address = SocketAddr::new(ip, port);
+    if !ctx
+        .server
+        .send_to(to, &format!("/direct {username} {address} {token}"))
+    {
+        ctx.reply(&format!("No such user: {to}"));
+    }
+    Flow::Continue
+}
+
+/// Registers the actor's name with a password, reserving it: from then on whoever
+/// joins under it, even while they are offline, is asked for the password.
+fn register(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no name to reserve");
+        return Flow::Continue;
+    };
+    if args.is_empty() {
+        ctx.reply("Usage: /register <password>");
+        return Flow::Continue;
+    }
+    // The name may predate the username policy
+    let checked = ctx.server.usernames.lock().unwrap().check(username);
+    if let Err(error) = checked {
+        ctx.reply(&format!("{username} can't be registered: {error}"));
+        return Flow::Continue;
+    }
+    let mut accounts = ctx.server.accounts.lock().unwrap();
+    match accounts.find(username) {
+        Some(registered) if registered == username.as_str() => {
+            ctx.reply(&format!("{username} is registered already"));
+            return Flow::Continue;
+        }
+        Some(registered) => {
+            ctx.reply(&format!(
+                "{username} is too much like {registered}, who is registered"
+            ));
+            return Flow::Continue;
+        }
+        None if accounts.is_forgotten(username) => {
+            ctx.reply(&format!(
+                "{username} can't be registered: it belonged to an account that was erased"
+            ));
+            return Flow::Continue;
+        }
+        None => {}
+    }
+    match accounts.set_password(username, args) {
+        Ok(()) => ctx.reply(&format!(
+            "Registered {username}: the name is yours, log in with your password from now on"
+        )),
+        Err(e) => ctx.reply(&format!("Failed to register {username}: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Shows a registered user's profile (the actor's own without a name), or sets a
+/// field of the actor's profile. An empty value clears the field.
+fn profile(ctx: &Context, args: &str) -> Flow {
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    if first != "set" {
+        let username = if first.is_empty() { ctx.name() } else { first };
+        let accounts = ctx.server.accounts.lock().unwrap();
+        let Some(account) = accounts.get(username) else {
+            ctx.reply(&format!(
+                "{username} has no account, only registered users have profiles"
+            ));
+            return Flow::Continue;
+        };
+        let mut out = format!("Profile of {username}:");
+        for (field, value) in account.profile.fields() {
+            let _ = write!(out, "\n  {field}: {value}");
+        }
+        if account.profile == profile::Profile::default() {
+            out.push_str("\n  (empty)");
+        }
+        drop(accounts);
+        ctx.reply(&out);
+        return Flow::Continue;
+    }
+
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no profile");
+        return Flow::Continue;
+    };
+    let (field, value) = rest.split_once(' ').unwrap_or((rest, ""));
+    if field.is_empty() {
+        ctx.reply("Usage: /profile set <field> [value]");
+        return Flow::Continue;
+    }
+    let mut accounts = ctx.server.accounts.lock().unwrap();
+    let Some(account) = accounts.get(username) else {
+        ctx.reply("Only registered users have profiles");
+        return Flow::Continue;
+    };
+    let mut profile = account.profile.clone();
+    let result = profile.set(field, value.trim()).and_then(|()| {
+        accounts
+            .set_profile(username, profile)
+            .map_err(|e| e.to_string())
+    });
+    drop(accounts);
+    match result {
+        Ok(_) if value.trim().is_empty() => ctx.reply(&format!("Cleared your {field}")),
+        Ok(_) => ctx.reply(&format!("Set your {field}")),
+        Err(e) => ctx.reply(&e),
+    }
+    Flow::Continue
+}
+
+/// Shows whether the actor gets digests of what they miss while offline, turns them
+/// on or off, or sets or clears the address they go to.
+fn digest(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no account");
+        return Flow::Continue;
+    };
+    let mut accounts = ctx.server.accounts.lock().unwrap();
+    let Some(account) = accounts.get(username) else {
+        ctx.reply("Only registered users get digests");
+        return Flow::Continue;
+    };
+    let (mut email, mut on) = (account.email.clone(), account.digest);
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let reply = match (first, rest.trim()) {
+        ("", _) => {
+            drop(accounts);
+            let waiting = ctx.server.digests.lock().unwrap().len(username);
+            let mut out = match (&email, on) {
+                (Some(email), true) => format!("Digests are on, mailed to {email}"),
+                (Some(email), false) => format!("Digests are off, your email is {email}"),
+                (None, _) => "Digests are off, and you have no email on file".to_string(),
+            };
+            if waiting > 0 {
+                let _ = write!(out, ", {waiting} message(s) waiting for the next one");
+            }
+            if !ctx.server.sends_email() {
+                out.push_str(". This server doesn't send mail yet");
+            }
+            ctx.reply(&out);
+            return Flow::Continue;
+        }
+        ("on", _) if email.is_none() => {
+            ctx.reply("Set your email first: /digest email <address>");
+            return Flow::Continue;
+        }
+        ("on", _) => {
+            on = true;
+            "Digests are on: you'll be mailed private messages and @mentions you miss while offline"
+                .to_string()
+        }
+        ("off", _) => {
+            on = false;
+            "Digests are off".to_string()
+        }
+        ("email", "") => {
+            (email, on) = (None, false);
+            "Removed your email, and with it your digests".to_string()
+        }
+        ("email", address) => {
+            if let Err(e) = mail::validate_address(address) {
+                ctx.reply(&e);
+                return Flow::Continue;
+            }
+            email = Some(address.to_string());
+            format!("Your email is now {address}")
+        }
+        _ => {
+            ctx.reply("Usage: /digest [on|off|email [address]]");
+            return Flow::Continue;
+        }
+    };
+    let saved = accounts.set_digest(username, email, on);
+    drop(accounts);
+    match saved {
+        Ok(_) => {
+            if !on {
+                ctx.server.digests.lock().unwrap().clear(username);
+            }
+            ctx.reply(&reply);
+        }
+        Err(e) => ctx.reply(&format!("Failed to save your digest settings: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Lists the devices the actor gets pushes on, registers or removes one, or sends
+/// each a test push.
+fn push(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no account");
+        return Flow::Continue;
+    };
+    if ctx.server.accounts.lock().unwrap().get(username).is_none() {
+        ctx.reply("Only registered users can get pushes");
+        return Flow::Continue;
+    }
+    let mut devices = ctx.server.push_devices.lock().unwrap();
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let saved = match (first, rest.trim()) {
+        ("", _) => {
+            let list: Vec<String> = (devices.of(username))
+                .map(|(name, device)| format!("\n  {name}: {}", device.describe()))
+                .collect();
+            drop(devices);
+            match list.is_empty() {
+                true => ctx.reply("You have no devices registered for pushes"),
+                false => ctx.reply(&format!("Your push devices:{}", list.concat())),
+            }
+            return Flow::Continue;
+        }
+        ("test", _) => {
+            let all: Vec<Device> = devices.of(username).map(|(_, d)| d.clone()).collect();
+            drop(devices);
+            let count = all.len();
+            for device in all {
+                let text = "Pushes from the chat server reach this device";
+                push::send(device, Notification::new("Test".to_string(), text));
+            }
+            ctx.reply(&format!("Sent a test push to {count} device(s)"));
+            return Flow::Continue;
+        }
+        ("add", rest) => {
+            let (name, device) = rest.split_once(' ').unwrap_or((rest, ""));
+            let device = match Device::parse(device) {
+                Ok(device) if !name.is_empty() => Ok(device),
+                Ok(_) => Err("Usage: /push add <name> <provider> <url> [token]".to_string()),
+                Err(e) => Err(e),
+            };
+            match device {
+                Ok(device) => devices
+                    .add(username, name, device)
+                    .map(|added| match added {
+                        true => format!("Registered {name} for pushes while you're offline"),
+                        false => format!("You can register at most {} devices", push::MAX_DEVICES),
+                    }),
+                Err(e) => Ok(e),
+            }
+        }
+        ("remove", name) if !name.is_empty() => {
+            devices
+                .remove(username, Some(name))
+                .map(|removed| match removed {
+                    true => format!("Removed {name}"),
+                    false => format!("You have no device named {name}"),
+                })
+        }
+        _ => {
+            Ok("Usage: /push [add <name> <provider> <url> [token]|remove <name>|test]".to_string())
+        }
+    };
+    drop(devices);
+    match saved {
+        Ok(reply) => ctx.reply(&reply),
+        Err(e) => ctx.reply(&format!("Failed to save your push devices: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Describes a connected user: whether they are registered, their role, the room
+/// they are in unless the requester may not see it, and for each of their sessions
+/// how long it has been connected and idle, over which transport and what its client
+/// supports. Moderators also see the address of every session.
+fn whois(ctx: &Context, args: &str) -> Flow {
+    let username = args;
+    if username.is_empty() {
+        ctx.reply("Usage: /whois <user>");
+        return Flow::Continue;
+    }
+    let Some(sessions) = ctx.server.session_info(username) else {
+        ctx.reply(&format!("No such user: {username}"));
+        return Flow::Continue;
+    };
+    let registered = ctx.server.accounts.lock().unwrap().get(username).is_some();
+    let role = ctx.server.role(username).unwrap_or(Role::User);
+    let mut out = format!(
+        "{username}: {}, {role}",
+        if registered {
+            "registered"
+        } else {
+            "unregistered"
+        }
+    );
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        let _ = write!(out, ", muted for {}", duration::format(remaining));
+    }
+    if let Some(reason) = ctx.server.away(username) {
+        let _ = write!(out, ", away: {reason}");
+    }
+    // Those who can't see an invite-only room aren't told who is in it
+    let requester = match ctx.actor {
+        Actor::User(name, _) => Some(name.as_str()),
+        Actor::Console => None,
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    if let Some(room) = (rooms.room_of(username)).filter(|room| rooms.is_visible(room, requester)) {
+        let _ = write!(out, ", in #{room}");
+    }
+    drop(rooms);
+    for session in sessions {
+        let _ = write!(
+            out,
+            "\n  connected {} ago over {}, ",
+            duration::format(session.connected_for),
+            session.transport
+        );
+        if session.connected {
+            let _ = write!(out, "idle for {}", duration::format(session.idle_for));
+        } else {
+            out.push_str("disconnected, waiting to be resumed");
+        }
+        let _ = write!(
+            out,
+            ", {} bytes sent, {} received",
+            session.usage.sent, session.usage.received
+        );
+        if !session.capabilities.is_empty() {
+            let _ = write!(out, ", client supports {}", session.capabilities.join(", "));
+        }
+        if ctx.role >= Role::Moderator {
+            let _ = write!(out, ", from {}", session.ip);
+            if let Some(origin) = &session.origin {
+                let _ = write!(out, " ({origin})");
+            }
+        }
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Files a report against a user, along with their most recent messages, and lets
+/// the moderators who are online know about it.
+fn report(ctx: &Context, args: &str) -> Flow {
+    let Some((user, reason)) = args.split_once(' ') else {
+        ctx.reply("Usage: /report <user> <reason>");
+        return Flow::Continue;
+    };
+    let context = ctx
+        .server
+        .rooms
+        .lock()
+        .unwrap()
+        .recent_from(user, REPORT_CONTEXT);
+    if context.is_empty() && !ctx.server.is_taken(user) {
+        ctx.reply(&format!("No such user: {user}"));
+        return Flow::Continue;
+    }
+    let reason = reason.trim();
+    let id = ctx
+        .server
+        .reports
+        .lock()
+        .unwrap()
+        .file(ctx.name(), user, reason, context);
+    ctx.reply(&format!(
+        "Thanks, report #{id} has been sent to the moderators"
+    ));
+    let notice = format!(
+        "New report #{id} against {user} by {}: {reason}",
+        ctx.name()
+    );
+    ctx.server.events.emit(Event::ReportFiled {
+        id,
+        user,
+        by: ctx.name(),
+        reason,
+    });
+    ctx.server.broadcast_to_role(Role::Moderator, &notice);
+    Flow::Continue
+}
+
+/// Lists open reports, shows one with its message context, or closes one.
+fn reports(ctx: &Context, args: &str) -> Flow {
+    let mut reports = ctx.server.reports.lock().unwrap();
+    let id = |id: &str| id.trim_start_matches('#').parse::<u64>().ok();
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let mut out = String::from("Open reports:");
+            for report in reports.open() {
+                let _ = write!(
+                    out,
+                    "\n  #{} {} reported {} ({}): {}",
+                    report.id,
+                    report.reporter,
+                    report.target,
+                    ctx.server.time_format.format(report.at),
+                    report.reason
+                );
+            }
+            ctx.reply(&out);
+        }
+        ["show", report] => match id(report).and_then(|id| reports.get(id)) {
+            Some(report) => {
+                let mut out = format!(
+                    "Report #{} by {} against {} ({}): {}\nRecent messages from {}:",
+                    report.id,
+                    report.reporter,
+                    report.target,
+                    ctx.server.time_format.format(report.at),
+                    report.reason,
+                    report.target
+                );
+                for message in &report.context {
+                    let _ = write!(
+                        out,
+                        "\n  ({}) {}",
+                        ctx.server.time_format.format(message.at),
+                        message.text
+                    );
+                }
+                ctx.reply(&out);
+            }
+            None => ctx.reply(&format!("No open report {report}")),
+        },
+        ["close", report] => match id(report).and_then(|id| reports.close(id)) {
+            Some(report) => {
+                let detail = format!("#{}", report.id);
+                ctx.audit(Action::CloseReport, Some(&report.target), &detail, "");
+                ctx.reply(&format!("Closed report #{}", report.id));
+            }
+            None => ctx.reply(&format!("No open report {report}")),
+        },
+        _ => ctx.reply("Usage: /reports [show|close <id>]"),
+    }
+    Flow::Continue
+}
+
+/// Mutes a user for a while, in one room if a room is given, or else everywhere,
+/// which only moderators can do. Users can't be muted by someone with a lower role.
+fn mute(ctx: &Context, args: &str) -> Flow {
+    if args.starts_with('#') {
+        return mute_in_room(ctx, args);
+    }
+    if ctx.role < Role::Moderator {
+        ctx.reply("Only moderators can mute users everywhere. Room ops mute them in their room with /mute <#room> <user> <duration>");
+        return Flow::Continue;
+    }
+    let mut args = args.splitn(3, ' ');
+    let (Some(user), Some(duration)) = (args.next(), args.next()) else {
+        ctx.reply("Usage: /mute <user> <duration> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let duration = match duration::parse(duration) {
+        Ok(duration) => duration,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    match ctx.server.role(user) {
+        None => ctx.reply(&format!("No such user: {user}")),
+        Some(role) if role > ctx.role => ctx.reply(&format!("You can't mute {user}")),
+        Some(_) => {
+            if let Err(e) = ctx.server.mute(user, duration) {
+                ctx.reply(&e);
+                return Flow::Continue;
+            }
+            let duration = duration::format(duration);
+            ctx.audit(Action::Mute, Some(user), &duration, reason);
+            ctx.server.send_to(
+                user,
+                &format!("You have been muted by {} for {duration}", ctx.name()),
+            );
+            ctx.reply(&format!("{user} is muted for {duration}"));
+        }
+    }
+    Flow::Continue
+}
+
+/// Mutes a user in a room, for the room's ops (and moderators).
+fn mute_in_room(ctx: &Context, args: &str) -> Flow {
+    let mut args = args.splitn(4, ' ');
+    let (Some(name), Some(user), Some(duration)) = (args.next(), args.next(), args.next()) else {
+        ctx.reply("Usage: /mute <#room> <user> <duration> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let (name, duration) = match (rooms::parse_name(name), duration::parse(duration)) {
+        (Ok(name), Ok(duration)) => (name, duration),
+        (Err(e), _) | (_, Err(e)) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = moderated_room(ctx, &mut rooms, name) else {
+        return Flow::Continue;
+    };
+    if !outranks(ctx, room, user, "mute") {
+        return Flow::Continue;
+    }
+    if let Err(e) = room.mute(user, duration) {
+        ctx.reply(&e);
+        return Flow::Continue;
+    }
+    let duration = duration::format(duration);
+    ctx.audit(
+        Action::Mute,
+        Some(user),
+        &format!("{duration} in #{name}"),
+        reason,
+    );
+    ctx.server.send_to(
+        user,
+        &format!(
+            "You have been muted in #{name} by {} for {duration}",
+            ctx.name()
+        ),
+    );
+    ctx.reply(&format!("{user} is muted in #{name} for {duration}"));
+    Flow::Continue
+}
+
+/// Lifts a mute before it expires, in one room if a room is given.
+fn unmute(ctx: &Context, args: &str) -> Flow {
+    if args.starts_with('#') {
+        return unmute_in_room(ctx, args);
+    }
+    if ctx.role < Role::Moderator {
+        ctx.reply("Usage: /unmute <#room> <user> [reason]");
+        return Flow::Continue;
+    }
+    let (user, reason) = args.split_once(' ').unwrap_or((args, ""));
+    if ctx.server.unmute(user) {
+        ctx.audit(Action::Unmute, Some(user), "", reason.trim());
+        ctx.server.send_to(user, "You are no longer muted");
+        ctx.reply(&format!("{user} is no longer muted"));
+    } else {
+        ctx.reply(&format!("{user} is not muted"));
+    }
+    Flow::Continue
+}
+
+/// Lifts a mute in a room before it expires.
+fn unmute_in_room(ctx: &Context, args: &str) -> Flow {
+    let mut args = args.splitn(3, ' ');
+    let (Some(name), Some(user)) = (args.next(), args.next()) else {
+        ctx.reply("Usage: /unmute <#room> <user> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let name = match rooms::parse_name(name) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = moderated_room(ctx, &mut rooms, name) else {
+        return Flow::Continue;
+    };
+    if room.unmute(user) {
+        ctx.audit(Action::Unmute, Some(user), &format!("in #{name}"), reason);
+        ctx.server
+            .send_to(user, &format!("You are no longer muted in #{name}"));
+        ctx.reply(&format!("{user} is no longer muted in #{name}"));
+    } else {
+        ctx.reply(&format!("{user} is not muted in #{name}"));
+    }
+    Flow::Continue
+}
+
+/// Bans an address or a network from connecting, for a while or for good. Users
+/// already connected from it stay connected.
+fn ban(ctx: &Context, args: &str) -> Flow {
+    let mut args = args.splitn(4, ' ');
+    let (Some("ip"), Some(cidr), Some(duration)) = (args.next(), args.next(), args.next()) else {
+        ctx.reply("Usage: /ban ip <address|cidr> <duration|forever> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let duration = match duration {
+        "forever" => Ok(None),
+        duration => duration::parse(duration).map(Some),
+    };
+    let (cidr, duration) = match (cidr.parse::<Cidr>(), duration) {
+        (Ok(cidr), Ok(duration)) => (cidr, duration),
+        (Err(e), _) | (_, Err(e)) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let until = duration.map(|duration| {
+        let until = SystemTime::now() + duration;
+        until
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs())
+    });
+    let ban = Ban {
+        cidr,
+        until,
+        by: ctx.name().to_string(),
+        reason: reason.to_string(),
+    };
+    if let Err(e) = ctx.server.ban(ban) {
+        ctx.reply(&format!("Failed to save the ban: {e}"));
+        return Flow::Continue;
+    }
+    let detail = duration.map_or("forever".to_string(), duration::format);
+    ctx.audit(Action::Ban, Some(&cidr.to_string()), &detail, reason);
+    let mut out = match duration {
+        Some(duration) => format!("{cidr} is banned for {}", duration::format(duration)),
+        None => format!("{cidr} is banned for good"),
+    };
+    let connected = ctx.server.users_from(cidr);
+    if !connected.is_empty() {
+        let _ = write!(out, ". Still connected from it: {}", connected.join(", "));
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Lifts a ban before it runs out.
+fn unban(ctx: &Context, args: &str) -> Flow {
+    let mut args = args.splitn(3, ' ');
+    let (Some("ip"), Some(cidr)) = (args.next(), args.next()) else {
+        ctx.reply("Usage: /unban ip <address|cidr> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let cidr = match cidr.parse::<Cidr>() {
+        Ok(cidr) => cidr,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    match ctx.server.bans.lock().unwrap().unban(cidr) {
+        Ok(true) => {
+            ctx.audit(Action::Unban, Some(&cidr.to_string()), "", reason);
+            ctx.reply(&format!("{cidr} is no longer banned"));
+        }
+        Ok(false) => ctx.reply(&format!("{cidr} is not banned")),
+        Err(e) => ctx.reply(&format!("Failed to save the bans: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Lists the bans in force, with who banned each and for how much longer.
+fn bans(ctx: &Context, _args: &str) -> Flow {
+    let now = SystemTime::now();
+    let bans = ctx.server.bans.lock().unwrap();
+    let mut out = String::new();
+    for ban in bans.active(now) {
+        let _ = write!(out, "\n  {}, by {}", ban.cidr, ban.by);
+        match ban.remaining(now) {
+            Some(remaining) => {
+                let _ = write!(out, ", for another {}", duration::format(remaining));
+            }
+            None => out.push_str(", for good"),
+        }
+        if !ban.reason.is_empty() {
+            let _ = write!(out, ": {}", ban.reason);
+        }
+    }
+    if out.is_empty() {
+        ctx.reply("Nothing is banned");
+    } else {
+        ctx.reply(&format!("Bans:{out}"));
+    }
+    Flow::Continue
+}
+
+/// Shows the allowlist, turns it on or off, or adds or removes a user or network.
+/// Users who are connected already stay connected either way.
+fn allowlist(ctx: &Context, args: &str) -> Flow {
+    let mut allowlist = ctx.server.allowlist.lock().unwrap();
+    let (verb, entry) = args.split_once(' ').unwrap_or((args, ""));
+    // Users are let in by their account, so that no one else can take the name
+    let parsed = match (verb, entry.trim().parse::<Entry>()) {
+        ("add", Ok(Entry::User(username))) => {
+            match ctx.server.accounts.lock().unwrap().find(&username) {
+                Some(registered) => Ok(Entry::User(registered.to_string())),
+                None => Err(format!(
+                    "{username} isn't registered, only registered users can be on the allowlist"
+                )),
+            }
+        }
+        (_, parsed) => parsed,
+    };
+    // What changed, if anything, and what to tell the admin
+    let result = match (verb, parsed) {
+        ("", _) => {
+            let entries: Vec<String> = allowlist.entries().map(|e| e.to_string()).collect();
+            let state = if allowlist.is_enforced() { "on" } else { "off" };
+            let entries = match entries.is_empty() {
+                true => "nobody".to_string(),
+                false => entries.join(", "),
+            };
+            ctx.reply(&format!("The allowlist is {state}: {entries}"));
+            return Flow::Continue;
+        }
+        ("on" | "off", _) if entry.is_empty() => {
+            let on = verb == "on";
+            allowlist.enforce(on).map(|changed| match (changed, on) {
+                (false, _) => (false, format!("The allowlist is {verb} already")),
+                (true, true) => (true, "Only those on the allowlist may join now".to_string()),
+                (true, false) => (true, "Everyone may join again".to_string()),
+            })
+        }
+        ("add", Ok(entry)) => allowlist.add(entry.clone()).map(|added| match added {
+            true => (true, format!("{entry} is on the allowlist")),
+            false => (false, format!("{entry} is on the allowlist already")),
+        }),
+        ("remove", Ok(entry)) => allowlist.remove(&entry).map(|removed| match removed {
+            true => (true, format!("{entry} is no longer on the allowlist")),
+            false => (false, format!("{entry} is not on the allowlist")),
+        }),
+        ("add" | "remove", Err(e)) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+        _ => {
+            ctx.reply("Usage: /allowlist [on|off|add <user|cidr>|remove <user|cidr>]");
+            return Flow::Continue;
+        }
+    };
+    drop(allowlist);
+    match result {
+        Ok((changed, reply)) => {
+            if changed {
+                ctx.audit(Action::Allowlist, None, args, "");
+            }
+            ctx.reply(&reply);
+        }
+        Err(e) => ctx.reply(&format!("Failed to save the allowlist: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Lists the HTTP API's tokens, creates one, which may post to (or, if read-only,
+/// stream) the rooms given or any room, or revokes one. A new token is shown once, only to the admin who created it.
+fn tokens(ctx: &Context, args: &str) -> Flow {
+    let mut words = args.split_whitespace();
+    let mut tokens = ctx.server.api_tokens.lock().unwrap();
+    let result = match (words.next(), words.next()) {
+        (None, _) => {
+            let list: Vec<String> = (tokens.all())
+                .map(|(name, token)| {
+                    let rooms = match token.rooms.is_empty() {
+                        true => "any room".to_string(),
+                        false => token
+                            .rooms
+                            .iter()
+                            .map(|room| format!("#{room}"))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    };
+                    let may = if token.read_only { "stream" } else { "post to" };
+                    format!(
+                        "\n  {name}: may {may} {rooms}, created by {}",
+                        token.created_by
+                    )
+                })
+                .collect();
+            match list.is_empty() {
+                true => ctx.reply("There are no API tokens"),
+                false => ctx.reply(&format!("API tokens:{}", list.concat())),
+            }
+            return Flow::Continue;
+        }
+        (Some("create"), Some(name)) if !name.starts_with('#') => {
+            let mut words = words.peekable();
+            let read_only = words.next_if_eq(&"read-only").is_some();
+            let rooms: Result<BTreeSet<String>, String> = words
+                .map(|room| rooms::parse_name(room).map(str::to_string))
+                .collect();
+            let rooms = match rooms {
+                Ok(rooms) => rooms,
+                Err(e) => {
+                    ctx.reply(&e);
+                    return Flow::Continue;
+                }
+            };
+            let hint = match read_only {
+                true => "Stream with: curl -N -H 'Authorization: Bearer {secret}' http://<server>/api/rooms/<room>/events",
+                false => "Post with: curl -H 'Authorization: Bearer {secret}' --data-binary @- http://<server>/api/rooms/<room>/messages",
+            };
+            (tokens.create(name, rooms, read_only, ctx.name())).map(|secret| match secret {
+                Some(secret) => (
+                    true,
+                    format!(
+                        "Created the API token {name}: {secret}\nIt won't be shown again. {}",
+                        hint.replace("{secret}", &secret)
+                    ),
+                ),
+                None => (false, format!("There is a token named {name} already")),
+            })
+        }
+        (Some("revoke"), Some(name)) => tokens.revoke(name).map(|revoked| match revoked {
+            true => (true, format!("Revoked the API token {name}")),
+            false => (false, format!("There is no API token named {name}")),
+        }),
+        _ => {
+            ctx.reply("Usage: /tokens [create <name> [read-only] [#room...]|revoke <name>]");
+            return Flow::Continue;
+        }
+    };
+    drop(tokens);
+    match result {
+        Ok((changed, reply)) => {
+            if changed {
+                ctx.audit(Action::ApiToken, None, args, "");
+            }
+            ctx.reply(&reply);
+        }
+        Err(e) => ctx.reply(&format!("Failed to save the API tokens: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Sets or clears a room's topic and tells everyone in the room. The topics of
+/// permanent rooms are kept across restarts; clearing one goes back to the topic in
+/// the config file, if it has one.
+fn topic(ctx: &Context, args: &str) -> Flow {
+    let (name, text) = args.split_once(' ').unwrap_or((args, ""));
+    let name = match rooms::parse_name(name) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&format!("Usage: /topic <#room> [text]. {e}"));
+            return Flow::Continue;
+        }
+    };
+    let text = text.trim();
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    if moderated_room(ctx, &mut rooms, name).is_none() {
+        return Flow::Continue;
+    }
+    match rooms.set_topic(name, (!text.is_empty()).then(|| text.to_string())) {
+        Ok(true) => {}
+        Ok(false) => {
+            ctx.reply(&format!("There is no room #{name}"));
+            return Flow::Continue;
+        }
+        Err(e) => {
+            // The topic is set until the server restarts
+            eprintln!("Failed to save the rooms: {e}");
+        }
+    }
+    ctx.audit(Action::SetTopic, Some(&format!("#{name}")), text, "");
+    let Some(room) = rooms.get(name) else {
+        return Flow::Continue;
+    };
+    let line = match &room.topic {
+        Some(topic) => format!("{} set the topic of #{name}: {topic}", ctx.name()),
+        None => format!("{} cleared the topic of #{name}", ctx.name()),
+    };
+    tell_room(ctx, room, &line);
+    Flow::Continue
+}
+
+/// Pins a message of the actor's room, picked by its number, for everyone entering
+/// the room to see.
+fn pin(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let Ok(seq) = args.parse::<u64>() else {
+        ctx.reply("Usage: /pin <seq>");
+        return Flow::Continue;
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(name) = rooms.room_of(username).map(str::to_string) else {
+        return Flow::Continue;
+    };
+    let Some(room) = moderated_room(ctx, &mut rooms, &name) else {
+        return Flow::Continue;
+    };
+    let pinned = room.pins().iter().any(|pin| pin.seq == seq);
+    if !pinned && room.pins().len() >= rooms::MAX_PINS {
+        ctx.reply(&format!(
+            "#{name} has {} pinned messages already, unpin one first",
+            rooms::MAX_PINS
+        ));
+        return Flow::Continue;
+    }
+    let Some(message) = room.history.range(seq..=seq).pop() else {
+        ctx.reply(&format!("There is no message #{seq} in #{name}"));
+        return Flow::Continue;
+    };
+    let pin = rooms::Pin {
+        seq,
+        from: message.from.to_string(),
+        text: message.body(),
+        by: username.to_string(),
+    };
+    if let Err(e) = rooms.pin(&name, pin) {
+        // The pin is kept until the server restarts
+        eprintln!("Failed to save the rooms: {e}");
+    }
+    ctx.audit(Action::Pin, Some(&format!("#{name}")), &seq.to_string(), "");
+    if let Some(room) = rooms.get(&name) {
+        tell_room(
+            ctx,
+            room,
+            &format!("{username} pinned message #{seq} in #{name}"),
+        );
+    }
+    Flow::Continue
+}
+
+/// Unpins a message of the actor's room.
+fn unpin(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let Ok(seq) = args.parse::<u64>() else {
+        ctx.reply("Usage: /unpin <seq>");
+        return Flow::Continue;
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(name) = rooms.room_of(username).map(str::to_string) else {
+        return Flow::Continue;
+    };
+    if moderated_room(ctx, &mut rooms, &name).is_none() {
+        return Flow::Continue;
+    }
+    match rooms.unpin(&name, seq) {
+        Ok(true) => {}
+        Ok(false) => {
+            ctx.reply(&format!("Message #{seq} isn't pinned in #{name}"));
+            return Flow::Continue;
+        }
+        Err(e) => eprintln!("Failed to save the rooms: {e}"),
+    }
+    ctx.audit(
+        Action::Unpin,
+        Some(&format!("#{name}")),
+        &seq.to_string(),
+        "",
+    );
+    if let Some(room) = rooms.get(&name) {
+        tell_room(
+            ctx,
+            room,
+            &format!("{username} unpinned message #{seq} in #{name}"),
+        );
+    }
+    Flow::Continue
+}
+
+/// Lists the messages pinned in the actor's room.
+fn pins(ctx: &Context, _args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let Some((name, room)) =
+        (rooms.room_of(username)).and_then(|name| Some((name, rooms.get(name)?)))
+    else {
+        return Flow::Continue;
+    };
+    if room.pins().is_empty() {
+        ctx.reply(&format!("No messages are pinned in #{name}"));
+        return Flow::Continue;
+    }
+    let mut lines = vec![format!("Pinned in #{name}:")];
+    lines.extend(room.pins().iter().map(|pin| format!("  {}", pin.line())));
+    ctx.reply(&lines.join("\n"));
+    Flow::Continue
+}
+
+/// Sends a message to several rooms at once, as a room message in each, tagged with
+/// the rooms it went to. The actor must be allowed to moderate every one of them,
+/// or it goes to none.
+fn broadcast(ctx: &Context, args: &str) -> Flow {
+    let Some((names, text)) = args.split_once(' ') else {
+        ctx.reply("Usage: /broadcast <#room,#room...> <text>");
+        return Flow::Continue;
+    };
+    if let Some(remaining) = ctx.server.muted_for(ctx.name()) {
+        ctx.reply(&muted_notice(remaining));
+        return Flow::Continue;
+    }
+    let mut targets: Vec<String> = Vec::new();
+    for name in names.split(',') {
+        match rooms::parse_name(name) {
+            Ok(name) if !targets.iter().any(|target| target == name) => {
+                targets.push(name.to_string())
+            }
+            Ok(_) => {}
+            Err(e) => {
+                ctx.reply(&e);
+                return Flow::Continue;
+            }
+        }
+    }
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    for name in &targets {
+        if moderated_room(ctx, &mut rooms, name).is_none() {
+            return Flow::Continue;
+        }
+    }
+    // A copy in every room counts like a message to it, or the broadcast goes nowhere
+    if let Actor::User(username, _) = ctx.actor {
+        if !may_post(ctx, username, targets.len(), text.trim()) {
+            return Flow::Continue;
+        }
+    }
+    let from = match ctx.actor {
+        Actor::User(username, _) => Arc::clone(username),
+        Actor::Console => Arc::new(ctx.name().to_string()),
+    };
+    let received = Instant::now();
+    for name in &targets {
+        let Some(room) = rooms.get_mut(name) else {
+            continue;
+        };
+        let via = Some(Via::Broadcast(targets.clone()));
+        let message =
+            room.history
+                .push_via(Arc::clone(&from), text.trim(), None, via, Spans::default());
+        ctx.server
+            .publish(name, room, &message, None, None, received);
+    }
+    let sent: Vec<String> = targets.iter().map(|name| format!("#{name}")).collect();
+    ctx.reply(&format!("Sent to {}", sent.join(", ")));
+    Flow::Continue
+}
+
+/// Counts `copies` room messages of `text` that `username` posts at once against the
+/// message rate limit and their daily quotas, like those they type. Returns false,
+/// having told them why and counted none, if that goes over either.
+fn may_post(ctx: &Context, username: &str, copies: usize, text: &str) -> bool {
+    if let Err(wait) = ctx
+        .server
+        .throttle_several(Limit::Messages, username, copies as u32)
+    {
+        ctx.reply(&throttled(Limit::Messages, wait, None));
+        return false;
+    }
+    let sent = ctx.server.quotas.lock().unwrap().send_several(
+        username,
+        copies as u64,
+        text.len() as u64,
+        SystemTime::now(),
+    );
+    if let Err(exceeded) = sent {
+        ctx.server.stats.over_quota.bump();
+        ctx.reply(&exceeded.line(None));
+        return false;
+    }
+    true
+}
+
+/// Sends a message from the user's room on to another room, as a room message of
+/// theirs there, or to a user, as a private message, saying where it came from and
+/// who wrote it. Messages are picked by their number, as long as they are still in
+/// the room's history.
+fn forward(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let Some((seq, to)) = args
+        .split_once(' ')
+        .and_then(|(seq, to)| Some((seq.parse::<u64>().ok()?, to.trim())))
+    else {
+        ctx.reply("Usage: /forward <seq> <#room|user>");
+        return Flow::Continue;
+    };
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        ctx.reply(&muted_notice(remaining));
+        return Flow::Continue;
+    }
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(from_room) = rooms.room_of(username).map(str::to_string) else {
+        return Flow::Continue;
+    };
+    let Some(original) =
+        (rooms.current(username)).and_then(|room| room.history.range(seq..=seq).pop())
+    else {
+        ctx.reply(&format!("There is no message #{seq} in #{from_room}"));
+        return Flow::Continue;
+    };
+    // A message forwarded again still says where it was first sent
+    let (from_room, author) = match original.via {
+        Some(Via::Forward { room, author }) => (room, author),
+        _ => (from_room, original.from.to_string()),
+    };
+    let Some(to) = to.strip_prefix('#') else {
+        drop(rooms);
+        if let Err(wait) = ctx.server.throttle(Limit::DirectMessages, username) {
+            ctx.reply(&throttled(Limit::DirectMessages, wait, None));
+            return Flow::Continue;
+        }
+        let text = history::forwarded_text(&from_room, &author, &original.text);
+        let line = Tags::default()
+            .time(SystemTime::now())
+            .forwarded(&from_room)
+            .spans(&history::forwarded_spans(
+                &from_room,
+                &author,
+                &original.spans,
+            ))
+            .apply(&format!("[{username} -> {to}]: {text}"));
+        if send_private(ctx, to, &line, &text) {
+            ctx.reply(&format!("Forwarded to {to}"));
+        }
+        return Flow::Continue;
+    };
+    let Some(room) = rooms.get_mut(to) else {
+        ctx.reply(&format!("There is no room #{to}"));
+        return Flow::Continue;
+    };
+    if !room.access.admits(username, ctx.role) {
+        ctx.reply(&format!("You may not post to #{to}"));
+        return Flow::Continue;
+    }
+    if room.is_spectator(username) {
+        ctx.reply(&spectating_notice(to));
+        return Flow::Continue;
+    }
+    if let Some(remaining) = room.muted_for(username) {
+        ctx.reply(&format!(
+            "You are muted in #{to}. The mute expires in {}",
+            duration::format(remaining)
+        ));
+        return Flow::Continue;
+    }
+    if !may_post(ctx, username, 1, &original.text) {
+        return Flow::Continue;
+    }
+    let via = Some(Via::Forward {
+        room: from_room,
+        author,
+    });
+    let message = room.history.push_via(
+        Arc::clone(username),
+        &original.text,
+        None,
+        via,
+        original.spans,
+    );
+    ctx.server
+        .publish(to, room, &message, None, None, Instant::now());
+    ctx.reply(&format!("Forwarded to #{to}"));
+    Flow::Continue
+}
+
+/// Opens a poll in the actor's room, posted as a message of theirs, which the room's
+/// members answer with `/vote`.
+fn poll(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let (question, options) = match polls::parse_args(args) {
+        Ok(poll) => poll,
+        Err(e) => {
+            ctx.reply(&format!(
+                "Usage: /poll \"<question>\" <option> <option>... {e}"
+            ));
+            return Flow::Continue;
+        }
+    };
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        ctx.reply(&muted_notice(remaining));
+        return Flow::Continue;
+    }
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(name) = rooms.room_of(username).map(str::to_string) else {
+        return Flow::Continue;
+    };
+    let Some(room) = rooms.get_mut(&name) else {
+        return Flow::Continue;
+    };
+    if let Some(remaining) = room.muted_for(username) {
+        ctx.reply(&room_muted_notice(remaining));
+        return Flow::Continue;
+    }
+    if room.is_spectator(username) {
+        ctx.reply(&spectating_notice(&name));
+        return Flow::Continue;
+    }
+    if !may_post(ctx, username, 1, args) {
+        return Flow::Continue;
+    }
+    let text = ctx
+        .server
+        .polls
+        .lock()
+        .unwrap()
+        .open(&name, question, options)
+        .text();
+    let message = room.history.push(Arc::clone(username), &text, None);
+    ctx.server
+        .publish(&name, room, &message, None, None, Instant::now());
+    Flow::Continue
+}
+
+/// Records the actor's vote in a poll of their room, and tells the room the results
+/// so far.
+fn vote(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console isn't in a room");
+        return Flow::Continue;
+    };
+    let Some((id, option)) = args
+        .split_once(' ')
+        .and_then(|(id, option)| Some((id.parse::<u64>().ok()?, option.trim().parse().ok()?)))
+    else {
+        ctx.reply("Usage: /vote <poll> <n>");
+        return Flow::Continue;
+    };
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let mut polls = ctx.server.polls.lock().unwrap();
+    let Some(poll) = polls.get(id) else {
+        ctx.reply(&format!("There is no poll #{id}"));
+        return Flow::Continue;
+    };
+    let Some(room) = rooms.get(&poll.room) else {
+        ctx.reply(&format!("There is no poll #{id}"));
+        return Flow::Continue;
+    };
+    if rooms.room_of(username) != Some(poll.room.as_str()) || room.is_spectator(username) {
+        ctx.reply(&format!("Poll #{id} is for the members of #{}", poll.room));
+        return Flow::Continue;
+    }
+    match polls.vote(id, username, option) {
+        Ok(poll) => tell_room(ctx, room, &poll.results()),
+        Err(e) => ctx.reply(&e),
+    }
+    Flow::Continue
+}
+
+/// What a line from a user posts to their room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Posted {
+    /// A message, unless the line is a command.
+    Message,
+    /// A reply to message `seq`, with `/quote`.
+    Quote(u64),
+    /// An action, with `/me`.
+    Action,
+}
+
+/// Tells the commands that post a room message, `/quote` and `/me`, from a line,
+/// and takes the message's text out of them. They are posted like any other
+/// message, see `handle_client`, and only reach [`dispatch`] if they can't be read.
+pub fn posted(line: &str) -> (&str, Posted) {
+    let (text, posted) = match line.split_once(' ') {
+        Some(("/quote", args)) => match args.trim_start().split_once(' ') {
+            Some((seq, text)) => match seq.parse() {
+                Ok(seq) => (text.trim(), Posted::Quote(seq)),
+                Err(_) => return (line, Posted::Message),
+            },
+            None => return (line, Posted::Message),
+        },
+        Some(("/me", text)) => (text.trim(), Posted::Action),
+        _ => return (line, Posted::Message),
+    };
+    if text.is_empty() {
+        return (line, Posted::Message);
+    }
+    (text, posted)
+}
+
+fn quote(ctx: &Context, _args: &str) -> Flow {
+    match ctx.actor {
+        Actor::User(..) => ctx.reply("Usage: /quote <seq> <text>"),
+        _ => ctx.reply("The console isn't in a room"),
+    }
+    Flow::Continue
+}
+
+fn me(ctx: &Context, _args: &str) -> Flow {
+    match ctx.actor {
+        Actor::User(..) => ctx.reply("Usage: /me <action>"),
+        _ => ctx.reply("The console isn't in a room"),
+    }
+    Flow::Continue
+}
+
+/// Sends a user in a room back to the lobby. They may come back; kicking is a
+/// warning, a mute keeps them quiet.
+fn kick(ctx: &Context, args: &str) -> Flow {
+    let mut args = args.splitn(3, ' ');
+    let (Some(name), Some(user)) = (args.next(), args.next()) else {
+        ctx.reply("Usage: /kick <#room> <user> [reason]");
+        return Flow::Continue;
+    };
+    let reason = args.next().unwrap_or_default().trim();
+    let name = match rooms::parse_name(name) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    if name == rooms::LOBBY {
+        ctx.reply("Nobody can be kicked out of the lobby");
+        return Flow::Continue;
+    }
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = moderated_room(ctx, &mut rooms, name) else {
+        return Flow::Continue;
+    };
+    if !outranks(ctx, room, user, "kick") {
+        return Flow::Continue;
+    }
+    let Some(target) = room
+        .members()
+        .find(|member| member.as_str() == user)
+        .cloned()
+    else {
+        ctx.reply(&format!("{user} isn't in #{name}"));
+        return Flow::Continue;
+    };
+    let mut line = format!("{user} was kicked out of #{name} by {}", ctx.name());
+    if !reason.is_empty() {
+        let _ = write!(line, ": {reason}");
+    }
+    tell_room(ctx, room, &line);
+    ctx.audit(Action::Kick, Some(user), &format!("#{name}"), reason);
+    rooms.enter(&target, rooms::LOBBY);
+    if let Some(lobby) = rooms.get(rooms::LOBBY) {
+        ctx.server
+            .send_to(user, &lobby.welcome(rooms::LOBBY).join("\n"));
+    }
+    Flow::Continue
+}
+
+fn op(ctx: &Context, args: &str) -> Flow {
+    set_op(ctx, args, true)
+}
+
+fn deop(ctx: &Context, args: &str) -> Flow {
+    set_op(ctx, args, false)
+}
+
+/// Makes a user a room op, or no longer one. Moderators may do so in any room, the
+/// creator of an ad hoc room in theirs. Ops of permanent rooms are kept across
+/// restarts.
+fn set_op(ctx: &Context, args: &str, op: bool) -> Flow {
+    let command = if op { "op" } else { "deop" };
+    let Some((name, user)) = args.split_once(' ') else {
+        ctx.reply(&format!("Usage: /{command} <#room> <user>"));
+        return Flow::Continue;
+    };
+    let user = user.trim();
+    let name = match rooms::parse_name(name) {
+        Ok(name) => name,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    let mut rooms = ctx.server.rooms.lock().unwrap();
+    let Some(room) = rooms.get(name) else {
+        ctx.reply(&format!("There is no room #{name}"));
+        return Flow::Continue;
+    };
+    if ctx.role < Role::Moderator && room.creator() != Some(ctx.name()) {
+        ctx.reply(&format!(
+            "Only moderators and the user who opened #{name} can change its room ops"
+        ));
+        return Flow::Continue;
+    }
+    if ctx.server.role(user).is_none() {
+        ctx.reply(&format!("No such user: {user}"));
+        return Flow::Continue;
+    }
+    // Anyone may take an unregistered name once its user leaves, ops included
+    if op && ctx.server.accounts.lock().unwrap().get(user).is_none() {
+        ctx.reply(&format!(
+            "{user} isn't registered, only registered users can be room ops"
+        ));
+        return Flow::Continue;
+    }
+    if room.creator() == Some(user) {
+        ctx.reply(&format!(
+            "{user} opened #{name}, and is always one of its room ops"
+        ));
+        return Flow::Continue;
+    }
+    if room.is_op(user) == op {
+        let already = if op { "already" } else { "not" };
+        ctx.reply(&format!("{user} is {already} a room op of #{name}"));
+        return Flow::Continue;
+    }
+    if let Err(e) = rooms.set_op(name, user, op) {
+        // They are a room op until the server restarts
+        eprintln!("Failed to save the rooms: {e}");
+    }
+    let (action, line) = if op {
+        (
+            Action::Op,
+            format!("{} made {user} a room op of #{name}", ctx.name()),
+        )
+    } else {
+        (
+            Action::Deop,
+            format!(
+                "{} took away {user}'s room op status in #{name}",
+                ctx.name()
+            ),
+        )
+    };
+    ctx.audit(action, Some(user), &format!("#{name}"), "");
+    let Some(room) = rooms.get(name) else {
+        return Flow::Continue;
+    };
+    tell_room(ctx, room, &line);
+    if !room.members().any(|member| member.as_str() == user) {
+        ctx.server.send_to(user, &line);
+    }
+    Flow::Continue
+}
+
+/// Looks up the room `name` for a command moderating it, which moderators may run
+/// in any room and room ops in theirs. Tells the actor if there is no such room or
+/// they may not.
+fn moderated_room<'a>(
+    ctx: &Context,
+    rooms: &'a mut rooms::Rooms,
+    name: &str,
+) -> Option<&'a mut rooms::Room> {
+    let Some(room) = rooms.get_mut(name) else {
+        ctx.reply(&format!("There is no room #{name}"));
+        return None;
+    };
+    if ctx.role < Role::Moderator && !room.is_op(ctx.name()) {
+        ctx.reply(&format!("You aren't a room op of #{name}"));
+        return None;
+    }
+    Some(room)
+}
+
+/// Checks that the actor may `act` (kick or mute) on `user` in `room`, and tells
+/// them if not. Moderators may act on anyone whose role isn't above theirs; room
+/// ops only on users who are neither moderators nor room ops themselves.
+fn outranks(ctx: &Context, room: &rooms::Room, user: &str, act: &str) -> bool {
+    let Some(role) = ctx.server.role(user) else {
+        ctx.reply(&format!("No such user: {user}"));
+        return false;
+    };
+    let outranks = if ctx.role >= Role::Moderator {
+        role <= ctx.role
+    } else {
+        role == Role::User && !room.is_op(user)
+    };
+    if !outranks {
+        ctx.reply(&format!("You can't {act} {user}"));
+    }
+    outranks
+}
+
+/// Sends a line to everyone in `room`, and to the actor if they aren't in it.
+fn tell_room(ctx: &Context, room: &rooms::Room, line: &str) {
+    for member in room.members() {
+        ctx.server.send_to(member, line);
+    }
+    if !room.members().any(|member| member.as_str() == ctx.name()) {
+        ctx.reply(line);
+    }
+}
+
+/// Broadcasts a system message to every connected user, including the sender.
+fn announce(ctx: &Context, text: &str) -> Flow {
+    if text.is_empty() {
+        ctx.reply("Usage: /announce <text>");
+        return Flow::Continue;
+    }
+    ctx.audit(Action::Announce, None, text, "");
+    ctx.server.broadcast_all(&format!(
+        "*** Announcement from {}: {} ***",
+        ctx.name(),
+        text
+    ));
+    Flow::Continue
+}
+
+/// Changes the role of a connected user.
+fn role(ctx: &Context, args: &str) -> Flow {
+    let Some((user, role)) = args.split_once(' ') else {
+        ctx.reply("Usage: /role <user> <role>");
+        return Flow::Continue;
+    };
+    let role: Role = match role.trim().parse() {
+        Ok(role) => role,
+        Err(e) => {
+            ctx.reply(&e);
+            return Flow::Continue;
+        }
+    };
+    if ctx.server.set_role(user, role) {
+        ctx.audit(Action::RoleChange, Some(user), &role.to_string(), "");
+        ctx.server
+            .send_to(user, &format!("{} made you {role}", ctx.name()));
+        ctx.reply(&format!("{user} is now {role}"));
+    } else {
+        ctx.reply(&format!("No such user: {user}"));
+    }
+    Flow::Continue
+}
+
+/// Reports how long the server has been up, who is connected, how much traffic it
+/// has seen and how much it holds in memory and on disk.
+fn stats(ctx: &Context, _args: &str) -> Flow {
+    let server = ctx.server;
+    let stats = &server.stats;
+    let users = server.user_counts();
+    let (rooms, history) = {
+        let rooms = server.rooms.lock().unwrap();
+        let history = rooms
+            .iter()
+            .map(|(_, room)| room.history.len())
+            .sum::<usize>();
+        (rooms.iter().count(), history)
+    };
+    let reports = server.reports.lock().unwrap().open().count();
+    let mut out = format!("Up for {}", duration::format(stats.uptime()));
+    let _ = write!(
+        out,
+        "\n  Users: {} ({} sessions, {} waiting to be resumed)",
+        users.users, users.sessions, users.suspended
+    );
+    let _ = write!(
+        out,
+        "\n  Connections: {} accepted, {} refused",
+        stats.connections.get(),
+        stats.refused.get()
+    );
+    let _ = write!(
+        out,
+        "\n  Messages: {} to the room ({:.1}/min), {} private",
+        stats.messages.get(),
+        stats.messages_per_minute(),
+        stats.private_messages.get()
+    );
+    let fanout = &stats.fanout;
+    if let (Some(p50), Some(p90), Some(p99)) = (
+        fanout.percentile(50.0),
+        fanout.percentile(90.0),
+        fanout.percentile(99.0),
+    ) {
+        let _ = write!(
+            out,
+            "\n  Fan-out: {} deliveries, p50 {}, p90 {}, p99 {}, max {}",
+            fanout.count(),
+            histogram::format(p50),
+            histogram::format(p90),
+            histogram::format(p99),
+            histogram::format(fanout.max())
+        );
+    }
+    let traffic = server.traffic();
+    let mut total = Usage::default();
+    for (_, usage) in &traffic {
+        total += *usage;
+    }
+    let _ = write!(
+        out,
+        "\n  Traffic: {} bytes sent, {} received",
+        total.sent, total.received
+    );
+    let heaviest: Vec<String> = (traffic.iter().take(TRAFFIC_TOP))
+        .map(|(username, usage)| format!("{username} ({} bytes)", usage.total()))
+        .collect();
+    if !heaviest.is_empty() {
+        let _ = write!(out, ", most by {}", heaviest.join(", "));
+    }
+    if server.webhooks.len() > 0 {
+        let counts = server.webhooks.counts();
+        let _ = write!(
+            out,
+            "\n  Webhooks: {} configured, {} deliveries made, {} retried, {} dead-lettered",
+            server.webhooks.len(),
+            counts.delivered.get(),
+            counts.retried.get(),
+            counts.dead.get()
+        );
+    }
+    let _ = write!(
+        out,
+        "\n  Quotas: {} room message(s) over the daily quotas turned away, {} line(s) for disconnected sessions dropped",
+        stats.over_quota.get(),
+        stats.dropped_lines.get()
+    );
+    let buffers = &BUFFERS;
+    let _ = write!(
+        out,
+        "\n  Buffers: {} write buffer(s) reused, {} allocated ({:.1}% from the pool)",
+        buffers.hits.get(),
+        buffers.misses.get(),
+        buffers.hit_rate()
+    );
+    let _ = write!(
+        out,
+        "\n  Writes: {} line(s) in {} write(s), {} batch(es) of {:.1} line(s) on average",
+        WRITES.lines.get(),
+        WRITES.writes.get(),
+        WRITES.batches.get(),
+        WRITES.batch_size()
+    );
+    let _ = write!(
+        out,
+        "\n  Queued: {} line(s) for disconnected sessions, {history} in the history of {rooms} room(s) (up to {} each), {reports} open report(s)",
+        users.missed_lines,
+        server::HISTORY_SIZE
+    );
+    match dir_size(&server.data_dir) {
+        Ok(bytes) => {
+            let _ = write!(
+                out,
+                "\n  Storage: {bytes} bytes in {}",
+                server.data_dir.display()
+            );
+        }
+        Err(e) => {
+            let _ = write!(out, "\n  Storage: unknown ({e})");
+        }
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Adds up the sizes of the files in a directory (the data directory is flat).
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Shows recent audit log entries, optionally only those involving a user, or
+/// checks the log's hash chain.
+fn audit(ctx: &Context, args: &str) -> Flow {
+    let audit = ctx.server.audit.lock().unwrap();
+    if args == "verify" {
+        match audit.verify() {
+            Ok(Ok(count)) => ctx.reply(&format!("Audit log intact ({count} entries)")),
+            Ok(Err(e)) => ctx.reply(&format!("Audit log has been tampered with: {e}")),
+            Err(e) => ctx.reply(&format!("Failed to read the audit log: {e}")),
+        }
+        return Flow::Continue;
+    }
+
+    let entries = match audit.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            ctx.reply(&format!("Failed to read the audit log: {e}"));
+            return Flow::Continue;
+        }
+    };
+    let (count, user) = match args.parse::<usize>() {
+        Ok(count) => (count, None),
+        Err(_) if args.is_empty() => (AUDIT_PAGE, None),
+        Err(_) => (AUDIT_PAGE, Some(args)),
+    };
+    let involves = |entry: &AuditEntry| match user {
+        Some(user) => entry.actor == user || entry.target.as_deref() == Some(user),
+        None => true,
+    };
+    let mut matching: Vec<&AuditEntry> = entries
+        .iter()
+        .rev()
+        .filter(|e| involves(e))
+        .take(count)
+        .collect();
+    matching.reverse();
+
+    let mut out = String::from("Audit log:");
+    for entry in matching {
+        let at = UNIX_EPOCH + Duration::from_secs(entry.at);
+        let _ = write!(
+            out,
+            "\n  #{} ({}) {} {}",
+            entry.seq,
+            ctx.server.time_format.format(at),
+            entry.actor,
+            entry.action
+        );
+        if let Some(target) = &entry.target {
+            let _ = write!(out, " {target}");
+        }
+        if !entry.detail.is_empty() {
+            let _ = write!(out, ": {}", entry.detail);
+        }
+        if !entry.reason.is_empty() {
+            let _ = write!(out, " ({})", entry.reason);
+        }
+    }
+    ctx.reply(&out);
+    Flow::Continue
+}
+
+/// Sends the user the data the server stores about them.
+fn export(ctx: &Context, _args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no data to export");
+        return Flow::Continue;
+    };
+    let export = privacy::export(ctx.server, username);
+    match serde_json::to_string_pretty(&export) {
+        Ok(json) => ctx.reply(&json),
+        Err(e) => ctx.reply(&format!("Failed to export your data: {e}")),
+    }
+    Flow::Continue
+}
+
+/// Erases what the server stores about the user and disconnects them. Requires an
+/// explicit `confirm`, as it can't be undone.
+fn forget(ctx: &Context, args: &str) -> Flow {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply("The console has no data to erase");
+        return Flow::Continue;
+    };
+    if args != "confirm" {
+        ctx.reply("This deletes your account, anonymizes your messages and reports and disconnects you. Type /forget confirm to go ahead");
+        return Flow::Continue;
+    }
+    privacy::erase(ctx.server, username);
+    ctx.reply("Your data has been erased. Goodbye!");
+    Flow::Leave
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Access, RoomDef};
+    use crate::events::EventSink;
+    use crate::history::Retention;
+    use crate::timestamp::TimeFormat;
+    use crate::transport::Writer;
+    use std::collections::HashSet;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    /// Joins `username` to `server`, returning their session and the other end of its
+    /// connection, to read what they are sent.
+    fn connect(server: &Server, username: &Arc<String>) -> (SessionId, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        let writer = Writer::Plain(stream);
+        let joined = server.join(username.clone(), writer, address.ip(), None, false);
+        (joined.ok().unwrap(), client)
+    }
+
+    /// Keeps the room messages the server tells its event sinks about.
+    struct Posts(Arc<Mutex<Vec<String>>>);
+
+    impl EventSink for Posts {
+        fn event(&self, event: &Event<'_>) {
+            if let Event::MessageBroadcast {
+                room, from, text, ..
+            } = event
+            {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("#{room} {from}: {text}"));
+            }
+        }
+    }
+
+    /// What was sent over `client` since it was last read.
+    fn received(client: &mut TcpStream) -> String {
+        let mut text = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Ok(len @ 1..) = client.read(&mut buffer) {
+            text.extend_from_slice(&buffer[..len]);
+        }
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn test_posted_takes_the_text_out() {
+        assert_eq!(posted("hi"), ("hi", Posted::Message));
+        assert_eq!(posted("/quote 3 me too"), ("me too", Posted::Quote(3)));
+        assert_eq!(posted("/me waves "), ("waves", Posted::Action));
+        // Not posted, so the commands reply with their usage
+        for line in ["/quote x hi", "/quote 3", "/me  ", "/me", "/meet"] {
+            assert_eq!(posted(line), (line, Posted::Message), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_help_lists_commands_for_role() {
+        let help = help_text(Role::User);
+        for command in COMMANDS.iter().filter(|c| c.role == Role::User) {
+            assert!(help.contains(command.usage));
+        }
+        let hidden = COMMANDS.iter().filter(|c| c.role > Role::User);
+        for command in hidden {
+            assert!(!help.contains(command.usage));
+        }
+
+        let help = help_text(Role::Guest);
+        assert!(help.contains("/join <#room>"));
+        assert!(!help.contains("/msg <user> <text>"));
+    }
+
+    #[test]
+    fn test_whois_shows_the_room_and_capabilities() {
+        let dir = std::env::temp_dir().join(format!("commands-whois-{}", std::process::id()));
+        let server = Server::new(&dir, TimeFormat::Relative, Retention::Forever, None).unwrap();
+        server.rooms.lock().unwrap().configure(vec![RoomDef {
+            name: "staff".to_string(),
+            topic: None,
+            access: Access {
+                allow: Some(HashSet::from(["alice".to_string(), "carol".to_string()])),
+                min_role: None,
+            },
+            retention: None,
+            max_members: None,
+        }]);
+        let [alice, bob, carol] = ["alice", "bob", "carol"].map(|name| Arc::new(name.to_string()));
+        let (session, _) = connect(&server, &alice);
+        server.set_capabilities(
+            &alice,
+            session,
+            vec!["acks".to_string(), "spans".to_string()],
+        );
+        server.rooms.lock().unwrap().enter(&alice, "staff");
+        let (bob_session, mut bob_client) = connect(&server, &bob);
+        let (carol_session, mut carol_client) = connect(&server, &carol);
+        let whois_by = |username, session, client: &mut TcpStream| {
+            let ctx = Context {
+                server: &server,
+                actor: Actor::User(username, session),
+                role: Role::User,
+            };
+            whois(&ctx, "alice");
+            received(client)
+        };
+
+        // Only those allowed in an invite-only room see who is in it
+        let seen = whois_by(&bob, bob_session, &mut bob_client);
+        assert!(seen.contains("alice: unregistered, user\n"), "{seen}");
+        assert!(seen.contains(", client supports acks, spans"), "{seen}");
+        assert!(whois_by(&carol, carol_session, &mut carol_client).contains(", in #staff"));
+        server.rooms.lock().unwrap().enter(&alice, "games");
+        assert!(whois_by(&bob, bob_session, &mut bob_client)
+            .contains("alice: unregistered, user, in #games\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_commands_post_like_typed_messages() {
+        let dir = std::env::temp_dir().join(format!("commands-posts-{}", std::process::id()));
+        let server = Server::new(&dir, TimeFormat::Relative, Retention::Forever, None).unwrap();
+        let posts = Arc::new(Mutex::new(Vec::new()));
+        server.events.register(Posts(Arc::clone(&posts)));
+        let [alice, bob] = ["alice", "bob"].map(|name| Arc::new(name.to_string()));
+        let (session, mut alice_client) = connect(&server, &alice);
+        let (_, mut bob_client) = connect(&server, &bob);
+        server.rooms.lock().unwrap().enter(&alice, rooms::LOBBY);
+        server.rooms.lock().unwrap().enter(&bob, "games");
+        let ctx = Context {
+            server: &server,
+            actor: Actor::User(&alice, session),
+            role: Role::Moderator,
+        };
+
+        broadcast(&ctx, "#lobby,#games hi @carol");
+        forward(&ctx, "1 #games");
+        poll(&ctx, "\"Lunch?\" yes no");
+        let posts = posts.lock().unwrap();
+        assert_eq!(
+            posts[..3],
+            [
+                "#lobby alice: hi @carol",
+                "#games alice: hi @carol",
+                "#games alice: hi @carol"
+            ]
+        );
+        assert!(posts[3].starts_with("#lobby alice: Poll #1: Lunch?"));
+        assert_eq!(server.stats.messages.get(), 4);
+        // The sender sees them too, having typed commands rather than the messages
+        assert!(received(&mut alice_client).contains("Poll #1"));
+        assert_eq!(received(&mut bob_client).matches("hi @carol").count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
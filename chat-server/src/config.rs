@@ -0,0 +1,577 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::cidr::Cidr;
+use crate::connections::Caps;
+use crate::duration;
+use crate::history::Retention;
+use crate::mail;
+use crate::names::{self, Class};
+use crate::quota::Limits;
+use crate::ratelimit::{Limit, Rate};
+use crate::rooms;
+use crate::server::Role;
+use crate::sockopt::SocketOptions;
+use crate::webhooks::{Kind, Webhook};
+
+/// How often digests are mailed if the config file doesn't say.
+const DIGEST_EVERY: Duration = Duration::from_secs(3600);
+
+/// The server's config file, in TOML. It declares the permanent rooms, the
+/// accounts' quotas, which usernames may be picked and how fast and how often users
+/// and addresses may do things:
+///
+/// ```toml
+/// [[rooms]]
+/// name = "staff"
+/// topic = "Moderators only"
+/// min_role = "moderator"
+/// retention = "7d"
+///
+/// [[rooms]]
+/// name = "ama"
+/// max_members = 5
+///
+/// [[rooms]]
+/// name = "project-x"
+/// allow = ["alice", "bob"]
+///
+/// [usernames]
+/// min_length = 3
+/// max_length = 20
+/// allow = ["letters", "digits", "_-."]
+/// banned = ["admin", "root"]
+///
+/// [quotas]
+/// offline_lines = 200
+/// messages_per_day = 1000
+/// bytes_per_day = 1000000
+///
+/// [quotas.accounts.newsbot]
+/// messages_per_day = 10000
+///
+/// [rate_limits.messages]
+/// burst = 10
+/// every = "2s"
+///
+/// [connections]
+/// per_ip = 5
+/// allow = ["203.0.113.0/24"]
+///
+/// [sockets]
+/// nodelay = true
+/// keepalive = "2m"
+/// keepalive_interval = "15s"
+/// send_buffer = 65536
+///
+/// [[webhooks]]
+/// url = "http://hooks.internal/chat"
+/// secret = "s3cret"
+/// events = ["mention", "moderation"]
+/// keywords = ["outage", "deploy"]
+///
+/// [email]
+/// relay = "localhost:25"
+/// from = "chat@example.com"
+/// digest_every = "6h"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    rooms: Vec<RawRoom>,
+    #[serde(default)]
+    usernames: RawUsernames,
+    #[serde(default)]
+    quotas: RawQuotas,
+    #[serde(default)]
+    rate_limits: HashMap<String, RawRate>,
+    #[serde(default)]
+    connections: RawConnections,
+    #[serde(default)]
+    sockets: RawSockets,
+    #[serde(default)]
+    webhooks: Vec<RawWebhook>,
+    email: Option<RawEmail>,
+}
+
+/// The username policy as written in the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUsernames {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    allow: Option<Vec<String>>,
+    #[serde(default)]
+    banned: Vec<String>,
+}
+
+/// How mail is sent as written in the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEmail {
+    relay: String,
+    from: String,
+    digest_every: Option<String>,
+}
+
+/// A webhook as written in the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebhook {
+    url: String,
+    secret: Option<String>,
+    events: Vec<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+}
+
+/// The options set on client connections as written in the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSockets {
+    nodelay: Option<bool>,
+    keepalive: Option<String>,
+    keepalive_interval: Option<String>,
+    send_buffer: Option<usize>,
+    receive_buffer: Option<usize>,
+}
+
+/// The caps on each address's connections as written in the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConnections {
+    per_ip: Option<usize>,
+    #[serde(default)]
+    allow: Vec<String>,
+}
+
+/// A rate limit as written in the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRate {
+    burst: u32,
+    every: String,
+}
+
+/// The quotas as written in the config file: those of every account, with
+/// exceptions for some.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQuotas {
+    offline_lines: Option<usize>,
+    messages_per_day: Option<u64>,
+    bytes_per_day: Option<u64>,
+    #[serde(default)]
+    accounts: HashMap<String, RawLimits>,
+}
+
+/// An account's quotas, each falling back on the one for every account.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLimits {
+    offline_lines: Option<usize>,
+    messages_per_day: Option<u64>,
+    bytes_per_day: Option<u64>,
+}
+
+/// The config file, checked.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub rooms: Vec<RoomDef>,
+    pub usernames: names::Policy,
+    /// The quotas of every account without quotas of its own.
+    pub quotas: Limits,
+    pub account_quotas: HashMap<String, Limits>,
+    /// How fast users may do what is rate limited. Anything not in here isn't.
+    pub rate_limits: HashMap<Limit, Rate>,
+    pub connections: Caps,
+    pub sockets: SocketOptions,
+    pub webhooks: Vec<Webhook>,
+    /// How mail is sent, if at all.
+    pub email: Option<mail::Settings>,
+}
+
+/// A room as written in the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoom {
+    name: String,
+    topic: Option<String>,
+    allow: Option<Vec<String>>,
+    min_role: Option<String>,
+    retention: Option<String>,
+    max_members: Option<usize>,
+}
+
+/// A permanent room, checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomDef {
+    pub name: String,
+    pub topic: Option<String>,
+    pub access: Access,
+    /// How long the room keeps its messages, if not as long as `--retention` says.
+    pub retention: Option<Retention>,
+    /// The most members the room may have; spectators don't count.
+    pub max_members: Option<usize>,
+}
+
+/// Who may join a room.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    /// Only these users may join, if set: the room is invite-only.
+    pub allow: Option<HashSet<String>>,
+    /// Users below this role may not join.
+    pub min_role: Option<Role>,
+}
+
+impl Access {
+    pub fn admits(&self, username: &str, role: Role) -> bool {
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.contains(username))
+            && self.min_role.is_none_or(|min_role| role >= min_role)
+    }
+}
+
+/// Reads the config file at `path`.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    parse(&text).map_err(|e| format!("Invalid config in {}: {e}", path.display()))
+}
+
+fn parse(text: &str) -> Result<Config, String> {
+    let config: RawConfig = toml::from_str(text).map_err(|e| e.message().to_string())?;
+    let quotas = Limits {
+        offline_lines: config
+            .quotas
+            .offline_lines
+            .unwrap_or(Limits::default().offline_lines),
+        messages_per_day: config.quotas.messages_per_day,
+        bytes_per_day: config.quotas.bytes_per_day,
+    };
+    let account_quotas = (config.quotas.accounts.into_iter())
+        .map(|(username, limits)| {
+            let limits = Limits {
+                offline_lines: limits.offline_lines.unwrap_or(quotas.offline_lines),
+                messages_per_day: limits.messages_per_day.or(quotas.messages_per_day),
+                bytes_per_day: limits.bytes_per_day.or(quotas.bytes_per_day),
+            };
+            (username, limits)
+        })
+        .collect();
+    let rate_limits = (config.rate_limits.into_iter())
+        .map(|(name, rate)| {
+            let limit: Limit = name.parse()?;
+            if rate.burst == 0 {
+                return Err(format!(
+                    "The burst of the {limit} rate limit must be at least 1"
+                ));
+            }
+            let every = duration::parse(&rate.every)?;
+            Ok((
+                limit,
+                Rate {
+                    burst: rate.burst,
+                    every,
+                },
+            ))
+        })
+        .collect::<Result<_, String>>()?;
+    if config.connections.per_ip == Some(0) {
+        return Err("connections.per_ip must be at least 1".to_string());
+    }
+    let connections = Caps {
+        per_ip: config.connections.per_ip,
+        allow: (config.connections.allow.iter())
+            .map(|cidr| cidr.parse::<Cidr>())
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(Config {
+        rooms: parse_rooms(config.rooms)?,
+        usernames: parse_usernames(config.usernames)?,
+        quotas,
+        account_quotas,
+        rate_limits,
+        connections,
+        sockets: parse_sockets(config.sockets)?,
+        webhooks: parse_webhooks(config.webhooks)?,
+        email: config.email.map(parse_email).transpose()?,
+    })
+}
+
+fn parse_usernames(usernames: RawUsernames) -> Result<names::Policy, String> {
+    let min_length = usernames.min_length.unwrap_or(1);
+    if min_length == 0 {
+        return Err("usernames.min_length must be at least 1".to_string());
+    }
+    if usernames.max_length.is_some_and(|max| max < min_length) {
+        return Err("usernames.max_length must be at least usernames.min_length".to_string());
+    }
+    let allow = (usernames.allow)
+        .map(|classes| classes.iter().map(|class| class.parse::<Class>()).collect())
+        .transpose()?;
+    if allow.as_ref().is_some_and(Vec::is_empty) {
+        return Err("usernames.allow must have at least one character class".to_string());
+    }
+    if usernames.banned.iter().any(|word| word.trim().is_empty()) {
+        return Err("usernames.banned can't have empty words".to_string());
+    }
+    Ok(names::Policy {
+        min_length,
+        max_length: usernames.max_length,
+        allow,
+        banned: usernames.banned,
+    })
+}
+
+fn parse_email(email: RawEmail) -> Result<mail::Settings, String> {
+    mail::validate_address(&email.from)?;
+    let digest_every = match email.digest_every {
+        Some(every) => duration::parse(&every)?,
+        None => DIGEST_EVERY,
+    };
+    if digest_every < Duration::from_secs(60) {
+        return Err("email.digest_every must be at least a minute".to_string());
+    }
+    Ok(mail::Settings {
+        relay: email.relay,
+        from: email.from,
+        digest_every,
+    })
+}
+
+fn parse_webhooks(webhooks: Vec<RawWebhook>) -> Result<Vec<Webhook>, String> {
+    (webhooks.into_iter())
+        .map(|webhook| {
+            let events = (webhook.events.iter())
+                .map(|event| event.parse())
+                .collect::<Result<Vec<Kind>, _>>()?;
+            if events.is_empty() {
+                return Err(format!("The webhook {} has no events", webhook.url));
+            }
+            if events.contains(&Kind::Mention) == webhook.keywords.is_empty() {
+                return Err(format!(
+                    "The webhook {} needs keywords for mention events, and only them",
+                    webhook.url
+                ));
+            }
+            Ok(Webhook {
+                url: webhook.url.parse()?,
+                secret: webhook.secret,
+                events,
+                keywords: webhook.keywords,
+            })
+        })
+        .collect()
+}
+
+fn parse_sockets(sockets: RawSockets) -> Result<SocketOptions, String> {
+    if sockets.keepalive_interval.is_some() && sockets.keepalive.is_none() {
+        return Err("sockets.keepalive_interval needs sockets.keepalive".to_string());
+    }
+    if sockets.send_buffer == Some(0) || sockets.receive_buffer == Some(0) {
+        return Err("The socket buffers must be at least 1 byte".to_string());
+    }
+    Ok(SocketOptions {
+        nodelay: sockets.nodelay,
+        keepalive: sockets
+            .keepalive
+            .as_deref()
+            .map(duration::parse)
+            .transpose()?,
+        keepalive_interval: (sockets.keepalive_interval.as_deref())
+            .map(duration::parse)
+            .transpose()?,
+        send_buffer: sockets.send_buffer,
+        receive_buffer: sockets.receive_buffer,
+    })
+}
+
+fn parse_rooms(rooms: Vec<RawRoom>) -> Result<Vec<RoomDef>, String> {
+    let mut names = HashSet::new();
+    rooms
+        .into_iter()
+        .map(|room| {
+            let name = rooms::parse_name(&room.name)?.to_string();
+            if !names.insert(name.clone()) {
+                return Err(format!("#{name} is declared twice"));
+            }
+            Ok(RoomDef {
+                topic: room.topic,
+                access: Access {
+                    allow: room.allow.map(HashSet::from_iter),
+                    min_role: room.min_role.as_deref().map(str::parse).transpose()?,
+                },
+                retention: room.retention.as_deref().map(str::parse).transpose()?,
+                max_members: room.max_members,
+                name,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rooms() {
+        let rooms = parse(
+            r##"
+            [[rooms]]
+            name = "#staff"
+            topic = "Moderators only"
+            min_role = "moderator"
+            retention = "7d"
+
+            [[rooms]]
+            name = "project-x"
+            allow = ["alice"]
+            max_members = 3
+            "##,
+        )
+        .unwrap()
+        .rooms;
+        assert_eq!(rooms[0].name, "staff");
+        assert_eq!(
+            rooms[0].retention,
+            Some(Retention::For(Duration::from_secs(7 * 86400)))
+        );
+        assert!(!rooms[0].access.admits("alice", Role::User));
+        assert!(rooms[0].access.admits("alice", Role::Admin));
+        assert!(rooms[1].access.admits("alice", Role::User));
+        assert_eq!(
+            (rooms[0].max_members, rooms[1].max_members),
+            (None, Some(3))
+        );
+        assert!(!rooms[1].access.admits("bob", Role::Admin));
+
+        assert!(parse("[[rooms]]\nname = \"a b\"").is_err());
+        assert!(parse("[[rooms]]\nname = \"a\"\n[[rooms]]\nname = \"#a\"").is_err());
+        assert!(parse("[[rooms]]\nname = \"a\"\nmin_role = \"king\"").is_err());
+        assert!(parse("[[rooms]]\nname = \"a\"\ncolour = \"red\"").is_err());
+        assert_eq!(parse(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn test_parse_quotas() {
+        let config = parse(
+            r#"
+            [quotas]
+            messages_per_day = 100
+
+            [quotas.accounts.newsbot]
+            offline_lines = 10
+            bytes_per_day = 5000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.quotas.messages_per_day, Some(100));
+        assert_eq!(config.quotas.offline_lines, Limits::default().offline_lines);
+        assert_eq!(
+            config.account_quotas["newsbot"],
+            Limits {
+                offline_lines: 10,
+                messages_per_day: Some(100),
+                bytes_per_day: Some(5000)
+            }
+        );
+        assert!(parse("[quotas]\nfile_transfers = 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let config = parse("[rate_limits.joins]\nburst = 3\nevery = \"1m\"").unwrap();
+        assert_eq!(
+            config.rate_limits[&Limit::Joins],
+            Rate {
+                burst: 3,
+                every: Duration::from_secs(60)
+            }
+        );
+        assert!(parse("[rate_limits.file_chunks]\nburst = 3\nevery = \"1s\"").is_err());
+        assert!(parse("[rate_limits.joins]\nburst = 0\nevery = \"1s\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_connections() {
+        let config = parse("[connections]\nper_ip = 3\nallow = [\"10.0.0.0/8\"]").unwrap();
+        assert_eq!(config.connections.per_ip, Some(3));
+        assert!(config.connections.allow[0].contains("10.9.8.7".parse().unwrap()));
+        assert!(parse("[connections]\nper_ip = 0").is_err());
+        assert!(parse("[connections]\nallow = [\"10.0.0.0/40\"]").is_err());
+    }
+
+    #[test]
+    fn test_parse_sockets() {
+        let config = parse("[sockets]\nnodelay = true\nkeepalive = \"2m\"").unwrap();
+        assert_eq!(
+            config.sockets,
+            SocketOptions {
+                nodelay: Some(true),
+                keepalive: Some(Duration::from_secs(120)),
+                ..SocketOptions::default()
+            }
+        );
+        assert!(parse("[sockets]\nkeepalive_interval = \"10s\"").is_err());
+        assert!(parse("[sockets]\nsend_buffer = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_usernames() {
+        assert_eq!(parse("").unwrap().usernames, names::Policy::default());
+        let config =
+            parse("[usernames]\nmin_length = 3\nallow = [\"latin\", \"_\"]\nbanned = [\"root\"]")
+                .unwrap();
+        assert_eq!(
+            config.usernames,
+            names::Policy {
+                min_length: 3,
+                max_length: None,
+                allow: Some(vec![Class::Latin, Class::Chars("_".to_string())]),
+                banned: vec!["root".to_string()],
+            }
+        );
+        assert!(parse("[usernames]\nmin_length = 0").is_err());
+        assert!(parse("[usernames]\nmin_length = 5\nmax_length = 4").is_err());
+        assert!(parse("[usernames]\nallow = []").is_err());
+        assert!(parse("[usernames]\nallow = [\"\"]").is_err());
+    }
+
+    #[test]
+    fn test_parse_email() {
+        let config =
+            parse("[email]\nrelay = \"localhost:25\"\nfrom = \"chat@example.com\"").unwrap();
+        assert_eq!(config.email.unwrap().digest_every, DIGEST_EVERY);
+        assert!(parse("[email]\nrelay = \"localhost:25\"\nfrom = \"chat\"").is_err());
+        assert!(parse(
+            "[email]\nrelay = \"localhost:25\"\nfrom = \"chat@example.com\"\ndigest_every = \"10s\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_webhooks() {
+        let config = parse(
+            "[[webhooks]]\nurl = \"http://hooks.local:8080/chat\"\nevents = [\"join\", \"mention\"]\nkeywords = [\"deploy\"]",
+        )
+        .unwrap();
+        assert_eq!(
+            config.webhooks[0].url.to_string(),
+            "http://hooks.local:8080/chat"
+        );
+        assert_eq!(config.webhooks[0].events, [Kind::Join, Kind::Mention]);
+        assert!(parse("[[webhooks]]\nurl = \"https://hooks.local\"\nevents = [\"join\"]").is_err());
+        assert!(
+            parse("[[webhooks]]\nurl = \"http://hooks.local\"\nevents = [\"mention\"]").is_err()
+        );
+        assert!(
+            parse("[[webhooks]]\nurl = \"http://hooks.local\"\nevents = [\"typing\"]").is_err()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::cidr::Cidr;
+
+/// How many connections one address may hold open at a time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Caps {
+    /// The most connections an address may hold open, if they are capped.
+    pub per_ip: Option<usize>,
+    /// Addresses that aren't capped, e.g. offices where everyone is behind one NAT.
+    pub allow: Vec<Cidr>,
+}
+
+/// Counts the connections each address holds open, to turn away those over the cap
+/// before any handshake work is done for them.
+#[derive(Default)]
+pub struct Connections {
+    caps: Caps,
+    open: HashMap<IpAddr, usize>,
+}
+
+impl Connections {
+    /// Sets the caps, e.g. from the config file. Connections already open stay open.
+    pub fn configure(&mut self, caps: Caps) {
+        self.caps = caps;
+    }
+
+    /// Counts a new connection from `ip`, unless the address holds as many open as it
+    /// may already.
+    pub fn open(&mut self, ip: IpAddr) -> bool {
+        let open = self.open.entry(ip).or_default();
+        let capped = self.caps.per_ip.is_some_and(|per_ip| *open >= per_ip)
+            && !self.caps.allow.iter().any(|cidr| cidr.contains(ip));
+        if capped {
+            return false;
+        }
+        *open += 1;
+        true
+    }
+
+    /// Counts a connection from `ip` that closed.
+    pub fn close(&mut self, ip: IpAddr) {
+        if let Some(open) = self.open.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                self.open.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps_per_address() {
+        let mut connections = Connections::default();
+        connections.configure(Caps {
+            per_ip: Some(2),
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+        });
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(connections.open(ip));
+        assert!(connections.open(ip));
+        assert!(!connections.open(ip));
+        assert!(connections.open("198.51.100.2".parse().unwrap()));
+        connections.close(ip);
+        assert!(connections.open(ip));
+
+        let office: IpAddr = "10.1.2.3".parse().unwrap();
+        for _ in 0..5 {
+            assert!(connections.open(office));
+        }
+    }
+}
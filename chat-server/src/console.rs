@@ -0,0 +1,31 @@
+use std::io::{self, BufRead};
+use std::sync::Arc;
+use std::thread;
+
+use crate::commands::{self, Actor, Context};
+use crate::server::{Role, Server};
+
+/// Spawns the admin console: a thread reading `/commands` from the server's stdin
+/// and running them with admin privileges, e.g. `/announce Restarting in 5 minutes`.
+pub fn spawn(server: Arc<Server>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with('/') {
+                println!("Console input must be a /command. Type /help for a list of commands");
+                continue;
+            }
+            let ctx = Context {
+                server: &server,
+                actor: Actor::Console,
+                role: Role::Admin,
+            };
+            // There is no connection to leave, so `/leave` is a no-op here
+            let _ = commands::dispatch(&ctx, line);
+        }
+    });
+}
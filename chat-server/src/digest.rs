@@ -0,0 +1,125 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::SystemTime;
+
+/// The most messages kept for one user's next digest; older ones make way.
+const MAX_ITEMS: usize = 100;
+
+/// A message a user missed while offline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Missed {
+    pub at: SystemTime,
+    pub from: String,
+    /// The room it was said in, for a mention, or `None` for a private message.
+    pub room: Option<String>,
+    pub text: String,
+}
+
+/// What users who want digests missed since their last one.
+#[derive(Default)]
+pub struct Digests {
+    pending: HashMap<String, Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    missed: Vec<Missed>,
+    /// How many older messages made way for newer ones.
+    dropped: usize,
+}
+
+impl Digests {
+    pub fn add(&mut self, username: &str, missed: Missed) {
+        let pending = self.pending.entry(username.to_string()).or_default();
+        if pending.missed.len() == MAX_ITEMS {
+            pending.missed.remove(0);
+            pending.dropped += 1;
+        }
+        pending.missed.push(missed);
+    }
+
+    /// Takes every pending digest, as the text of its mail, by username.
+    pub fn take(&mut self) -> Vec<(String, String)> {
+        (self.pending.drain())
+            .map(|(username, pending)| {
+                let body = compose(&username, &pending);
+                (username, body)
+            })
+            .collect()
+    }
+
+    /// Forgets what `username` missed, e.g. when they stop wanting digests.
+    pub fn clear(&mut self, username: &str) {
+        self.pending.remove(username);
+    }
+
+    /// How many messages wait for `username`'s next digest.
+    pub fn len(&self, username: &str) -> usize {
+        self.pending.get(username).map_or(0, |p| p.missed.len())
+    }
+}
+
+fn compose(username: &str, pending: &Pending) -> String {
+    let mut body = format!(
+        "Hi {username},\n\nhere is what you missed on the chat server while you were away:\n\n"
+    );
+    if pending.dropped > 0 {
+        let _ = writeln!(body, "({} older message(s) left out)\n", pending.dropped);
+    }
+    for missed in &pending.missed {
+        let at = DateTime::<Utc>::from(missed.at).format("%Y-%m-%d %H:%M UTC");
+        let _ = match &missed.room {
+            Some(room) => writeln!(body, "{at}  #{room}  {}: {}", missed.from, missed.text),
+            None => writeln!(body, "{at}  {} (private): {}", missed.from, missed.text),
+        };
+    }
+    body.push_str("\nTurn these mails off with /digest off.\n");
+    body
+}
+
+/// The users `@mentioned` in a room message, e.g. `bob` in `ping @bob, lunch?`.
+pub fn mentions(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = (text.split_whitespace())
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_mentions() {
+        assert_eq!(mentions("ping @bob, and @alice: @bob!"), ["alice", "bob"]);
+        assert!(mentions("mail me at bob@example.com").is_empty());
+    }
+
+    #[test]
+    fn test_digest_keeps_the_newest() {
+        let mut digests = Digests::default();
+        for i in 0..MAX_ITEMS + 2 {
+            let missed = Missed {
+                at: UNIX_EPOCH + Duration::from_secs(60 * i as u64),
+                from: "alice".to_string(),
+                room: (i % 2 == 0).then(|| "lobby".to_string()),
+                text: format!("message {i}"),
+            };
+            digests.add("bob", missed);
+        }
+        assert_eq!(digests.len("bob"), MAX_ITEMS);
+        let taken = digests.take();
+        assert_eq!(digests.len("bob"), 0);
+        let (username, body) = &taken[0];
+        assert_eq!(username, "bob");
+        assert!(body.contains("(2 older message(s) left out)"));
+        assert!(!body.contains("message 1\n"));
+        assert!(body.contains("1970-01-01 01:41 UTC  alice (private): message 101"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+
+/// UDP port servers listen on for discovery probes.
+pub const DISCOVERY_PORT: u16 = 12346;
+/// Sent by clients looking for servers.
+const PROBE: &[u8] = b"SIMPLE-CHAT?";
+
+/// The beacon sent back to a probe: `SIMPLE-CHAT <port> <name>`. The client takes the
+/// server's address from the datagram it arrives in.
+fn beacon(name: &str, port: u16) -> String {
+    format!("SIMPLE-CHAT {port} {name}")
+}
+
+/// Answers discovery probes from clients on the LAN, so they can find this server
+/// without anyone typing its address. `port` is the port chat clients connect to.
+pub fn advertise(name: &str, port: u16) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT))?;
+    let beacon = beacon(name, port);
+    thread::spawn(move || respond(&socket, &beacon));
+    Ok(())
+}
+
+/// DNS-SD service type the server registers under.
+#[cfg(feature = "mdns")]
+const SERVICE_TYPE: &str = "_simple-chat._tcp.local.";
+
+/// Advertises the server over mDNS as well, for clients that browse with
+/// Bonjour/Avahi. The returned daemon keeps answering queries in the background.
+#[cfg(feature = "mdns")]
+pub fn register_mdns(name: &str, port: u16) -> io::Result<mdns_sd::ServiceDaemon> {
+    let daemon = mdns_sd::ServiceDaemon::new().map_err(io::Error::other)?;
+    // Host names can't contain spaces, and the port keeps several servers apart
+    let host: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let info = mdns_sd::ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &format!("{host}-{port}.local."),
+        "",
+        port,
+        &[] as &[(&str, &str)],
+    )
+    .map_err(io::Error::other)?
+    .enable_addr_auto();
+    daemon.register(info).map_err(io::Error::other)?;
+    Ok(daemon)
+}
+
+fn respond(socket: &UdpSocket, beacon: &str) {
+    let mut buf = [0; 64];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) if &buf[..len] == PROBE => {
+                let _ = socket.send_to(beacon.as_bytes(), from);
+            }
+            // Anything else on the port isn't for us
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Discovery stopped: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_probe_gets_beacon() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || respond(&server, &beacon("Lounge", 12345)));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.send_to(b"hello", address).unwrap();
+        client.send_to(PROBE, address).unwrap();
+        let mut buf = [0; 64];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, address);
+        assert_eq!(&buf[..len], b"SIMPLE-CHAT 12345 Lounge");
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::{Duration, SystemTime};
+
+/// The longest duration [`parse`] accepts, ten years: longer ones may not fit in an
+/// `Instant` once added to the current time.
+pub const MAX: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// Parses a duration such as `30s`, `10m`, `2h` or `1d`. A bare number is in seconds.
+/// Durations over [`MAX`] are an error.
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration: {s}"))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "Invalid duration: {s} (use e.g. 30s, 10m, 2h or 1d)"
+            ))
+        }
+    };
+    match value.checked_mul(multiplier).map(Duration::from_secs) {
+        Some(duration) if duration <= MAX => Ok(duration),
+        _ => Err(format!("Duration too long: {s} (at most {})", format(MAX))),
+    }
+}
+
+/// Formats a duration as days, hours, minutes and seconds, e.g. `1h30m`. Sub-second
+/// precision is rounded up so a pending deadline never shows as `0s`.
+pub fn format(d: Duration) -> String {
+    let mut secs = d.as_secs().saturating_add(u64::from(d.subsec_nanos() > 0));
+    let mut out = String::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60)] {
+        if secs >= size {
+            out.push_str(&format!("{}{unit}", secs / size));
+            secs %= size;
+        }
+    }
+    if secs > 0 || out.is_empty() {
+        out.push_str(&format!("{secs}s"));
+    }
+    out
+}
+
+/// Formats how long ago `at` was, e.g. `5m ago`.
+pub fn ago(at: SystemTime) -> String {
+    format!("{} ago", format(at.elapsed().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse("soon").is_err());
+        assert!(parse("5y").is_err());
+        assert_eq!(parse("3650d"), Ok(MAX));
+        assert!(parse("3651d").is_err());
+        assert!(parse("18446744073709551615").is_err());
+        assert!(parse("18446744073709551615d").is_err());
+    }
+
+    #[test]
+    fn test_format_roundtrip() {
+        assert_eq!(format(Duration::from_secs(5400)), "1h30m");
+        assert_eq!(format(Duration::from_millis(1500)), "2s");
+        assert_eq!(format(Duration::ZERO), "0s");
+    }
+}
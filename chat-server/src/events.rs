@@ -0,0 +1,151 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::audit::Action;
+use crate::duration;
+
+/// Something that happened on the server, for whoever observes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A user joined, with a new session. `sessions` counts their devices.
+    UserJoined { username: &'a str, sessions: usize },
+    /// A client took back its session after losing its connection.
+    SessionResumed { username: &'a str },
+    /// A user's client lost its connection. The session is kept for `grace`.
+    ConnectionLost { username: &'a str, grace: Duration },
+    /// A session that wasn't resumed in time ended.
+    SessionExpired { username: &'a str },
+    /// A user ended one of their sessions.
+    UserLeft { username: &'a str },
+    /// A room message went out to the room, numbered `seq`.
+    MessageBroadcast {
+        room: &'a str,
+        from: &'a str,
+        seq: u64,
+        text: &'a str,
+    },
+    /// A private message or whisper was delivered.
+    PrivateMessage {
+        from: &'a str,
+        to: &'a str,
+        whisper: bool,
+    },
+    /// A user reported another to the moderators.
+    ReportFiled {
+        id: u64,
+        user: &'a str,
+        by: &'a str,
+        reason: &'a str,
+    },
+    /// A moderator or admin did something privileged, e.g. kicked or banned someone,
+    /// as recorded in the audit log.
+    Moderated {
+        action: Action,
+        by: &'a str,
+        target: Option<&'a str>,
+        detail: &'a str,
+        reason: &'a str,
+    },
+}
+
+/// Observes the server's events, e.g. to log them or pass them on. Sinks are called
+/// on the thread the event happened on, possibly with the server's locks held, so
+/// they must be quick and must not call back into the server: slow work, such as
+/// network requests, belongs on a thread of the sink's own.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event<'_>);
+}
+
+/// A sink that is also reached from elsewhere, e.g. to configure it.
+impl<T: EventSink> EventSink for Arc<T> {
+    fn event(&self, event: &Event<'_>) {
+        (**self).event(event);
+    }
+}
+
+/// The sinks events go to, in the order they were registered.
+#[derive(Default)]
+pub struct Events {
+    sinks: RwLock<Vec<Box<dyn EventSink>>>,
+}
+
+impl Events {
+    pub fn register(&self, sink: impl EventSink + 'static) {
+        self.sinks.write().unwrap().push(Box::new(sink));
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        for sink in self.sinks.read().unwrap().iter() {
+            sink.event(&event);
+        }
+    }
+}
+
+/// Logs the comings and goings of users, and reports, to stdout.
+pub struct Log;
+
+impl EventSink for Log {
+    fn event(&self, event: &Event<'_>) {
+        match *event {
+            Event::UserJoined {
+                username,
+                sessions: 1,
+            } => println!("User {username} has joined"),
+            Event::UserJoined { username, sessions } => {
+                println!("User {username} has joined from another device ({sessions} sessions)")
+            }
+            Event::SessionResumed { username } => {
+                println!("User {username} resumed their session")
+            }
+            Event::ConnectionLost { username, grace } => println!(
+                "User {username} lost their connection, keeping their session for {}",
+                duration::format(grace)
+            ),
+            Event::SessionExpired { username } => println!("Session of {username} expired"),
+            Event::UserLeft { username } => println!("User {username} has left"),
+            Event::ReportFiled {
+                id,
+                user,
+                by,
+                reason,
+            } => println!("New report #{id} against {user} by {by}: {reason}"),
+            // Messages aren't logged, and moderation is in the audit log
+            Event::MessageBroadcast { .. }
+            | Event::PrivateMessage { .. }
+            | Event::Moderated { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl EventSink for Recorder {
+        fn event(&self, event: &Event<'_>) {
+            self.0.lock().unwrap().push(format!("{event:?}"));
+        }
+    }
+
+    #[test]
+    fn test_every_sink_gets_every_event() {
+        let events = Events::default();
+        let (first, second) = (Arc::default(), Arc::default());
+        events.register(Recorder(Arc::clone(&first)));
+        events.register(Recorder(Arc::clone(&second)));
+        events.emit(Event::UserLeft { username: "alice" });
+        events.emit(Event::SessionExpired { username: "bob" });
+        for recorded in [first, second] {
+            assert_eq!(
+                *recorded.lock().unwrap(),
+                [
+                    "UserLeft { username: \"alice\" }",
+                    "SessionExpired { username: \"bob\" }"
+                ]
+            );
+        }
+    }
+}
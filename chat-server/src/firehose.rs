@@ -0,0 +1,107 @@
+use serde_json::json;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::{Event, EventSink};
+
+/// The most streams open at once, each holding a thread.
+const MAX_STREAMS: usize = 64;
+/// Messages waiting to be written to a stream; a stream that falls this far behind
+/// is closed, and its client reconnects with `Last-Event-ID` to catch up.
+const QUEUE: usize = 256;
+
+/// Streams rooms' messages, as Server-Sent Events, to read-only clients of the HTTP
+/// API such as dashboards.
+#[derive(Default)]
+pub struct Firehose {
+    streams: Mutex<Vec<Stream>>,
+}
+
+struct Stream {
+    room: String,
+    events: SyncSender<String>,
+}
+
+impl Firehose {
+    /// Starts streaming `room`'s messages, as SSE events ready to write. Returns
+    /// `None` if there are as many streams as there may be.
+    pub fn subscribe(&self, room: &str) -> Option<Receiver<String>> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() >= MAX_STREAMS {
+            return None;
+        }
+        let (events, receiver) = mpsc::sync_channel(QUEUE);
+        streams.push(Stream {
+            room: room.to_string(),
+            events,
+        });
+        Some(receiver)
+    }
+}
+
+impl EventSink for Firehose {
+    fn event(&self, event: &Event<'_>) {
+        let Event::MessageBroadcast {
+            room,
+            from,
+            seq,
+            text,
+        } = *event
+        else {
+            return;
+        };
+        let mut streams = self.streams.lock().unwrap();
+        if !streams.iter().any(|stream| stream.room == room) {
+            return;
+        }
+        let message = message(room, from, seq, text, SystemTime::now());
+        // Streams whose client went away, or fell behind, are dropped
+        streams.retain(|stream| {
+            stream.room != room || stream.events.try_send(message.clone()).is_ok()
+        });
+    }
+}
+
+/// A room message as an SSE event, numbered with its sequence number so a client
+/// that reconnects can say where it left off.
+pub fn message(room: &str, from: &str, seq: u64, text: &str, at: SystemTime) -> String {
+    let at = at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let data = json!({"room": room, "from": from, "seq": seq, "text": text, "at": at});
+    format!("id: {seq}\nevent: message\ndata: {data}\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_reach_the_room_streams() {
+        let firehose = Firehose::default();
+        let lobby = firehose.subscribe("lobby").unwrap();
+        let support = firehose.subscribe("support").unwrap();
+        firehose.event(&Event::MessageBroadcast {
+            room: "support",
+            from: "alice",
+            seq: 3,
+            text: "printer is on fire",
+        });
+        let event = support.try_recv().unwrap();
+        assert!(event.starts_with("id: 3\nevent: message\ndata: {"));
+        assert!(event.contains("\"text\":\"printer is on fire\""));
+        assert!(event.ends_with("}\n\n"));
+        assert!(lobby.try_recv().is_err());
+
+        // A stream whose client went away is dropped with the next message
+        drop(support);
+        firehose.event(&Event::MessageBroadcast {
+            room: "support",
+            from: "alice",
+            seq: 4,
+            text: "never mind",
+        });
+        assert_eq!(firehose.streams.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Window over which connections and failed handshakes are counted.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Connections an address may open within [`WINDOW`].
+const MAX_CONNECTS: usize = 10;
+/// Failed username handshakes an address may make within [`WINDOW`].
+const MAX_FAILURES: usize = 5;
+/// How long an address is refused once it goes over either limit.
+const BLOCK_FOR: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default)]
+struct Address {
+    connects: VecDeque<Instant>,
+    failures: VecDeque<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+impl Address {
+    fn prune(&mut self, now: Instant) {
+        for events in [&mut self.connects, &mut self.failures] {
+            while events
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+            {
+                events.pop_front();
+            }
+        }
+        if self.blocked_until.is_some_and(|until| until <= now) {
+            self.blocked_until = None;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.connects.is_empty() && self.failures.is_empty() && self.blocked_until.is_none()
+    }
+}
+
+/// Temporarily refuses addresses that connect too often or keep failing the
+/// username handshake, e.g. bots cycling connections.
+///
+/// This is independent of what users do once they have joined.
+#[derive(Default)]
+pub struct FloodGuard {
+    addresses: HashMap<IpAddr, Address>,
+}
+
+impl FloodGuard {
+    /// Records a new connection from `ip`. Returns how long the address remains
+    /// blocked if the connection should be refused.
+    pub fn connect(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        // Forget addresses that have been quiet for a while, so the map doesn't grow forever
+        self.addresses.retain(|_, address| {
+            address.prune(now);
+            !address.is_idle()
+        });
+
+        let address = self.addresses.entry(ip).or_default();
+        if let Some(until) = address.blocked_until {
+            return Err(until - now);
+        }
+        address.connects.push_back(now);
+        if address.connects.len() > MAX_CONNECTS {
+            return Err(Self::block(address, now));
+        }
+        Ok(())
+    }
+
+    /// Records a failed username handshake from `ip`. Returns how long the address
+    /// is blocked for if it has now failed too often.
+    pub fn handshake_failed(&mut self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let address = self.addresses.entry(ip).or_default();
+        address.failures.push_back(now);
+        if address.failures.len() > MAX_FAILURES {
+            return Err(Self::block(address, now));
+        }
+        Ok(())
+    }
+
+    fn block(address: &mut Address, now: Instant) -> Duration {
+        address.blocked_until = Some(now + BLOCK_FOR);
+        address.connects.clear();
+        address.failures.clear();
+        BLOCK_FOR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_blocks_flooding_address() {
+        let mut guard = FloodGuard::default();
+        let flooder = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        for _ in 0..MAX_CONNECTS {
+            assert!(guard.connect(flooder, start).is_ok());
+        }
+        assert_eq!(guard.connect(flooder, start), Err(BLOCK_FOR));
+        assert!(guard.connect(flooder, start + WINDOW).is_err());
+        assert!(guard.connect(other, start).is_ok());
+
+        // The block lifts on its own
+        assert!(guard.connect(flooder, start + BLOCK_FOR).is_ok());
+    }
+
+    #[test]
+    fn test_blocks_repeated_failed_handshakes() {
+        let mut guard = FloodGuard::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = Instant::now();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(guard.handshake_failed(ip, start).is_ok());
+        }
+        assert!(guard.handshake_failed(ip, start).is_err());
+        assert!(guard.connect(ip, start).is_err());
+    }
+}
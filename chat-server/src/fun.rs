@@ -0,0 +1,170 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::commands::{self, Actor, Command, Context, Flow};
+use crate::ratelimit::Limit;
+use crate::server::Role;
+
+/// Most dice rolled at once.
+const MAX_DICE: u64 = 20;
+/// Most sides a die may have.
+const MAX_SIDES: u64 = 1000;
+
+const ANSWERS: &[&str] = &[
+    "It is certain",
+    "Without a doubt",
+    "Yes, definitely",
+    "Most likely",
+    "Outlook good",
+    "Signs point to yes",
+    "Reply hazy, try again",
+    "Ask again later",
+    "Cannot predict now",
+    "Don't count on it",
+    "My sources say no",
+    "Very doubtful",
+];
+
+/// A pack of commands for fun, built in with the `fun` feature: dice, coins and a
+/// magic 8-ball. Listed in `commands::PACKS`, they are run like the server's own
+/// commands, and show how to add a pack.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "roll",
+        usage: "/roll [NdM]",
+        summary: "Roll N dice with M sides for your room to see, one six-sided die by default",
+        role: Role::User,
+        handler: roll,
+    },
+    Command {
+        name: "flip",
+        usage: "/flip",
+        summary: "Flip a coin for your room to see",
+        role: Role::User,
+        handler: flip,
+    },
+    Command {
+        name: "8ball",
+        usage: "/8ball <question>",
+        summary: "Ask the magic 8-ball, in front of your room",
+        role: Role::User,
+        handler: eight_ball,
+    },
+];
+
+fn roll(ctx: &Context, args: &str) -> Flow {
+    let Some((dice, sides)) = parse_dice(args) else {
+        ctx.reply(&format!(
+            "Usage: /roll [NdM], with up to {MAX_DICE} dice of 2 to {MAX_SIDES} sides"
+        ));
+        return Flow::Continue;
+    };
+    let rolls: Vec<u64> = (0..dice).map(|_| random(sides) + 1).collect();
+    let total: u64 = rolls.iter().sum();
+    let line = match rolls.as_slice() {
+        [roll] => format!("{} rolled {dice}d{sides}: {roll}", ctx.name()),
+        _ => {
+            let rolls: Vec<String> = rolls.iter().map(u64::to_string).collect();
+            let rolls = rolls.join(" + ");
+            format!("{} rolled {dice}d{sides}: {rolls} = {total}", ctx.name())
+        }
+    };
+    tell_room(ctx, &line);
+    Flow::Continue
+}
+
+fn flip(ctx: &Context, _args: &str) -> Flow {
+    let side = if random(2) == 0 { "heads" } else { "tails" };
+    tell_room(ctx, &format!("{} flipped a coin: {side}", ctx.name()));
+    Flow::Continue
+}
+
+fn eight_ball(ctx: &Context, args: &str) -> Flow {
+    if args.is_empty() {
+        ctx.reply("Usage: /8ball <question>");
+        return Flow::Continue;
+    }
+    let answer = ANSWERS[random(ANSWERS.len() as u64) as usize];
+    let line = format!("{} asked the magic 8-ball \"{args}\": {answer}", ctx.name());
+    tell_room(ctx, &line);
+    Flow::Continue
+}
+
+/// Reads `NdM`, `dM` or nothing, for one six-sided die.
+fn parse_dice(args: &str) -> Option<(u64, u64)> {
+    if args.is_empty() {
+        return Some((1, 6));
+    }
+    let (dice, sides) = args.to_lowercase().split_once('d').map(|(dice, sides)| {
+        let dice = if dice.is_empty() {
+            Some(1)
+        } else {
+            dice.parse().ok()
+        };
+        (dice, sides.parse().ok())
+    })?;
+    let (dice, sides) = (dice?, sides?);
+    ((1..=MAX_DICE).contains(&dice) && (2..=MAX_SIDES).contains(&sides)).then_some((dice, sides))
+}
+
+/// Shows `line` to everyone in the actor's room, as long as they may talk there:
+/// it counts against their rate limit like a message.
+fn tell_room(ctx: &Context, line: &str) {
+    let Actor::User(username, _) = ctx.actor else {
+        ctx.reply(line);
+        return;
+    };
+    if let Some(remaining) = ctx.server.muted_for(username) {
+        ctx.reply(&commands::muted_notice(remaining));
+        return;
+    }
+    let rooms = ctx.server.rooms.lock().unwrap();
+    let Some((name, room)) =
+        (rooms.room_of(username)).and_then(|name| Some((name, rooms.get(name)?)))
+    else {
+        return;
+    };
+    if let Some(remaining) = room.muted_for(username) {
+        ctx.reply(&commands::room_muted_notice(remaining));
+        return;
+    }
+    if room.is_spectator(username) {
+        ctx.reply(&commands::spectating_notice(name));
+        return;
+    }
+    if let Err(wait) = ctx.server.throttle(Limit::Messages, username) {
+        ctx.reply(&commands::throttled(Limit::Messages, wait, None));
+        return;
+    }
+    for member in room.members() {
+        ctx.server.send_to(member, line);
+    }
+}
+
+/// A random number below `n`, good enough for games but not for secrets.
+fn random(n: u64) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+    hasher.finish() % n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dice() {
+        assert_eq!(parse_dice(""), Some((1, 6)));
+        assert_eq!(parse_dice("2d6"), Some((2, 6)));
+        assert_eq!(parse_dice("D20"), Some((1, 20)));
+        for bad in ["0d6", "21d6", "2d1", "2d", "d", "two"] {
+            assert_eq!(parse_dice(bad), None, "{bad}");
+        }
+        assert!((0..100).all(|_| random(6) < 6));
+    }
+}
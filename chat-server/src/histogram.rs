@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Buckets of a histogram: bucket `i` counts durations of under 2^i microseconds
+/// (and at least half that), and the last one everything longer.
+const BUCKETS: usize = 32;
+
+/// A histogram of durations in buckets that double in size, recorded without taking
+/// any lock. Percentiles come out as the upper bound of their bucket, so they are at
+/// most twice the real value.
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    /// The longest duration recorded, in microseconds.
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// How many durations were recorded.
+    pub fn count(&self) -> u64 {
+        (self.buckets.iter())
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The duration that `percent` of those recorded took at most, or `None` if none
+    /// were.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * percent / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                let bound = Duration::from_micros(1 << i);
+                return Some(bound.min(self.max()));
+            }
+        }
+        Some(self.max())
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max.load(Ordering::Relaxed))
+    }
+}
+
+/// Formats a short duration for `/stats`, e.g. `850µs`, `1.2ms` or `3.0s`.
+pub fn format(d: Duration) -> String {
+    let micros = d.as_micros();
+    if micros < 1000 {
+        format!("{micros}µs")
+    } else if micros < 1_000_000 {
+        format!("{:.1}ms", micros as f64 / 1000.0)
+    } else {
+        format!("{:.1}s", d.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(30));
+        }
+        assert_eq!(histogram.count(), 100);
+        // 100µs falls in the bucket up to 128µs
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(128)));
+        assert_eq!(histogram.percentile(90.0), Some(Duration::from_micros(128)));
+        // ... and the slowest tenth are capped at the longest seen
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_millis(30)));
+        assert_eq!(format(histogram.max()), "30.0ms");
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::duration;
+use crate::spans::Spans;
+use crate::tags::Tags;
+
+/// How long the room keeps its messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// Until the history is full.
+    #[default]
+    Forever,
+    /// Messages older than this are deleted, even if there is room for them.
+    For(Duration),
+    /// Messages are passed on but never stored, so they can't be fetched again.
+    Ephemeral,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    /// Parses `forever`, `ephemeral` or a duration such as `7d`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forever" => Ok(Retention::Forever),
+            "ephemeral" => Ok(Retention::Ephemeral),
+            _ => duration::parse(s).map(Retention::For),
+        }
+    }
+}
+
+/// Longest excerpt of a message a reply quotes, in characters.
+const MAX_EXCERPT: usize = 60;
+
+/// How a message came about, if it wasn't simply sent to the room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+    /// Sent to several rooms at once with `/broadcast`, named here.
+    Broadcast(Vec<String>),
+    /// Forwarded with `/forward` from a message `author` sent to `room`.
+    Forward { room: String, author: String },
+    /// A reply with `/quote` to message `seq`, which `author` sent, quoting an
+    /// excerpt of it.
+    Quote {
+        seq: u64,
+        author: String,
+        excerpt: String,
+    },
+    /// An action sent with `/me`, what the sender is doing.
+    Action,
+}
+
+/// A chat message as broadcast to the room.
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// The message's place in the room, counting up from 1 without gaps.
+    pub seq: u64,
+    pub at: SystemTime,
+    pub from: Arc<String>,
+    pub text: String,
+    /// The id the sender's client gave the message, if any.
+    pub id: Option<String>,
+    pub via: Option<Via>,
+    /// How the text is formatted.
+    pub spans: Spans,
+}
+
+impl Message {
+    /// The line the message is sent to clients as, tagged with its time, sequence
+    /// number and how it got to the room.
+    pub fn line(&self) -> String {
+        let mut tags = Tags::default().time(self.at).seq(self.seq);
+        match &self.via {
+            Some(Via::Broadcast(rooms)) => tags = tags.broadcast(rooms).spans(&self.spans),
+            Some(Via::Forward { room, author }) => {
+                tags = (tags.forwarded(room)).spans(&forwarded_spans(room, author, &self.spans))
+            }
+            Some(Via::Quote {
+                seq,
+                author,
+                excerpt,
+            }) => {
+                let length = quoted_text(author, excerpt, "").chars().count() - 1;
+                tags = tags.reply(*seq).spans(&self.spans.quoted(length))
+            }
+            Some(Via::Action) => tags = tags.action(),
+            None => tags = tags.spans(&self.spans),
+        }
+        tags.apply(&format!("[{}]: {}", self.from, self.body()))
+    }
+
+    /// The text as shown, saying where it was forwarded from if it was, or with the
+    /// excerpt it quotes in front of it.
+    pub fn body(&self) -> String {
+        match &self.via {
+            Some(Via::Forward { room, author }) => forwarded_text(room, author, &self.text),
+            Some(Via::Quote {
+                author, excerpt, ..
+            }) => quoted_text(author, excerpt, &self.text),
+            _ => self.text.clone(),
+        }
+    }
+}
+
+/// The text of a reply to `author`, quoting `excerpt` of their message. Clients that
+/// know the `fmt` tag show the quote on a line of its own, the others in front.
+pub fn quoted_text(author: &str, excerpt: &str, text: &str) -> String {
+    format!("> {author}: {excerpt} {text}")
+}
+
+/// The start of `text`, up to [`MAX_EXCERPT`] characters and cut between words if
+/// it is longer, for a reply to quote.
+pub fn excerpt(text: &str) -> String {
+    if text.chars().count() <= MAX_EXCERPT {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(MAX_EXCERPT).collect();
+    let cut = match cut.rfind(' ') {
+        Some(at) if at > 0 => &cut[..at],
+        _ => &cut,
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// The text of a message `author` sent to `room`, forwarded elsewhere.
+pub fn forwarded_text(room: &str, author: &str, text: &str) -> String {
+    format!("(forwarded from #{room} by {author}) {text}")
+}
+
+/// The spans of a message's text once forwarded, past what [`forwarded_text`] puts
+/// in front of it.
+pub fn forwarded_spans(room: &str, author: &str, spans: &Spans) -> Spans {
+    spans.shifted(forwarded_text(room, author, "").chars().count())
+}
+
+/// Bounded in-memory history of the most recent room messages. Once full, the
+/// oldest message is dropped for every new one.
+pub struct History {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    retention: Retention,
+    /// The sequence number of the next message.
+    next_seq: u64,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            retention: Retention::Forever,
+            next_seq: 1,
+        }
+    }
+
+    /// Keeps messages only as long as `retention` says.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.set_retention(retention);
+        self
+    }
+
+    /// Changes how long messages are kept. Becoming ephemeral deletes every message.
+    pub fn set_retention(&mut self, retention: Retention) {
+        if retention == Retention::Ephemeral {
+            self.messages.clear();
+        }
+        self.retention = retention;
+    }
+
+    /// How old messages may get, if there is a limit.
+    pub fn max_age(&self) -> Option<Duration> {
+        match self.retention {
+            Retention::For(max_age) => Some(max_age),
+            _ => None,
+        }
+    }
+
+    /// Number of messages held.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Numbers a message with the next sequence number and records it, evicting the
+    /// oldest one if the history is full. Ephemeral rooms only number it.
+    pub fn push(&mut self, from: Arc<String>, text: &str, id: Option<&str>) -> Message {
+        self.push_via(from, text, id, None, Spans::default())
+    }
+
+    /// Like [`History::push`], for a message that is formatted with `spans`, or got to
+    /// the room `via` another way.
+    pub fn push_via(
+        &mut self,
+        from: Arc<String>,
+        text: &str,
+        id: Option<&str>,
+        via: Option<Via>,
+        spans: Spans,
+    ) -> Message {
+        let message = Message {
+            seq: self.next_seq,
+            at: SystemTime::now(),
+            from,
+            text: text.to_string(),
+            id: id.map(str::to_string),
+            via,
+            spans,
+        };
+        self.next_seq += 1;
+        if self.retention != Retention::Ephemeral {
+            if self.messages.len() == self.capacity {
+                self.messages.pop_front();
+            }
+            self.messages.push_back(message.clone());
+        }
+        message
+    }
+
+    /// Deletes the messages older than the retention allows at `now`. Returns how many
+    /// were deleted.
+    pub fn expire(&mut self, now: SystemTime) -> usize {
+        let Retention::For(max_age) = self.retention else {
+            return 0;
+        };
+        let before = self.messages.len();
+        // Messages are in the order they came in
+        while let Some(oldest) = self.messages.front() {
+            match now.duration_since(oldest.at) {
+                Ok(age) if age > max_age => self.messages.pop_front(),
+                _ => break,
+            };
+        }
+        before - self.messages.len()
+    }
+
+    /// Finds a message `from` sent with the id `id`, if it is still held.
+    pub fn find(&self, from: &str, id: &str) -> Option<&Message> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.from.as_str() == from && message.id.as_deref() == Some(id))
+    }
+
+    /// The sequence number of the oldest message still held.
+    pub fn first_seq(&self) -> Option<u64> {
+        self.messages.front().map(|message| message.seq)
+    }
+
+    /// The sequence number of the latest message, 0 before the first one.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Returns the messages still held whose sequence numbers are in `seqs`, oldest first.
+    pub fn range(&self, seqs: RangeInclusive<u64>) -> Vec<Message> {
+        self.messages
+            .iter()
+            .filter(|message| seqs.contains(&message.seq))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns up to `count` of the most recent messages sent by `username`, oldest first.
+    pub fn recent_from(&self, username: &str, count: usize) -> Vec<Message> {
+        let mut recent: Vec<Message> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.from.as_str() == username)
+            .take(count)
+            .cloned()
+            .collect();
+        recent.reverse();
+        recent
+    }
+
+    /// Returns every message sent by `username` that is still in the history, oldest first.
+    pub fn from(&self, username: &str) -> Vec<Message> {
+        self.recent_from(username, usize::MAX)
+    }
+
+    /// Attributes every message sent by `username` to `replacement` instead, including
+    /// those forwarded from them and the quotes of theirs.
+    pub fn anonymize(&mut self, username: &str, replacement: &Arc<String>) {
+        for message in self.messages.iter_mut() {
+            if message.from.as_str() == username {
+                message.from = replacement.clone();
+            }
+            if let Some(Via::Forward { author, .. } | Via::Quote { author, .. }) = &mut message.via
+            {
+                if author == username {
+                    *author = replacement.to_string();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_is_bounded() {
+        let alice = Arc::new("alice".to_string());
+        let bob = Arc::new("bob".to_string());
+        let mut history = History::new(3);
+        history.push(alice.clone(), "one", None);
+        history.push(bob.clone(), "two", None);
+        history.push(alice.clone(), "three", None);
+        history.push(alice.clone(), "four", None);
+
+        let recent: Vec<_> = history
+            .recent_from("alice", 5)
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(recent, vec!["three", "four"]);
+        assert_eq!(history.recent_from("bob", 5).len(), 1);
+    }
+
+    #[test]
+    fn test_sequence_numbers_survive_eviction() {
+        let alice = Arc::new("alice".to_string());
+        let mut history = History::new(2);
+        for text in ["one", "two", "three"] {
+            history.push(alice.clone(), text, None);
+        }
+        assert_eq!(history.first_seq(), Some(2));
+        assert_eq!(history.last_seq(), 3);
+        let texts: Vec<_> = history
+            .range(1..=2)
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, vec!["two"]);
+        assert!(history.range(4..=u64::MAX).is_empty());
+
+        history.push(alice.clone(), "four", Some("id-4"));
+        assert_eq!(
+            history.find("alice", "id-4").map(|message| message.seq),
+            Some(4)
+        );
+        assert!(history.find("bob", "id-4").is_none());
+    }
+
+    #[test]
+    fn test_retention() {
+        let alice = Arc::new("alice".to_string());
+        let mut history = History::new(3).with_retention("1h".parse().unwrap());
+        history.push(alice.clone(), "one", None);
+        history.push(alice.clone(), "two", None);
+        assert_eq!(history.expire(SystemTime::now()), 0);
+        let later = SystemTime::now() + Duration::from_secs(2 * 60 * 60);
+        assert_eq!(history.expire(later), 2);
+        assert_eq!(history.len(), 0);
+
+        let mut history = History::new(3).with_retention(Retention::Ephemeral);
+        assert_eq!(history.push(alice.clone(), "one", None).seq, 1);
+        assert_eq!(history.push(alice, "two", None).seq, 2);
+        assert_eq!(history.len(), 0);
+        assert_eq!(history.last_seq(), 2);
+        assert!("soon".parse::<Retention>().is_err());
+    }
+
+    #[test]
+    fn test_broadcast_copies_are_tagged() {
+        let alice = Arc::new("alice".to_string());
+        let mut history = History::new(3);
+        let rooms = vec!["lobby".to_string(), "games".to_string()];
+        let message = history.push_via(
+            alice,
+            "hi all",
+            None,
+            Some(Via::Broadcast(rooms)),
+            Spans::default(),
+        );
+        assert!(message
+            .line()
+            .contains(";broadcast=lobby,games [alice]: hi all"));
+        assert_eq!(history.range(1..=1)[0].via, message.via);
+    }
+
+    #[test]
+    fn test_forwarded_messages_say_where_from() {
+        let bob = Arc::new("bob".to_string());
+        let mut history = History::new(3);
+        let via = Some(Via::Forward {
+            room: "games".to_string(),
+            author: "alice".to_string(),
+        });
+        let message = history.push_via(bob, "gg", None, via, Spans::parse("b0-2", "gg").unwrap());
+        assert!(message
+            .line()
+            .ends_with(";forwarded=games;fmt=b33-35 [bob]: (forwarded from #games by alice) gg"));
+
+        history.anonymize("alice", &Arc::new("deleted user".to_string()));
+        assert_eq!(
+            history.from("bob")[0].body(),
+            "(forwarded from #games by deleted user) gg"
+        );
+    }
+
+    #[test]
+    fn test_replies_quote_an_excerpt() {
+        let bob = Arc::new("bob".to_string());
+        let mut history = History::new(3);
+        let long = "so who is bringing the snacks to the game night on friday, and who the drinks?";
+        let quoted = excerpt(long);
+        assert_eq!(
+            quoted,
+            "so who is bringing the snacks to the game night on friday,…"
+        );
+        assert_eq!(excerpt("short"), "short");
+        let via = Some(Via::Quote {
+            seq: 4,
+            author: "alice".to_string(),
+            excerpt: quoted.clone(),
+        });
+        let message = history.push_via(bob, "me", None, via, Spans::default());
+        assert!(message.line().ends_with(&format!(
+            ";reply=4;fmt=q0-68,n68-69 [bob]: > alice: {quoted} me"
+        )));
+
+        history.anonymize("alice", &Arc::new("deleted user".to_string()));
+        assert!(history.from("bob")[0]
+            .body()
+            .starts_with("> deleted user: so who"));
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let alice = Arc::new("alice".to_string());
+        let bob = Arc::new("bob".to_string());
+        let mut history = History::new(3);
+        history.push(alice.clone(), "one", None);
+        history.push(bob.clone(), "two", None);
+
+        history.anonymize("alice", &Arc::new("deleted user".to_string()));
+        assert!(history.from("alice").is_empty());
+        assert_eq!(history.from("deleted user")[0].text, "one");
+        assert_eq!(history.from("bob").len(), 1);
+    }
+}
@@ -0,0 +1,529 @@
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::api::{ApiToken, ApiTokens};
+use crate::firehose;
+use crate::poll::{self, Polls};
+use crate::pool::Pool;
+use crate::ratelimit::Limit;
+use crate::rooms;
+use crate::server::Server;
+use crate::transport::{self, OnConnection};
+
+/// Requests served at once; any more wait in a queue of the same length, and past
+/// that are turned away.
+const WORKERS: usize = 16;
+/// How long a client may take to send its request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest request body, or header line, accepted.
+const MAX_BODY: usize = 16 * 1024;
+/// The most header lines accepted.
+const MAX_HEADERS: usize = 64;
+/// How often an idle stream gets a comment, to keep proxies from closing it.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// The most messages one request may post.
+const MAX_LINES: usize = 20;
+
+/// What requests are served with.
+struct Api {
+    server: Arc<Server>,
+    polls: Polls,
+    /// Lets in the clients that connect by long-polling.
+    on_connection: Box<OnConnection>,
+}
+
+/// Serves the HTTP API on `listener` for as long as the server runs:
+///
+/// `POST /api/rooms/<room>/messages` with `Authorization: Bearer <token>` posts the
+/// body to the room, under the token's name, one message per line. The body is plain
+/// text, or JSON `{"text": "..."}` if its content type says so. The answer is `201`
+/// and `{"room": "<room>", "seqs": [<number>, ...]}`.
+///
+/// `POST /api/send` and `GET /api/poll` connect a client by long-polling, for
+/// networks that let nothing else through. The first send opens a connection, which
+/// goes through `on_connection` like any other, and answers `201` and `{"id": "<id>"}`.
+/// From then on `POST /api/send?id=<id>` sends lines to the server, one per line of
+/// the body, and `GET /api/poll?id=<id>` waits for lines from it, answering
+/// `{"lines": [...], "closed": <bool>}`. The lines are those of a TCP connection, so
+/// the client joins and resumes its session as it would over TCP.
+///
+/// `GET /api/rooms/<room>/events` streams the room's messages as Server-Sent Events,
+/// to any token that covers the room; read-only tokens may do nothing else. Each
+/// event's id is the message's sequence number, so a client that reconnects with
+/// `Last-Event-ID` first gets what it missed.
+pub fn serve(listener: TcpListener, server: &Arc<Server>, on_connection: Box<OnConnection>) {
+    let api = Arc::new(Api {
+        server: Arc::clone(server),
+        polls: Polls::default(),
+        on_connection,
+    });
+    let pool = Pool::new(WORKERS, WORKERS, handle);
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if let Err((mut stream, _)) = pool.submit((stream, Arc::clone(&api))) {
+            let response = Response::error(503, "The server is busy, try again later");
+            let _ = response.write(&mut stream);
+        }
+    }
+}
+
+/// A request, as far as the API cares.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    /// Header names are lowercased.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The path, without the query.
+    fn path(&self) -> &str {
+        self.path
+            .split_once('?')
+            .map_or(&self.path, |(path, _)| path)
+    }
+
+    /// The value of a query parameter, e.g. `id` of `/api/poll?id=abc`.
+    fn query(&self, name: &str) -> Option<String> {
+        let (_, query) = self.path.split_once('?')?;
+        (query.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        (self.headers.iter())
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: Value,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Response {
+    fn new(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::new(status, json!({"error": message}))
+    }
+
+    fn with_header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            411 => "Length Required",
+            413 => "Content Too Large",
+            429 => "Too Many Requests",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        let body = self.body.to_string();
+        let mut head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        stream.write_all(format!("{head}\r\n{body}").as_bytes())
+    }
+}
+
+fn handle((mut stream, api): (TcpStream, Arc<Api>)) {
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+    let Ok(clone) = stream.try_clone() else {
+        return;
+    };
+    let request = match read_request(&mut BufReader::new(clone)) {
+        Ok(request) => request,
+        Err(response) => {
+            let _ = response.write(&mut stream);
+            return;
+        }
+    };
+    // A poll may wait for a while, on a thread of its own rather than a worker. There
+    // are at most as many as there are connections, as a newer poll of a connection
+    // ends an older one.
+    if request.method == "GET" && request.path() == "/api/poll" {
+        thread::spawn(move || {
+            let response = wait_for_lines(&request, &api.polls);
+            let _ = response.write(&mut stream);
+        });
+        return;
+    }
+    // So does a stream, for as long as its client listens
+    if let ("GET", Some(room)) = (request.method.as_str(), events_room(request.path())) {
+        let room = percent_decode(room);
+        thread::spawn(move || {
+            if let Err(response) = stream_events(&request, &room, &mut stream, &api.server) {
+                let _ = response.write(&mut stream);
+            }
+        });
+        return;
+    }
+    let response = route(&request, &stream, &api);
+    let _ = response.write(&mut stream);
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request, Response> {
+    let bad_request = || Response::error(400, "Malformed request");
+    let request_line = read_line(reader).ok_or_else(bad_request)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request());
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(bad_request());
+    }
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader).ok_or_else(bad_request)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Response::error(400, "Too many headers"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(bad_request)?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    if request.method == "POST" {
+        if request.header("transfer-encoding").is_some() {
+            return Err(Response::error(411, "Send a Content-Length"));
+        }
+        let length: usize = match request.header("content-length").map(str::parse) {
+            Some(Ok(length)) => length,
+            Some(Err(_)) => return Err(bad_request()),
+            None => return Err(Response::error(411, "Send a Content-Length")),
+        };
+        if length > MAX_BODY {
+            return Err(Response::error(413, "The body is too long"));
+        }
+        request.body = vec![0; length];
+        reader
+            .read_exact(&mut request.body)
+            .map_err(|_| bad_request())?;
+    }
+    Ok(request)
+}
+
+/// Reads a line ending in CRLF (or LF), without it.
+fn read_line(reader: &mut impl BufRead) -> Option<String> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_BODY as u64)
+        .read_until(b'\n', &mut line)
+        .ok()?;
+    if line.last() != Some(&b'\n') {
+        return None;
+    }
+    let line = String::from_utf8(line).ok()?;
+    Some(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn route(request: &Request, stream: &TcpStream, api: &Api) -> Response {
+    let path = request.path();
+    match path {
+        "/api/send" if request.method == "POST" => return send_lines(request, stream, api),
+        "/api/send" => {
+            return Response::error(405, "Only POST is allowed").with_header("Allow", "POST")
+        }
+        // Polls have been answered by now
+        "/api/poll" => {
+            return Response::error(405, "Only GET is allowed").with_header("Allow", "GET")
+        }
+        _ => {}
+    }
+    if events_room(path).is_some() {
+        // Streams have been served by now
+        return Response::error(405, "Only GET is allowed").with_header("Allow", "GET");
+    }
+    let room = (path.strip_prefix("/api/rooms/"))
+        .and_then(|rest| rest.strip_suffix("/messages"))
+        .filter(|room| !room.contains('/'));
+    let Some(room) = room else {
+        return Response::error(404, "No such endpoint");
+    };
+    if request.method != "POST" {
+        return Response::error(405, "Only POST is allowed").with_header("Allow", "POST");
+    }
+    post_message(request, &percent_decode(room), &api.server)
+}
+
+/// Sends the body's lines to a long-polling connection, opening one without an `id`.
+fn send_lines(request: &Request, stream: &TcpStream, api: &Api) -> Response {
+    let Ok(body) = std::str::from_utf8(&request.body) else {
+        return Response::error(400, "The body must be UTF-8");
+    };
+    let lines: Vec<&str> = (body.lines())
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.len() > MAX_LINES {
+        return Response::error(413, &format!("Send at most {MAX_LINES} lines at once"));
+    }
+    if let Some(id) = request.query("id") {
+        return match api.polls.send(&id, &lines) {
+            true => Response::new(202, json!({})),
+            false => Response::error(404, "No such connection, open a new one"),
+        };
+    }
+    let Ok(peer) = stream.peer_addr() else {
+        return Response::error(400, "Malformed request");
+    };
+    let Some((id, reader, writer)) = api.polls.open() else {
+        return Response::error(503, "Too many long-polling connections, try again later");
+    };
+    println!("Received a long-polling connection from: {peer}");
+    (api.on_connection)(
+        transport::Reader::Poll(reader),
+        transport::Writer::Poll(writer),
+        peer.ip(),
+    );
+    api.polls.send(&id, &lines);
+    Response::new(201, json!({"id": id}))
+}
+
+/// Waits for lines to a long-polling connection.
+fn wait_for_lines(request: &Request, polls: &Polls) -> Response {
+    let polled = (request.query("id")).and_then(|id| polls.poll(&id, poll::POLL_WAIT));
+    match polled {
+        Some(polled) => Response::new(200, json!({"lines": polled.lines, "closed": polled.closed})),
+        None => Response::error(404, "No such connection, open a new one"),
+    }
+}
+
+fn post_message(request: &Request, room: &str, server: &Server) -> Response {
+    let room = match rooms::parse_name(room) {
+        Ok(room) => room,
+        Err(e) => return Response::error(404, &e),
+    };
+    let name = {
+        let tokens = server.api_tokens.lock().unwrap();
+        let Some((name, token)) = authorize(request, &tokens) else {
+            return unauthorized();
+        };
+        if token.read_only {
+            return Response::error(403, "This token may only stream rooms");
+        }
+        if !token.covers(room) {
+            return Response::error(403, &format!("This token may not post to #{room}"));
+        }
+        name.to_string()
+    };
+    let text = match body_text(request) {
+        Ok(text) => text,
+        Err(e) => return Response::error(400, &e),
+    };
+    let lines: Vec<&str> = (text.lines())
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    if lines.is_empty() {
+        return Response::error(400, "There is nothing to post");
+    }
+    if lines.len() > MAX_LINES {
+        return Response::error(413, &format!("Post at most {MAX_LINES} lines at once"));
+    }
+    if let Err(wait) = server.throttle(Limit::Messages, &name) {
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return Response::error(429, "Posting too fast").with_header("Retry-After", retry_after);
+    }
+    match server.post(room, &name, &lines) {
+        Some(seqs) => Response::new(201, json!({"room": room, "seqs": seqs})),
+        None => Response::error(404, &format!("There is no room #{room}")),
+    }
+}
+
+/// The name and details of the API token the request carries, if it is one. Tokens
+/// go in an `Authorization: Bearer` header, or the `token` query parameter for
+/// clients that can't set headers, such as a browser's `EventSource`.
+fn authorize<'a>(request: &Request, tokens: &'a ApiTokens) -> Option<(&'a str, &'a ApiToken)> {
+    let secret = (request.header("authorization"))
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| request.query("token"))?;
+    tokens.check(secret.trim())
+}
+
+fn unauthorized() -> Response {
+    Response::error(401, "A valid API token is needed").with_header("WWW-Authenticate", "Bearer")
+}
+
+/// The room of a path that streams one, e.g. `lobby` of `/api/rooms/lobby/events`.
+fn events_room(path: &str) -> Option<&str> {
+    (path.strip_prefix("/api/rooms/"))
+        .and_then(|rest| rest.strip_suffix("/events"))
+        .filter(|room| !room.contains('/'))
+}
+
+/// Streams a room's messages to the client as Server-Sent Events until it goes away.
+/// A client that reconnects with `Last-Event-ID` first gets the messages it missed,
+/// as far as the room's history goes back. Returns the response to answer with if
+/// the stream can't start.
+fn stream_events(
+    request: &Request,
+    room: &str,
+    stream: &mut TcpStream,
+    server: &Server,
+) -> Result<(), Response> {
+    let room = rooms::parse_name(room).map_err(|e| Response::error(404, &e))?;
+    {
+        let tokens = server.api_tokens.lock().unwrap();
+        let (_, token) = authorize(request, &tokens).ok_or_else(unauthorized)?;
+        if !token.covers(room) {
+            return Err(Response::error(
+                403,
+                &format!("This token may not stream #{room}"),
+            ));
+        }
+    }
+    let since: Option<u64> = (request.header("last-event-id")).and_then(|id| id.parse().ok());
+    // Subscribe before catching up, so no message falls in between
+    let events = (server.firehose.subscribe(room))
+        .ok_or_else(|| Response::error(503, "Too many streams, try again later"))?;
+    let missed = {
+        let rooms = server.rooms.lock().unwrap();
+        let history = &rooms
+            .get(room)
+            .ok_or_else(|| Response::error(404, &format!("There is no room #{room}")))?
+            .history;
+        match since {
+            Some(since) if since < history.last_seq() => {
+                history.range(since + 1..=history.last_seq())
+            }
+            _ => Vec::new(),
+        }
+    };
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if stream.write_all(head.as_bytes()).is_err() {
+        return Ok(());
+    }
+    let mut last = since.unwrap_or(0);
+    for message in missed {
+        let event = firehose::message(room, &message.from, message.seq, &message.text, message.at);
+        if stream.write_all(event.as_bytes()).is_err() {
+            return Ok(());
+        }
+        last = message.seq;
+    }
+    loop {
+        let event = match events.recv_timeout(KEEPALIVE) {
+            Ok(event) => event,
+            // Dropped for falling behind
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            // A comment, which also finds out if the client is gone
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+        };
+        let seq = (event.strip_prefix("id: "))
+            .and_then(|rest| rest.split('\n').next())
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if seq.is_some_and(|seq| seq <= last) {
+            continue;
+        }
+        if stream.write_all(event.as_bytes()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// The text to post: the body, or its `text` if it is JSON.
+fn body_text(request: &Request) -> Result<String, String> {
+    let body = String::from_utf8(request.body.clone())
+        .map_err(|_| "The body must be UTF-8".to_string())?;
+    let is_json = (request.header("content-type"))
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return Ok(body);
+    }
+    let value: Value = serde_json::from_str(&body).map_err(|e| format!("Invalid JSON: {e}"))?;
+    match value.get("text").and_then(Value::as_str) {
+        Some(text) => Ok(text.to_string()),
+        None => Err("The JSON needs a \"text\" string".to_string()),
+    }
+}
+
+/// Decodes `%XX` escapes, e.g. `%23` for the `#` of a room name.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /api/rooms/%23builds/messages HTTP/1.1\r\nHost: chat\r\nAuthorization: Bearer abc\r\nContent-Type: application/json\r\nContent-Length: 22\r\n\r\n{\"text\": \"Build #42\"}\n";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("authorization"), Some("Bearer abc"));
+        assert_eq!(body_text(&request).unwrap(), "Build #42");
+        assert_eq!(percent_decode("%23builds"), "#builds");
+
+        let raw = "POST /api/rooms/builds/messages HTTP/1.1\r\n\r\n";
+        assert_eq!(read_request(&mut raw.as_bytes()).err().unwrap().status, 411);
+        let raw = "POST /x HTTP/1.1\r\nContent-Length: 99999\r\n\r\n";
+        assert_eq!(read_request(&mut raw.as_bytes()).err().unwrap().status, 413);
+    }
+}
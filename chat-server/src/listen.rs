@@ -0,0 +1,115 @@
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+
+/// Connections waiting to be accepted, per listener.
+const BACKLOG: libc::c_int = 1024;
+
+/// Binds `count` listeners to `addr`. More than one share the port with
+/// `SO_REUSEPORT`, and the kernel spreads incoming connections across them, so
+/// each can be accepted from on a thread of its own.
+pub fn bind(addr: &str, count: usize) -> io::Result<Vec<TcpListener>> {
+    if count == 1 {
+        return Ok(vec![TcpListener::bind(addr)?]);
+    }
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind to"))?;
+    let first = bind_reuse_port(addr)?;
+    // Binding to port 0 picks one, the others need the same
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind_reuse_port(addr)?);
+    }
+    Ok(listeners)
+}
+
+/// Checks the result of a libc call, which fails with -1.
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: the descriptor is owned from here on, so it is closed on errors
+    let socket =
+        unsafe { OwnedFd::from_raw_fd(check(libc::socket(domain, libc::SOCK_STREAM, 0))?) };
+    let fd = socket.as_raw_fd();
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // SAFETY: `on` is a c_int, as these options expect
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                ptr::addr_of!(on).cast(),
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        })?;
+    }
+    // SAFETY: all zeroes is a valid sockaddr_storage, which is large enough for either
+    // kind of address
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in>();
+            // SAFETY: `sin` points into `storage`
+            unsafe {
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_port = addr.port().to_be();
+                (*sin).sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            }
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in6>();
+            // SAFETY: `sin6` points into `storage`
+            unsafe {
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_port = addr.port().to_be();
+                (*sin6).sin6_addr.s6_addr = addr.ip().octets();
+                (*sin6).sin6_scope_id = addr.scope_id();
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    // SAFETY: `storage` holds an address of `len` bytes
+    check(unsafe { libc::bind(fd, ptr::addr_of!(storage).cast(), len as libc::socklen_t) })?;
+    // SAFETY: `fd` is a bound socket
+    check(unsafe { libc::listen(fd, BACKLOG) })?;
+    Ok(TcpListener::from(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_listeners_share_the_port() {
+        let listeners = bind("127.0.0.1:0", 3).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap().port(), port);
+        }
+        // Whichever listener the kernel picks accepts it
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        for listener in &listeners {
+            listener.set_nonblocking(true).unwrap();
+        }
+        let accepted = listeners.iter().filter(|l| l.accept().is_ok()).count();
+        assert_eq!(accepted, 1);
+    }
+}
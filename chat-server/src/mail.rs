@@ -0,0 +1,151 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long the relay may take to answer each command.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How mail leaves the server, as set in the config file's `[email]` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// The SMTP relay, as `host:port`. It must accept mail from the server without
+    /// authentication or TLS, e.g. a local MTA that passes it on.
+    pub relay: String,
+    /// The sender of every mail.
+    pub from: String,
+    /// How often digests of missed messages are sent.
+    pub digest_every: Duration,
+}
+
+/// Checks an address enough to catch typos: a local part, an `@`, and a domain with
+/// a dot, without whitespace or characters that could break out of an SMTP command.
+pub fn validate_address(address: &str) -> Result<(), String> {
+    let valid = address.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty() && domain.contains('.') && !domain.starts_with('.')
+    }) && !address.contains(|c: char| c.is_whitespace() || "<>,;\"".contains(c));
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid email address: {address}")),
+    }
+}
+
+/// Sends a plain text mail through the relay.
+pub fn send(settings: &Settings, to: &str, subject: &str, body: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(&settings.relay)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut smtp = Smtp {
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+    };
+    smtp.expect(220)?;
+    smtp.command("HELO chat-server", 250)?;
+    smtp.command(&format!("MAIL FROM:<{}>", settings.from), 250)?;
+    smtp.command(&format!("RCPT TO:<{to}>"), 250)?;
+    smtp.command("DATA", 354)?;
+    let date = chrono::Utc::now().to_rfc2822();
+    let mut message = format!(
+        "From: {}\r\nTo: {to}\r\nSubject: {subject}\r\nDate: {date}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        settings.from
+    );
+    for line in body.lines() {
+        // Dot-stuffing, so no line of the body ends the data early
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    smtp.writer.write_all(message.as_bytes())?;
+    smtp.expect(250)?;
+    smtp.command("QUIT", 221)
+}
+
+/// An SMTP conversation with the relay.
+struct Smtp {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Smtp {
+    fn command(&mut self, command: &str, code: u16) -> io::Result<()> {
+        self.writer.write_all(format!("{command}\r\n").as_bytes())?;
+        self.expect(code)
+    }
+
+    /// Reads a reply, which may span several lines, and checks its code.
+    fn expect(&mut self, code: u16) -> io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::other("The relay closed the connection"));
+            }
+            if line.get(..3).and_then(|got| got.parse::<u16>().ok()) != Some(code) {
+                return Err(io::Error::other(format!(
+                    "The relay answered {}",
+                    line.trim_end()
+                )));
+            }
+            // `250-...` continues the reply, `250 ...` ends it
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_validate_address() {
+        assert!(validate_address("alice@example.com").is_ok());
+        assert!(validate_address("alice").is_err());
+        assert!(validate_address("alice@localhost").is_err());
+        assert!(validate_address("alice@example.com>\r\nRCPT TO:<x@y.z").is_err());
+    }
+
+    #[test]
+    fn test_send() {
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            relay: relay.local_addr().unwrap().to_string(),
+            from: "chat@example.com".to_string(),
+            digest_every: Duration::from_secs(3600),
+        };
+        let server = thread::spawn(move || {
+            let (stream, _) = relay.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let mut data = Vec::new();
+            writer.write_all(b"220 relay\r\n").unwrap();
+            while let Some(Ok(line)) = lines.next() {
+                let reply: &[u8] = match line.as_str() {
+                    "DATA" => b"354 go ahead\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply).unwrap();
+                if line == "DATA" {
+                    for line in lines.by_ref() {
+                        let line = line.unwrap();
+                        if line == "." {
+                            break;
+                        }
+                        data.push(line);
+                    }
+                    writer.write_all(b"250 queued\r\n").unwrap();
+                }
+            }
+            data
+        });
+        send(&settings, "bob@example.com", "Hi", "one\n.two").unwrap();
+        let data = server.join().unwrap();
+        assert!(data.contains(&"Subject: Hi".to_string()));
+        assert_eq!(data[data.len() - 2..], ["one", "..two"]);
+    }
+}
@@ -1,105 +1,1056 @@
-use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{TcpListener, TcpStream};
+mod accounts;
+mod admin;
+mod allowlist;
+mod api;
+mod audit;
+mod away;
+mod bans;
+mod buffers;
+mod cidr;
+mod commands;
+mod config;
+mod connections;
+mod console;
+mod digest;
+mod discovery;
+mod duration;
+mod events;
+mod firehose;
+mod flood;
+#[cfg(feature = "fun")]
+mod fun;
+mod histogram;
+mod history;
+mod http;
+mod listen;
+mod mail;
+mod mmdb;
+mod names;
+#[cfg(feature = "noise")]
+mod noise;
+mod origin;
+mod otlp;
+mod outbound;
+mod poll;
+mod polls;
+mod pool;
+mod pow;
+mod privacy;
+mod profile;
+mod proxy;
+mod push;
+#[cfg(feature = "quic")]
+mod quic;
+mod quota;
+mod ratelimit;
+mod reports;
+mod rooms;
+mod scheduler;
+mod server;
+mod sockopt;
+mod spans;
+mod stats;
+mod tags;
+mod timestamp;
+mod token;
+mod tor;
+mod traffic;
+mod transport;
+mod version;
+mod webhooks;
+
+use clap::{Parser, Subcommand};
+use std::io::{self, BufReader};
+use std::net::{IpAddr, TcpListener};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use commands::{Actor, Context, Flow, Posted};
+use events::Event;
+use flood::FloodGuard;
+use history::Via;
+use otlp::Span;
+use ratelimit::Limit;
+use rooms::Rooms;
+use server::{Role, Server, SessionId};
+use spans::Spans;
+use timestamp::TimeFormat;
+use transport::{Reader, Writer};
+
+/// Command-line arguments for the chat server.
+#[derive(Parser)]
+struct Args {
+    /// The address to listen on
+    #[arg(long, default_value = "0.0.0.0:12345")]
+    bind: String,
+
+    /// Directory where persistent data (e.g. the audit log) is kept
+    #[arg(long, default_value = "data")]
+    data_dir: PathBuf,
+
+    /// Let clients on the LAN find this server with `--discover`
+    #[arg(long)]
+    advertise: bool,
+
+    /// Name shown to clients discovering this server
+    #[arg(long, default_value = "simple-chat")]
+    name: String,
+
+    /// Publish the server as a Tor onion service through the control port at this
+    /// address (e.g. 127.0.0.1:9051). The service key is kept in <data-dir>/onion.key
+    #[arg(long)]
+    tor_control: Option<std::net::SocketAddr>,
+
+    /// Make clients solve a proof-of-work challenge of this many bits before joining
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=32))]
+    pow_bits: Option<u32>,
+
+    /// Accept Noise encrypted connections, using the key pair in <data-dir>/noise.key
+    #[cfg(feature = "noise")]
+    #[arg(long)]
+    noise: bool,
+
+    /// Also accept QUIC connections on this (UDP) address
+    #[cfg(feature = "quic")]
+    #[arg(long)]
+    quic: Option<std::net::SocketAddr>,
+
+    /// Show times (e.g. in /reports and /audit) in this strftime-style format, such as
+    /// "%H:%M", instead of how long ago they were
+    #[arg(long)]
+    time_format: Option<String>,
+
+    /// The format of times before today (default: the date, then --time-format)
+    #[arg(long, requires = "time_format")]
+    older_time_format: Option<String>,
+
+    /// How long room messages are kept for clients to fetch again: `forever` (until
+    /// the last 1000), a duration such as `7d`, or `ephemeral` to never keep them
+    #[arg(long, default_value = "forever")]
+    retention: history::Retention,
+
+    /// Config file (TOML) declaring the permanent rooms, reloaded by `/rooms reload`
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Look up the host name of connecting addresses, for the connection log and
+    /// moderators' /whois
+    #[arg(long)]
+    reverse_dns: bool,
+
+    /// Look up where connecting addresses are in this GeoIP database (MaxMind DB
+    /// format, e.g. GeoLite2-City.mmdb), for the connection log and moderators' /whois
+    #[arg(long)]
+    geoip: Option<PathBuf>,
+
+    /// Expect a PROXY protocol header (v1 or v2) with the client's address on TCP
+    /// connections from these load balancers, given as addresses or CIDR blocks
+    #[arg(long, value_name = "CIDR")]
+    proxy_protocol: Vec<cidr::Cidr>,
+
+    /// Accept connections on this many threads, each with a listener of its own
+    /// sharing the port (SO_REUSEPORT), for connection storms
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=256))]
+    acceptors: u16,
+
+    /// The most connections served at once, each on a worker thread of its own
+    #[arg(long, default_value_t = 1024, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: usize,
+
+    /// Connections that may wait for a worker once they are all busy; any more are
+    /// turned away
+    #[arg(long, default_value_t = 16)]
+    worker_queue: usize,
 
-/// Type alias for the list of users connected to the chat server.
-type UserList = Arc<Mutex<HashMap<Arc<String>, TcpStream>>>;
-/// Type alias for the list of active users/connections.
-type ActiveUsers = Arc<Mutex<HashSet<Arc<String>>>>;
+    /// Bytes read from a client's TCP connection at a time
+    #[arg(long, default_value_t = 8192, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(64..))]
+    read_buffer: usize,
+
+    /// Bytes the buffers lines are written to clients from start with. They grow for
+    /// longer lines, and are reused from a pool
+    #[arg(long, default_value_t = buffers::WRITE_BUFFER, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(16..))]
+    write_buffer: usize,
+
+    /// Export traces of connections, handshakes, commands and broadcasts to this
+    /// OpenTelemetry collector (OTLP over HTTP, e.g. http://localhost:4318)
+    #[arg(long, value_name = "URL")]
+    otlp: Option<otlp::Endpoint>,
+
+    /// Serve the HTTP API, which scripts post messages to rooms with using tokens from
+    /// /tokens, on this address (e.g. 127.0.0.1:8080)
+    #[arg(long, value_name = "ADDRESS")]
+    http: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Maintenance tasks, run instead of the server.
+#[derive(Subcommand)]
+enum Command {
+    /// Manage registered accounts (stop the server first)
+    Account {
+        #[command(subcommand)]
+        command: admin::AccountCommand,
+    },
+}
+
+/// Lines a client may send before its proof-of-work solution.
+const MAX_HELD_LINES: usize = 32;
+
+/// Capabilities kept of those a client announces with `/caps`.
+const MAX_CAPABILITIES: usize = 16;
+
+/// How long a load balancer has to send the PROXY header.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Handles a connected client.
 ///
 /// This function processes messages sent by the client and broadcasts them to
-/// other connected users. It also removes the user from the list when they leave.
+/// other connected users. Lines starting with `/` are routed to the command
+/// handlers instead. It also removes the user from the list when they leave, or
+/// keeps their session for a while if they lost their connection.
 fn handle_client(
-    stream: TcpStream,
+    lines: impl Iterator<Item = io::Result<String>>,
     username: Arc<String>,
-    user_list: UserList,
-    active_usrs: ActiveUsers,
+    session: SessionId,
+    server: Arc<Server>,
+    span: &Span,
 ) {
-    let reader = BufReader::new(stream);
-    for line in reader.lines() {
-        let message = match line {
-            Ok(msg) => msg,
-            Err(e) => e.to_string(),
-        };
-        if message == "/leave" {
+    let mut left = false;
+    for line in lines {
+        // A read error means the connection is gone
+        let Ok(line) = line else {
             break;
-        }
-        // Broadcast message to everyone in the user_list, except the sender
-        let mut user_list = user_list.lock().unwrap();
-        for (user, user_stream) in user_list.iter_mut() {
-            if user != &username {
-                writeln!(user_stream, "[{}]: {}", username, message)
-                    .expect("Failed to send message");
+        };
+        let received = Instant::now();
+        let (tags, message) = tags::split(&line);
+        server.touch(&username, session, line.len() + 1);
+        // Replies and actions are posted like any other message
+        let (message, posted) = commands::posted(message);
+        if posted == Posted::Message && message.starts_with('/') {
+            let mut span = span.child("command");
+            span.set("command", message.split(' ').next().unwrap_or(message));
+            let ctx = Context {
+                server: &server,
+                actor: Actor::User(&username, session),
+                role: server.role(&username).unwrap_or(Role::User),
+            };
+            match commands::dispatch(&ctx, message) {
+                Flow::Continue => continue,
+                Flow::Leave => {
+                    left = true;
+                    break;
+                }
             }
         }
+        if let Some(remaining) = server.muted_for(&username) {
+            server.send_to_session(&username, session, &commands::muted_notice(remaining));
+            continue;
+        }
+        // Held while broadcasting, so messages go out in the order of their numbers
+        let mut rooms = server.rooms.lock().unwrap();
+        let Some((name, room)) = rooms.current_mut(&username) else {
+            continue;
+        };
+        if let Some(remaining) = room.muted_for(&username) {
+            let notice = commands::room_muted_notice(remaining);
+            server.send_to_session(&username, session, &notice);
+            continue;
+        }
+        if room.is_spectator(&username) {
+            server.send_to_session(&username, session, &commands::spectating_notice(name));
+            continue;
+        }
+        let via = match posted {
+            Posted::Quote(seq) => match room.history.range(seq..=seq).pop() {
+                Some(original) => Some(Via::Quote {
+                    seq,
+                    author: original.from.to_string(),
+                    excerpt: history::excerpt(&original.text),
+                }),
+                None => {
+                    let notice = format!("There is no message #{seq} to quote");
+                    server.send_to_session(&username, session, &notice);
+                    continue;
+                }
+            },
+            Posted::Action => Some(Via::Action),
+            Posted::Message => None,
+        };
+        if let Some(seq) = tags
+            .id
+            .and_then(|id| room.history.find(&username, id))
+            .map(|message| message.seq)
+        {
+            // Sent again after a reconnect, but it had made it the first time
+            server.send_to_session(&username, session, &server::ack(seq, tags.id));
+            continue;
+        }
+        if let Err(wait) = server.throttle(Limit::Messages, &username) {
+            server.send_to_session(
+                &username,
+                session,
+                &commands::throttled(Limit::Messages, wait, tags.id),
+            );
+            continue;
+        }
+        let sent =
+            server
+                .quotas
+                .lock()
+                .unwrap()
+                .send(&username, message.len() as u64, SystemTime::now());
+        if let Err(exceeded) = sent {
+            server.stats.over_quota.bump();
+            server.send_to_session(&username, session, &exceeded.line(tags.id));
+            continue;
+        }
+        // The spans of a command's line don't belong to the reply
+        let spans = match posted {
+            Posted::Message => tags.spans,
+            _ => Spans::default(),
+        };
+        let message = (room.history).push_via(username.clone(), message, tags.id, via, spans);
+        // Broadcast message to everyone in the room, except the sending session, which
+        // is told the number its message got instead
+        let mut broadcast = span.child("broadcast");
+        broadcast.set("members", room.members().count());
+        broadcast.set("seq", message.seq);
+        let sender = Some((&username, session));
+        server.publish(name, room, &message, sender, tags.id, received);
+        drop(broadcast);
     }
 
     // Cleanup after user leaves
-    user_list.lock().unwrap().remove(&username);
-    active_usrs.lock().unwrap().remove(&username);
-    println!("User {} has left", username);
+    if left {
+        server.leave(&username, session);
+        server.events.emit(Event::UserLeft {
+            username: &username,
+        });
+    } else {
+        server.suspend(&username, session);
+        server.events.emit(Event::ConnectionLost {
+            username: &username,
+            grace: server::RESUME_GRACE,
+        });
+    }
 }
 
-/// Main function that initializes the server and listens for incoming connections.
-/// The server waits for a username from the client, verifies its uniqueness, and then
-/// allows the user to join the chat room.
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:12345").expect("Failed to bind");
-    let user_list = Arc::new(Mutex::new(HashMap::new()));
-    let active_usernames = Arc::new(Mutex::new(HashSet::new()));
-    let mut stream;
+/// Turns away a connection from an address the flood guard has blocked.
+fn refuse(writer: &mut Writer, ip: IpAddr, blocked_for: Duration) {
+    println!(
+        "Refusing connection from {ip} for {}",
+        duration::format(blocked_for)
+    );
+    let _ = writer.write_line(&format!(
+        "Too many connection attempts, try again in {}",
+        duration::format(blocked_for)
+    ));
+}
 
+/// Accepts connections on `listener` for as long as the server runs.
+fn serve(listener: TcpListener, server: &Arc<Server>, admission: &Arc<Admission>) {
     for s in listener.incoming() {
-        match s {
-            Ok(s) => {
-                println!("Received a connection from: {:?}", s.peer_addr().unwrap());
-                stream = s
-            }
+        let stream = match s {
+            Ok(s) => s,
             Err(e) => {
                 println!("Failed to accept new connection: {}", e);
                 continue;
             }
         };
+        let addr = match stream.peer_addr() {
+            Ok(addr) => {
+                println!("Received a connection from: {:?}", addr);
+                addr
+            }
+            Err(_) => continue,
+        };
+        if let Err(e) = server.socket_options().apply(&stream) {
+            println!("Failed to set the socket options of {addr}: {e}");
+        }
+        let mut reader = match stream.try_clone() {
+            Ok(clone) => BufReader::with_capacity(admission.read_buffer, clone),
+            Err(_) => continue,
+        };
+        if !admission
+            .proxies
+            .iter()
+            .any(|cidr| cidr.contains(addr.ip()))
+        {
+            accept(
+                Reader::Plain(reader),
+                Writer::Plain(stream),
+                addr.ip(),
+                server,
+                admission,
+            );
+            continue;
+        }
+        // Behind a load balancer, the client's address comes first. It's read on a
+        // thread of its own, so a slow proxy doesn't hold up the others
+        let server = Arc::clone(server);
+        let admission = Arc::clone(admission);
+        thread::spawn(move || {
+            let _ = stream.set_read_timeout(Some(PROXY_TIMEOUT));
+            let ip = match proxy::read_header(&mut reader) {
+                Ok(Some(client)) => {
+                    println!("Connection from {addr} is proxied for {client}");
+                    client.ip()
+                }
+                Ok(None) => addr.ip(),
+                Err(e) => {
+                    println!("Dropping connection from {addr}: {e}");
+                    return;
+                }
+            };
+            let _ = stream.set_read_timeout(None);
+            accept(
+                Reader::Plain(reader),
+                Writer::Plain(stream),
+                ip,
+                &server,
+                &admission,
+            );
+        });
+    }
+}
 
-        // Get a unique username from the client.
-        let mut buffer = [0; 512];
-        let mut username = String::new();
+/// How clients get in, shared by every connection thread.
+struct Admission {
+    flood_guard: Mutex<FloodGuard>,
+    pow_bits: Option<u32>,
+    /// Load balancers whose connections start with a PROXY header.
+    proxies: Vec<cidr::Cidr>,
+    /// Bytes read from a TCP connection at a time.
+    read_buffer: usize,
+    #[cfg(feature = "noise")]
+    noise_key: Option<noise::StaticKey>,
+    /// Looks up where clients connect from, if the operator turned that on.
+    lookup: origin::Lookup,
+    /// The workers connections run on, one each.
+    pool: pool::Pool<Connection>,
+}
+
+/// A client that made it through the handshake.
+struct Joined {
+    username: String,
+    reader: Reader,
+    writer: Writer,
+    /// Lines the client sent ahead of its proof-of-work solution.
+    held: Vec<String>,
+    /// The token of the session the client is resuming, if it is.
+    resume: Option<String>,
+    /// The last message a client that came back too late to resume its session saw,
+    /// and the room it was in, to put it back there and send it what came after.
+    since: Option<(u64, String)>,
+    /// Whether the client logged into a registered account, which may be connected
+    /// from several devices at once.
+    registered: bool,
+    /// What the client said it supports, with `/caps` ahead of its username.
+    capabilities: Vec<String>,
+}
+
+/// Optionally switches the connection to the Noise transport, then gets a unique
+/// username from the client and, if the server asks for one, a proof-of-work
+/// solution. Registered usernames also need their password. Returns `None` if the
+/// client never got through.
+fn handshake(
+    mut reader: Reader,
+    mut writer: Writer,
+    ip: IpAddr,
+    server: &Server,
+    admission: &Admission,
+) -> Option<Joined> {
+    let read_line = |reader: &mut Reader| match reader.next() {
+        Some(Ok(line)) => Some(line.trim().to_string()),
+        Some(Err(_)) | None => None,
+    };
+    // Tells the client what went wrong, returns false once the address is blocked
+    let reject = |writer: &mut Writer, error: &str| {
+        let result = admission
+            .flood_guard
+            .lock()
+            .unwrap()
+            .handshake_failed(ip, Instant::now());
+        match result {
+            Ok(()) => {
+                let _ = writer.write_line(error);
+                true
+            }
+            Err(blocked_for) => {
+                refuse(writer, ip, blocked_for);
+                false
+            }
+        }
+    };
+
+    // The username is line delimited, so that messages the client sends right after
+    // it are not mistaken for part of it.
+    let mut first = true;
+    let mut since = None;
+    let mut capabilities = Vec::new();
+    let username = loop {
+        let username = read_line(&mut reader)?;
+        // Clients may open with `/noise` to switch to the encrypted transport first
+        if first && username == "/noise" {
+            first = false;
+            (reader, writer) = upgrade(reader, writer, admission)?;
+            continue;
+        }
+        first = false;
+        // And may say what they support, once, for `/whois`
+        if let Some(flags) = (username.strip_prefix("/caps ")).filter(|_| capabilities.is_empty()) {
+            capabilities = parse_capabilities(flags);
+            continue;
+        }
+        // A client that lost its connection may reclaim its session, with no need for
+        // a proof of work or password as it already went through that
+        let resume = username.strip_prefix("/resume ").and_then(|rest| {
+            let mut parts = rest.split(' ');
+            let (name, token) = (parts.next()?, parts.next()?);
+            let seq = parts.next().and_then(|seq| seq.parse().ok());
+            let room = parts.next().unwrap_or(rooms::LOBBY);
+            Some((name, token, seq.map(|seq| (seq, room.to_string()))))
+        });
+        // The name the client asked for, and the one it gets
+        let (asked, username) = match resume {
+            Some((name, token, _)) if server.can_resume(name, token) => {
+                return Some(Joined {
+                    username: name.to_string(),
+                    reader,
+                    writer,
+                    held: Vec::new(),
+                    resume: Some(token.to_string()),
+                    since: None,
+                    registered: false,
+                    capabilities,
+                });
+            }
+            // Too late, it joins afresh under the same name, and catches up from the
+            // last room message it saw
+            Some((name, _, seq)) => {
+                since = seq;
+                (name.to_string(), name.to_string())
+            }
+            // Those who give no name come in as a guest
+            None if username.is_empty() || username == "/guest" => (username, server.guest_name()),
+            None => (username.clone(), names::canonical(&username)),
+        };
+        // Registered accounts may log in from another device, their password shows
+        // it's the same user. They may type their name in any case.
+        let account = server
+            .accounts
+            .lock()
+            .unwrap()
+            .find(&username)
+            .map(str::to_string);
+        let registered = account.is_some();
+        let username = account.unwrap_or(username);
+        // Registered names, and those given to guests, were checked already
+        let checked = if registered || server::is_guest_name(&username) {
+            Ok(())
+        } else {
+            server.usernames.lock().unwrap().check(&username)
+        };
+        let mut suggestion = None;
+        let error = if let Err(error) = checked {
+            error
+        } else if !registered && server.accounts.lock().unwrap().is_forgotten(&username) {
+            "That name belonged to an account that was erased".to_string()
+        } else if !server
+            .allowlist
+            .lock()
+            .unwrap()
+            .admits(&username, registered, ip)
+        {
+            "This server is invite-only, and you aren't on its allowlist".to_string()
+        } else if !registered && server.is_taken(&username) {
+            // Ensure the username is unique, offering one that is free instead, e.g. to
+            // a client whose last session hasn't expired yet
+            suggestion = server.free_name(&username);
+            "Username is already taken".to_string()
+        } else {
+            // Clients are told the name they go by, if it isn't the one they sent
+            if username != asked {
+                writer.write_line(&format!("/name {username}")).ok()?;
+            }
+            break username;
+        };
+        if !reject(&mut writer, &error) {
+            return None;
+        }
+        if let Some(suggestion) = suggestion {
+            writer.write_line(&format!("/suggest {suggestion}")).ok()?;
+        }
+    };
+
+    // Reads the client's answer to a challenge, the next line starting with `prefix`.
+    // Clients may send queued messages before they see the challenge, those are held
+    // until they have joined. The first of them is answered with `hint`, if given, for
+    // clients that don't know how to answer.
+    let read_answer = |reader: &mut Reader,
+                       writer: &mut Writer,
+                       held: &mut Vec<String>,
+                       prefix: &str,
+                       what: &str,
+                       hint: Option<&str>| loop {
+        let line = read_line(reader)?;
+        if line.starts_with(prefix) {
+            return Some(line);
+        }
+        if held.len() == MAX_HELD_LINES {
+            reject(writer, &format!("No {what} received"));
+            return None;
+        }
+        if let Some(hint) = hint.filter(|_| held.is_empty()) {
+            writer.write_line(hint).ok()?;
+        }
+        held.push(line);
+    };
+
+    let mut held = Vec::new();
+    if let Some(bits) = admission.pow_bits {
+        let challenge = pow::Challenge::new(bits);
+        writer.write_line(&challenge.line()).ok()?;
         loop {
-            let bytes_read = stream.read(&mut buffer).expect("Failed to read username");
-            username.push_str(String::from_utf8_lossy(&buffer[..bytes_read]).trim());
+            let line = read_answer(
+                &mut reader,
+                &mut writer,
+                &mut held,
+                "/pow ",
+                "proof-of-work solution",
+                None,
+            )?;
+            if challenge.verify(&line) {
+                break;
+            } else if !reject(&mut writer, "Invalid proof-of-work solution") {
+                return None;
+            }
+        }
+    }
 
-            if username.contains(" ") || username.contains("/leave") {
-                writeln!(&mut stream, "Invalid username").expect("Failed to write");
+    // Registered usernames are reserved, and need their password, checked after the
+    // proof of work as hashing it is expensive
+    let account = server.accounts.lock().unwrap().get(&username).cloned();
+    if let Some(account) = &account {
+        writer.write_line("/password").ok()?;
+        let hint = format!("{username} is reserved, please authenticate with /password <password>");
+        let line = read_answer(
+            &mut reader,
+            &mut writer,
+            &mut held,
+            "/password ",
+            "password",
+            Some(&hint),
+        )?;
+        let password = line.trim_start_matches("/password ").trim();
+        match accounts::verify(account, password) {
+            accounts::Login::Valid => {}
+            accounts::Login::Stale => {
+                let mut accounts = server.accounts.lock().unwrap();
+                if let Err(e) = accounts.set_password(&username, password) {
+                    eprintln!("Failed to rehash the password of {username}: {e}");
+                }
+            }
+            accounts::Login::Invalid => {
+                reject(&mut writer, "Wrong password");
+                return None;
             }
+        }
+    }
+    Some(Joined {
+        username,
+        reader,
+        writer,
+        held,
+        resume: None,
+        since,
+        registered: account.is_some(),
+        capabilities,
+    })
+}
 
-            // Ensure the username is unique
-            if active_usernames.lock().unwrap().contains(&username) {
-                writeln!(&mut stream, "Username is already taken").expect("Failed to write");
-                continue;
+/// The capabilities in a `/caps <name>,<name>...` line: names of letters, digits and
+/// dashes, as many as the server keeps. Anything else is left out.
+fn parse_capabilities(flags: &str) -> Vec<String> {
+    flags
+        .split(',')
+        .map(str::trim)
+        .filter(|flag| {
+            (1..=32).contains(&flag.len())
+                && flag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        .take(MAX_CAPABILITIES)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Runs the Noise handshake for a client that opened with `/noise`.
+#[cfg(feature = "noise")]
+fn upgrade(reader: Reader, mut writer: Writer, admission: &Admission) -> Option<(Reader, Writer)> {
+    let (Some(key), Reader::Plain(reader), Writer::Plain(stream)) =
+        (&admission.noise_key, reader, &mut writer)
+    else {
+        let _ = writer.write_line("This server doesn't accept Noise connections");
+        return None;
+    };
+    let stream = stream.try_clone().ok()?;
+    match noise::accept(reader, stream, key) {
+        Ok((reader, writer, client_key)) => {
+            println!("Noise session with client key {}", noise::hex(&client_key));
+            Some((reader, writer))
+        }
+        Err(e) => {
+            println!("Noise handshake failed: {e}");
+            None
+        }
+    }
+}
+
+/// Without Noise support, clients asking for it are turned away.
+#[cfg(not(feature = "noise"))]
+fn upgrade(
+    _reader: Reader,
+    mut writer: Writer,
+    _admission: &Admission,
+) -> Option<(Reader, Writer)> {
+    let _ = writer.write_line("This server doesn't accept Noise connections");
+    None
+}
+
+/// Admits a new connection, whichever transport it came in on: banned addresses and
+/// those that flood the server or hold too many connections open are turned away, as
+/// is everyone once the workers and their queue are taken. Everyone else is handed to
+/// a worker, where the handshake runs and, once they have joined, their messages are
+/// handled.
+fn accept(
+    reader: Reader,
+    mut writer: Writer,
+    ip: IpAddr,
+    server: &Arc<Server>,
+    admission: &Arc<Admission>,
+) {
+    if let Some(ban) = server.banned(ip) {
+        server.stats.refused.bump();
+        println!("Refusing connection from {ip}, {} is banned", ban.cidr);
+        let line = match ban.remaining(SystemTime::now()) {
+            Some(remaining) => format!(
+                "Your address is banned from this server for another {}",
+                duration::format(remaining)
+            ),
+            None => "Your address is banned from this server".to_string(),
+        };
+        let _ = writer.write_line(&line);
+        return;
+    }
+    let connect = admission
+        .flood_guard
+        .lock()
+        .unwrap()
+        .connect(ip, Instant::now());
+    if let Err(blocked_for) = connect {
+        server.stats.refused.bump();
+        refuse(&mut writer, ip, blocked_for);
+        return;
+    }
+    let Some(slot) = server.open_connection(ip) else {
+        server.stats.refused.bump();
+        println!("Refusing connection from {ip}, it has too many open");
+        let _ = writer.write_line("Too many connections from your address");
+        return;
+    };
+
+    let connection = Connection {
+        reader,
+        writer,
+        ip,
+        _slot: slot,
+        server: Arc::clone(server),
+        admission: Arc::clone(admission),
+    };
+    if let Err(mut connection) = admission.pool.submit(connection) {
+        server.stats.refused.bump();
+        println!("Refusing connection from {ip}, every worker is busy");
+        let _ = connection
+            .writer
+            .write_line("The server is full, try again later");
+        return;
+    }
+    server.stats.connections.bump();
+}
+
+/// A connection that was let in, waiting for a worker.
+struct Connection {
+    reader: Reader,
+    writer: Writer,
+    ip: IpAddr,
+    /// Held until the connection closes, however it does.
+    _slot: server::Slot,
+    server: Arc<Server>,
+    admission: Arc<Admission>,
+}
+
+/// Runs a connection on its worker: the handshake, then, once the client has joined,
+/// its messages until it leaves or disconnects.
+fn run(connection: Connection) {
+    let Connection {
+        reader,
+        writer,
+        ip,
+        _slot,
+        server,
+        admission,
+    } = connection;
+    let mut span = Span::root("connection");
+    span.set("client.address", ip);
+    span.set("transport", writer.transport());
+    let origin = admission.lookup.origin(ip);
+    if let Some(origin) = &origin {
+        println!("Connection from {ip} ({origin})");
+    }
+    let handshaking = span.child("handshake");
+    let Some(mut joined) = handshake(reader, writer, ip, &server, &admission) else {
+        println!("Connection closed before a username was chosen");
+        return;
+    };
+    drop(handshaking);
+    span.set("user", &joined.username);
+    // Tell the client what it is talking to, before anything else
+    if joined.writer.write_line(&version::announcement()).is_err() {
+        return;
+    }
+
+    // Arc avoids unecessary `String` allocations
+    let usr = Arc::new(joined.username);
+
+    // Register user, or hand them back their session
+    let session = match joined.resume {
+        Some(token) => match server.resume(&usr, &token, joined.writer, ip, origin) {
+            Ok(session) => {
+                server.events.emit(Event::SessionResumed { username: &usr });
+                session
+            }
+            Err(mut writer) => {
+                let _ = writer.write_line("Your session has expired");
+                return;
+            }
+        },
+        None => {
+            // Messages are numbered and broadcast under this lock, so a new user
+            // misses none between joining and getting into their room
+            let mut rooms = server.rooms.lock().unwrap();
+            match server.join(usr.clone(), joined.writer, ip, origin, joined.registered) {
+                Ok(session) => {
+                    catch_up(&server, &mut rooms, &usr, session, joined.since);
+                    server.events.emit(Event::UserJoined {
+                        username: &usr,
+                        sessions: server.sessions(&usr),
+                    });
+                    session
+                }
+                Err(mut writer) => {
+                    let _ = writer.write_line("Username is already taken");
+                    return;
+                }
             }
-            break;
         }
+    };
 
-        // Arc avoids unecessary `String` allocations
-        let usr = Arc::new(username);
+    server.set_capabilities(&usr, session, joined.capabilities);
 
-        // Register user
-        println!("User {} has joined", usr.as_str());
-        active_usernames.lock().unwrap().insert(usr.clone());
-        user_list
-            .lock()
-            .unwrap()
-            .insert(usr.clone(), stream.try_clone().expect("Failed to clone"));
+    let lines = joined.held.into_iter().map(Ok).chain(joined.reader);
+    handle_client(lines, usr, session, server, &span);
+}
 
-        // Spawn a new thread to handle this client's connection
-        let user_list_clone = Arc::clone(&user_list);
-        let active_usrs_clone = Arc::clone(&active_usernames);
-        thread::spawn(move || {
-            handle_client(stream, usr, user_list_clone, active_usrs_clone);
-        });
+/// Puts a client that just joined in a room and tells it which with `/room <name>`:
+/// the room its user's other devices are in, or the one it was in before it lost its
+/// connection if that is still open and it may still join it, or the lobby. A client
+/// that comes back to its room is sent the messages after `since`, the last one it
+/// saw. Then it gets `/seq <latest>`, the number of the room's latest message. A
+/// client that saw a higher number than that was connected before the server
+/// restarted and numbered messages from 1 again.
+fn catch_up(
+    server: &Server,
+    rooms: &mut Rooms,
+    username: &Arc<String>,
+    session: SessionId,
+    since: Option<(u64, String)>,
+) {
+    let role = server.role(username).unwrap_or(Role::User);
+    let name = match (rooms.room_of(username), &since) {
+        (Some(name), _) => name.to_string(),
+        (None, Some((_, room)))
+            if rooms
+                .get(room)
+                .is_some_and(|room| room.access.admits(username, role) && !room.is_full()) =>
+        {
+            room.clone()
+        }
+        _ => rooms::LOBBY.to_string(),
+    };
+    rooms.enter(username, &name);
+    let room = rooms.get(&name).expect("just entered");
+    let mut lines = room.welcome(&name);
+    if let Some((seq, _)) = since.filter(|(_, room)| *room == name) {
+        // The missed messages go right before `/seq`
+        let latest = lines.pop().expect("welcome ends with /seq");
+        lines.extend(
+            room.history
+                .range(seq.saturating_add(1)..=u64::MAX)
+                .iter()
+                .map(|message| message.line()),
+        );
+        lines.push(latest);
+    }
+    server.send_to_session(username, session, &lines.join("\n"));
+}
+
+/// Main function that initializes the server and listens for incoming connections.
+/// Each connection gets its own thread, where the server waits for a username from
+/// the client, verifies its uniqueness, and then allows the user to join the chat room.
+fn main() {
+    let args = Args::parse();
+    if let Some(Command::Account { command }) = args.command {
+        // New accounts are held to the config file's username policy
+        let usernames = match args.config.as_deref().map(config::load).transpose() {
+            Ok(config) => config.map(|config| config.usernames).unwrap_or_default(),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = admin::run(command, &args.data_dir, &usernames) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    let listeners = listen::bind(&args.bind, usize::from(args.acceptors)).expect("Failed to bind");
+    let time_format = match &args.time_format {
+        Some(today) => TimeFormat::absolute(today, args.older_time_format.as_deref())
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }),
+        None => TimeFormat::Relative,
+    };
+    let server = Server::new(
+        &args.data_dir,
+        time_format,
+        args.retention,
+        args.config.clone(),
+    )
+    .expect("Failed to open the data directory");
+    if let Some(config) = &args.config {
+        if let Err(e) = server.load_config(config) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    buffers::BUFFERS.configure(args.write_buffer);
+    if let Some(endpoint) = args.otlp {
+        otlp::start(endpoint);
+    }
+    let admission = Arc::new(Admission {
+        flood_guard: Mutex::new(FloodGuard::default()),
+        pow_bits: args.pow_bits,
+        proxies: args.proxy_protocol,
+        read_buffer: args.read_buffer,
+        #[cfg(feature = "noise")]
+        noise_key: args.noise.then(|| {
+            let key = noise::StaticKey::load_or_generate(&args.data_dir.join("noise.key"))
+                .expect("Failed to load the Noise key");
+            println!(
+                "Accepting Noise connections, public key {}",
+                noise::hex(&key.public)
+            );
+            key
+        }),
+        lookup: origin::Lookup {
+            reverse_dns: args.reverse_dns,
+            geoip: args.geoip.as_deref().map(|path| {
+                mmdb::Mmdb::open(path).unwrap_or_else(|e| {
+                    eprintln!("Failed to open the GeoIP database: {e}");
+                    std::process::exit(1);
+                })
+            }),
+        },
+        pool: pool::Pool::new(args.workers, args.worker_queue, run),
+    });
+
+    // Commands typed on the server's stdin run with admin privileges
+    console::spawn(Arc::clone(&server));
+
+    if args.advertise {
+        let port = listeners[0]
+            .local_addr()
+            .expect("Failed to get the port")
+            .port();
+        discovery::advertise(&args.name, port).expect("Failed to start discovery");
+        #[cfg(feature = "mdns")]
+        let _mdns = discovery::register_mdns(&args.name, port).expect("Failed to start mDNS");
+        println!(
+            "Advertising as \"{}\" on UDP port {}",
+            args.name,
+            discovery::DISCOVERY_PORT
+        );
+    }
+
+    // Tor connects to us locally, so an unspecified bind address is reached over loopback
+    let _onion = args.tor_control.map(|control| {
+        let mut local = listeners[0].local_addr().expect("Failed to get the port");
+        if local.ip().is_unspecified() {
+            local.set_ip(IpAddr::from([127, 0, 0, 1]));
+        }
+        let service = tor::publish(
+            control,
+            &args.data_dir.join("onion.key"),
+            local.port(),
+            local,
+        )
+        .expect("Failed to publish the onion service");
+        println!("Published as {}:{}", service.address, local.port());
+        service
+    });
+
+    #[cfg(feature = "quic")]
+    if let Some(address) = args.quic {
+        let server = Arc::clone(&server);
+        let admission = Arc::clone(&admission);
+        let on_connection =
+            move |reader, writer, ip| accept(reader, writer, ip, &server, &admission);
+        let address = quic::listen(address, &args.data_dir, Arc::new(on_connection))
+            .expect("Failed to start the QUIC listener");
+        println!(
+            "Accepting QUIC connections on {address}, clients need a copy of {}",
+            args.data_dir.join("quic-cert.der").display()
+        );
+    }
+
+    if let Some(address) = &args.http {
+        let listener = TcpListener::bind(address).expect("Failed to bind the HTTP API");
+        println!(
+            "Serving the HTTP API on {}",
+            listener.local_addr().expect("Failed to get the port")
+        );
+        let (server, admission) = (Arc::clone(&server), Arc::clone(&admission));
+        let on_connection = {
+            let server = Arc::clone(&server);
+            move |reader, writer, ip| accept(reader, writer, ip, &server, &admission)
+        };
+        thread::spawn(move || http::serve(listener, &server, Box::new(on_connection)));
+    }
+
+    // Every listener but the first gets a thread of its own
+    let mut listeners = listeners.into_iter();
+    let first = listeners.next().expect("There is always a listener");
+    for listener in listeners {
+        let server = Arc::clone(&server);
+        let admission = Arc::clone(&admission);
+        thread::spawn(move || serve(listener, &server, &admission));
     }
+    serve(first, &server, &admission);
 }
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Starts the metadata at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+/// Zero bytes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+/// How deeply values may nest, pointers included, so a corrupt file can't recurse
+/// forever.
+const MAX_DEPTH: usize = 32;
+
+/// A value from a MaxMind DB file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Map(BTreeMap<String, Value>),
+    Array(Vec<Value>),
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    /// Follows a path of map keys, e.g. `["country", "iso_code"]`.
+    pub fn get(&self, path: &[&str]) -> Option<&Value> {
+        path.iter().try_fold(self, |value, key| match value {
+            Value::Map(map) => map.get(*key),
+            _ => None,
+        })
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Value::Uint(n) => usize::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind DB file, such as GeoLite2-City.mmdb, read into memory: a binary search
+/// tree over the bits of the address that leads into a section of data records.
+///
+/// Only lookups are supported, which is all the server needs.
+pub struct Mmdb {
+    bytes: Vec<u8>,
+    node_count: usize,
+    /// Bits per record, two records to a node.
+    record_size: usize,
+    ip_version: usize,
+    data_start: usize,
+}
+
+impl Mmdb {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::parse(bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Self, String> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("Not a MaxMind DB file")?;
+        let metadata = &bytes[marker + METADATA_MARKER.len()..];
+        let (metadata, _) = decode(metadata, 0, 0)?;
+        let field = |name| {
+            metadata
+                .get(&[name])
+                .and_then(Value::as_usize)
+                .ok_or_else(|| format!("Missing {name} in the metadata"))
+        };
+        let node_count = field("node_count")?;
+        let record_size = field("record_size")?;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("Unsupported record size {record_size}"));
+        }
+        let data_start = node_count
+            .checked_mul(record_size / 4)
+            .and_then(|tree| tree.checked_add(DATA_SEPARATOR))
+            .filter(|&start| start <= marker)
+            .ok_or("The search tree is larger than the file")?;
+        Ok(Mmdb {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    /// Looks up the data record for `ip`, if the file has one.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, len) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(ip) => (u128::from(ip), 128),
+        };
+        let mut node = 0;
+        // IPv4 addresses live under ::/96 in an IPv6 tree
+        if len == 32 && self.ip_version == 6 {
+            for _ in 0..96 {
+                if node >= self.node_count {
+                    break;
+                }
+                node = self.record(node, 0)?;
+            }
+        }
+        for i in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> i) as usize & 1)?;
+        }
+        // Past the last node are pointers into the data section, the first of them
+        // meaning there is no data
+        let offset = node.checked_sub(self.node_count + DATA_SEPARATOR)?;
+        let data = &self.bytes[self.data_start..];
+        decode(data, offset, 0).ok().map(|(value, _)| value)
+    }
+
+    /// One of a node's two records: the left for a 0 bit, the right for a 1.
+    fn record(&self, node: usize, side: usize) -> Option<usize> {
+        let node_size = self.record_size / 4;
+        let b = self.bytes.get(node * node_size..(node + 1) * node_size)?;
+        let record = match (self.record_size, side) {
+            (28, 0) => usize::from(b[3] >> 4) << 24 | be(&b[..3]),
+            (28, _) => usize::from(b[3] & 0x0f) << 24 | be(&b[4..]),
+            (size, side) => {
+                let len = size / 8;
+                be(&b[side * len..(side + 1) * len])
+            }
+        };
+        Some(record)
+    }
+}
+
+/// Reads a big-endian number.
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &byte| n << 8 | usize::from(byte))
+}
+
+/// Decodes the value at `offset` in `data`, the data section (or the metadata),
+/// returning it and where the next one starts.
+fn decode(data: &[u8], offset: usize, depth: usize) -> Result<(Value, usize), String> {
+    if depth > MAX_DEPTH {
+        return Err("Values nest too deeply".to_string());
+    }
+    let take = |from: usize, len: usize| {
+        data.get(from..from + len)
+            .ok_or_else(|| "Truncated data section".to_string())
+    };
+    let control = take(offset, 1)?[0];
+    let mut pos = offset + 1;
+    let mut kind = usize::from(control >> 5);
+    if kind == 1 {
+        // A pointer to a value elsewhere in the data section
+        let high = usize::from(control & 0x07);
+        let (len, base) = match (control >> 3) & 0x03 {
+            0 => (1, 0),
+            1 => (2, 2048),
+            2 => (3, 526_336),
+            _ => (4, 0),
+        };
+        let low = be(take(pos, len)?);
+        let pointer = if len == 4 {
+            low
+        } else {
+            (high << (8 * len) | low) + base
+        };
+        let (value, _) = decode(data, pointer, depth + 1)?;
+        return Ok((value, pos + len));
+    }
+    if kind == 0 {
+        kind = 7 + usize::from(take(pos, 1)?[0]);
+        pos += 1;
+    }
+    let mut size = usize::from(control & 0x1f);
+    if size >= 29 {
+        let len = size - 28;
+        size = [29, 285, 65_821][len - 1] + be(take(pos, len)?);
+        pos += len;
+    }
+    let value = match kind {
+        2 => {
+            Value::String(String::from_utf8(take(pos, size)?.to_vec()).map_err(|e| e.to_string())?)
+        }
+        3 => Value::Double(f64::from_be_bytes(
+            take(pos, 8)?.try_into().map_err(|_| "Invalid double")?,
+        )),
+        4 => Value::Bytes(take(pos, size)?.to_vec()),
+        5 | 6 | 9 | 10 if size <= 16 => Value::Uint(
+            take(pos, size)?
+                .iter()
+                .fold(0, |n, &byte| n << 8 | u128::from(byte)),
+        ),
+        8 if size <= 4 => Value::Int(be(take(pos, size)?) as u32 as i32),
+        7 => {
+            let mut map = BTreeMap::new();
+            for _ in 0..size {
+                let (key, next) = decode(data, pos, depth + 1)?;
+                let Value::String(key) = key else {
+                    return Err("Map keys must be strings".to_string());
+                };
+                let (value, next) = decode(data, next, depth + 1)?;
+                map.insert(key, value);
+                pos = next;
+            }
+            return Ok((Value::Map(map), pos));
+        }
+        11 => {
+            let mut array = Vec::new();
+            for _ in 0..size {
+                let (value, next) = decode(data, pos, depth + 1)?;
+                array.push(value);
+                pos = next;
+            }
+            return Ok((Value::Array(array), pos));
+        }
+        14 => return Ok((Value::Bool(size != 0), pos)),
+        15 => Value::Float(f32::from_be_bytes(
+            take(pos, 4)?.try_into().map_err(|_| "Invalid float")?,
+        )),
+        _ => return Err(format!("Unsupported data type {kind}")),
+    };
+    let size = match kind {
+        3 => 8,
+        15 => 4,
+        _ => size,
+    };
+    Ok((value, pos + size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = vec![2 << 5 | s.len() as u8];
+        bytes.extend_from_slice(s.as_bytes());
+        bytes
+    }
+
+    fn map(pairs: usize) -> Vec<u8> {
+        vec![7 << 5 | pairs as u8]
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        let mut bytes = vec![5 << 5 | 2];
+        bytes.extend_from_slice(&n.to_be_bytes());
+        bytes
+    }
+
+    /// A database with one node, where addresses starting with a 1 bit (128.0.0.0/1)
+    /// are in Germany and the others aren't in it.
+    fn database() -> Vec<u8> {
+        let node_count = 1;
+        let mut bytes = vec![];
+        // The left record is empty, the right one points at the data's offset 0
+        bytes.extend_from_slice(&(node_count as u32).to_be_bytes()[1..]);
+        bytes.extend_from_slice(&((node_count + DATA_SEPARATOR) as u32).to_be_bytes()[1..]);
+        bytes.extend_from_slice(&[0; DATA_SEPARATOR]);
+        let data = [
+            map(2),
+            string("country"),
+            map(1),
+            string("iso_code"),
+            string("DE"),
+            string("city"),
+            map(1),
+            string("names"),
+            map(1),
+            string("en"),
+            // A pointer back to "DE", at offset 19, standing in for a city name
+            vec![1 << 5, 19],
+        ];
+        bytes.extend(data.concat());
+        bytes.extend_from_slice(METADATA_MARKER);
+        let metadata = [
+            map(3),
+            string("node_count"),
+            uint16(node_count as u16),
+            string("record_size"),
+            uint16(24),
+            string("ip_version"),
+            uint16(4),
+        ];
+        bytes.extend(metadata.concat());
+        bytes
+    }
+
+    #[test]
+    fn test_lookup() {
+        let db = Mmdb::parse(database()).unwrap();
+        let found = db.lookup("203.0.113.7".parse().unwrap()).unwrap();
+        let field = |path: &[&str]| found.get(path).and_then(Value::as_str);
+        assert_eq!(field(&["country", "iso_code"]), Some("DE"));
+        assert_eq!(field(&["city", "names", "en"]), Some("DE"));
+        assert_eq!(field(&["continent", "code"]), None);
+        assert!(db.lookup("10.0.0.1".parse().unwrap()).is_none());
+        assert!(db.lookup("2001:db8::1".parse().unwrap()).is_none());
+
+        assert!(Mmdb::parse(b"not a database".to_vec()).is_err());
+    }
+}
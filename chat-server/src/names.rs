@@ -0,0 +1,300 @@
+use std::str::FromStr;
+
+/// The Latin letters with a diacritic that have a precomposed form, by the combining
+/// mark they decompose into: the letters without it, then the same letters with it.
+/// Taken from the Unicode Character Database, for U+00C0 to U+024F.
+const COMPOSITIONS: &[(char, &str, &str)] = &[
+    ('\u{300}', "AEIOUaeiouÜüNn", "ÀÈÌÒÙàèìòùǛǜǸǹ"),
+    (
+        '\u{301}',
+        "AEIOUYaeiouyCcLlNnRrSsZzÜüGgÅåÆæØø",
+        "ÁÉÍÓÚÝáéíóúýĆćĹĺŃńŔŕŚśŹźǗǘǴǵǺǻǼǽǾǿ",
+    ),
+    (
+        '\u{302}',
+        "AEIOUaeiouCcGgHhJjSsWwYy",
+        "ÂÊÎÔÛâêîôûĈĉĜĝĤĥĴĵŜŝŴŵŶŷ",
+    ),
+    ('\u{303}', "ANOanoIiUu", "ÃÑÕãñõĨĩŨũ"),
+    (
+        '\u{304}',
+        "AaEeIiOoUuÜüÄäȦȧÆæǪǫÖöÕõȮȯYy",
+        "ĀāĒēĪīŌōŪūǕǖǞǟǠǡǢǣǬǭȪȫȬȭȰȱȲȳ",
+    ),
+    ('\u{306}', "AaEeGgIiOoUu", "ĂăĔĕĞğĬĭŎŏŬŭ"),
+    ('\u{307}', "CcEeGgIZzAaOo", "ĊċĖėĠġİŻżȦȧȮȯ"),
+    ('\u{308}', "AEIOUaeiouyY", "ÄËÏÖÜäëïöüÿŸ"),
+    ('\u{30a}', "AaUu", "ÅåŮů"),
+    ('\u{30b}', "OoUu", "ŐőŰű"),
+    (
+        '\u{30c}',
+        "CcDdEeLlNnRrSsTtZzAaIiOoUuÜüGgKkƷʒjHh",
+        "ČčĎďĚěĽľŇňŘřŠšŤťŽžǍǎǏǐǑǒǓǔǙǚǦǧǨǩǮǯǰȞȟ",
+    ),
+    ('\u{30f}', "AaEeIiOoRrUu", "ȀȁȄȅȈȉȌȍȐȑȔȕ"),
+    ('\u{311}', "AaEeIiOoRrUu", "ȂȃȆȇȊȋȎȏȒȓȖȗ"),
+    ('\u{31b}', "OoUu", "ƠơƯư"),
+    ('\u{326}', "SsTt", "ȘșȚț"),
+    ('\u{327}', "CcGgKkLlNnRrSsTtEe", "ÇçĢģĶķĻļŅņŖŗŞşŢţȨȩ"),
+    ('\u{328}', "AaEeIiUuOo", "ĄąĘęĮįŲųǪǫ"),
+];
+
+/// Letters from other scripts, and digits, that look like a Latin letter, and the
+/// letter. A capital I looks like a small L, so `i` and `l` count as one letter.
+const CONFUSABLES: &[(&str, &str)] = &[
+    // Cyrillic
+    ("аеорсухѕіјһԁԛԝӏ", "aeopcyxsljhdqwl"),
+    ("АВЕКМНОРСТХУЅІЈ", "abekmhopctxyslj"),
+    // Greek
+    ("οναιρκχυ", "ovalpkxu"),
+    ("ΑΒΕΖΗΙΚΜΝΟΡΤΥΧ", "abezhlkmnoptyx"),
+    // Latin and digits
+    ("iIıɡℓ01", "lllglol"),
+];
+
+/// Which usernames may be picked, as the config file says. Names that are registered
+/// already, and those the server gives guests, aren't held to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub min_length: usize,
+    pub max_length: Option<usize>,
+    /// The characters names may be made of, if not any.
+    pub allow: Option<Vec<Class>>,
+    /// Words names may not contain, however they are spelled; see [`skeleton`].
+    pub banned: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            min_length: 1,
+            max_length: None,
+            allow: None,
+            banned: Vec::new(),
+        }
+    }
+}
+
+/// A kind of character usernames may be made of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Class {
+    /// Letters of any script.
+    Letters,
+    /// `a` to `z`, either case.
+    Latin,
+    /// `0` to `9`.
+    Digits,
+    /// These characters, e.g. `-_.`.
+    Chars(String),
+}
+
+impl FromStr for Class {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "letters" => Ok(Class::Letters),
+            "latin" => Ok(Class::Latin),
+            "digits" => Ok(Class::Digits),
+            "" => Err("An empty character class".to_string()),
+            chars => Ok(Class::Chars(chars.to_string())),
+        }
+    }
+}
+
+impl Class {
+    fn admits(&self, c: char) -> bool {
+        match self {
+            Class::Letters => c.is_alphabetic(),
+            Class::Latin => c.is_ascii_alphabetic(),
+            Class::Digits => c.is_ascii_digit(),
+            Class::Chars(chars) => chars.contains(c),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Class::Letters => "letters".to_string(),
+            Class::Latin => "the letters a to z".to_string(),
+            Class::Digits => "digits".to_string(),
+            Class::Chars(chars) => chars.clone(),
+        }
+    }
+}
+
+impl Policy {
+    /// Checks a username against the policy, and the rules that always hold: it isn't
+    /// empty, has no whitespace or control characters, and doesn't start with `/`.
+    /// Every way of picking a name goes through here.
+    pub fn check(&self, name: &str) -> Result<(), String> {
+        if name.is_empty()
+            || name.starts_with('/')
+            || name.contains(|c: char| c.is_whitespace() || c.is_control())
+        {
+            return Err("Invalid username".to_string());
+        }
+        let length = name.chars().count();
+        if length < self.min_length || self.max_length.is_some_and(|max| length > max) {
+            return Err(match self.max_length {
+                Some(max) => format!("Usernames are {} to {max} characters long", self.min_length),
+                None => format!("Usernames are at least {} characters long", self.min_length),
+            });
+        }
+        if let Some(allow) = &self.allow {
+            if !name
+                .chars()
+                .all(|c| allow.iter().any(|class| class.admits(c)))
+            {
+                let classes: Vec<String> = allow.iter().map(Class::describe).collect();
+                return Err(format!(
+                    "Usernames may only be made of {}",
+                    classes.join(", ")
+                ));
+            }
+        }
+        let skeleton = skeleton(name);
+        if let Some(word) =
+            (self.banned.iter()).find(|word| skeleton.contains(&self::skeleton(word)))
+        {
+            return Err(format!("Usernames may not contain \"{word}\""));
+        }
+        Ok(())
+    }
+}
+
+/// The form a username is kept and shown in: Latin letters followed by a combining
+/// diacritic are composed into one, as in Unicode's NFC, so the same name typed on
+/// two keyboards is the same string.
+pub fn canonical(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        let composed = out.chars().last().and_then(|base| compose(base, c));
+        match composed {
+            Some(composed) => {
+                out.pop();
+                out.push(composed);
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+/// What a username looks like, for telling names apart: without case, diacritics or
+/// width, and with lookalike letters from other scripts spelled in Latin ones. Two
+/// names with the same skeleton would pass for each other, e.g. `Alice`, `alice`,
+/// `alicé` and `аlice` with a Cyrillic `а`.
+pub fn skeleton(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        let mut base = c;
+        while let Some((letter, _)) = decompose(base) {
+            base = letter;
+        }
+        if is_combining(base) {
+            continue;
+        }
+        // Fullwidth forms, e.g. `ａ`
+        if ('\u{ff01}'..='\u{ff5e}').contains(&base) {
+            base = char::from_u32(base as u32 - 0xfee0).unwrap_or(base);
+        }
+        match confusable(base) {
+            Some(letter) => out.push(letter),
+            None => out.extend(base.to_lowercase()),
+        }
+    }
+    out
+}
+
+fn compose(base: char, mark: char) -> Option<char> {
+    let (_, bases, composed) = COMPOSITIONS.iter().find(|(m, ..)| *m == mark)?;
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+fn decompose(c: char) -> Option<(char, char)> {
+    COMPOSITIONS.iter().find_map(|(mark, bases, composed)| {
+        let index = composed.chars().position(|composed| composed == c)?;
+        Some((bases.chars().nth(index)?, *mark))
+    })
+}
+
+fn is_combining(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36f}' | '\u{1ab0}'..='\u{1aff}' | '\u{1dc0}'..='\u{1dff}' | '\u{20d0}'..='\u{20ff}' | '\u{fe20}'..='\u{fe2f}')
+}
+
+fn confusable(c: char) -> Option<char> {
+    CONFUSABLES.iter().find_map(|(from, to)| {
+        let index = from.chars().position(|from| from == c)?;
+        to.chars().nth(index)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_composes_diacritics() {
+        assert_eq!(canonical("Jose\u{301}"), "José");
+        assert_eq!(canonical("José"), "José");
+        // Composed twice: ü, then ǖ
+        assert_eq!(canonical("u\u{308}\u{304}"), "ǖ");
+        assert_eq!(canonical("bob"), "bob");
+    }
+
+    #[test]
+    fn test_lookalikes_share_a_skeleton() {
+        let alice = skeleton("alice");
+        for lookalike in [
+            "Alice",
+            "ALICE",
+            "alicé",
+            "alice\u{301}",
+            "\u{430}lice",
+            "aIice",
+            "ａｌｉｃｅ",
+        ] {
+            assert_eq!(skeleton(lookalike), alice, "{lookalike}");
+        }
+        assert_ne!(skeleton("alina"), alice);
+        assert_eq!(skeleton("b0b"), skeleton("BOB"));
+        assert_eq!(skeleton("Ωmega"), "ωmega");
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::default();
+        assert!(policy.check("José").is_ok());
+        assert!(policy.check("").is_err());
+        assert!(policy.check("/quit").is_err());
+        assert!(policy.check("bob\tsmith").is_err());
+
+        let policy = Policy {
+            min_length: 3,
+            max_length: Some(8),
+            allow: Some(vec![Class::Latin, Class::Digits, "_-".parse().unwrap()]),
+            banned: vec!["admin".to_string()],
+        };
+        assert!(policy.check("bob_2").is_ok());
+        assert_eq!(
+            policy.check("bo"),
+            Err("Usernames are 3 to 8 characters long".to_string())
+        );
+        assert_eq!(
+            policy.check("josé"),
+            Err("Usernames may only be made of the letters a to z, digits, _-".to_string())
+        );
+        assert!(policy.check("xAdm1nx").is_err());
+    }
+
+    #[test]
+    fn test_tables_line_up() {
+        for (_, bases, composed) in COMPOSITIONS {
+            assert_eq!(bases.chars().count(), composed.chars().count());
+        }
+        for (from, to) in CONFUSABLES {
+            assert_eq!(from.chars().count(), to.chars().count(), "{from}");
+        }
+    }
+}
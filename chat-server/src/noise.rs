@@ -0,0 +1,252 @@
+use snow::{Builder, StatelessTransportState};
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::transport;
+
+/// Handshake pattern and primitives. XX transmits both static keys, so neither side
+/// needs to know the other's key beforehand.
+const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, and the authentication tag added to every one of them.
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// The server's long-term Noise key pair.
+pub struct StaticKey {
+    private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl StaticKey {
+    /// Loads the key pair from `path`, generating (and saving) one on first use.
+    /// The file holds the private and public key, hex encoded, one per line.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        if let Ok(contents) = fs::read_to_string(path) {
+            let mut lines = contents.lines().map(from_hex);
+            return match (lines.next().flatten(), lines.next().flatten()) {
+                (Some(private), Some(public)) => Ok(StaticKey { private, public }),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid key file", path.display()),
+                )),
+            };
+        }
+        let keypair = Builder::new(params())
+            .generate_keypair()
+            .map_err(io::Error::other)?;
+        let contents = format!("{}\n{}\n", hex(&keypair.private), hex(&keypair.public));
+        fs::write(path, contents)?;
+        Ok(StaticKey {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+}
+
+fn params() -> snow::params::NoiseParams {
+    PARAMS.parse().expect("valid Noise parameters")
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Reads a length-prefixed Noise message.
+fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut frame = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// Prefixes a Noise message with its length.
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = (message.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Runs the responder side of the handshake on a connection whose client asked for
+/// Noise, returning the encrypted halves of the connection and the client's static key.
+pub fn accept(
+    mut reader: BufReader<TcpStream>,
+    mut stream: TcpStream,
+    key: &StaticKey,
+) -> io::Result<(transport::Reader, transport::Writer, Vec<u8>)> {
+    let mut handshake = Builder::new(params())
+        .local_private_key(&key.private)
+        .build_responder()
+        .map_err(io::Error::other)?;
+    let mut buf = vec![0; MAX_MESSAGE];
+
+    // -> e
+    handshake
+        .read_message(&read_frame(&mut reader)?, &mut buf)
+        .map_err(invalid)?;
+    // <- e, ee, s, es
+    let len = handshake.write_message(&[], &mut buf).map_err(invalid)?;
+    stream.write_all(&frame(&buf[..len]))?;
+    // -> s, se
+    handshake
+        .read_message(&read_frame(&mut reader)?, &mut buf)
+        .map_err(invalid)?;
+
+    let client_key = handshake.get_remote_static().unwrap_or_default().to_vec();
+    let transport = Arc::new(handshake.into_stateless_transport_mode().map_err(invalid)?);
+    let reader = Reader {
+        inner: reader,
+        transport: Arc::clone(&transport),
+        nonce: 0,
+        buffer: Vec::new(),
+    };
+    let writer = Writer {
+        stream,
+        transport,
+        nonce: 0,
+    };
+    Ok((
+        transport::Reader::Noise(reader),
+        transport::Writer::Noise(writer),
+        client_key,
+    ))
+}
+
+fn invalid(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Decrypts messages from the client into lines. Each direction has its own nonce,
+/// so the reader and writer can be used from different threads.
+pub struct Reader {
+    inner: BufReader<TcpStream>,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// Decrypted data that doesn't make up a complete line yet.
+    buffer: Vec<u8>,
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                return Some(Ok(line.trim_end_matches(['\r', '\n']).to_string()));
+            }
+            let message = match read_frame(&mut self.inner) {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            let mut payload = vec![0; message.len()];
+            match self
+                .transport
+                .read_message(self.nonce, &message, &mut payload)
+            {
+                Ok(len) => self.buffer.extend_from_slice(&payload[..len]),
+                Err(e) => return Some(Err(invalid(e))),
+            }
+            self.nonce += 1;
+        }
+    }
+}
+
+/// Encrypts lines sent to the client.
+pub struct Writer {
+    stream: TcpStream,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Writer {
+    /// Encrypts `line` and its newline, split over as many messages as it takes,
+    /// and sends them in a single write.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let plaintext = format!("{line}\n");
+        let mut out = Vec::new();
+        let mut message = vec![0; MAX_MESSAGE];
+        for chunk in plaintext.as_bytes().chunks(MAX_MESSAGE - TAG_LEN) {
+            let len = self
+                .transport
+                .write_message(self.nonce, chunk, &mut message)
+                .map_err(invalid)?;
+            self.nonce += 1;
+            out.extend_from_slice(&frame(&message[..len]));
+        }
+        self.stream.write_all(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_encrypted_lines_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let keypair = Builder::new(params()).generate_keypair().unwrap();
+            let mut handshake = Builder::new(params())
+                .local_private_key(&keypair.private)
+                .build_initiator()
+                .unwrap();
+            let mut buf = vec![0; MAX_MESSAGE];
+            let len = handshake.write_message(&[], &mut buf).unwrap();
+            stream.write_all(&frame(&buf[..len])).unwrap();
+            handshake
+                .read_message(&read_frame(&mut stream).unwrap(), &mut buf)
+                .unwrap();
+            let len = handshake.write_message(&[], &mut buf).unwrap();
+            stream.write_all(&frame(&buf[..len])).unwrap();
+
+            let mut transport = handshake.into_transport_mode().unwrap();
+            let len = transport.write_message(b"hello\nwor", &mut buf).unwrap();
+            stream.write_all(&frame(&buf[..len])).unwrap();
+            let len = transport.write_message(b"ld\n", &mut buf).unwrap();
+            stream.write_all(&frame(&buf[..len])).unwrap();
+
+            let message = read_frame(&mut stream).unwrap();
+            let mut payload = vec![0; message.len()];
+            let len = transport.read_message(&message, &mut payload).unwrap();
+            (keypair.public, payload[..len].to_vec())
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let path = std::env::temp_dir().join(format!("noise-{}.key", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let key = StaticKey::load_or_generate(&path).unwrap();
+        assert_eq!(
+            StaticKey::load_or_generate(&path).unwrap().public,
+            key.public
+        );
+        fs::remove_file(&path).unwrap();
+
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        let (mut reader, mut writer, client_key) = accept(reader, stream, &key).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), "hello");
+        assert_eq!(reader.next().unwrap().unwrap(), "world");
+        writer.write_line("welcome").unwrap();
+
+        let (public, reply) = client.join().unwrap();
+        assert_eq!(client_key, public);
+        assert_eq!(reply, b"welcome\n");
+    }
+}
@@ -0,0 +1,114 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+
+use crate::mmdb::{Mmdb, Value};
+
+/// Longest host name `getnameinfo` returns, with its terminating nul.
+const MAX_HOST: usize = 1025;
+
+/// What is known about where a connection comes from, for operators diagnosing
+/// abuse.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// The address's host name, from reverse DNS. Whoever controls the address picks
+    /// it, so it is a hint rather than proof.
+    pub host: Option<String>,
+    pub city: Option<String>,
+    /// The country's ISO code, e.g. `DE`.
+    pub country: Option<String>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [&self.host, &self.city, &self.country];
+        let parts: Vec<_> = parts.into_iter().flatten().map(String::as_str).collect();
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Looks up where connecting addresses are. Both lookups give away more about users
+/// than their address does, so they are off unless the operator turns them on.
+#[derive(Default)]
+pub struct Lookup {
+    /// Resolve each address's host name.
+    pub reverse_dns: bool,
+    /// The GeoIP database to look addresses up in, e.g. GeoLite2-City.mmdb.
+    pub geoip: Option<Mmdb>,
+}
+
+impl Lookup {
+    /// Looks up `ip`, returning `None` if nothing is known about it. Reverse DNS can
+    /// take a while, so this runs on the connection's own thread.
+    pub fn origin(&self, ip: IpAddr) -> Option<Origin> {
+        let mut origin = Origin {
+            host: self.reverse_dns.then(|| reverse_dns(ip)).flatten(),
+            ..Origin::default()
+        };
+        if let Some(found) = self.geoip.as_ref().and_then(|geoip| geoip.lookup(ip)) {
+            let field = |path: &[&str]| found.get(path).and_then(Value::as_str).map(String::from);
+            origin.city = field(&["city", "names", "en"]);
+            origin.country = field(&["country", "iso_code"]);
+        }
+        (origin != Origin::default()).then_some(origin)
+    }
+}
+
+/// Asks the system's resolver for the host name of `ip`.
+fn reverse_dns(ip: IpAddr) -> Option<String> {
+    let mut host = [0 as libc::c_char; MAX_HOST];
+    // SAFETY: `addr` points at a socket address of `len` bytes, and `host` is as long
+    // as we say
+    let mut resolve = |addr: *const libc::sockaddr, len: usize| unsafe {
+        libc::getnameinfo(
+            addr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    let result = match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            // SAFETY: all zeroes is a valid sockaddr_in
+            let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+            resolve(ptr::addr_of!(addr).cast(), mem::size_of_val(&addr))
+        }
+        IpAddr::V6(ip) => {
+            // SAFETY: all zeroes is a valid sockaddr_in6
+            let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr.sin6_addr.s6_addr = ip.octets();
+            resolve(ptr::addr_of!(addr).cast(), mem::size_of_val(&addr))
+        }
+    };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: getnameinfo succeeded, so `host` holds a nul-terminated string
+    let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+    host.to_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let origin = Origin {
+            host: Some("dsl-1.example.net".to_string()),
+            city: None,
+            country: Some("DE".to_string()),
+        };
+        assert_eq!(origin.to_string(), "dsl-1.example.net, DE");
+        // Nothing is looked up unless it is turned on
+        assert_eq!(Lookup::default().origin(IpAddr::from([127, 0, 0, 1])), None);
+    }
+}
@@ -0,0 +1,280 @@
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::token;
+
+/// Spans waiting to be exported; any more are dropped rather than slowing the server.
+const QUEUE: usize = 4096;
+/// The most spans sent in one request.
+const BATCH: usize = 512;
+/// How long a span waits for others to be sent with.
+const FLUSH_EVERY: Duration = Duration::from_secs(5);
+/// How long the collector may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where finished spans go, once `--otlp` has started the exporter.
+static EXPORTER: OnceLock<SyncSender<Finished>> = OnceLock::new();
+
+/// An OTLP/HTTP collector's address, e.g. `http://localhost:4318`. Spans are posted to
+/// its `/v1/traces` as JSON. There is no TLS: run the collector as a local agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// `host:port`, 4318 (OTLP/HTTP) if the URL has no port.
+    address: String,
+    /// The path spans are posted to.
+    path: String,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("The OTLP endpoint must be an http:// URL: {s}"))?;
+        let (authority, base) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("The OTLP endpoint has no host: {s}"));
+        }
+        let address = match authority.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_string(),
+            _ => format!("{authority}:4318"),
+        };
+        Ok(Endpoint {
+            address,
+            path: format!("{}/v1/traces", base.trim_end_matches('/')),
+        })
+    }
+}
+
+/// Starts exporting spans to `endpoint` from a thread of its own.
+pub fn start(endpoint: Endpoint) {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE);
+    if EXPORTER.set(sender).is_ok() {
+        thread::spawn(move || export(&endpoint, &receiver));
+    }
+}
+
+/// A timed operation, e.g. a connection or a command, exported when it is dropped.
+/// Spans started from another share its trace, so a connection's commands and
+/// messages show up under it. Without `--otlp`, spans cost next to nothing and go
+/// nowhere.
+pub struct Span(Option<Finished>);
+
+/// What is exported of a span.
+struct Finished {
+    trace_id: String,
+    span_id: String,
+    parent_id: Option<String>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    /// Starts a span of a trace of its own.
+    pub fn root(name: &'static str) -> Self {
+        Span::start(name, token::generate(), None)
+    }
+
+    /// Starts a span within this one's trace.
+    pub fn child(&self, name: &'static str) -> Self {
+        match &self.0 {
+            Some(parent) => {
+                Span::start(name, parent.trace_id.clone(), Some(parent.span_id.clone()))
+            }
+            None => Span(None),
+        }
+    }
+
+    fn start(name: &'static str, trace_id: String, parent_id: Option<String>) -> Self {
+        if EXPORTER.get().is_none() {
+            return Span(None);
+        }
+        let now = SystemTime::now();
+        Span(Some(Finished {
+            trace_id,
+            span_id: token::generate()[..16].to_string(),
+            parent_id,
+            name,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+        }))
+    }
+
+    /// Notes something about what the span covers, e.g. the client's address.
+    pub fn set(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(span) = &mut self.0 {
+            span.attributes.push((key, value.to_string()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let (Some(mut span), Some(exporter)) = (self.0.take(), EXPORTER.get()) else {
+            return;
+        };
+        span.end = SystemTime::now();
+        let _ = exporter.try_send(span);
+    }
+}
+
+/// Sends spans to the collector in batches, as many as have finished every few
+/// seconds.
+fn export(endpoint: &Endpoint, receiver: &Receiver<Finished>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + FLUSH_EVERY;
+    loop {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(span) => {
+                batch.push(span);
+                if batch.len() < BATCH {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if !batch.is_empty() {
+            if let Err(e) = post(endpoint, &request(&batch)) {
+                println!("Failed to export {} span(s): {e}", batch.len());
+            }
+            batch.clear();
+        }
+        deadline = Instant::now() + FLUSH_EVERY;
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` for `spans`, in its JSON encoding.
+fn request(spans: &[Finished]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(encode).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute("service.name", "chat-server")]},
+            "scopeSpans": [{
+                "scope": {"name": "chat-server", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn encode(span: &Finished) -> Value {
+    let nanos = |at: SystemTime| {
+        at.duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos())
+            .to_string()
+    };
+    let mut encoded = json!({
+        "traceId": span.trace_id,
+        "spanId": span.span_id,
+        "name": span.name,
+        // SERVER: every span is the server handling something a client did
+        "kind": 2,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": span.attributes.iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(parent_id) = &span.parent_id {
+        encoded["parentSpanId"] = json!(parent_id);
+    }
+    encoded
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+/// Posts `body` to the collector, over a connection of its own.
+fn post(endpoint: &Endpoint, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        endpoint.path,
+        endpoint.address,
+        body.len()
+    );
+    let mut stream = TcpStream::connect(&endpoint.address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "The collector answered {}",
+            status.trim()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint: Endpoint = "http://collector".parse().unwrap();
+        assert_eq!(endpoint.address, "collector:4318");
+        assert_eq!(endpoint.path, "/v1/traces");
+        let endpoint: Endpoint = "http://127.0.0.1:9000/otlp/".parse().unwrap();
+        assert_eq!(endpoint.address, "127.0.0.1:9000");
+        assert_eq!(endpoint.path, "/otlp/v1/traces");
+        assert!("https://collector".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_spans_are_posted() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint: Endpoint = format!("http://{}", collector.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1);
+        let span = Finished {
+            trace_id: "0".repeat(32),
+            span_id: "1".repeat(16),
+            parent_id: Some("2".repeat(16)),
+            name: "command",
+            start: at,
+            end: at + Duration::from_millis(3),
+            attributes: vec![("command", "/stats".to_string())],
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = collector.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 4096];
+            // Until the body's closing brace
+            while request.last() != Some(&b'}') {
+                let n = stream.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        post(&endpoint, &request(&[span])).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["parentSpanId"], "2".repeat(16));
+        assert_eq!(span["endTimeUnixNano"], "1003000000");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "/stats");
+    }
+}
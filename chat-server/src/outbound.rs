@@ -0,0 +1,124 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long the other end may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An `http://` URL the server posts to, e.g. a webhook or a push service. There is
+/// no TLS: put a proxy in front of endpoints that need it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// `host:port`, 80 if the URL has no port.
+    address: String,
+    path: String,
+}
+
+impl Url {
+    /// The URL with `path` appended to its own, e.g. an API's endpoint below its base.
+    pub fn join(&self, path: &str) -> Url {
+        Url {
+            address: self.address.clone(),
+            path: format!("{}{path}", self.path.trim_end_matches('/')),
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Not an http:// URL: {s}"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() || authority.contains(char::is_whitespace) {
+            return Err(format!("The URL has no valid host: {s}"));
+        }
+        if path.contains(char::is_whitespace) {
+            return Err(format!("The URL has whitespace in its path: {s}"));
+        }
+        let address = match authority.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_string(),
+            _ => format!("{authority}:80"),
+        };
+        Ok(Url {
+            address,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.address, self.path)
+    }
+}
+
+/// Posts `body` to `url` over a connection of its own, and returns the answer's
+/// status line.
+pub fn post(url: &Url, headers: &[(&str, String)], body: &[u8]) -> io::Result<Status> {
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+        url.path,
+        url.address,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    let mut stream = TcpStream::connect(&url.address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match line.split_whitespace().nth(1).map(str::parse) {
+        Some(Ok(code)) => Ok(Status {
+            code,
+            line: line.trim().to_string(),
+        }),
+        _ => Err(io::Error::other(format!(
+            "Malformed answer {:?}",
+            line.trim()
+        ))),
+    }
+}
+
+/// The status of an answer.
+#[derive(Debug)]
+pub struct Status {
+    pub code: u16,
+    /// The whole status line, e.g. `HTTP/1.1 404 Not Found`, for errors.
+    pub line: String,
+}
+
+impl Status {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url: Url = "http://hooks.local/chat".parse().unwrap();
+        assert_eq!(url.address, "hooks.local:80");
+        assert_eq!(url.path, "/chat");
+        let url: Url = "http://127.0.0.1:8080".parse().unwrap();
+        assert_eq!(url.to_string(), "http://127.0.0.1:8080/");
+        assert_eq!(url.join("/message").path, "/message");
+        assert!("https://hooks.local".parse::<Url>().is_err());
+        assert!("http:///chat".parse::<Url>().is_err());
+    }
+}
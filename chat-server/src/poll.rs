@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::token;
+
+/// The most long-polling connections open at once, as each may hold a thread
+/// waiting in a poll.
+pub const MAX_CONNECTIONS: usize = 256;
+/// How long a poll waits for lines before it answers without any.
+pub const POLL_WAIT: Duration = Duration::from_secs(25);
+/// How long a client may go without polling or sending before its connection counts
+/// as lost, and its session waits to be resumed like that of a dropped TCP client.
+const GONE_AFTER: Duration = Duration::from_secs(60);
+/// The most lines held for a client that doesn't poll; past that its connection is
+/// treated as broken.
+const MAX_OUTBOX: usize = 10_000;
+
+/// The long-polling connections, by id. A connection carries the same lines as a TCP
+/// one: `POST /api/send` feeds its reader, and `GET /api/poll` drains what the server
+/// wrote to it. The id is a random token, so only the client that opened the
+/// connection can use it.
+#[derive(Default)]
+pub struct Polls {
+    connections: Mutex<HashMap<String, Arc<Channel>>>,
+}
+
+/// One long-polling connection, shared by its reader, its writer and the requests
+/// that use it.
+struct Channel {
+    lines: Mutex<Sender<io::Result<String>>>,
+    outbox: Mutex<Outbox>,
+    /// Wakes up the poll waiting for lines.
+    written: Condvar,
+    last_seen: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct Outbox {
+    lines: VecDeque<String>,
+    /// The server is done with the connection, e.g. the user left.
+    closed: bool,
+    /// Bumped by every poll, so a newer poll sends an older one waiting home empty.
+    polls: u64,
+}
+
+impl Channel {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+}
+
+/// What a poll found.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Polled {
+    pub lines: Vec<String>,
+    /// Whether the server closed the connection; it is forgotten once polled.
+    pub closed: bool,
+}
+
+impl Polls {
+    /// Opens a connection, returning its id and the two halves the server uses, or
+    /// `None` if there are as many as there may be.
+    pub fn open(&self) -> Option<(String, Reader, Writer)> {
+        let mut connections = self.connections.lock().unwrap();
+        // Forget those closed that their client never polled again
+        connections.retain(|_, channel| {
+            channel.last_seen.lock().unwrap().elapsed() < GONE_AFTER
+                || !channel.outbox.lock().unwrap().closed
+        });
+        if connections.len() >= MAX_CONNECTIONS {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let channel = Arc::new(Channel {
+            lines: Mutex::new(sender),
+            outbox: Mutex::default(),
+            written: Condvar::new(),
+            last_seen: Mutex::new(Instant::now()),
+        });
+        let id = token::generate();
+        connections.insert(id.clone(), Arc::clone(&channel));
+        let reader = Reader {
+            lines: receiver,
+            channel: Arc::clone(&channel),
+        };
+        Some((id, reader, Writer(channel)))
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Channel>> {
+        self.connections.lock().unwrap().get(id).cloned()
+    }
+
+    /// Passes lines the client sent on to the server. Returns false if there is no
+    /// such connection.
+    pub fn send(&self, id: &str, lines: &[&str]) -> bool {
+        let Some(channel) = self.get(id) else {
+            return false;
+        };
+        channel.touch();
+        let sender = channel.lines.lock().unwrap();
+        lines
+            .iter()
+            .all(|line| sender.send(Ok(line.to_string())).is_ok())
+    }
+
+    /// Waits up to `wait` for lines to the client, and takes them. Returns `None` if
+    /// there is no such connection.
+    pub fn poll(&self, id: &str, wait: Duration) -> Option<Polled> {
+        let channel = self.get(id)?;
+        channel.touch();
+        let deadline = Instant::now() + wait;
+        let mut outbox = channel.outbox.lock().unwrap();
+        outbox.polls += 1;
+        let poll = outbox.polls;
+        while outbox.lines.is_empty() && !outbox.closed && outbox.polls == poll {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            outbox = channel.written.wait_timeout(outbox, left).unwrap().0;
+        }
+        let polled = Polled {
+            lines: outbox.lines.drain(..).collect(),
+            closed: outbox.closed,
+        };
+        drop(outbox);
+        channel.touch();
+        if polled.closed {
+            self.connections.lock().unwrap().remove(id);
+        }
+        Some(polled)
+    }
+}
+
+/// Lines a long-polling client sent. Ends when the client has gone quiet for too
+/// long, as if its connection dropped.
+pub struct Reader {
+    lines: Receiver<io::Result<String>>,
+    channel: Arc<Channel>,
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.lines.recv_timeout(GONE_AFTER / 4) {
+                Ok(line) => return Some(line),
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.channel.last_seen.lock().unwrap().elapsed() >= GONE_AFTER {
+                        return None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+/// Holds lines for a long-polling client until it polls. Dropping it closes the
+/// connection.
+pub struct Writer(Arc<Channel>);
+
+impl Writer {
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let mut outbox = self.0.outbox.lock().unwrap();
+        if outbox.lines.len() == MAX_OUTBOX {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        // Some writes carry several lines, which a poll hands out one by one
+        outbox.lines.extend(line.split('\n').map(str::to_string));
+        self.0.written.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.0.outbox.lock().unwrap().closed = true;
+        self.0.written.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_lines_both_ways() {
+        let polls = Arc::new(Polls::default());
+        let (id, mut reader, mut writer) = polls.open().unwrap();
+        assert!(polls.send(&id, &["alice", "hello"]));
+        assert_eq!(reader.next().unwrap().unwrap(), "alice");
+        assert_eq!(reader.next().unwrap().unwrap(), "hello");
+        assert!(!polls.send("guess", &["hello"]));
+
+        // A poll waits for the next line
+        let waiting = {
+            let (polls, id) = (Arc::clone(&polls), id.clone());
+            thread::spawn(move || polls.poll(&id, Duration::from_secs(5)).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        writer.write_line("welcome").unwrap();
+        let polled = waiting.join().unwrap();
+        assert_eq!(polled.lines, ["welcome"]);
+        assert!(!polled.closed);
+
+        writer.write_line("bye").unwrap();
+        drop(writer);
+        let polled = polls.poll(&id, Duration::ZERO).unwrap();
+        assert_eq!(polled.lines, ["bye"]);
+        assert!(polled.closed);
+        assert!(polls.poll(&id, Duration::ZERO).is_none());
+    }
+}
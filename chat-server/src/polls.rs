@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Most options a poll may have.
+const MAX_OPTIONS: usize = 10;
+/// How many polls are kept. Once there are more, the oldest is closed.
+const MAX_POLLS: usize = 100;
+
+/// A question put to a room with `/poll`, which its members answer with `/vote`.
+pub struct Poll {
+    pub id: u64,
+    pub room: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// The option each user voted for, by its index.
+    votes: HashMap<String, usize>,
+}
+
+impl Poll {
+    /// The poll as posted to the room: `Poll #3: Lunch? 1) pizza 2) sushi (vote with
+    /// /vote 3 <n>)`.
+    pub fn text(&self) -> String {
+        let options: Vec<String> = (self.options.iter().enumerate())
+            .map(|(i, option)| format!("{}) {option}", i + 1))
+            .collect();
+        format!(
+            "Poll #{}: {} {} (vote with /vote {} <n>)",
+            self.id,
+            self.question,
+            options.join(" "),
+            self.id
+        )
+    }
+
+    /// The results so far: `Poll #3, Lunch?: pizza 2, sushi 1 (3 votes)`.
+    pub fn results(&self) -> String {
+        let mut counts = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            counts[option] += 1;
+        }
+        let tally: Vec<String> = (self.options.iter().zip(counts))
+            .map(|(option, count)| format!("{option} {count}"))
+            .collect();
+        let votes = match self.votes.len() {
+            1 => "1 vote".to_string(),
+            n => format!("{n} votes"),
+        };
+        format!(
+            "Poll #{}, {}: {} ({votes})",
+            self.id,
+            self.question,
+            tally.join(", ")
+        )
+    }
+}
+
+/// The open polls, by id. They are kept in memory only, so they close when the
+/// server restarts.
+pub struct Polls {
+    next_id: u64,
+    open: BTreeMap<u64, Poll>,
+}
+
+impl Polls {
+    pub fn new() -> Self {
+        Polls {
+            next_id: 1,
+            open: BTreeMap::new(),
+        }
+    }
+
+    /// Opens a poll in `room`, closing the oldest one if there are too many.
+    pub fn open(&mut self, room: &str, question: String, options: Vec<String>) -> &Poll {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.open.len() == MAX_POLLS {
+            self.open.pop_first();
+        }
+        self.open.entry(id).or_insert(Poll {
+            id,
+            room: room.to_string(),
+            question,
+            options,
+            votes: HashMap::new(),
+        })
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Poll> {
+        self.open.get(&id)
+    }
+
+    /// Records `username`'s vote for `option`, counting from 1. Everyone votes once.
+    pub fn vote(&mut self, id: u64, username: &str, option: usize) -> Result<&Poll, String> {
+        let Some(poll) = self.open.get_mut(&id) else {
+            return Err(format!("There is no poll #{id}"));
+        };
+        if !(1..=poll.options.len()).contains(&option) {
+            return Err(format!(
+                "Poll #{id} has options 1 to {}",
+                poll.options.len()
+            ));
+        }
+        if poll.votes.contains_key(username) {
+            return Err(format!("You already voted in poll #{id}"));
+        }
+        poll.votes.insert(username.to_string(), option - 1);
+        Ok(poll)
+    }
+}
+
+/// Reads the arguments of `/poll`: the question, then the options, each a word or
+/// in double quotes, e.g. `"Lunch at noon?" pizza "fried rice"`.
+pub fn parse_args(args: &str) -> Result<(String, Vec<String>), String> {
+    let mut words = Vec::new();
+    let mut rest = args.trim();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or("A quote isn't closed")?,
+            None => rest.split_once(' ').unwrap_or((rest, "")),
+        };
+        if word.trim().is_empty() {
+            return Err("Questions and options can't be empty".to_string());
+        }
+        words.push(word.trim().to_string());
+        rest = after.trim_start();
+    }
+    let mut words = words.into_iter();
+    let question = words.next().ok_or("A poll needs a question")?;
+    let options: Vec<String> = words.collect();
+    if !(2..=MAX_OPTIONS).contains(&options.len()) {
+        return Err(format!("A poll needs 2 to {MAX_OPTIONS} options"));
+    }
+    Ok((question, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polls_count_each_vote_once() {
+        let (question, options) = parse_args(r#""Lunch at noon?" pizza "fried rice""#).unwrap();
+        assert_eq!(question, "Lunch at noon?");
+        assert_eq!(options, ["pizza", "fried rice"]);
+        assert!(parse_args("Lunch? pizza").is_err());
+        assert!(parse_args(r#""Lunch? pizza sushi"#).is_err());
+
+        let mut polls = Polls::new();
+        let id = polls.open("lobby", question, options).id;
+        assert_eq!(
+            polls.get(id).unwrap().text(),
+            "Poll #1: Lunch at noon? 1) pizza 2) fried rice (vote with /vote 1 <n>)"
+        );
+        polls.vote(id, "alice", 2).unwrap();
+        let poll = polls.vote(id, "bob", 2).unwrap();
+        assert_eq!(
+            poll.results(),
+            "Poll #1, Lunch at noon?: pizza 0, fried rice 2 (2 votes)"
+        );
+        assert!(polls.vote(id, "alice", 1).is_err());
+        assert!(polls.vote(id, "carol", 3).is_err());
+        assert!(polls.vote(9, "carol", 1).is_err());
+    }
+}
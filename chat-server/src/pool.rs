@@ -0,0 +1,109 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+struct Shared<T> {
+    jobs: Mutex<Receiver<T>>,
+    handler: fn(T),
+    /// Workers started so far. They are started as they are needed, and stay.
+    workers: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+}
+
+/// Runs jobs, e.g. client connections, on at most `max_workers` threads. When every
+/// worker is busy, jobs wait in a queue of limited length for one to be free, and
+/// once that is full they are turned away.
+pub struct Pool<T> {
+    queue: SyncSender<T>,
+    shared: Arc<Shared<T>>,
+    max_workers: usize,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    pub fn new(max_workers: usize, queue: usize, handler: fn(T)) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        Pool {
+            queue: sender,
+            shared: Arc::new(Shared {
+                jobs: Mutex::new(receiver),
+                handler,
+                workers: AtomicUsize::new(0),
+                idle: AtomicUsize::new(0),
+            }),
+            max_workers,
+        }
+    }
+
+    /// Hands `job` to an idle worker, a new one if there is room for it, or the
+    /// queue. Gives it back if all of them are taken.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        let shared = &self.shared;
+        let start = shared.idle.load(Ordering::SeqCst) == 0
+            && (shared.workers)
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                    (workers < self.max_workers).then_some(workers + 1)
+                })
+                .is_ok();
+        if !start {
+            return self.queue.try_send(job).map_err(|e| match e {
+                TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+            });
+        }
+        let shared = Arc::clone(shared);
+        thread::spawn(move || {
+            let mut job = job;
+            loop {
+                // A job that panics takes down its connection, not the worker
+                let handler = shared.handler;
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(job)));
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                let next = shared.jobs.lock().unwrap().recv();
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+                match next {
+                    Ok(next) => job = next,
+                    Err(_) => return,
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+    use std::time::Duration;
+
+    /// Waits for the test to say it may finish.
+    fn wait(done: (Receiver<()>, Sender<()>)) {
+        let (until, finished) = done;
+        until.recv().unwrap();
+        finished.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_jobs_queue_then_are_turned_away() {
+        let pool = Pool::new(2, 1, wait);
+        let (finished_tx, finished) = mpsc::channel();
+        let mut releases = Vec::new();
+        for _ in 0..3 {
+            let (release, until) = mpsc::channel();
+            assert!(pool.submit((until, finished_tx.clone())).is_ok());
+            releases.push(release);
+        }
+        // Two workers are busy and one job is queued, there is no room for another
+        let (_, until) = mpsc::channel();
+        assert!(pool.submit((until, finished_tx.clone())).is_err());
+
+        for release in &releases {
+            release.send(()).unwrap();
+        }
+        for _ in 0..3 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+}
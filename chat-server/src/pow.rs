@@ -0,0 +1,65 @@
+use sha2::{Digest, Sha256};
+
+use crate::token;
+
+/// A hashcash-style proof-of-work challenge.
+///
+/// The client has to find a nonce such that `SHA-256("<challenge>:<nonce>")` starts
+/// with at least `bits` zero bits. Every extra bit doubles the expected work, while
+/// checking a solution takes a single hash.
+pub struct Challenge {
+    pub challenge: String,
+    pub bits: u32,
+}
+
+impl Challenge {
+    /// Creates a challenge with a fresh random string, so solutions can't be reused.
+    pub fn new(bits: u32) -> Self {
+        Challenge {
+            challenge: token::generate(),
+            bits,
+        }
+    }
+
+    /// The line sent to the client, `/pow <challenge> <bits>`.
+    pub fn line(&self) -> String {
+        format!("/pow {} {}", self.challenge, self.bits)
+    }
+
+    /// Checks a client's `/pow <nonce>` answer.
+    pub fn verify(&self, answer: &str) -> bool {
+        match answer.strip_prefix("/pow ") {
+            Some(nonce) => leading_zero_bits(&self.challenge, nonce.trim()) >= self.bits,
+            None => false,
+        }
+    }
+}
+
+fn leading_zero_bits(challenge: &str, nonce: &str) -> u32 {
+    let digest = Sha256::digest(format!("{challenge}:{nonce}"));
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let challenge = Challenge::new(8);
+        let solves = |nonce: &u64| leading_zero_bits(&challenge.challenge, &nonce.to_string()) >= 8;
+        let nonce = (0u64..).find(solves).unwrap();
+        assert!(challenge.verify(&format!("/pow {nonce}")));
+        let wrong = (0u64..).find(|nonce| !solves(nonce)).unwrap();
+        assert!(!challenge.verify(&format!("/pow {wrong}")));
+        assert!(!challenge.verify("alice"));
+        assert_ne!(challenge.challenge, Challenge::new(8).challenge);
+    }
+}
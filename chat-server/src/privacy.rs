@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::profile::Profile;
+use crate::server::{Role, Server};
+
+/// Name that erased users' messages are attributed to. It contains a space, so it
+/// can never clash with a real username.
+pub const ERASED_NAME: &str = "deleted user";
+
+/// Everything the server stores about a user, as handed out by `/export`.
+///
+/// Private messages are delivered without being stored, so they are not part of it.
+#[derive(Debug, Serialize)]
+pub struct Export {
+    pub username: String,
+    pub role: String,
+    /// Seconds left on the user's mute, if they are muted.
+    pub muted_for: Option<u64>,
+    /// The user's messages still in the room history, oldest first.
+    pub messages: Vec<ExportedMessage>,
+    /// Open reports the user has filed.
+    pub reports: Vec<ExportedReport>,
+    /// The user's profile, if they have an account.
+    pub profile: Option<Profile>,
+    /// Where their digests go, if they gave an address.
+    pub email: Option<String>,
+    /// The devices they get pushes on, without their tokens.
+    pub push_devices: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMessage {
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedReport {
+    pub id: u64,
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    pub target: String,
+    pub reason: String,
+}
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Collects the data stored about `username`.
+pub fn export(server: &Server, username: &str) -> Export {
+    let messages = server
+        .rooms
+        .lock()
+        .unwrap()
+        .from(username)
+        .into_iter()
+        .map(|message| ExportedMessage {
+            at: unix_secs(message.at),
+            text: message.text,
+        })
+        .collect();
+    let reports = server
+        .reports
+        .lock()
+        .unwrap()
+        .filed_by(username)
+        .map(|report| ExportedReport {
+            id: report.id,
+            at: unix_secs(report.at),
+            target: report.target.clone(),
+            reason: report.reason.clone(),
+        })
+        .collect();
+    let email =
+        (server.accounts.lock().unwrap().get(username)).and_then(|account| account.email.clone());
+    let push_devices = (server.push_devices.lock().unwrap().of(username))
+        .map(|(name, device)| format!("{name}: {}", device.describe()))
+        .collect();
+    Export {
+        username: username.to_string(),
+        role: server.role(username).unwrap_or(Role::User).to_string(),
+        muted_for: server.muted_for(username).map(|d| d.as_secs()),
+        messages,
+        reports,
+        profile: server
+            .accounts
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|account| account.profile.clone()),
+        email,
+        push_devices,
+    }
+}
+
+/// Removes what the server stores about `username`: their account (password,
+/// profile and email), mute, pending digest and push devices are dropped, and their
+/// messages, pins and the reports they filed are attributed to [`ERASED_NAME`]. Only
+/// the account's name is remembered, so no one can take it over.
+///
+/// Reports against the user and the audit log are kept, they are the moderators'
+/// record of what happened (and the audit log can't be rewritten).
+pub fn erase(server: &Server, username: &str) {
+    let erased = Arc::new(ERASED_NAME.to_string());
+    server.unmute(username);
+    let mut rooms = server.rooms.lock().unwrap();
+    for (_, room) in rooms.iter_mut() {
+        room.history.anonymize(username, &erased);
+    }
+    if let Err(e) = rooms.anonymize_pins(username, ERASED_NAME) {
+        eprintln!("Failed to save the rooms: {e}");
+    }
+    drop(rooms);
+    server.reports.lock().unwrap().anonymize(username, &erased);
+    if let Err(e) = server.accounts.lock().unwrap().forget(username) {
+        eprintln!("Failed to erase the account of {username}: {e}");
+    }
+    server.digests.lock().unwrap().clear(username);
+    if let Err(e) = server.push_devices.lock().unwrap().remove(username, None) {
+        eprintln!("Failed to erase the push devices of {username}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Retention;
+    use crate::timestamp::TimeFormat;
+
+    #[test]
+    fn test_erase_deletes_the_account() {
+        let dir = std::env::temp_dir().join(format!("privacy-erase-{}", std::process::id()));
+        let server = Server::new(&dir, TimeFormat::Relative, Retention::Forever, None).unwrap();
+        server
+            .accounts
+            .lock()
+            .unwrap()
+            .set_password("alice", "hunter2")
+            .unwrap();
+
+        erase(&server, "alice");
+        let accounts = server.accounts.lock().unwrap();
+        assert!(accounts.get("alice").is_none());
+        assert!(accounts.is_forgotten("alice"));
+        drop(accounts);
+        assert!(!std::fs::read_to_string(dir.join("accounts.json"))
+            .unwrap()
+            .contains("alice"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+/// Longest bio, in characters.
+const MAX_BIO: usize = 200;
+/// Longest value of any other field, in characters.
+const MAX_FIELD: usize = 64;
+
+/// The fields of a profile, in the order they are shown.
+pub const FIELDS: &[&str] = &["bio", "pronouns", "timezone", "avatar"];
+
+/// What a registered user tells others about themselves, shown by `/profile <user>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    /// A time zone name, e.g. `Europe/Berlin`.
+    pub timezone: Option<String>,
+    /// Hex encoded hash of the user's avatar image, for clients that can show one.
+    pub avatar: Option<String>,
+}
+
+impl Profile {
+    /// Sets a field, or clears it if `value` is empty.
+    pub fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let max = if field == "bio" { MAX_BIO } else { MAX_FIELD };
+        if value.chars().count() > max {
+            return Err(format!("The {field} can be at most {max} characters long"));
+        }
+        let slot = match field {
+            "bio" => &mut self.bio,
+            "pronouns" => &mut self.pronouns,
+            "timezone" => {
+                let valid = |c: char| c.is_ascii_alphanumeric() || "/_+-".contains(c);
+                if !value.chars().all(valid) {
+                    return Err(format!("Invalid time zone: {value}"));
+                }
+                &mut self.timezone
+            }
+            "avatar" => {
+                if !value.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err("The avatar has to be a hex encoded hash".to_string());
+                }
+                &mut self.avatar
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown profile field: {field}, expected one of {}",
+                    FIELDS.join(", ")
+                ))
+            }
+        };
+        *slot = (!value.is_empty()).then(|| value.to_string());
+        Ok(())
+    }
+
+    /// The fields that are set, as `(field, value)` pairs.
+    pub fn fields(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("bio", &self.bio),
+            ("pronouns", &self.pronouns),
+            ("timezone", &self.timezone),
+            ("avatar", &self.avatar),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value.as_deref()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_validates_and_clears() {
+        let mut profile = Profile::default();
+        profile.set("bio", "Rust and tea").unwrap();
+        profile.set("timezone", "Europe/Berlin").unwrap();
+        assert!(profile.set("timezone", "not a zone").is_err());
+        assert!(profile.set("avatar", "not-hex").is_err());
+        assert!(profile.set("bio", &"x".repeat(MAX_BIO + 1)).is_err());
+        assert!(profile.set("age", "42").is_err());
+        assert_eq!(
+            profile.fields().collect::<Vec<_>>(),
+            [("bio", "Rust and tea"), ("timezone", "Europe/Berlin")]
+        );
+
+        profile.set("bio", "").unwrap();
+        assert_eq!(profile.bio, None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{self, BufRead, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Starts a version 2 (binary) header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a version 1 (text) header may be, line ending included.
+const V1_MAX: u64 = 107;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid PROXY header: {what}"),
+    )
+}
+
+/// Reads the PROXY protocol header, version 1 or 2, that a load balancer puts in
+/// front of the connections it passes on. Returns the address of the client it
+/// came from, or `None` if the proxy doesn't say (e.g. its own health checks), in
+/// which case the connection is the proxy's.
+pub fn read_header(reader: &mut impl BufRead) -> io::Result<Option<SocketAddr>> {
+    let first = reader.fill_buf()?.first().copied();
+    match first {
+        Some(b'P') => read_v1(reader),
+        Some(b'\r') => read_v2(reader),
+        Some(_) => Err(invalid("missing")),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`, or
+/// `PROXY UNKNOWN ...\r\n`.
+fn read_v1(reader: &mut impl BufRead) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    reader.take(V1_MAX).read_until(b'\n', &mut line)?;
+    let line = std::str::from_utf8(&line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("unterminated line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid(line)),
+    }
+}
+
+/// The signature, a version and command byte, an address family and protocol byte,
+/// the length of what follows, then the addresses (and possibly extensions, which are
+/// skipped).
+fn read_v2(reader: &mut impl BufRead) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0; 16];
+    reader.read_exact(&mut header)?;
+    if header[..12] != V2_SIGNATURE[..] || header[12] >> 4 != 2 {
+        return Err(invalid("bad signature or version"));
+    }
+    let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+    let mut rest = vec![0; len];
+    reader.read_exact(&mut rest)?;
+    // LOCAL connections come from the proxy itself
+    if header[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([rest[at], rest[at + 1]]);
+    match header[13] >> 4 {
+        1 if len >= 12 => {
+            let ip: [u8; 4] = rest[..4].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if len >= 36 => {
+            let ip: [u8; 16] = rest[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // Unix sockets and unspecified families don't have an address we can use
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_v1() {
+        let mut reader =
+            Cursor::new(&b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 12345\r\nalice\n"[..]);
+        let client = read_header(&mut reader).unwrap();
+        assert_eq!(client, Some("203.0.113.7:51000".parse().unwrap()));
+        // What the client sent is left for the handshake
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!(rest, "alice\n");
+
+        let mut unknown = Cursor::new(&b"PROXY UNKNOWN\r\n"[..]);
+        assert_eq!(read_header(&mut unknown).unwrap(), None);
+        assert!(read_header(&mut Cursor::new(&b"alice\n"[..])).is_err());
+        assert!(read_header(&mut Cursor::new(&b"PROXY TCP4 nowhere\r\n"[..])).is_err());
+    }
+
+    #[test]
+    fn test_read_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY command, TCP over IPv6
+        header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        let source: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        header.extend_from_slice(&source.octets());
+        header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        header.extend_from_slice(&51000u16.to_be_bytes());
+        header.extend_from_slice(&12345u16.to_be_bytes());
+        header.extend_from_slice(b"alice\n");
+        let mut reader = Cursor::new(header);
+        let client = read_header(&mut reader).unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:51000".parse().unwrap()));
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!(rest, "alice\n");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut Cursor::new(local)).unwrap(), None);
+    }
+}
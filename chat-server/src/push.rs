@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::outbound::{self, Url};
+
+/// The most devices one user may register.
+pub const MAX_DEVICES: usize = 5;
+/// Pushes waiting to be sent; any more are dropped rather than slowing the server.
+const QUEUE: usize = 256;
+/// The longest text a push carries, in characters.
+const MAX_TEXT: usize = 200;
+
+/// What a push tells the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub title: String,
+    pub text: String,
+}
+
+impl Notification {
+    /// A notification of `text`, cut short if it is long.
+    pub fn new(title: String, text: &str) -> Self {
+        let mut short: String = text.chars().take(MAX_TEXT).collect();
+        if short.len() < text.len() {
+            short.push('…');
+        }
+        Notification { title, text: short }
+    }
+}
+
+/// The request that hands a push to a provider.
+pub struct Request {
+    pub url: Url,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A push service, which delivers notifications to a user's phone or desktop. Each
+/// knows how to ask its service to deliver a notification; adding a service means
+/// implementing this and adding it to [`Device`].
+pub trait Provider {
+    fn request(&self, notification: &Notification) -> Request;
+}
+
+/// [ntfy](https://ntfy.sh): notifications are posted to a topic the user's devices
+/// subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ntfy {
+    /// The topic's URL, e.g. `http://ntfy.local/alice-chat`.
+    pub topic: String,
+    /// An access token, for topics that need one.
+    pub token: Option<String>,
+}
+
+impl Provider for Ntfy {
+    fn request(&self, notification: &Notification) -> Request {
+        let mut headers = vec![("Title", notification.title.clone())];
+        if let Some(token) = &self.token {
+            headers.push(("Authorization", format!("Bearer {token}")));
+        }
+        Request {
+            url: parse_checked(&self.topic),
+            headers,
+            body: notification.text.clone().into_bytes(),
+        }
+    }
+}
+
+/// [Gotify](https://gotify.net): notifications are posted to the server's message
+/// API with an application token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gotify {
+    /// The Gotify server, e.g. `http://gotify.local`.
+    pub server: String,
+    pub token: String,
+}
+
+impl Provider for Gotify {
+    fn request(&self, notification: &Notification) -> Request {
+        let body = json!({
+            "title": notification.title,
+            "message": notification.text,
+            "priority": 5,
+        });
+        Request {
+            url: parse_checked(&self.server).join("/message"),
+            headers: vec![
+                ("Content-Type", "application/json".to_string()),
+                ("X-Gotify-Key", self.token.clone()),
+            ],
+            body: body.to_string().into_bytes(),
+        }
+    }
+}
+
+/// A URL checked when its device was registered.
+fn parse_checked(url: &str) -> Url {
+    url.parse().expect("URLs are checked on registration")
+}
+
+/// A device a user registered for pushes, with the provider that reaches it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum Device {
+    Ntfy(Ntfy),
+    Gotify(Gotify),
+}
+
+impl Device {
+    /// Parses `/push add`'s arguments after the name: the provider, its URL, and a
+    /// token, which Gotify needs and ntfy may.
+    pub fn parse(args: &str) -> Result<Device, String> {
+        let mut args = args.split_whitespace();
+        let (provider, url, token) = (args.next(), args.next(), args.next());
+        if let Some(url) = url {
+            url.parse::<Url>()?;
+        }
+        let token = token.map(str::to_string);
+        match (provider, url) {
+            (Some("ntfy"), Some(topic)) => Ok(Device::Ntfy(Ntfy {
+                topic: topic.to_string(),
+                token,
+            })),
+            (Some("gotify"), Some(server)) => Ok(Device::Gotify(Gotify {
+                server: server.to_string(),
+                token: token.ok_or("Gotify needs an application token")?,
+            })),
+            (Some(provider), Some(_)) if !["ntfy", "gotify"].contains(&provider) => Err(format!(
+                "Unknown push provider {provider}, expected ntfy or gotify"
+            )),
+            _ => Err(
+                "Usage: /push add <name> ntfy <topic URL> [token] | gotify <server URL> <token>"
+                    .to_string(),
+            ),
+        }
+    }
+
+    fn provider(&self) -> &dyn Provider {
+        match self {
+            Device::Ntfy(ntfy) => ntfy,
+            Device::Gotify(gotify) => gotify,
+        }
+    }
+
+    /// Where pushes go, without the token.
+    pub fn describe(&self) -> String {
+        match self {
+            Device::Ntfy(ntfy) => format!("ntfy {}", ntfy.topic),
+            Device::Gotify(gotify) => format!("gotify {}", gotify.server),
+        }
+    }
+}
+
+/// The devices users registered, by username and then by the name they gave each.
+/// Kept as JSON in a single file; it holds the providers' tokens, so keep it private.
+pub struct Devices {
+    path: PathBuf,
+    devices: BTreeMap<String, BTreeMap<String, Device>>,
+}
+
+impl Devices {
+    /// Opens the devices stored at `path`. A missing file means there are none yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let devices = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Devices {
+            path: path.to_path_buf(),
+            devices,
+        })
+    }
+
+    /// The devices of `username`, by name.
+    pub fn of(&self, username: &str) -> impl Iterator<Item = (&String, &Device)> {
+        self.devices.get(username).into_iter().flatten()
+    }
+
+    /// Registers a device, or replaces the one by that name. Returns false if the
+    /// user has as many devices as they may already.
+    pub fn add(&mut self, username: &str, name: &str, device: Device) -> io::Result<bool> {
+        let devices = self.devices.entry(username.to_string()).or_default();
+        if devices.len() == MAX_DEVICES && !devices.contains_key(name) {
+            return Ok(false);
+        }
+        devices.insert(name.to_string(), device);
+        self.save().map(|_| true)
+    }
+
+    /// Forgets a device, or every device of the user without a name. Returns false if
+    /// there was none.
+    pub fn remove(&mut self, username: &str, name: Option<&str>) -> io::Result<bool> {
+        let removed = match name {
+            Some(name) => (self.devices.get_mut(username))
+                .is_some_and(|devices| devices.remove(name).is_some()),
+            None => self.devices.remove(username).is_some(),
+        };
+        if self.devices.get(username).is_some_and(BTreeMap::is_empty) {
+            self.devices.remove(username);
+        }
+        if !removed {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Writes the devices to a temporary file first, so a crash can't leave a
+    /// truncated file behind.
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.devices)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Where pushes wait for the thread that sends them, started with the first push.
+static PUSHER: OnceLock<SyncSender<(Device, Notification)>> = OnceLock::new();
+
+/// Sends a push to a device from a thread of its own. Pushes are best effort: one
+/// that fails is logged and not retried, as it would be stale by then.
+pub fn send(device: Device, notification: Notification) {
+    let pusher = PUSHER.get_or_init(|| {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        thread::spawn(move || push(&receiver));
+        sender
+    });
+    let _ = pusher.try_send((device, notification));
+}
+
+fn push(receiver: &Receiver<(Device, Notification)>) {
+    for (device, notification) in receiver {
+        let request = device.provider().request(&notification);
+        match outbound::post(&request.url, &request.headers, &request.body) {
+            Ok(status) if status.is_success() => {}
+            Ok(status) => println!("Push to {} failed: {}", device.describe(), status.line),
+            Err(e) => println!("Push to {} failed: {e}", device.describe()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let notification = Notification::new("alice (private)".to_string(), "lunch?");
+        let ntfy = Device::parse("ntfy http://ntfy.local/bob-chat").unwrap();
+        let request = ntfy.provider().request(&notification);
+        assert_eq!(request.url.to_string(), "http://ntfy.local:80/bob-chat");
+        assert_eq!(request.headers, [("Title", "alice (private)".to_string())]);
+        assert_eq!(request.body, b"lunch?");
+
+        let gotify = Device::parse("gotify http://gotify.local:8080/ AbC").unwrap();
+        let request = gotify.provider().request(&notification);
+        assert_eq!(request.url.to_string(), "http://gotify.local:8080/message");
+        assert!(request
+            .headers
+            .contains(&("X-Gotify-Key", "AbC".to_string())));
+        assert_eq!(gotify.describe(), "gotify http://gotify.local:8080/");
+
+        assert!(Device::parse("gotify http://gotify.local").is_err());
+        assert!(Device::parse("pager http://pager.local").is_err());
+        assert!(Device::parse("ntfy https://ntfy.sh/bob").is_err());
+    }
+
+    #[test]
+    fn test_devices_are_capped_and_removed() {
+        let path = std::env::temp_dir().join(format!("push-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut devices = Devices::open(&path).unwrap();
+        let device = Device::parse("ntfy http://ntfy.local/bob").unwrap();
+        for i in 0..MAX_DEVICES {
+            assert!(devices
+                .add("bob", &format!("phone{i}"), device.clone())
+                .unwrap());
+        }
+        assert!(!devices.add("bob", "tablet", device.clone()).unwrap());
+        assert!(devices.add("bob", "phone0", device).unwrap());
+
+        let mut devices = Devices::open(&path).unwrap();
+        assert_eq!(devices.of("bob").count(), MAX_DEVICES);
+        assert!(devices.remove("bob", Some("phone1")).unwrap());
+        assert!(!devices.remove("bob", Some("phone1")).unwrap());
+        assert!(devices.remove("bob", None).unwrap());
+        assert_eq!(devices.of("bob").count(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
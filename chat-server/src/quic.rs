@@ -0,0 +1,209 @@
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use quinn::{Endpoint, Incoming, ServerConfig};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::transport::{self, OnConnection};
+
+/// Name the server's certificate is issued for. Clients pin the certificate itself,
+/// so this doesn't need to match any DNS name.
+const SERVER_NAME: &str = "simple-chat";
+
+/// Loads the server's self-signed certificate and key from `data_dir`, generating
+/// them on first use. Clients need a copy of `quic-cert.der` to connect.
+fn load_or_generate_cert(
+    data_dir: &Path,
+) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let cert_path = data_dir.join("quic-cert.der");
+    let key_path = data_dir.join("quic-key.der");
+    if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+        return Ok((cert.into(), PrivatePkcs8KeyDer::from(key).into()));
+    }
+    let generated = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+        .map_err(io::Error::other)?;
+    let key = generated.key_pair.serialize_der();
+    fs::write(&cert_path, generated.cert.der())?;
+    fs::write(&key_path, &key)?;
+    Ok((
+        generated.cert.der().clone(),
+        PrivatePkcs8KeyDer::from(key).into(),
+    ))
+}
+
+/// Starts a QUIC listener on `address`, driven by a single-threaded async runtime
+/// on a thread of its own. Returns the address the listener is bound to.
+///
+/// Each client opens one bidirectional stream, which carries the same lines as a TCP
+/// connection. Lines are passed to and from the client's (blocking) connection thread
+/// over channels, so the rest of the server is unaware of QUIC. Clients keep their
+/// connection when their address changes, as QUIC connections aren't tied to one.
+pub fn listen(
+    address: SocketAddr,
+    data_dir: &Path,
+    on_connection: Arc<OnConnection>,
+) -> io::Result<SocketAddr> {
+    let (cert, key) = load_or_generate_cert(data_dir)?;
+    let config = ServerConfig::with_single_cert(vec![cert], key).map_err(io::Error::other)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let endpoint = {
+        let _guard = runtime.enter();
+        Endpoint::server(config, address)?
+    };
+    let local = endpoint.local_addr()?;
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                tokio::spawn(serve(incoming, Arc::clone(&on_connection)));
+            }
+        })
+    });
+    Ok(local)
+}
+
+/// Bridges a client's stream to its connection thread.
+async fn serve(incoming: Incoming, on_connection: Arc<OnConnection>) {
+    let Ok(connection) = incoming.await else {
+        return;
+    };
+    let ip = connection.remote_address().ip();
+    println!(
+        "Received a QUIC connection from: {:?}",
+        connection.remote_address()
+    );
+    let Ok((mut send, recv)) = connection.accept_bi().await else {
+        return;
+    };
+
+    let (lines_tx, lines_rx) = mpsc::channel();
+    let (out_tx, mut out_rx) = unbounded_channel::<Vec<u8>>();
+    on_connection(
+        transport::Reader::Quic(Reader(lines_rx)),
+        transport::Writer::Quic(Writer(out_tx)),
+        ip,
+    );
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(recv).lines();
+        loop {
+            let line = lines.next_line().await.transpose();
+            let done = !matches!(line, Some(Ok(_)));
+            if let Some(line) = line {
+                let _ = lines_tx.send(line);
+            }
+            if done {
+                break;
+            }
+        }
+    });
+    // Runs until the connection thread drops its writer, or the client goes away
+    while let Some(data) = out_rx.recv().await {
+        if send.write_all(&data).await.is_err() {
+            break;
+        }
+    }
+    // Let the client read what was sent before closing the connection
+    let _ = send.finish();
+    let _ = send.stopped().await;
+    connection.close(0u32.into(), b"");
+}
+
+/// Lines received from a QUIC client.
+pub struct Reader(mpsc::Receiver<io::Result<String>>);
+
+impl Iterator for Reader {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.recv().ok()
+    }
+}
+
+/// Sends lines to a QUIC client.
+pub struct Writer(UnboundedSender<Vec<u8>>);
+
+impl Writer {
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.0
+            .send(format!("{line}\n").into_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::rustls::RootCertStore;
+    use quinn::ClientConfig;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_lines_over_quic() {
+        let data_dir = std::env::temp_dir().join(format!("quic-{}", std::process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+        let (connections_tx, connections) = mpsc::channel();
+        let connections_tx = Mutex::new(connections_tx);
+        let on_connection = move |reader, writer, _ip| {
+            connections_tx
+                .lock()
+                .unwrap()
+                .send((reader, writer))
+                .unwrap();
+        };
+        let address = listen(
+            "127.0.0.1:0".parse().unwrap(),
+            &data_dir,
+            Arc::new(on_connection),
+        )
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                fs::read(data_dir.join("quic-cert.der")).unwrap(),
+            ))
+            .unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
+        let config = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut reply = vec![0; 8];
+        runtime.block_on(async {
+            let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(config);
+            let connection = endpoint
+                .connect(address, SERVER_NAME)
+                .unwrap()
+                .await
+                .unwrap();
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            send.write_all(b"alice\nhello\n").await.unwrap();
+
+            let (mut reader, mut writer) =
+                tokio::task::spawn_blocking(move || connections.recv().unwrap())
+                    .await
+                    .unwrap();
+            let lines = tokio::task::spawn_blocking(move || {
+                let first = reader.next().unwrap().unwrap();
+                let second = reader.next().unwrap().unwrap();
+                writer.write_line("welcome").unwrap();
+                (first, second)
+            })
+            .await
+            .unwrap();
+            assert_eq!(lines, ("alice".to_string(), "hello".to_string()));
+            recv.read_exact(&mut reply).await.unwrap();
+        });
+        assert_eq!(reply, b"welcome\n");
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lines kept for a disconnected session, unless the config file says otherwise.
+pub const OFFLINE_LINES: usize = 500;
+
+/// What an account's use of the server is limited on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// Lines kept for each of its disconnected sessions until they are resumed.
+    OfflineLines,
+    /// Room messages sent per day (UTC).
+    MessagesPerDay,
+    /// Bytes of room messages sent per day (UTC).
+    BytesPerDay,
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Quota::OfflineLines => "offline_lines",
+            Quota::MessagesPerDay => "messages_per_day",
+            Quota::BytesPerDay => "bytes_per_day",
+        })
+    }
+}
+
+/// A quota that was reached, sent to the client as `/quota <quota> <limit> [id]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    pub quota: Quota,
+    pub limit: u64,
+}
+
+impl Exceeded {
+    /// The line telling the client, along with the id of the message that was
+    /// turned away, if it had one.
+    pub fn line(&self, id: Option<&str>) -> String {
+        match id {
+            Some(id) => format!("/quota {} {} {id}", self.quota, self.limit),
+            None => format!("/quota {} {}", self.quota, self.limit),
+        }
+    }
+}
+
+/// The quotas of one account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Lines kept for each of the account's disconnected sessions; any more are dropped.
+    pub offline_lines: usize,
+    /// Room messages the account may send per day (UTC), if they are limited.
+    pub messages_per_day: Option<u64>,
+    /// Bytes of room messages the account may send per day (UTC), if they are limited.
+    pub bytes_per_day: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            offline_lines: OFFLINE_LINES,
+            messages_per_day: None,
+            bytes_per_day: None,
+        }
+    }
+}
+
+/// The quotas of every account, and how much of them they have used.
+#[derive(Default)]
+pub struct Quotas {
+    default: Limits,
+    /// Accounts with quotas of their own.
+    accounts: HashMap<String, Limits>,
+    /// What each account sent on the day it last sent a room message.
+    sent: HashMap<String, Sent>,
+}
+
+/// The room messages an account sent on a day, counted from the epoch.
+#[derive(Default)]
+struct Sent {
+    day: u64,
+    messages: u64,
+    bytes: u64,
+}
+
+impl Quotas {
+    /// Sets the quotas, e.g. from the config file, keeping what was used of them today.
+    pub fn configure(&mut self, default: Limits, accounts: HashMap<String, Limits>) {
+        self.default = default;
+        self.accounts = accounts;
+    }
+
+    pub fn limits(&self, username: &str) -> Limits {
+        self.accounts.get(username).copied().unwrap_or(self.default)
+    }
+
+    /// Counts a room message of `bytes` that `username` sends at `now`, unless they
+    /// have sent as many, or as much, as they may today already.
+    pub fn send(&mut self, username: &str, bytes: u64, now: SystemTime) -> Result<(), Exceeded> {
+        self.send_several(username, 1, bytes, now)
+    }
+
+    /// Counts `count` room messages of `bytes` each that `username` sends at once at
+    /// `now`, e.g. to several rooms: all of them, or none if that goes over a quota.
+    pub fn send_several(
+        &mut self,
+        username: &str,
+        count: u64,
+        bytes: u64,
+        now: SystemTime,
+    ) -> Result<(), Exceeded> {
+        let limits = self.limits(username);
+        if limits.messages_per_day.is_none() && limits.bytes_per_day.is_none() {
+            return Ok(());
+        }
+        let today = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() / 86400);
+        let sent = self.sent.entry(username.to_string()).or_default();
+        if sent.day != today {
+            *sent = Sent {
+                day: today,
+                ..Sent::default()
+            };
+        }
+        let bytes = bytes.saturating_mul(count);
+        if let Some(limit) = limits
+            .messages_per_day
+            .filter(|&limit| sent.messages + count > limit)
+        {
+            return Err(Exceeded {
+                quota: Quota::MessagesPerDay,
+                limit,
+            });
+        }
+        if let Some(limit) = (limits.bytes_per_day).filter(|&limit| sent.bytes + bytes > limit) {
+            return Err(Exceeded {
+                quota: Quota::BytesPerDay,
+                limit,
+            });
+        }
+        sent.messages += count;
+        sent.bytes += bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_messages_per_day() {
+        let mut quotas = Quotas::default();
+        let limits = |messages_per_day| Limits {
+            messages_per_day: Some(messages_per_day),
+            ..Limits::default()
+        };
+        quotas.configure(limits(2), HashMap::from([("bot".to_string(), limits(3))]));
+        let now = UNIX_EPOCH + Duration::from_secs(10 * 86400);
+        assert!(quotas.send("alice", 5, now).is_ok());
+        assert!(quotas.send("alice", 5, now).is_ok());
+        let exceeded = quotas.send("alice", 5, now).unwrap_err();
+        assert_eq!(
+            exceeded.line(Some("4f1c")),
+            "/quota messages_per_day 2 4f1c"
+        );
+        assert!(quotas.send("bot", 5, now).is_ok());
+        // A new day
+        assert!(quotas
+            .send("alice", 5, now + Duration::from_secs(86400))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_bytes_per_day() {
+        let mut quotas = Quotas::default();
+        let limits = Limits {
+            bytes_per_day: Some(10),
+            ..Limits::default()
+        };
+        quotas.configure(limits, HashMap::new());
+        let now = UNIX_EPOCH;
+        assert!(quotas.send("alice", 6, now).is_ok());
+        assert_eq!(
+            quotas.send("alice", 6, now).unwrap_err().line(None),
+            "/quota bytes_per_day 10"
+        );
+        // A shorter one still fits
+        assert!(quotas.send("alice", 4, now).is_ok());
+    }
+
+    #[test]
+    fn test_several_at_once_or_none() {
+        let mut quotas = Quotas::default();
+        let limits = Limits {
+            messages_per_day: Some(3),
+            ..Limits::default()
+        };
+        quotas.configure(limits, HashMap::new());
+        let now = UNIX_EPOCH;
+        assert!(quotas.send("alice", 5, now).is_ok());
+        assert_eq!(
+            quotas
+                .send_several("alice", 3, 5, now)
+                .unwrap_err()
+                .line(None),
+            "/quota messages_per_day 3"
+        );
+        assert!(quotas.send_several("alice", 2, 5, now).is_ok());
+        assert!(quotas.send("alice", 5, now).is_err());
+    }
+}
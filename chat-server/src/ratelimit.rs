@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// What a user does that may be rate limited, each with limiters of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// Room messages.
+    Messages,
+    /// Moving to another room with `/join`.
+    Joins,
+    /// Private messages and whispers.
+    DirectMessages,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Messages => "messages",
+            Limit::Joins => "joins",
+            Limit::DirectMessages => "direct_messages",
+        })
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "messages" => Ok(Limit::Messages),
+            "joins" => Ok(Limit::Joins),
+            "direct_messages" => Ok(Limit::DirectMessages),
+            _ => Err(format!("Unknown rate limit: {s}")),
+        }
+    }
+}
+
+/// How fast something may happen: up to `burst` times in a row, then once `every`
+/// so often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub burst: u32,
+    pub every: Duration,
+}
+
+/// A token bucket: it holds up to `burst` tokens, every event takes one, and one
+/// comes back `every` so often.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let refilled = elapsed.as_secs_f64() / rate.every.as_secs_f64().max(f64::EPSILON);
+        self.tokens = (self.tokens + refilled).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    /// Takes `count` tokens if there are as many, or a full bucket's worth if `count`
+    /// is more than it holds. Otherwise takes none and returns how long until there are.
+    fn take(&mut self, rate: Rate, now: Instant, count: u32) -> Result<(), Duration> {
+        self.refill(rate, now);
+        let needed = f64::from(count.min(rate.burst));
+        if self.tokens >= needed {
+            self.tokens -= needed;
+            return Ok(());
+        }
+        Err(rate.every.mul_f64(needed - self.tokens))
+    }
+
+    fn is_full(&self, rate: Rate) -> bool {
+        self.tokens >= f64::from(rate.burst)
+    }
+}
+
+/// Rate limiters for what users do, each a token bucket per user. Limits without a
+/// rate, the default, let everything through.
+#[derive(Default)]
+pub struct RateLimits {
+    rates: HashMap<Limit, Rate>,
+    buckets: HashMap<(Limit, String), Bucket>,
+}
+
+impl RateLimits {
+    /// Sets the rates, e.g. from the config file. Buckets start over full.
+    pub fn configure(&mut self, rates: HashMap<Limit, Rate>) {
+        self.rates = rates;
+        self.buckets.clear();
+    }
+
+    /// Counts something `username` does at `now`. Returns how long until they may do
+    /// it again if they are going over the rate.
+    pub fn check(&mut self, limit: Limit, username: &str, now: Instant) -> Result<(), Duration> {
+        self.check_several(limit, username, 1, now)
+    }
+
+    /// Counts something `username` does `count` times at once at `now`, e.g. a message
+    /// sent to several rooms: all of them, or none if that goes over the rate.
+    pub fn check_several(
+        &mut self,
+        limit: Limit,
+        username: &str,
+        count: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let Some(&rate) = self.rates.get(&limit) else {
+            return Ok(());
+        };
+        let key = (limit, username.to_string());
+        if !self.buckets.contains_key(&key) {
+            // Forget the buckets that have filled up again, so the map doesn't grow forever
+            let rates = &self.rates;
+            self.buckets.retain(|(limit, _), bucket| {
+                rates.get(limit).is_some_and(|&rate| {
+                    bucket.refill(rate, now);
+                    !bucket.is_full(rate)
+                })
+            });
+        }
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::full(rate, now))
+            .take(rate, now, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursts_then_refills() {
+        let mut limits = RateLimits::default();
+        let rate = Rate {
+            burst: 3,
+            every: Duration::from_secs(2),
+        };
+        limits.configure(HashMap::from([(Limit::Messages, rate)]));
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limits.check(Limit::Messages, "alice", start).is_ok());
+        }
+        assert_eq!(
+            limits.check(Limit::Messages, "alice", start),
+            Err(Duration::from_secs(2))
+        );
+        // Others have buckets of their own, and unlimited limits let everything through
+        assert!(limits.check(Limit::Messages, "bob", start).is_ok());
+        assert!(limits.check(Limit::Joins, "alice", start).is_ok());
+
+        let later = start + Duration::from_secs(3);
+        assert!(limits.check(Limit::Messages, "alice", later).is_ok());
+        assert_eq!(
+            limits.check(Limit::Messages, "alice", later),
+            Err(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_several_at_once_or_none() {
+        let mut limits = RateLimits::default();
+        let rate = Rate {
+            burst: 3,
+            every: Duration::from_secs(2),
+        };
+        limits.configure(HashMap::from([(Limit::Messages, rate)]));
+        let start = Instant::now();
+        assert!(limits.check(Limit::Messages, "alice", start).is_ok());
+        assert_eq!(
+            limits.check_several(Limit::Messages, "alice", 3, start),
+            Err(Duration::from_secs(2))
+        );
+        // Nothing was taken
+        assert!(limits
+            .check_several(Limit::Messages, "alice", 2, start)
+            .is_ok());
+        // More than a burst takes a full bucket
+        let later = start + Duration::from_secs(6);
+        assert!(limits
+            .check_several(Limit::Messages, "alice", 5, later)
+            .is_ok());
+        assert!(limits.check(Limit::Messages, "alice", later).is_err());
+    }
+}
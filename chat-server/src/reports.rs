@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::history::Message;
+
+/// A user's report about another user, awaiting review by a moderator.
+pub struct Report {
+    pub id: u64,
+    pub at: SystemTime,
+    pub reporter: String,
+    pub target: String,
+    pub reason: String,
+    /// The target's most recent messages at the time of the report.
+    pub context: Vec<Message>,
+}
+
+/// Open reports, ordered by id (and therefore by age).
+pub struct Reports {
+    next_id: u64,
+    open: BTreeMap<u64, Report>,
+}
+
+impl Reports {
+    pub fn new() -> Self {
+        Reports {
+            next_id: 1,
+            open: BTreeMap::new(),
+        }
+    }
+
+    /// Files a new report and returns its id.
+    pub fn file(
+        &mut self,
+        reporter: &str,
+        target: &str,
+        reason: &str,
+        context: Vec<Message>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(
+            id,
+            Report {
+                id,
+                at: SystemTime::now(),
+                reporter: reporter.to_string(),
+                target: target.to_string(),
+                reason: reason.to_string(),
+                context,
+            },
+        );
+        id
+    }
+
+    /// Open reports, oldest first.
+    pub fn open(&self) -> impl Iterator<Item = &Report> {
+        self.open.values()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Report> {
+        self.open.get(&id)
+    }
+
+    /// Reports filed by `username`, oldest first.
+    pub fn filed_by<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a Report> {
+        self.open
+            .values()
+            .filter(move |report| report.reporter == username)
+    }
+
+    /// Replaces `username` as the reporter of any open report, and as the sender of
+    /// any message attached to one.
+    pub fn anonymize(&mut self, username: &str, replacement: &Arc<String>) {
+        for report in self.open.values_mut() {
+            if report.reporter == username {
+                report.reporter = replacement.to_string();
+            }
+            for message in &mut report.context {
+                if message.from.as_str() == username {
+                    message.from = replacement.clone();
+                }
+            }
+        }
+    }
+
+    /// Closes a report once it has been dealt with.
+    pub fn close(&mut self, id: u64) -> Option<Report> {
+        self.open.remove(&id)
+    }
+}
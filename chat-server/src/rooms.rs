@@ -0,0 +1,791 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{Access, RoomDef};
+use crate::duration;
+use crate::history::{History, Message, Retention};
+use crate::server::HISTORY_SIZE;
+
+/// The room users are in when they join.
+pub const LOBBY: &str = "lobby";
+
+/// Longest room name.
+const MAX_NAME: usize = 32;
+
+/// Most messages a room may have pinned.
+pub const MAX_PINS: usize = 10;
+
+/// A message pinned in a room. It is a copy, kept with the room's state, as the
+/// room's history may lose the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    /// The message's number in the room.
+    pub seq: u64,
+    pub from: String,
+    pub text: String,
+    /// Who pinned it.
+    pub by: String,
+}
+
+impl Pin {
+    /// The pin as listed: `#12 [bob]: text (by carol)`.
+    pub fn line(&self) -> String {
+        format!(
+            "#{} [{}]: {} (by {})",
+            self.seq, self.from, self.text, self.by
+        )
+    }
+}
+
+/// A room and the users in it. Each user is in exactly one room at a time.
+pub struct Room {
+    /// Recent messages, numbered per room.
+    pub history: History,
+    members: HashSet<Arc<String>>,
+    /// The members who only follow the room: they get its messages but may not send
+    /// any, and don't count against `max_members`.
+    spectators: HashSet<Arc<String>>,
+    /// The most members the room may have, as the config file says.
+    pub max_members: Option<usize>,
+    /// Ad hoc rooms, opened with `/join`, close once their last member leaves and never
+    /// store their messages. The others are declared in the config file, or the lobby.
+    pub ephemeral: bool,
+    /// The topic set with `/topic`, or else the one in the config file.
+    pub topic: Option<String>,
+    /// The topic the config file gives the room.
+    config_topic: Option<String>,
+    /// Who may join. Ad hoc rooms are open to everyone.
+    pub access: Access,
+    /// The user who opened an ad hoc room, who may make others room ops.
+    creator: Option<String>,
+    /// Room ops, who may kick, mute and set the topic in this room only.
+    ops: HashSet<String>,
+    /// Pinned messages, in the order they were pinned.
+    pins: Vec<Pin>,
+    /// Users muted in this room, until when.
+    mutes: HashMap<String, Instant>,
+}
+
+impl Room {
+    fn new(retention: Retention, ephemeral: bool) -> Self {
+        Room {
+            history: History::new(HISTORY_SIZE).with_retention(retention),
+            members: HashSet::new(),
+            spectators: HashSet::new(),
+            max_members: None,
+            ephemeral,
+            topic: None,
+            config_topic: None,
+            access: Access::default(),
+            creator: None,
+            ops: HashSet::new(),
+            pins: Vec::new(),
+            mutes: HashMap::new(),
+        }
+    }
+
+    /// The pinned messages, in the order they were pinned.
+    pub fn pins(&self) -> &[Pin] {
+        &self.pins
+    }
+
+    /// The user who opened the room, if it is an ad hoc room.
+    pub fn creator(&self) -> Option<&str> {
+        self.creator.as_deref()
+    }
+
+    /// Whether `username` is a room op. The creator of an ad hoc room always is.
+    pub fn is_op(&self, username: &str) -> bool {
+        self.ops.contains(username) || self.creator() == Some(username)
+    }
+
+    /// Rejects `username`'s messages to this room for a while.
+    pub fn mute(&mut self, username: &str, duration: Duration) -> Result<(), String> {
+        let until = (Instant::now().checked_add(duration))
+            .ok_or_else(|| format!("Duration too long: {}", duration::format(duration)))?;
+        self.mutes.insert(username.to_string(), until);
+        Ok(())
+    }
+
+    /// Lifts a mute early. Returns false if the user wasn't muted here.
+    pub fn unmute(&mut self, username: &str) -> bool {
+        self.mutes
+            .remove(username)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// How long `username` is still muted in this room, if they are.
+    pub fn muted_for(&self, username: &str) -> Option<Duration> {
+        let until = self.mutes.get(username)?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    /// Everyone who gets the room's messages, spectators included.
+    pub fn members(&self) -> impl Iterator<Item = &Arc<String>> {
+        self.members.iter()
+    }
+
+    /// The members who take part, sorted: everyone but the spectators.
+    pub fn participants(&self) -> Vec<&str> {
+        let mut participants: Vec<&str> = (self.members.difference(&self.spectators))
+            .map(|member| member.as_str())
+            .collect();
+        participants.sort_unstable();
+        participants
+    }
+
+    /// The spectators, sorted.
+    pub fn spectators(&self) -> Vec<&str> {
+        let mut spectators: Vec<&str> = self.spectators.iter().map(|s| s.as_str()).collect();
+        spectators.sort_unstable();
+        spectators
+    }
+
+    pub fn is_spectator(&self, username: &str) -> bool {
+        self.spectators
+            .iter()
+            .any(|spectator| spectator.as_str() == username)
+    }
+
+    /// Whether the room has as many members as it may, not counting spectators.
+    pub fn is_full(&self) -> bool {
+        self.max_members
+            .is_some_and(|max| self.members.len() - self.spectators.len() >= max)
+    }
+
+    /// What a user entering the room is told, line by line: `/room <name>`, the topic
+    /// if there is one, the pinned messages, and `/seq <latest>`.
+    pub fn welcome(&self, name: &str) -> Vec<String> {
+        let mut lines = vec![format!("/room {name}")];
+        if let Some(topic) = &self.topic {
+            lines.push(format!("Topic of #{name}: {topic}"));
+        }
+        for pin in &self.pins {
+            lines.push(format!("Pinned: {}", pin.line()));
+        }
+        lines.push(format!("/seq {}", self.history.last_seq()));
+        lines
+    }
+}
+
+/// What is kept about a permanent room across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct RoomState {
+    /// The topic set with `/topic`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// The room ops.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    ops: BTreeSet<String>,
+    /// The pinned messages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pins: Vec<Pin>,
+}
+
+/// Every room on the server, and who is in which.
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    /// The room each connected user is in.
+    member_of: HashMap<String, String>,
+    /// How long permanent rooms keep their messages, unless the config says otherwise.
+    retention: Retention,
+    /// The state of the permanent rooms, by name, kept as JSON in `path` if there is one.
+    state: BTreeMap<String, RoomState>,
+    path: Option<PathBuf>,
+}
+
+impl Rooms {
+    /// Opens the lobby, which keeps its messages as long as `retention` says. Nothing
+    /// is kept across restarts.
+    pub fn new(retention: Retention) -> Self {
+        let mut rooms = Rooms {
+            rooms: HashMap::from([(LOBBY.to_string(), Room::new(retention, false))]),
+            member_of: HashMap::new(),
+            retention,
+            state: BTreeMap::new(),
+            path: None,
+        };
+        rooms.configure(Vec::new());
+        rooms
+    }
+
+    /// Opens the lobby like [`Rooms::new`], keeping the state of permanent rooms (such
+    /// as their topics) in `path`. A missing file means there is none yet.
+    pub fn open(path: &Path, retention: Retention) -> io::Result<Self> {
+        let mut rooms = Rooms::new(retention);
+        rooms.state = match fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        rooms.path = Some(path.to_path_buf());
+        rooms.configure(Vec::new());
+        Ok(rooms)
+    }
+
+    /// Sets the topic of the room `name`, or with `None`, goes back to the one in the
+    /// config file. The topics of permanent rooms are kept across restarts. Returns
+    /// false if there is no such room.
+    pub fn set_topic(&mut self, name: &str, topic: Option<String>) -> io::Result<bool> {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return Ok(false);
+        };
+        room.topic = topic.clone().or_else(|| room.config_topic.clone());
+        self.update(name, |state| state.topic = topic)
+    }
+
+    /// Makes `username` a room op of the room `name`, or with `op` false, no longer
+    /// one. The ops of permanent rooms are kept across restarts. Returns false if
+    /// there is no such room.
+    pub fn set_op(&mut self, name: &str, username: &str, op: bool) -> io::Result<bool> {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return Ok(false);
+        };
+        if op {
+            room.ops.insert(username.to_string());
+        } else {
+            room.ops.remove(username);
+        }
+        self.update(name, |state| {
+            if op {
+                state.ops.insert(username.to_string());
+            } else {
+                state.ops.remove(username);
+            }
+        })
+    }
+
+    /// Takes away what `username` holds in rooms, for when someone else may take their
+    /// name: room op status everywhere, and that of creator of the rooms they opened.
+    pub fn revoke(&mut self, username: &str) -> io::Result<()> {
+        for room in self.rooms.values_mut() {
+            if room.creator() == Some(username) {
+                room.creator = None;
+            }
+            room.ops.remove(username);
+        }
+        let mut kept = false;
+        for state in self.state.values_mut() {
+            kept |= state.ops.remove(username);
+        }
+        if !kept {
+            return Ok(());
+        }
+        self.state.retain(|_, state| *state != RoomState::default());
+        self.save()
+    }
+
+    /// Pins a message in the room `name`, or moves it to the end if it is pinned
+    /// already. The pins of permanent rooms are kept across restarts. Returns false if
+    /// there is no such room.
+    pub fn pin(&mut self, name: &str, pin: Pin) -> io::Result<bool> {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return Ok(false);
+        };
+        room.pins.retain(|pinned| pinned.seq != pin.seq);
+        room.pins.push(pin.clone());
+        self.update(name, |state| {
+            state.pins.retain(|pinned| pinned.seq != pin.seq);
+            state.pins.push(pin);
+        })
+    }
+
+    /// Unpins message `seq` in the room `name`. Returns false if it wasn't pinned.
+    pub fn unpin(&mut self, name: &str, seq: u64) -> io::Result<bool> {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return Ok(false);
+        };
+        let pinned = room.pins.len();
+        room.pins.retain(|pin| pin.seq != seq);
+        if room.pins.len() == pinned {
+            return Ok(false);
+        }
+        self.update(name, |state| state.pins.retain(|pin| pin.seq != seq))
+    }
+
+    /// Attributes the pinned messages `username` sent or pinned to `replacement`
+    /// instead.
+    pub fn anonymize_pins(&mut self, username: &str, replacement: &str) -> io::Result<()> {
+        let pins = (self
+            .rooms
+            .values_mut()
+            .flat_map(|room| room.pins.iter_mut()))
+        .chain(
+            self.state
+                .values_mut()
+                .flat_map(|state| state.pins.iter_mut()),
+        );
+        let mut changed = false;
+        for pin in pins {
+            for name in [&mut pin.from, &mut pin.by] {
+                if name == username {
+                    *name = replacement.to_string();
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Changes the kept state of the room `name` and saves it, if it is a permanent
+    /// room. Returns false if there is no such room.
+    fn update(&mut self, name: &str, change: impl FnOnce(&mut RoomState)) -> io::Result<bool> {
+        match self.rooms.get(name) {
+            None => return Ok(false),
+            Some(room) if room.ephemeral => return Ok(true),
+            Some(_) => {}
+        }
+        change(self.state.entry(name.to_string()).or_default());
+        self.state.retain(|_, state| *state != RoomState::default());
+        self.save().map(|_| true)
+    }
+
+    /// Writes the state to a temporary file first, so a crash can't leave a truncated
+    /// file behind.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec_pretty(&self.state)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Opens the permanent rooms declared in the config file, or updates them if they
+    /// are open already. Permanent rooms no longer declared become ad hoc rooms, which
+    /// close once their last member leaves and lose their history, while the lobby,
+    /// which is always there, goes back to its defaults. Members who are no longer
+    /// allowed in a room stay until they leave it. Returns how many rooms are no
+    /// longer permanent.
+    pub fn configure(&mut self, defs: Vec<RoomDef>) -> usize {
+        let declared: HashSet<&str> = defs.iter().map(|def| def.name.as_str()).collect();
+        let retention = self.retention;
+        let state = &self.state;
+        let mut dropped = 0;
+        self.rooms.retain(|name, room| {
+            if room.ephemeral || declared.contains(name.as_str()) {
+                return true;
+            }
+            room.config_topic = None;
+            room.access = Access::default();
+            room.max_members = None;
+            if name == LOBBY {
+                let state = state.get(name).cloned().unwrap_or_default();
+                room.topic = state.topic;
+                room.ops = state.ops.into_iter().collect();
+                room.pins = state.pins;
+                room.history.set_retention(retention);
+                return true;
+            }
+            room.topic = None;
+            room.ops.clear();
+            room.pins.clear();
+            dropped += 1;
+            room.ephemeral = true;
+            room.history.set_retention(Retention::Ephemeral);
+            !room.members.is_empty()
+        });
+        for def in defs {
+            let state = self.state.get(&def.name).cloned().unwrap_or_default();
+            let room = self
+                .rooms
+                .entry(def.name)
+                .or_insert_with(|| Room::new(retention, false));
+            room.ephemeral = false;
+            room.topic = state.topic.or_else(|| def.topic.clone());
+            room.config_topic = def.topic;
+            room.ops = state.ops.into_iter().collect();
+            room.pins = state.pins;
+            room.access = def.access;
+            room.max_members = def.max_members;
+            room.history
+                .set_retention(def.retention.unwrap_or(retention));
+        }
+        dropped
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Room> {
+        self.rooms.get_mut(name)
+    }
+
+    /// The room `username` is in, if they are connected.
+    pub fn room_of(&self, username: &str) -> Option<&str> {
+        self.member_of.get(username).map(String::as_str)
+    }
+
+    /// The room `username` is in.
+    pub fn current(&self, username: &str) -> Option<&Room> {
+        self.rooms.get(self.room_of(username)?)
+    }
+
+    /// The room `username` is in, and its name, to post to it.
+    pub fn current_mut(&mut self, username: &str) -> Option<(&str, &mut Room)> {
+        let name = self.member_of.get(username)?;
+        Some((name, self.rooms.get_mut(name)?))
+    }
+
+    /// Every room, e.g. to look through their histories.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut Room)> {
+        self.rooms.iter_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Room)> {
+        self.rooms.iter()
+    }
+
+    /// The rooms `username` may see listed, by name: all but the invite-only rooms
+    /// they aren't allowed in or in already. `None` sees them all.
+    pub fn listed(&self, username: Option<&str>) -> Vec<(&str, &Room)> {
+        let mut listed: Vec<(&str, &Room)> = self
+            .rooms
+            .iter()
+            .filter(|(name, _)| self.is_visible(name, username))
+            .map(|(name, room)| (name.as_str(), room))
+            .collect();
+        listed.sort_by_key(|(name, _)| *name);
+        listed
+    }
+
+    /// Whether `username` may see the room called `name`, e.g. in `/rooms` or as where
+    /// someone is: unless it is invite-only and they aren't allowed in it or in it
+    /// already. `None` sees them all.
+    pub fn is_visible(&self, name: &str, username: Option<&str>) -> bool {
+        let Some(room) = self.rooms.get(name) else {
+            return false;
+        };
+        username.is_none_or(|username| {
+            room.access
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.contains(username))
+                || self.room_of(username) == Some(name)
+        })
+    }
+
+    /// Returns up to `count` of the most recent messages sent by `username`, in any
+    /// room, oldest first.
+    pub fn recent_from(&self, username: &str, count: usize) -> Vec<Message> {
+        let mut recent: Vec<Message> = self
+            .rooms
+            .values()
+            .flat_map(|room| room.history.recent_from(username, count))
+            .collect();
+        recent.sort_by_key(|message| message.at);
+        recent.split_off(recent.len().saturating_sub(count))
+    }
+
+    /// Returns every message sent by `username` that is still in the history of a room,
+    /// oldest first.
+    pub fn from(&self, username: &str) -> Vec<Message> {
+        let mut messages: Vec<Message> = self
+            .rooms
+            .values()
+            .flat_map(|room| room.history.from(username))
+            .collect();
+        messages.sort_by_key(|message| message.at);
+        messages
+    }
+
+    /// Moves `username` into the room `name`, opening it as an ad hoc room if there
+    /// is no such room, with them as its creator. A spectator of the room becomes a
+    /// member. Returns true if the room was opened.
+    pub fn enter(&mut self, username: &Arc<String>, name: &str) -> bool {
+        if self.room_of(username) == Some(name) {
+            if let Some(room) = self.rooms.get_mut(name) {
+                room.spectators.remove(username);
+            }
+            return false;
+        }
+        self.leave(username);
+        let opened = !self.rooms.contains_key(name);
+        let room = self.rooms.entry(name.to_string()).or_insert_with(|| {
+            let mut room = Room::new(Retention::Ephemeral, true);
+            room.creator = Some(username.to_string());
+            room
+        });
+        room.members.insert(Arc::clone(username));
+        self.member_of
+            .insert(username.to_string(), name.to_string());
+        opened
+    }
+
+    /// Moves `username` into the room `name` as a spectator. Spectating doesn't open
+    /// rooms: returns false if there is no such room.
+    pub fn spectate(&mut self, username: &Arc<String>, name: &str) -> bool {
+        if !self.rooms.contains_key(name) {
+            return false;
+        }
+        self.enter(username, name);
+        let room = self.rooms.get_mut(name).expect("just entered");
+        room.spectators.insert(Arc::clone(username));
+        true
+    }
+
+    /// Takes `username` out of their room, closing it if it was an ad hoc room and
+    /// they were the last one in it.
+    pub fn leave(&mut self, username: &Arc<String>) {
+        let Some(name) = self.member_of.remove(username.as_str()) else {
+            return;
+        };
+        if let Some(room) = self.rooms.get_mut(&name) {
+            room.members.remove(username);
+            room.spectators.remove(username);
+            if room.ephemeral && room.members.is_empty() {
+                self.rooms.remove(&name);
+            }
+        }
+    }
+}
+
+/// Checks a room name, given with or without its leading `#`, and returns it without.
+pub fn parse_name(name: &str) -> Result<&str, String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(valid) {
+        return Err(format!(
+            "Room names are up to {MAX_NAME} letters, digits, dashes and underscores"
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ad_hoc_rooms_close_when_empty() {
+        let alice = Arc::new("alice".to_string());
+        let bob = Arc::new("bob".to_string());
+        let mut rooms = Rooms::new(Retention::Forever);
+        rooms.enter(&alice, LOBBY);
+        assert!(rooms.enter(&alice, "games"));
+        assert!(!rooms.enter(&bob, "games"));
+        assert_eq!(rooms.get("games").unwrap().members().count(), 2);
+        assert_eq!(rooms.get(LOBBY).unwrap().members().count(), 0);
+
+        rooms.leave(&alice);
+        assert!(rooms.get("games").is_some());
+        rooms.enter(&bob, LOBBY);
+        assert!(rooms.get("games").is_none());
+        // The lobby stays open, even when empty
+        rooms.leave(&bob);
+        assert!(rooms.get(LOBBY).is_some());
+        assert_eq!(rooms.room_of("bob"), None);
+    }
+
+    #[test]
+    fn test_endless_mutes_are_refused() {
+        let mut rooms = Rooms::new(Retention::Forever);
+        let lobby = rooms.get_mut(LOBBY).unwrap();
+        assert!(lobby.mute("bob", Duration::MAX).is_err());
+        assert_eq!(lobby.muted_for("bob"), None);
+        assert!(lobby.mute("bob", Duration::from_secs(60)).is_ok());
+        assert!(lobby.muted_for("bob").is_some());
+    }
+
+    #[test]
+    fn test_configure_permanent_rooms() {
+        let alice = Arc::new("alice".to_string());
+        let def = |name: &str| RoomDef {
+            name: name.to_string(),
+            topic: Some(format!("All about {name}")),
+            access: Access::default(),
+            retention: None,
+            max_members: None,
+        };
+        let mut rooms = Rooms::new(Retention::Forever);
+        assert_eq!(rooms.configure(vec![def("rust"), def("go")]), 0);
+        assert!(!rooms.get("go").unwrap().ephemeral);
+        rooms.enter(&alice, "go");
+        rooms.enter(&alice, LOBBY);
+        assert!(rooms.get("go").is_some());
+
+        // Dropped from the config: closed right away when empty, or once everyone left
+        rooms.enter(&alice, "rust");
+        assert_eq!(rooms.configure(vec![def(LOBBY)]), 2);
+        assert!(rooms.get("go").is_none());
+        assert!(rooms.get("rust").unwrap().ephemeral);
+        assert_eq!(
+            rooms.get(LOBBY).unwrap().topic.as_deref(),
+            Some("All about lobby")
+        );
+        rooms.enter(&alice, LOBBY);
+        assert!(rooms.get("rust").is_none());
+        rooms.configure(Vec::new());
+        assert_eq!(rooms.get(LOBBY).unwrap().topic, None);
+    }
+
+    #[test]
+    fn test_topics_are_kept() {
+        let path = std::env::temp_dir().join(format!("rooms-{}.json", std::process::id()));
+        let def = RoomDef {
+            name: "rust".to_string(),
+            topic: Some("All about rust".to_string()),
+            access: Access::default(),
+            retention: None,
+            max_members: None,
+        };
+        let mut rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        rooms.configure(vec![def.clone()]);
+        assert!(rooms
+            .set_topic("rust", Some("Ask away".to_string()))
+            .unwrap());
+        assert!(rooms.set_topic(LOBBY, Some("Be nice".to_string())).unwrap());
+        assert!(!rooms.set_topic("go", None).unwrap());
+
+        let mut rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        assert_eq!(rooms.get(LOBBY).unwrap().topic.as_deref(), Some("Be nice"));
+        rooms.configure(vec![def.clone()]);
+        assert_eq!(
+            rooms.get("rust").unwrap().topic.as_deref(),
+            Some("Ask away")
+        );
+        // Cleared: back to the config's topic
+        rooms.set_topic("rust", None).unwrap();
+        assert_eq!(
+            rooms.get("rust").unwrap().topic.as_deref(),
+            Some("All about rust")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_room_ops() {
+        let path = std::env::temp_dir().join(format!("room-ops-{}.json", std::process::id()));
+        let alice = Arc::new("alice".to_string());
+        let mut rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        rooms.enter(&alice, "games");
+        assert_eq!(rooms.get("games").unwrap().creator(), Some("alice"));
+        assert!(rooms.get("games").unwrap().is_op("alice"));
+        assert!(rooms.set_op("games", "bob", true).unwrap());
+        assert!(rooms.set_op(LOBBY, "carol", true).unwrap());
+        assert!(!rooms.get(LOBBY).unwrap().is_op("alice"));
+
+        // Only the ops of permanent rooms are kept
+        let rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        assert!(rooms.get(LOBBY).unwrap().is_op("carol"));
+        assert!(rooms.get("games").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_revoke_drops_ops_and_creators() {
+        let path = std::env::temp_dir().join(format!("room-revoke-{}.json", std::process::id()));
+        let [alice, bob] = ["alice", "bob"].map(|name| Arc::new(name.to_string()));
+        let mut rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        rooms.enter(&alice, "games");
+        rooms.enter(&bob, "games");
+        rooms.set_op(LOBBY, "alice", true).unwrap();
+        rooms.revoke("alice").unwrap();
+        assert_eq!(rooms.get("games").unwrap().creator(), None);
+        assert!(!rooms.get("games").unwrap().is_op("alice"));
+        assert!(!rooms.get(LOBBY).unwrap().is_op("alice"));
+        let rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        assert!(!rooms.get(LOBBY).unwrap().is_op("alice"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pins_are_kept() {
+        let path = std::env::temp_dir().join(format!("room-pins-{}.json", std::process::id()));
+        let mut rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        let pin = |seq, from: &str| Pin {
+            seq,
+            from: from.to_string(),
+            text: "read the rules".to_string(),
+            by: "carol".to_string(),
+        };
+        assert!(rooms.pin(LOBBY, pin(3, "alice")).unwrap());
+        assert!(rooms.pin(LOBBY, pin(5, "bob")).unwrap());
+        assert!(rooms.pin(LOBBY, pin(3, "alice")).unwrap());
+        assert!(rooms.unpin(LOBBY, 5).unwrap());
+        assert!(!rooms.unpin(LOBBY, 5).unwrap());
+        assert!(!rooms.pin("nowhere", pin(1, "bob")).unwrap());
+        rooms.anonymize_pins("alice", "deleted user").unwrap();
+
+        let rooms = Rooms::open(&path, Retention::Forever).unwrap();
+        let lobby = rooms.get(LOBBY).unwrap();
+        assert_eq!(lobby.pins(), [pin(3, "deleted user")]);
+        assert_eq!(
+            lobby.welcome(LOBBY)[1],
+            "Pinned: #3 [deleted user]: read the rules (by carol)"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invite_only_rooms_are_not_listed() {
+        let alice = Arc::new("alice".to_string());
+        let mut rooms = Rooms::new(Retention::Forever);
+        rooms.configure(vec![RoomDef {
+            name: "staff".to_string(),
+            topic: None,
+            access: Access {
+                allow: Some(HashSet::from(["bob".to_string()])),
+                min_role: None,
+            },
+            retention: None,
+            max_members: None,
+        }]);
+        rooms.enter(&alice, "games");
+        let names = |username| -> Vec<&str> {
+            let listed = rooms.listed(username);
+            listed.into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(names(Some("alice")), ["games", LOBBY]);
+        assert_eq!(names(Some("bob")), ["games", LOBBY, "staff"]);
+        assert_eq!(names(None), ["games", LOBBY, "staff"]);
+    }
+
+    #[test]
+    fn test_spectators_are_not_members() {
+        let [alice, bob, carol] = ["alice", "bob", "carol"].map(|name| Arc::new(name.to_string()));
+        let mut rooms = Rooms::new(Retention::Forever);
+        rooms.configure(vec![RoomDef {
+            name: "ama".to_string(),
+            topic: None,
+            access: Access::default(),
+            retention: None,
+            max_members: Some(1),
+        }]);
+        assert!(!rooms.spectate(&alice, "nowhere"));
+        rooms.enter(&alice, "ama");
+        assert!(rooms.spectate(&bob, "ama"));
+        assert!(rooms.spectate(&carol, "ama"));
+        let room = rooms.get("ama").unwrap();
+        assert_eq!(room.members().count(), 3);
+        assert_eq!(room.participants(), ["alice"]);
+        assert_eq!(room.spectators(), ["bob", "carol"]);
+        assert!(room.is_full());
+
+        // A spectator who joins takes part, and one who leaves is forgotten
+        rooms.leave(&alice);
+        rooms.enter(&bob, "ama");
+        rooms.enter(&carol, LOBBY);
+        let room = rooms.get("ama").unwrap();
+        assert_eq!(room.participants(), ["bob"]);
+        assert!(room.spectators().is_empty());
+    }
+
+    #[test]
+    fn test_room_names() {
+        assert_eq!(parse_name("#games"), Ok("games"));
+        assert_eq!(parse_name("rust_beginners-2"), Ok("rust_beginners-2"));
+        assert!(parse_name("#").is_err());
+        assert!(parse_name("a b").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A one-shot task, run against the scheduler's target.
+type Task<T> = Box<dyn FnOnce(&T) + Send>;
+
+struct Entry<T> {
+    at: Instant,
+    /// Insertion order, so tasks due at the same instant run in the order they were scheduled.
+    seq: u64,
+    task: Task<T>,
+}
+
+// `BinaryHeap` is a max-heap: order entries so the earliest deadline is the greatest.
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+struct Queue<T> {
+    entries: BinaryHeap<Entry<T>>,
+    next_seq: u64,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    wakeup: Condvar,
+}
+
+/// Runs tasks at a later point in time (e.g. lifting a timed mute) on a dedicated thread.
+///
+/// Tasks receive a reference to the target, which is only weakly held so that the
+/// target may own its scheduler. Tasks should be quick, they run one after the other.
+pub struct Scheduler<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Send + Sync + 'static> Scheduler<T> {
+    /// Starts the scheduler thread.
+    pub fn new(target: Weak<T>) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            wakeup: Condvar::new(),
+        });
+        let worker = Arc::clone(&shared);
+        thread::spawn(move || run(&worker, &target));
+        Scheduler { shared }
+    }
+
+    /// Runs `task` once `delay` has elapsed. A delay too long to tell when that is
+    /// never elapses, and the task is dropped.
+    pub fn schedule(&self, delay: Duration, task: impl FnOnce(&T) + Send + 'static) {
+        let Some(at) = Instant::now().checked_add(delay) else {
+            return;
+        };
+        let mut queue = self.shared.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Entry {
+            at,
+            seq,
+            task: Box::new(task),
+        });
+        self.shared.wakeup.notify_one();
+    }
+}
+
+fn run<T>(shared: &Shared<T>, target: &Weak<T>) {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        match queue.entries.peek() {
+            Some(entry) if entry.at <= now => {
+                let entry = queue.entries.pop().expect("peeked entry");
+                // Don't hold the lock while the task runs, it may schedule more tasks
+                drop(queue);
+                match target.upgrade() {
+                    Some(target) => (entry.task)(&target),
+                    None => return,
+                }
+                queue = shared.queue.lock().unwrap();
+            }
+            Some(entry) => {
+                let wait = entry.at - now;
+                queue = shared.wakeup.wait_timeout(queue, wait).unwrap().0;
+            }
+            None => queue = shared.wakeup.wait(queue).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_tasks_run_in_deadline_order() {
+        let target = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::new(Arc::downgrade(&target));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        scheduler.schedule(Duration::from_millis(40), move |log: &Mutex<Vec<u32>>| {
+            log.lock().unwrap().push(2);
+            done_tx.send(()).unwrap();
+        });
+        scheduler.schedule(Duration::from_millis(10), |log: &Mutex<Vec<u32>>| {
+            log.lock().unwrap().push(1)
+        });
+
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*target.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_endless_delays_are_dropped() {
+        let target = Arc::new(Mutex::new(Vec::new()));
+        let scheduler = Scheduler::new(Arc::downgrade(&target));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        scheduler.schedule(Duration::MAX, |log: &Mutex<Vec<u32>>| {
+            log.lock().unwrap().push(1)
+        });
+        scheduler.schedule(Duration::ZERO, move |log: &Mutex<Vec<u32>>| {
+            log.lock().unwrap().push(2);
+            done_tx.send(()).unwrap();
+        });
+
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(*target.lock().unwrap(), vec![2]);
+    }
+}
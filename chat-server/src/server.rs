@@ -0,0 +1,1078 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::accounts::Accounts;
+use crate::allowlist::Allowlist;
+use crate::api::ApiTokens;
+use crate::audit::AuditLog;
+use crate::away::Away;
+use crate::bans::{Ban, Bans};
+use crate::cidr::Cidr;
+use crate::config;
+use crate::connections::Connections;
+use crate::digest::{self, Digests, Missed};
+use crate::duration;
+use crate::events::{self, Event, Events};
+use crate::firehose::Firehose;
+use crate::history::{Message, Retention};
+use crate::mail;
+use crate::names;
+use crate::origin::Origin;
+use crate::polls::Polls;
+use crate::push::{self, Device, Notification};
+use crate::quota::{Exceeded, Quota, Quotas};
+use crate::ratelimit::{Limit, RateLimits};
+use crate::reports::Reports;
+use crate::rooms::{Room, Rooms};
+use crate::scheduler::Scheduler;
+use crate::sockopt::SocketOptions;
+use crate::stats::Stats;
+use crate::timestamp::TimeFormat;
+use crate::token;
+use crate::traffic::{Traffic, Usage};
+use crate::transport::Writer;
+use crate::webhooks::Webhooks;
+
+/// Number of messages kept in the in-memory history of each room.
+pub const HISTORY_SIZE: usize = 1000;
+
+/// How often messages past the room's retention are deleted, at most.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to check whether digests are to be sent, while the config file has no
+/// `[email]` table.
+const DIGEST_CHECK: Duration = Duration::from_secs(60);
+
+/// How long a user who lost their connection keeps their session, and with it their
+/// username, for their client to resume it.
+pub const RESUME_GRACE: Duration = Duration::from_secs(120);
+
+/// Privilege level of a user. Roles are ordered: every role may run the commands
+/// of the roles below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Someone who connected without a name, and was given a `guest-NNNN` one. Guests
+    /// may talk in rooms, but not open them or send private messages.
+    Guest,
+    User,
+    Moderator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Guest => "guest",
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "guest" => Ok(Role::Guest),
+            "user" => Ok(Role::User),
+            "moderator" | "mod" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role: {s}")),
+        }
+    }
+}
+
+/// What the names the server gives guests start with.
+pub const GUEST_PREFIX: &str = "guest-";
+
+/// Whether `username` is one the server gives guests, e.g. `guest-0042`. Whoever goes
+/// by such a name is a guest, whether it was given or chosen.
+pub fn is_guest_name(username: &str) -> bool {
+    username
+        .strip_prefix(GUEST_PREFIX)
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Tells a client its room message was received, and the number it got, along with
+/// the id the client gave it.
+pub fn ack(seq: u64, id: Option<&str>) -> String {
+    match id {
+        Some(id) => format!("/ack {seq} {id}"),
+        None => format!("/ack {seq}"),
+    }
+}
+
+/// Identifies one of a user's connections. A resumed session keeps its id.
+pub type SessionId = u64;
+
+/// A user connected to the chat server, from one or more devices.
+pub struct User {
+    pub role: Role,
+    /// The user's connections. Only registered accounts may have more than one.
+    sessions: Vec<Session>,
+    /// Set with `/away`, until the user is back or their last session ends.
+    away: Option<Away>,
+}
+
+impl User {
+    /// Sends a line to every one of the user's sessions.
+    fn deliver(&mut self, line: &str) {
+        for session in &mut self.sessions {
+            session.deliver(line);
+        }
+    }
+
+    fn session(&mut self, id: SessionId) -> Option<&mut Session> {
+        self.sessions.iter_mut().find(|session| session.id == id)
+    }
+}
+
+/// One connection of a user.
+struct Session {
+    id: SessionId,
+    writer: Writer,
+    /// The address the connection came from, as seen by the server.
+    ip: IpAddr,
+    /// Where the address is, if the server looks that up.
+    origin: Option<Origin>,
+    /// Lets the client reclaim the session after losing its connection. A new one is
+    /// issued every time the session is resumed.
+    resume_token: String,
+    /// Lines that were meant for the session while it was disconnected. `None` while
+    /// it is connected.
+    missed: Option<Vec<String>>,
+    /// How many lines are kept in `missed`, the user's quota. Anything beyond is
+    /// dropped, and counted in `dropped`.
+    max_missed: usize,
+    dropped: usize,
+    /// When the session started. Resuming it doesn't reset this.
+    joined_at: Instant,
+    /// When the client last sent anything.
+    active_at: Instant,
+    /// Bytes sent to and received from the client, across resumes.
+    usage: Usage,
+    /// What the client said it supports with `/caps` at the handshake.
+    capabilities: Vec<String>,
+}
+
+/// What `/whois` shows about one of a user's sessions.
+pub struct SessionInfo {
+    pub ip: IpAddr,
+    pub origin: Option<Origin>,
+    pub transport: &'static str,
+    pub connected_for: Duration,
+    pub idle_for: Duration,
+    /// False while the session waits to be resumed.
+    pub connected: bool,
+    pub usage: Usage,
+    pub capabilities: Vec<String>,
+}
+
+impl Session {
+    /// Sends a line to the client, or keeps it for them if they are disconnected.
+    fn deliver(&mut self, line: &str) {
+        match &mut self.missed {
+            Some(missed) if missed.len() < self.max_missed => missed.push(line.to_string()),
+            Some(_) => self.dropped += 1,
+            // A failed write means the client is going away, its own connection
+            // thread takes care of the cleanup.
+            None => {
+                self.usage.sent += line.len() as u64 + 1;
+                let _ = self.writer.write_line(line);
+            }
+        }
+    }
+
+    /// Sends the client the token it can resume its session with.
+    fn send_resume_token(&mut self) {
+        let line = format!("/session {}", self.resume_token);
+        self.usage.sent += line.len() as u64 + 1;
+        let _ = self.writer.write_line(&line);
+    }
+
+    /// Sends the client several lines at once, batched into as few writes as possible.
+    fn deliver_all(&mut self, lines: &[String]) {
+        match &self.missed {
+            Some(_) => lines.iter().for_each(|line| self.deliver(line)),
+            None => {
+                let bytes: usize = lines.iter().map(|line| line.len() + 1).sum();
+                self.usage.sent += bytes as u64;
+                let _ = self.writer.write_lines(lines);
+            }
+        }
+    }
+}
+
+/// State shared between every client connection.
+pub struct Server {
+    /// Connected users, keyed by their (unique) username. `Arc<String>` avoids
+    /// unnecessary `String` allocations when the name is shared between threads.
+    users: Mutex<HashMap<Arc<String>, User>>,
+    /// The id the next session gets.
+    next_session: AtomicU64,
+    /// Muted usernames and when their mute expires. Kept apart from `users` so
+    /// that reconnecting doesn't lift a mute.
+    mutes: Mutex<HashMap<String, Instant>>,
+    /// Runs timed tasks, such as lifting mutes, against the server.
+    pub scheduler: Scheduler<Server>,
+    /// The rooms and their recent messages, e.g. to give moderators context on a
+    /// report. Locked before `users` when both are needed.
+    pub rooms: Mutex<Rooms>,
+    /// Reports waiting for a moderator.
+    pub reports: Mutex<Reports>,
+    /// The open polls. Locked after `rooms` when both are needed.
+    pub polls: Mutex<Polls>,
+    /// Tamper-evident record of every privileged action.
+    pub audit: Mutex<AuditLog>,
+    /// Registered accounts, whose usernames need a password.
+    pub accounts: Mutex<Accounts>,
+    /// What each account may use, and has used.
+    pub quotas: Mutex<Quotas>,
+    /// How fast users may do things.
+    rate_limits: Mutex<RateLimits>,
+    /// The connections each address holds open.
+    connections: Mutex<Connections>,
+    /// Set on every client connection as it is accepted.
+    sockets: Mutex<SocketOptions>,
+    /// Addresses that may not connect.
+    pub bans: Mutex<Bans>,
+    /// Who may join, if the server is allowlist-only.
+    pub allowlist: Mutex<Allowlist>,
+    /// Which usernames may be picked.
+    pub usernames: Mutex<names::Policy>,
+    /// The tokens scripts post to rooms over the HTTP API with.
+    pub api_tokens: Mutex<ApiTokens>,
+    /// What the sessions that have ended used. Locked after `users`.
+    traffic: Mutex<Traffic>,
+    /// The devices users get pushes on while offline.
+    pub push_devices: Mutex<push::Devices>,
+    /// What users who want digests missed while offline. Locked after `users`.
+    pub digests: Mutex<Digests>,
+    /// How mail is sent, if the config file says.
+    email: Mutex<Option<mail::Settings>>,
+    /// Where what happens on the server is observed.
+    pub events: Events,
+    /// Where events are delivered to over HTTP, as the config file says.
+    pub webhooks: Arc<Webhooks>,
+    /// Where rooms' messages are streamed to over HTTP.
+    pub firehose: Arc<Firehose>,
+    /// Counters for `/stats`.
+    pub stats: Stats,
+    /// Where persistent data is kept.
+    pub data_dir: PathBuf,
+    /// How times are shown to users.
+    pub time_format: TimeFormat,
+    /// The config file declaring the permanent rooms and quotas, if there is one.
+    pub config: Option<PathBuf>,
+}
+
+/// A connection counted against its address's cap until it is dropped, whether the
+/// client got through the handshake or not.
+pub struct Slot {
+    server: Arc<Server>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.server.connections.lock().unwrap().close(self.ip);
+    }
+}
+
+/// A snapshot of the connected users, for `/stats`.
+pub struct UserCounts {
+    pub users: usize,
+    pub sessions: usize,
+    /// Sessions waiting to be resumed.
+    pub suspended: usize,
+    /// Lines held for the suspended sessions.
+    pub missed_lines: usize,
+}
+
+impl Server {
+    /// Creates the server state, keeping persistent data (such as the audit log)
+    /// in `data_dir`, and room messages as long as `retention` says. The permanent
+    /// rooms and quotas in the `config` file are set with [`Server::load_config`].
+    pub fn new(
+        data_dir: &Path,
+        time_format: TimeFormat,
+        retention: Retention,
+        config: Option<PathBuf>,
+    ) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(data_dir)?;
+        let audit = AuditLog::open(&data_dir.join("audit.log"))?;
+        let accounts = Accounts::open(&data_dir.join("accounts.json"))?;
+        let rooms = Rooms::open(&data_dir.join("rooms.json"), retention)?;
+        let bans = Bans::open(&data_dir.join("bans.json"))?;
+        let allowlist = Allowlist::open(&data_dir.join("allowlist.json"))?;
+        let api_tokens = ApiTokens::open(&data_dir.join("api_tokens.json"))?;
+        let push_devices = push::Devices::open(&data_dir.join("push_devices.json"))?;
+        let expiries: Vec<u64> = bans.expiries().collect();
+        let server = Arc::new_cyclic(|server| Server {
+            users: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(0),
+            mutes: Mutex::new(HashMap::new()),
+            scheduler: Scheduler::new(server.clone()),
+            rooms: Mutex::new(rooms),
+            reports: Mutex::new(Reports::new()),
+            polls: Mutex::new(Polls::new()),
+            audit: Mutex::new(audit),
+            accounts: Mutex::new(accounts),
+            quotas: Mutex::new(Quotas::default()),
+            rate_limits: Mutex::new(RateLimits::default()),
+            connections: Mutex::new(Connections::default()),
+            sockets: Mutex::new(SocketOptions::default()),
+            traffic: Mutex::new(Traffic::default()),
+            push_devices: Mutex::new(push_devices),
+            digests: Mutex::new(Digests::default()),
+            email: Mutex::new(None),
+            bans: Mutex::new(bans),
+            allowlist: Mutex::new(allowlist),
+            usernames: Mutex::new(names::Policy::default()),
+            api_tokens: Mutex::new(api_tokens),
+            events: Events::default(),
+            webhooks: Arc::new(Webhooks::new(&data_dir.join("webhooks-dead.jsonl"))),
+            firehose: Arc::default(),
+            stats: Stats::new(),
+            data_dir: data_dir.to_path_buf(),
+            time_format,
+            config,
+        });
+        server.events.register(events::Log);
+        server.events.register(Arc::clone(&server.webhooks));
+        server.events.register(Arc::clone(&server.firehose));
+        server.reap();
+        server
+            .scheduler
+            .schedule(DIGEST_CHECK, Server::send_digests);
+        for until in expiries {
+            server.expire_ban_at(until);
+        }
+        Ok(server)
+    }
+
+    /// Opens or updates the permanent rooms declared in the config file `path`, and
+    /// sets the username policy, quotas, rate limits and connection caps. Returns how many rooms it
+    /// declares, and how many rooms are no longer permanent.
+    pub fn load_config(&self, path: &Path) -> Result<(usize, usize), String> {
+        let config = config::load(path)?;
+        let declared = config.rooms.len();
+        let dropped = self.rooms.lock().unwrap().configure(config.rooms);
+        *self.usernames.lock().unwrap() = config.usernames;
+        self.rate_limits
+            .lock()
+            .unwrap()
+            .configure(config.rate_limits);
+        self.connections
+            .lock()
+            .unwrap()
+            .configure(config.connections);
+        *self.sockets.lock().unwrap() = config.sockets;
+        *self.email.lock().unwrap() = config.email;
+        self.webhooks.configure(config.webhooks);
+        let mut quotas = self.quotas.lock().unwrap();
+        quotas.configure(config.quotas, config.account_quotas);
+        let mut users = self.users.lock().unwrap();
+        for (username, user) in users.iter_mut() {
+            let max_missed = quotas.limits(username).offline_lines;
+            for session in &mut user.sessions {
+                session.max_missed = max_missed;
+            }
+        }
+        Ok((declared, dropped))
+    }
+
+    /// Counts a new connection from `ip`, until the returned slot is dropped. Returns
+    /// `None` if the address holds as many connections open as it may already.
+    pub fn open_connection(self: &Arc<Self>, ip: IpAddr) -> Option<Slot> {
+        self.connections.lock().unwrap().open(ip).then(|| Slot {
+            server: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// The options to set on connections accepted from now on.
+    pub fn socket_options(&self) -> SocketOptions {
+        *self.sockets.lock().unwrap()
+    }
+
+    /// Counts something `username` does that may be rate limited. Returns how long
+    /// until they may do it again if they are going too fast.
+    pub fn throttle(&self, limit: Limit, username: &str) -> Result<(), Duration> {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        rate_limits.check(limit, username, Instant::now())
+    }
+
+    /// Counts something `username` does `count` times at once, all of them or none.
+    pub fn throttle_several(
+        &self,
+        limit: Limit,
+        username: &str,
+        count: u32,
+    ) -> Result<(), Duration> {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        rate_limits.check_several(limit, username, count, Instant::now())
+    }
+
+    /// Deletes the room messages past their retention, then checks again in a
+    /// minute, or sooner if a room keeps its messages for less than that.
+    fn reap(&self) {
+        let now = SystemTime::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let expired: usize = rooms
+            .iter_mut()
+            .map(|(_, room)| room.history.expire(now))
+            .sum();
+        let interval = rooms
+            .iter()
+            .filter_map(|(_, room)| room.history.max_age())
+            .fold(REAP_INTERVAL, Duration::min);
+        drop(rooms);
+        if expired > 0 {
+            println!("Deleted {expired} message(s) past their retention");
+        }
+        self.scheduler.schedule(interval, Server::reap);
+    }
+
+    /// Mails the pending digests to the users who still want them, then sends the
+    /// next ones after `digest_every`. The mails go out from a thread of their own.
+    fn send_digests(&self) {
+        let Some(settings) = self.email.lock().unwrap().clone() else {
+            self.scheduler.schedule(DIGEST_CHECK, Server::send_digests);
+            return;
+        };
+        let digests = self.digests.lock().unwrap().take();
+        let accounts = self.accounts.lock().unwrap();
+        let mails: Vec<(String, String)> = (digests.into_iter())
+            .filter_map(|(username, body)| {
+                let account = accounts.get(&username).filter(|account| account.digest)?;
+                Some((account.email.clone()?, body))
+            })
+            .collect();
+        drop(accounts);
+        if !mails.is_empty() {
+            let settings = settings.clone();
+            thread::spawn(move || {
+                for (to, body) in mails {
+                    let subject = "What you missed on the chat server";
+                    if let Err(e) = mail::send(&settings, &to, subject, &body) {
+                        println!("Failed to mail a digest to {to}: {e}");
+                    }
+                }
+            });
+        }
+        self.scheduler
+            .schedule(settings.digest_every, Server::send_digests);
+    }
+
+    /// Whether the config file says how to send mail, so there are digests.
+    pub fn sends_email(&self) -> bool {
+        self.email.lock().unwrap().is_some()
+    }
+
+    /// Lets `username` know of a message while none of their sessions is connected:
+    /// pushes it to the devices they registered, and keeps it for their next digest if
+    /// they want digests. Returns whether they'll hear of it either way.
+    pub fn miss(&self, username: &str, missed: Missed) -> bool {
+        let wants_digest = (self.accounts.lock().unwrap().get(username))
+            .is_some_and(|account| account.digest && account.email.is_some());
+        let devices: Vec<Device> = (self.push_devices.lock().unwrap().of(username))
+            .map(|(_, device)| device.clone())
+            .collect();
+        if (!wants_digest && devices.is_empty()) || self.is_connected(username) {
+            return false;
+        }
+        let title = match &missed.room {
+            Some(room) => format!("{} in #{room}", missed.from),
+            None => format!("{} (private)", missed.from),
+        };
+        for device in devices {
+            push::send(device, Notification::new(title.clone(), &missed.text));
+        }
+        if wants_digest {
+            self.digests.lock().unwrap().add(username, missed);
+        }
+        true
+    }
+
+    /// Keeps a room message for the digests of the users it `@mentions`.
+    pub fn note_mentions(&self, room: &str, from: &str, text: &str) {
+        for username in digest::mentions(text) {
+            if username == from {
+                continue;
+            }
+            let missed = Missed {
+                at: SystemTime::now(),
+                from: from.to_string(),
+                room: Some(room.to_string()),
+                text: text.to_string(),
+            };
+            self.miss(username, missed);
+        }
+    }
+
+    /// Whether any session of `username` is connected, rather than waiting to be
+    /// resumed.
+    pub fn is_connected(&self, username: &str) -> bool {
+        let users = self.users.lock().unwrap();
+        (users.get(&username.to_string()))
+            .is_some_and(|user| user.sessions.iter().any(|session| session.missed.is_none()))
+    }
+
+    /// Returns true if `username`, or a name it would pass for, is already in use,
+    /// including by a disconnected user whose session can still be resumed.
+    pub fn is_taken(&self, username: &str) -> bool {
+        let users = self.users.lock().unwrap();
+        if users.contains_key(&username.to_string()) {
+            return true;
+        }
+        // Nor may anyone pass for someone else
+        let skeleton = names::skeleton(username);
+        users.keys().any(|name| names::skeleton(name) == skeleton)
+    }
+
+    /// Registers a session for a user connected from `ip`, and sends them the token to
+    /// resume it with. `writer` is used to deliver messages to them. With `shared`, a
+    /// user who is already connected gets another session, otherwise the writer is
+    /// given back if the username was taken in the meantime.
+    pub fn join(
+        &self,
+        username: Arc<String>,
+        writer: Writer,
+        ip: IpAddr,
+        origin: Option<Origin>,
+        shared: bool,
+    ) -> Result<SessionId, Writer> {
+        let max_missed = self.quotas.lock().unwrap().limits(&username).offline_lines;
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(&username) && !shared {
+            return Err(writer);
+        }
+        let now = Instant::now();
+        let mut session = Session {
+            id: self.next_session.fetch_add(1, Ordering::Relaxed),
+            writer,
+            ip,
+            origin,
+            resume_token: token::generate(),
+            missed: None,
+            max_missed,
+            dropped: 0,
+            joined_at: now,
+            active_at: now,
+            usage: Usage::default(),
+            capabilities: Vec::new(),
+        };
+        session.send_resume_token();
+        let id = session.id;
+        let role = if is_guest_name(&username) {
+            Role::Guest
+        } else {
+            Role::User
+        };
+        users
+            .entry(username)
+            .or_insert_with(|| User {
+                role,
+                sessions: Vec::new(),
+                away: None,
+            })
+            .sessions
+            .push(session);
+        Ok(id)
+    }
+
+    /// A guest name no one goes by at the moment, e.g. `guest-0042`. Names get longer
+    /// if the short ones are hard to come by.
+    pub fn guest_name(&self) -> String {
+        for digits in 4.. {
+            for _ in 0..10 {
+                let random = u64::from_str_radix(&token::generate()[..16], 16).unwrap_or_default();
+                let number = random % 10u64.pow(digits);
+                let name = format!("{GUEST_PREFIX}{number:0width$}", width = digits as usize);
+                // Guest names can't be registered, but accounts may predate that
+                let accounts = self.accounts.lock().unwrap();
+                let reserved = accounts.find(&name).is_some() || accounts.is_forgotten(&name);
+                drop(accounts);
+                if !self.is_taken(&name) && !reserved {
+                    return name;
+                }
+            }
+        }
+        unreachable!("some guest name is free")
+    }
+
+    /// A name like `username` that no one goes by or has registered, and the username
+    /// policy allows, e.g. `alice_2` for `alice`, or `None` if the first hundred or so
+    /// are all taken.
+    pub fn free_name(&self, username: &str) -> Option<String> {
+        let policy = self.usernames.lock().unwrap().clone();
+        (2..100)
+            .map(|n| format!("{username}_{n}"))
+            .filter(|name| policy.check(name).is_ok())
+            .find(|name| {
+                let accounts = self.accounts.lock().unwrap();
+                let reserved = accounts.find(name).is_some() || accounts.is_forgotten(name);
+                drop(accounts);
+                !self.is_taken(name) && !reserved
+            })
+    }
+
+    /// Returns true if `token` lets `username` resume one of their disconnected sessions.
+    pub fn can_resume(&self, username: &str, token: &str) -> bool {
+        let users = self.users.lock().unwrap();
+        users.get(&username.to_string()).is_some_and(|user| {
+            user.sessions
+                .iter()
+                .any(|session| session.missed.is_some() && session.resume_token == token)
+        })
+    }
+
+    /// Hands a disconnected session over to its new connection, sends it a new resume
+    /// token and then whatever it missed. Gives the writer back if the session expired
+    /// in the meantime.
+    pub fn resume(
+        &self,
+        username: &str,
+        token: &str,
+        writer: Writer,
+        ip: IpAddr,
+        origin: Option<Origin>,
+    ) -> Result<SessionId, Writer> {
+        let mut users = self.users.lock().unwrap();
+        let session = users.get_mut(&username.to_string()).and_then(|user| {
+            user.sessions
+                .iter_mut()
+                .find(|session| session.missed.is_some() && session.resume_token == token)
+        });
+        let Some(session) = session else {
+            return Err(writer);
+        };
+        session.writer = writer;
+        session.ip = ip;
+        session.origin = origin;
+        session.active_at = Instant::now();
+        session.resume_token = token::generate();
+        // Delivered while holding the lock, so they come before anything new, and in
+        // one batch rather than a write for every line missed
+        let missed = session.missed.take().unwrap_or_default();
+        let mut lines = Vec::with_capacity(missed.len() + 3);
+        lines.push(format!("/session {}", session.resume_token));
+        if !missed.is_empty() {
+            lines.push(format!(
+                "Welcome back! You missed {} line(s) while you were away:",
+                missed.len()
+            ));
+        }
+        lines.extend(missed);
+        if session.dropped > 0 {
+            self.stats.dropped_lines.add(session.dropped as u64);
+            let exceeded = Exceeded {
+                quota: Quota::OfflineLines,
+                limit: session.max_missed as u64,
+            };
+            lines.push(exceeded.line(None));
+            session.dropped = 0;
+        }
+        session.deliver_all(&lines);
+        Ok(session.id)
+    }
+
+    /// Keeps a session that lost its connection, collecting what it misses, until it is
+    /// resumed or [`RESUME_GRACE`] runs out.
+    pub fn suspend(&self, username: &Arc<String>, id: SessionId) {
+        let mut users = self.users.lock().unwrap();
+        let Some(session) = users.get_mut(username).and_then(|user| user.session(id)) else {
+            return;
+        };
+        session.missed = Some(Vec::new());
+        let username = Arc::clone(username);
+        let token = session.resume_token.clone();
+        self.scheduler.schedule(RESUME_GRACE, move |server| {
+            server.expire_session(&username, id, &token)
+        });
+    }
+
+    /// Ends a suspended session whose grace period is over. A session that was
+    /// resumed in the meantime has a new token, and is left alone.
+    fn expire_session(&self, username: &Arc<String>, id: SessionId, token: &str) {
+        let expired = self
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(username)
+            .and_then(|user| user.session(id))
+            .is_some_and(|session| session.missed.is_some() && session.resume_token == token);
+        if expired {
+            self.leave(username, id);
+            self.events.emit(Event::SessionExpired { username });
+        }
+    }
+
+    /// Ends one of a user's sessions. The user is removed from the list of connected
+    /// users, and from their room, along with their last session. An unregistered user
+    /// also stops being a room op or the creator of a room then, as anyone may take
+    /// their name next.
+    pub fn leave(&self, username: &Arc<String>, id: SessionId) {
+        let registered = self.accounts.lock().unwrap().get(username).is_some();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut users = self.users.lock().unwrap();
+        let Some(user) = users.get_mut(username) else {
+            return;
+        };
+        user.sessions.retain(|session| {
+            if session.id == id {
+                self.stats.dropped_lines.add(session.dropped as u64);
+                let mut traffic = self.traffic.lock().unwrap();
+                traffic.record(username, session.usage);
+            }
+            session.id != id
+        });
+        if user.sessions.is_empty() {
+            users.remove(username);
+            rooms.leave(username);
+            if !registered {
+                if let Err(e) = rooms.revoke(username) {
+                    eprintln!("Failed to save the rooms: {e}");
+                }
+            }
+        }
+    }
+
+    /// Returns the number of sessions a connected user has.
+    pub fn sessions(&self, username: &str) -> usize {
+        let users = self.users.lock().unwrap();
+        users
+            .get(&username.to_string())
+            .map_or(0, |user| user.sessions.len())
+    }
+
+    /// Notes that a session's client just sent a line of `bytes`, for its idle time
+    /// and traffic.
+    pub fn touch(&self, username: &str, id: SessionId, bytes: usize) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(session) = users
+            .get_mut(&username.to_string())
+            .and_then(|user| user.session(id))
+        {
+            session.active_at = Instant::now();
+            session.usage.received += bytes as u64;
+        }
+    }
+
+    /// Keeps what a session's client announced it supports, for `/whois`. Clients
+    /// announce it again when they resume a session.
+    pub fn set_capabilities(&self, username: &str, id: SessionId, capabilities: Vec<String>) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(session) = users
+            .get_mut(&username.to_string())
+            .and_then(|user| user.session(id))
+        {
+            session.capabilities = capabilities;
+        }
+    }
+
+    /// The bytes every account sent and received since the server started, heaviest
+    /// users first.
+    pub fn traffic(&self) -> Vec<(String, Usage)> {
+        let users = self.users.lock().unwrap();
+        let live = users.iter().flat_map(|(username, user)| {
+            (user.sessions.iter()).map(|session| (username.to_string(), session.usage))
+        });
+        self.traffic.lock().unwrap().with(live)
+    }
+
+    /// Describes the sessions of a connected user, oldest first.
+    pub fn session_info(&self, username: &str) -> Option<Vec<SessionInfo>> {
+        let users = self.users.lock().unwrap();
+        let user = users.get(&username.to_string())?;
+        let now = Instant::now();
+        let info = user
+            .sessions
+            .iter()
+            .map(|session| SessionInfo {
+                ip: session.ip,
+                origin: session.origin.clone(),
+                transport: session.writer.transport(),
+                connected_for: now - session.joined_at,
+                idle_for: now - session.active_at,
+                connected: session.missed.is_none(),
+                usage: session.usage,
+                capabilities: session.capabilities.clone(),
+            })
+            .collect();
+        Some(info)
+    }
+
+    /// Counts the connected users and their sessions.
+    pub fn user_counts(&self) -> UserCounts {
+        let users = self.users.lock().unwrap();
+        let sessions = users.values().flat_map(|user| &user.sessions);
+        let missed = sessions.filter_map(|session| session.missed.as_ref());
+        let (suspended, missed_lines) = missed.fold((0, 0), |(count, lines), missed| {
+            (count + 1, lines + missed.len())
+        });
+        UserCounts {
+            users: users.len(),
+            sessions: users.values().map(|user| user.sessions.len()).sum(),
+            suspended,
+            missed_lines,
+        }
+    }
+
+    /// Returns the role of a connected user.
+    pub fn role(&self, username: &str) -> Option<Role> {
+        let users = self.users.lock().unwrap();
+        users.get(&username.to_string()).map(|user| user.role)
+    }
+
+    /// Returns the address one of a user's sessions came from.
+    pub fn ip(&self, username: &str, id: SessionId) -> Option<IpAddr> {
+        let mut users = self.users.lock().unwrap();
+        users
+            .get_mut(&username.to_string())
+            .and_then(|user| user.session(id))
+            .map(|session| session.ip)
+    }
+
+    /// Changes the role of a connected user. Returns false if there is no such user.
+    pub fn set_role(&self, username: &str, role: Role) -> bool {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&username.to_string()) {
+            Some(user) => {
+                user.role = role;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks a connected user away for `reason`, or back with `None`. Returns whether
+    /// they were away before.
+    pub fn set_away(&self, username: &str, reason: Option<String>) -> bool {
+        let mut users = self.users.lock().unwrap();
+        let Some(user) = users.get_mut(&username.to_string()) else {
+            return false;
+        };
+        std::mem::replace(&mut user.away, reason.map(Away::new)).is_some()
+    }
+
+    /// Why a connected user is away, if they are.
+    pub fn away(&self, username: &str) -> Option<String> {
+        let users = self.users.lock().unwrap();
+        let away = users.get(&username.to_string())?.away.as_ref()?;
+        Some(away.reason().to_string())
+    }
+
+    /// The reason to tell `from`, who sent `username` a private message, if `username`
+    /// is away and `from` wasn't told so lately.
+    pub fn away_reply(&self, username: &str, from: &str) -> Option<String> {
+        let mut users = self.users.lock().unwrap();
+        let away = users.get_mut(&username.to_string())?.away.as_mut()?;
+        away.reply_to(from, Instant::now()).map(str::to_string)
+    }
+
+    /// Mutes a user for `duration`, replacing any existing mute. The mute is lifted
+    /// (and the user told so) by the scheduler once it expires. Fails if `duration` is
+    /// too long to tell when that is.
+    pub fn mute(&self, username: &str, duration: Duration) -> Result<(), String> {
+        let until = (Instant::now().checked_add(duration))
+            .ok_or_else(|| format!("Duration too long: {}", duration::format(duration)))?;
+        self.mutes
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), until);
+        let username = username.to_string();
+        self.scheduler
+            .schedule(duration, move |server| server.expire_mute(&username));
+        Ok(())
+    }
+
+    /// Lifts a mute early. Returns false if the user wasn't muted.
+    pub fn unmute(&self, username: &str) -> bool {
+        self.mutes.lock().unwrap().remove(username).is_some()
+    }
+
+    /// Returns how long a user remains muted, if they are.
+    pub fn muted_for(&self, username: &str) -> Option<Duration> {
+        let mutes = self.mutes.lock().unwrap();
+        let until = mutes.get(username)?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    /// Lifts a mute whose time is up. A mute that was since replaced by a longer
+    /// one (or lifted by hand) is left alone.
+    fn expire_mute(&self, username: &str) {
+        let mut mutes = self.mutes.lock().unwrap();
+        match mutes.get(username) {
+            Some(until) if *until <= Instant::now() => {
+                mutes.remove(username);
+                drop(mutes);
+                self.send_to(username, "You are no longer muted");
+            }
+            _ => {}
+        }
+    }
+
+    /// Bans a block of addresses from connecting, and lifts the ban once it runs out
+    /// if it is timed.
+    pub fn ban(&self, ban: Ban) -> io::Result<()> {
+        let until = ban.until;
+        self.bans.lock().unwrap().ban(ban)?;
+        if let Some(until) = until {
+            self.expire_ban_at(until);
+        }
+        Ok(())
+    }
+
+    /// The ban keeping `ip` out, if there is one.
+    pub fn banned(&self, ip: IpAddr) -> Option<Ban> {
+        let bans = self.bans.lock().unwrap();
+        bans.find(ip, SystemTime::now()).cloned()
+    }
+
+    /// Forgets the bans that have run out at `until` (seconds since the Unix epoch).
+    /// A ban that was since extended is left alone.
+    fn expire_ban_at(&self, until: u64) {
+        let at = UNIX_EPOCH + Duration::from_secs(until);
+        let delay = at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.scheduler.schedule(delay, |server| {
+            if let Err(e) = server.bans.lock().unwrap().expire(SystemTime::now()) {
+                eprintln!("Failed to save the bans: {e}");
+            }
+        });
+    }
+
+    /// The users with a session connected from `cidr`, in order.
+    pub fn users_from(&self, cidr: Cidr) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+        let mut found: Vec<String> = (users.iter())
+            .filter(|(_, user)| {
+                let mut sessions = user.sessions.iter();
+                sessions.any(|session| cidr.contains(session.ip))
+            })
+            .map(|(username, _)| username.to_string())
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// Sends a line to every connected user.
+    pub fn broadcast_all(&self, line: &str) {
+        let mut users = self.users.lock().unwrap();
+        for recipient in users.values_mut() {
+            recipient.deliver(line);
+        }
+    }
+
+    /// Sends a line to every connected user whose role is at least `role`.
+    pub fn broadcast_to_role(&self, role: Role, line: &str) {
+        let mut users = self.users.lock().unwrap();
+        for recipient in users.values_mut().filter(|user| user.role >= role) {
+            recipient.deliver(line);
+        }
+    }
+
+    /// Sends a line to the sessions of everyone in `room`, except the session it came
+    /// from, if any, so the sender's other devices see it too. How long after the
+    /// message was `received` each of them got it goes into the fan-out histogram.
+    pub fn broadcast(
+        &self,
+        room: &Room,
+        except: Option<(&Arc<String>, SessionId)>,
+        line: &str,
+        received: Instant,
+    ) {
+        let mut users = self.users.lock().unwrap();
+        for member in room.members() {
+            let Some(recipient) = users.get_mut(member) else {
+                continue;
+            };
+            for session in &mut recipient.sessions {
+                if except != Some((member, session.id)) {
+                    session.deliver(line);
+                    self.stats.fanout.record(received.elapsed());
+                }
+            }
+        }
+    }
+
+    /// Posts messages to `room` from outside the chat, e.g. from a script over HTTP, as
+    /// `from`, who needn't be connected. Returns the numbers they got, or `None` if
+    /// there is no such room.
+    pub fn post(&self, room: &str, from: &str, texts: &[&str]) -> Option<Vec<u64>> {
+        let received = Instant::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let target = rooms.get_mut(room)?;
+        let from = Arc::new(from.to_string());
+        let mut seqs = Vec::with_capacity(texts.len());
+        for text in texts {
+            let message = target.history.push(Arc::clone(&from), text, None);
+            self.publish(room, target, &message, None, None, received);
+            seqs.push(message.seq);
+        }
+        Some(seqs)
+    }
+
+    /// Sends `message`, just added to the history of `room`, named `name`, to its
+    /// members, and tells the webhooks, the firehose and those it mentions. The session
+    /// it was typed in, if any, is told the number it got instead, with the client's
+    /// `id` for it. Every room message goes out this way, whatever posted it.
+    pub fn publish(
+        &self,
+        name: &str,
+        room: &Room,
+        message: &Message,
+        sender: Option<(&Arc<String>, SessionId)>,
+        id: Option<&str>,
+        received: Instant,
+    ) {
+        self.stats.messages.bump();
+        self.broadcast(room, sender, &message.line(), received);
+        if let Some((username, session)) = sender {
+            self.send_to_session(username, session, &ack(message.seq, id));
+        }
+        self.events.emit(Event::MessageBroadcast {
+            room: name,
+            from: &message.from,
+            seq: message.seq,
+            text: &message.text,
+        });
+        self.note_mentions(name, &message.from, &message.text);
+    }
+
+    /// Sends a line to a single session of a user, e.g. a reply to a command.
+    pub fn send_to_session(&self, username: &str, id: SessionId, line: &str) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(session) = users
+            .get_mut(&username.to_string())
+            .and_then(|user| user.session(id))
+        {
+            session.deliver(line);
+        }
+    }
+
+    /// Sends a line to every session of a single user. Returns false if there is no
+    /// such user.
+    pub fn send_to(&self, username: &str, line: &str) -> bool {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(&username.to_string()) {
+            Some(recipient) => {
+                recipient.deliver(line);
+                true
+            }
+            None => false,
+        }
+    }
+}
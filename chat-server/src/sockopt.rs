@@ -0,0 +1,124 @@
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::time::Duration;
+
+/// Options set on every client connection, from the config file. Those that aren't
+/// set are left at the OS's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small writes straight away (`TCP_NODELAY`) rather than waiting to
+    /// coalesce them, as interactive chat wants.
+    pub nodelay: Option<bool>,
+    /// Probe connections idle for this long (`SO_KEEPALIVE`, `TCP_KEEPIDLE`), so
+    /// clients that vanished without closing their connection are noticed.
+    pub keepalive: Option<Duration>,
+    /// How often to probe once the connection is idle (`TCP_KEEPINTVL`).
+    pub keepalive_interval: Option<Duration>,
+    /// The kernel's send buffer, in bytes (`SO_SNDBUF`).
+    pub send_buffer: Option<usize>,
+    /// The kernel's receive buffer, in bytes (`SO_RCVBUF`).
+    pub receive_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options on a newly accepted connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(idle) = self.keepalive {
+            set(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            set(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, seconds(idle))?;
+        }
+        if let Some(interval) = self.keepalive_interval {
+            set(
+                stream,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPINTVL,
+                seconds(interval),
+            )?;
+        }
+        if let Some(bytes) = self.send_buffer {
+            set(stream, libc::SOL_SOCKET, libc::SO_SNDBUF, clamp(bytes))?;
+        }
+        if let Some(bytes) = self.receive_buffer {
+            set(stream, libc::SOL_SOCKET, libc::SO_RCVBUF, clamp(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whole seconds, at least one, as the keepalive options take.
+fn seconds(duration: Duration) -> libc::c_int {
+    clamp(duration.as_secs().max(1) as usize)
+}
+
+fn clamp(n: usize) -> libc::c_int {
+    libc::c_int::try_from(n).unwrap_or(libc::c_int::MAX)
+}
+
+fn set(
+    stream: &TcpStream,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` is a c_int, as these options expect
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            option,
+            ptr::addr_of!(value).cast(),
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn get(stream: &TcpStream, level: libc::c_int, option: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+        // SAFETY: `value` is a c_int of `len` bytes
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                option,
+                ptr::addr_of_mut!(value).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0);
+        value
+    }
+
+    #[test]
+    fn test_options_are_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Duration::from_secs(90)),
+            keepalive_interval: Some(Duration::from_millis(500)),
+            ..SocketOptions::default()
+        };
+        options.apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 90);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 1);
+    }
+}
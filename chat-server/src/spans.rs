@@ -0,0 +1,195 @@
+use std::fmt;
+
+/// Most spans a message may carry.
+const MAX_SPANS: usize = 64;
+/// Longest language name a code block may be tagged with.
+const MAX_LANGUAGE: usize = 16;
+
+/// How a stretch of a message is formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Italic,
+    /// Monospace, e.g. a command or some code.
+    Code,
+    /// Hidden until the reader asks to see it.
+    Spoiler,
+    /// A block of code, set apart on lines of its own.
+    Pre,
+    /// A web address. Links are the address itself, never text hiding one.
+    Link,
+    /// A line break, in place of a single space, so messages stay on one line of the
+    /// protocol and clients that don't know the tag show a space.
+    Break,
+    /// An excerpt of the message a reply quotes, set apart on a line of its own.
+    Quote,
+}
+
+impl Style {
+    fn letter(self) -> char {
+        match self {
+            Style::Bold => 'b',
+            Style::Italic => 'i',
+            Style::Code => 'c',
+            Style::Spoiler => 's',
+            Style::Pre => 'p',
+            Style::Link => 'l',
+            Style::Break => 'n',
+            Style::Quote => 'q',
+        }
+    }
+
+    fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'b' => Some(Style::Bold),
+            'i' => Some(Style::Italic),
+            'c' => Some(Style::Code),
+            's' => Some(Style::Spoiler),
+            'p' => Some(Style::Pre),
+            'l' => Some(Style::Link),
+            'n' => Some(Style::Break),
+            'q' => Some(Style::Quote),
+            _ => None,
+        }
+    }
+}
+
+/// A formatted stretch of a message, from its `start`th character up to its `end`th.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub style: Style,
+    pub start: usize,
+    pub end: usize,
+    /// The language of a code block, e.g. `rust`, for clients to highlight it.
+    pub language: Option<String>,
+}
+
+/// How a message's text is formatted, carried beside it in the `fmt` tag rather than
+/// as markup in it: `fmt=b0-4,s10-17`, a style letter and a range of characters for
+/// each span, and for a code block maybe its language, `p0-30:rust`. Clients that
+/// don't know the tag show the text as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spans(Vec<Span>);
+
+impl Spans {
+    /// Parses the `fmt` tag of a message with `text`. Returns `None` if any span is
+    /// malformed, has an unknown style or isn't within the text, a link isn't a web
+    /// address or a line break isn't in place of a space.
+    pub fn parse(value: &str, text: &str) -> Option<Self> {
+        let chars: Vec<char> = text.chars().collect();
+        let length = chars.len();
+        let is_url = |start: usize, end: usize| {
+            let url: String = chars[start..end].iter().collect();
+            let rest = url
+                .strip_prefix("https://")
+                .or_else(|| url.strip_prefix("http://"));
+            rest.is_some_and(|rest| !rest.is_empty() && !rest.contains(char::is_whitespace))
+        };
+        let spans = (value.split(','))
+            .map(|span| {
+                let mut letters = span.chars();
+                let style = Style::from_letter(letters.next()?)?;
+                let (range, language) = match letters.as_str().split_once(':') {
+                    Some((range, language)) if style == Style::Pre => (range, Some(language)),
+                    Some(_) => return None,
+                    None => (letters.as_str(), None),
+                };
+                let (start, end) = range.split_once('-')?;
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                let fits = start < end && end <= length;
+                let valid = match style {
+                    Style::Link => is_url(start, end),
+                    Style::Break => end == start + 1 && chars[start] == ' ',
+                    _ => true,
+                };
+                let language_ok = language.is_none_or(|language| {
+                    let valid = |c: char| c.is_ascii_alphanumeric() || "+#-".contains(c);
+                    (1..=MAX_LANGUAGE).contains(&language.len()) && language.chars().all(valid)
+                });
+                (fits && valid && language_ok).then(|| Span {
+                    style,
+                    start,
+                    end,
+                    language: language.map(str::to_string),
+                })
+            })
+            .collect::<Option<Vec<Span>>>()?;
+        (spans.len() <= MAX_SPANS).then_some(Spans(spans))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The spans of the text once `by` characters are put in front of it.
+    pub fn shifted(&self, by: usize) -> Self {
+        let shift = |span: &Span| Span {
+            start: span.start + by,
+            end: span.end + by,
+            ..span.clone()
+        };
+        Spans(self.0.iter().map(shift).collect())
+    }
+
+    /// The spans of the text once a quote `length` characters long is put in front of
+    /// it, on a line of its own.
+    pub fn quoted(&self, length: usize) -> Self {
+        let span = |style, start, end| Span {
+            style,
+            start,
+            end,
+            language: None,
+        };
+        let mut spans = vec![
+            span(Style::Quote, 0, length),
+            span(Style::Break, length, length + 1),
+        ];
+        spans.extend(self.shifted(length + 1).0);
+        Spans(spans)
+    }
+}
+
+impl fmt::Display for Spans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, span) in self.0.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(
+                f,
+                "{separator}{}{}-{}",
+                span.style.letter(),
+                span.start,
+                span.end
+            )?;
+            if let Some(language) = &span.language {
+                write!(f, ":{language}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spans() {
+        let spans = Spans::parse("b0-4,s10-17", "bold, and spoiler").unwrap();
+        assert_eq!(spans.to_string(), "b0-4,s10-17");
+        assert_eq!(spans.shifted(2).to_string(), "b2-6,s12-19");
+        // Characters, not bytes
+        assert!(Spans::parse("i0-4", "josé").is_some());
+        assert_eq!(Spans::parse("i0-5", "josé"), None);
+        assert_eq!(Spans::parse("x0-1", "hi"), None);
+        assert_eq!(Spans::parse("b1-1", "hi"), None);
+        assert_eq!(Spans::parse("b0-1,", "hi"), None);
+        assert!(Spans::parse("l4-16", "see https://x.io").is_some());
+        assert_eq!(Spans::parse("l0-3", "see https://x.io"), None);
+        let code = "fn main() {     x(); }";
+        let spans = Spans::parse("p0-22:rust,n11-12,n20-21", code).unwrap();
+        assert_eq!(spans.to_string(), "p0-22:rust,n11-12,n20-21");
+        assert_eq!(Spans::parse("n10-11", code), None);
+        assert_eq!(Spans::parse("b0-2:rust", code), None);
+        assert_eq!(Spans::parse("p0-2:ru st", code), None);
+    }
+}
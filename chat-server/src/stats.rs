@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::histogram::Histogram;
+
+/// Running counters of what the server has done since it started, shown by `/stats`.
+pub struct Stats {
+    started: Instant,
+    /// Connections let in.
+    pub connections: Counter,
+    /// Connections turned away before the handshake, e.g. by a ban or the flood guard.
+    pub refused: Counter,
+    /// Messages broadcast to the room.
+    pub messages: Counter,
+    /// Private messages delivered.
+    pub private_messages: Counter,
+    /// Room messages turned away over the sender's daily quota.
+    pub over_quota: Counter,
+    /// Lines dropped over the quota of the disconnected session they were meant for.
+    pub dropped_lines: Counter,
+    /// How long after a room message was read each member got it, written to their
+    /// connection or queued for their disconnected session.
+    pub fanout: Histogram,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            connections: Counter::default(),
+            refused: Counter::default(),
+            messages: Counter::default(),
+            private_messages: Counter::default(),
+            over_quota: Counter::default(),
+            dropped_lines: Counter::default(),
+            fanout: Histogram::new(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Average number of room messages per minute since the server started.
+    pub fn messages_per_minute(&self) -> f64 {
+        let minutes = self.uptime().as_secs_f64() / 60.0;
+        self.messages.get() as f64 / minutes.max(1.0)
+    }
+}
+
+/// A count that only ever goes up, bumped without taking any lock.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn bump(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
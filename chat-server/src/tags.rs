@@ -0,0 +1,150 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::time::SystemTime;
+
+use crate::spans::Spans;
+
+/// Metadata sent in front of a chat line, IRCv3 style: `@key=value;key=value [bob]: hi`.
+/// Clients take the tags off before showing the line.
+#[derive(Debug, Default)]
+pub struct Tags(Vec<(&'static str, String)>);
+
+impl Tags {
+    /// When the server received the message, in UTC, so every client can show it in
+    /// its own time zone.
+    pub fn time(mut self, at: SystemTime) -> Self {
+        let at = DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Millis, true);
+        self.0.push(("time", at));
+        self
+    }
+
+    /// The message's sequence number in its room, so clients can tell they missed some.
+    pub fn seq(mut self, seq: u64) -> Self {
+        self.0.push(("seq", seq.to_string()));
+        self
+    }
+
+    /// The rooms a message was sent to at once, the same on every copy of it.
+    pub fn broadcast(mut self, rooms: &[String]) -> Self {
+        self.0.push(("broadcast", rooms.join(",")));
+        self
+    }
+
+    /// The room a forwarded message was first sent to.
+    pub fn forwarded(mut self, room: &str) -> Self {
+        self.0.push(("forwarded", room.to_string()));
+        self
+    }
+
+    /// The message a reply quotes, by its sequence number.
+    pub fn reply(mut self, seq: u64) -> Self {
+        self.0.push(("reply", seq.to_string()));
+        self
+    }
+
+    /// How the message's text is formatted, if it is.
+    pub fn spans(mut self, spans: &Spans) -> Self {
+        if !spans.is_empty() {
+            self.0.push(("fmt", spans.to_string()));
+        }
+        self
+    }
+
+    /// Marks a whisper, a private message within a room, for clients to show it
+    /// apart from other messages.
+    pub fn whisper(mut self) -> Self {
+        self.0.push(("kind", "whisper".to_string()));
+        self
+    }
+
+    /// Marks an action sent with `/me`, for clients to show `[alice]: waves` as
+    /// `* alice waves`.
+    pub fn action(mut self) -> Self {
+        self.0.push(("kind", "action".to_string()));
+        self
+    }
+
+    /// Puts the tags in front of `line`.
+    pub fn apply(&self, line: &str) -> String {
+        if self.0.is_empty() {
+            return line.to_string();
+        }
+        let tags: Vec<String> = self
+            .0
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        format!("@{} {line}", tags.join(";"))
+    }
+}
+
+/// Longest message id a client may send.
+const MAX_ID: usize = 64;
+
+/// Tags a client may put in front of its lines, in the same format.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ClientTags<'a> {
+    /// An id the client gave its message, e.g. a UUID, so that sending it again after
+    /// a reconnect doesn't post it twice.
+    pub id: Option<&'a str>,
+    /// How the message's text is formatted.
+    pub spans: Spans,
+}
+
+/// Takes the tags off the front of a line from a client, if it has any. Ids that
+/// are too long or contain anything but letters, digits and dashes are ignored, as
+/// are spans that don't fit the text.
+pub fn split(line: &str) -> (ClientTags<'_>, &str) {
+    let mut tags = ClientTags::default();
+    let Some((raw, rest)) = line
+        .strip_prefix('@')
+        .and_then(|tagged| tagged.split_once(' '))
+        // A message such as `@alice hi` isn't tagged
+        .filter(|(raw, _)| raw.split(';').all(|tag| tag.contains('=')))
+    else {
+        return (tags, line);
+    };
+    for (key, value) in raw.split(';').filter_map(|tag| tag.split_once('=')) {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-';
+        if key == "id" && value.len() <= MAX_ID && value.chars().all(valid) {
+            tags.id = Some(value);
+        }
+        if key == "fmt" {
+            tags.spans = Spans::parse(value, rest).unwrap_or_default();
+        }
+    }
+    (tags, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_tags_go_in_front_of_the_line() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_000_000_000_250);
+        assert_eq!(
+            Tags::default().time(at).apply("[bob]: hi"),
+            "@time=2001-09-09T01:46:40.250Z [bob]: hi"
+        );
+        assert_eq!(Tags::default().apply("[bob]: hi"), "[bob]: hi");
+    }
+
+    #[test]
+    fn test_split_client_tags() {
+        assert_eq!(
+            split("@id=4f1c-9a;other=1 hello"),
+            (
+                ClientTags {
+                    id: Some("4f1c-9a"),
+                    ..ClientTags::default()
+                },
+                "hello"
+            )
+        );
+        assert_eq!(split("@id=a_b hi").0.id, None);
+        assert_eq!(split("@fmt=b0-2 hi").0.spans.to_string(), "b0-2");
+        assert!(split("@fmt=b0-3 hi").0.spans.is_empty());
+        assert_eq!(split("@alice hi"), (ClientTags::default(), "@alice hi"));
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use std::time::SystemTime;
+
+use crate::duration;
+
+/// How times are shown in command output, such as `/reports` and `/audit`.
+#[derive(Debug, Clone, Default)]
+pub enum TimeFormat {
+    /// How long ago, e.g. `5m ago`.
+    #[default]
+    Relative,
+    /// The server's local time, in strftime-style formats: one for times of today
+    /// and one, usually with the date, for older ones.
+    Absolute { today: String, older: String },
+}
+
+impl TimeFormat {
+    /// Shows times in `today`'s format, or `older`'s before today. Without `older`,
+    /// older times are prefixed with their date.
+    pub fn absolute(today: &str, older: Option<&str>) -> Result<Self, String> {
+        let older = older.map_or_else(|| format!("%Y-%m-%d {today}"), str::to_string);
+        validate(today)?;
+        validate(&older)?;
+        Ok(TimeFormat::Absolute {
+            today: today.to_string(),
+            older,
+        })
+    }
+
+    pub fn format(&self, at: SystemTime) -> String {
+        match self {
+            TimeFormat::Relative => duration::ago(at),
+            TimeFormat::Absolute { today, older } => {
+                let at = DateTime::<Local>::from(at);
+                let format = if at.date_naive() == Local::now().date_naive() {
+                    today
+                } else {
+                    older
+                };
+                at.format(format).to_string()
+            }
+        }
+    }
+}
+
+/// Checks a format up front, as chrono only fails once it is used.
+fn validate(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("Invalid time format: {format}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_older_times_use_their_own_format() {
+        let format = TimeFormat::absolute("%H:%M", None).unwrap();
+        let long_ago = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert!(format.format(long_ago).starts_with("2001-09-"));
+        assert_eq!(format.format(SystemTime::now()).len(), "12:34".len());
+
+        let format = TimeFormat::absolute("%H:%M", Some("%Y")).unwrap();
+        assert_eq!(format.format(long_ago), "2001");
+        assert!(TimeFormat::absolute("%Q", None).is_err());
+    }
+}
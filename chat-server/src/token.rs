@@ -0,0 +1,23 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+/// Generates a random 128-bit token, hex encoded, e.g. for challenges or sessions. It
+/// comes from the operating system's generator, so it can stand for a secret: resume
+/// tokens let clients in without their password, and API secrets are tokens too.
+pub fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_random_hex() {
+        let token = generate();
+        assert_eq!(token.len(), 32);
+        assert!(token.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(token, generate());
+    }
+}
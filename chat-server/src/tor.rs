@@ -0,0 +1,183 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+
+/// A connection to Tor's control port, holding an onion service for the server.
+///
+/// Tor removes the service as soon as the control connection that added it closes,
+/// so this has to be kept around for as long as the server runs.
+pub struct OnionService {
+    /// The service's address, `<id>.onion`.
+    pub address: String,
+    _control: Control,
+}
+
+/// Publishes the server as an onion service through the Tor control port at
+/// `control`, forwarding the onion's `port` to `target`.
+///
+/// The service's private key is kept in `key_path`, so the onion address stays the
+/// same across restarts.
+pub fn publish(
+    control: SocketAddr,
+    key_path: &Path,
+    port: u16,
+    target: SocketAddr,
+) -> io::Result<OnionService> {
+    let mut control = Control::connect(control)?;
+    control.authenticate()?;
+
+    let key = match fs::read_to_string(key_path) {
+        Ok(key) => Some(key.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let key_arg = key.as_deref().unwrap_or("NEW:ED25519-V3");
+    let reply = control.command(&format!("ADD_ONION {key_arg} Port={port},{target}"))?;
+
+    let service_id = reply
+        .iter()
+        .find_map(|line| line.strip_prefix("ServiceID="))
+        .ok_or_else(|| invalid("Tor didn't return a service ID"))?;
+    if key.is_none() {
+        let new_key = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("PrivateKey="))
+            .ok_or_else(|| invalid("Tor didn't return the service's key"))?;
+        fs::write(key_path, format!("{new_key}\n"))?;
+    }
+    Ok(OnionService {
+        address: format!("{service_id}.onion"),
+        _control: control,
+    })
+}
+
+/// A line based control port connection.
+struct Control {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Control {
+    fn connect(address: SocketAddr) -> io::Result<Self> {
+        let writer = TcpStream::connect(address)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Control { reader, writer })
+    }
+
+    /// Sends a command and returns the lines of a successful (`250`) reply, without
+    /// their status code.
+    fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.writer.write_all(format!("{command}\r\n").as_bytes())?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            // Replies are `<code>-<text>` for every line but the last, `<code> <text>`
+            let (code, rest) = line.split_at(line.len().min(3));
+            if code != "250" {
+                return Err(io::Error::other(format!("Tor refused {command}: {line}")));
+            }
+            match rest.chars().next() {
+                Some('-') => lines.push(rest[1..].to_string()),
+                _ => return Ok(lines),
+            }
+        }
+    }
+
+    /// Authenticates with the cookie file if Tor asks for one, or with no
+    /// credentials at all if it doesn't.
+    fn authenticate(&mut self) -> io::Result<()> {
+        let info = self.command("PROTOCOLINFO 1")?;
+        let auth = info
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .unwrap_or_default();
+        let methods = auth
+            .split(' ')
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .unwrap_or_default();
+        if methods.split(',').any(|method| method == "NULL") {
+            return self.command("AUTHENTICATE").map(drop);
+        }
+        if !methods.split(',').any(|method| method == "COOKIE") {
+            return Err(invalid(&format!(
+                "Unsupported control port authentication: {methods}, enable CookieAuthentication"
+            )));
+        }
+        let cookie_file = auth
+            .split_once("COOKIEFILE=\"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(path, _)| path.replace("\\\\", "\\"))
+            .ok_or_else(|| invalid("Tor didn't say where its cookie file is"))?;
+        let cookie: String = fs::read(cookie_file)?
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        self.command(&format!("AUTHENTICATE {cookie}")).map(drop)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_publish_keeps_the_key() {
+        let control = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = control.local_addr().unwrap();
+        let tor = thread::spawn(move || {
+            let mut commands = Vec::new();
+            // One connection for the first publish, one for the restart
+            for _ in 0..2 {
+                let (stream, _) = control.accept().unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 {
+                    let reply = match line.split(' ').next().unwrap().trim() {
+                        "PROTOCOLINFO" => "250-AUTH METHODS=NULL\r\n250 OK\r\n",
+                        "ADD_ONION" => "250-ServiceID=abcdef\r\n250-PrivateKey=ED25519-V3:c2VjcmV0\r\n250 OK\r\n",
+                        _ => "250 OK\r\n",
+                    };
+                    commands.push(line.trim().to_string());
+                    writer.write_all(reply.as_bytes()).unwrap();
+                    line.clear();
+                    if commands.len() % 3 == 0 {
+                        break;
+                    }
+                }
+            }
+            commands
+        });
+
+        let key_path = std::env::temp_dir().join(format!("onion-{}.key", std::process::id()));
+        let _ = fs::remove_file(&key_path);
+        let target = "127.0.0.1:12345".parse().unwrap();
+        let service = publish(address, &key_path, 12345, target).unwrap();
+        assert_eq!(service.address, "abcdef.onion");
+        drop(service);
+        publish(address, &key_path, 12345, target).unwrap();
+
+        let commands = tor.join().unwrap();
+        assert_eq!(
+            commands[2],
+            "ADD_ONION NEW:ED25519-V3 Port=12345,127.0.0.1:12345"
+        );
+        // The restart reuses the key Tor generated the first time
+        assert_eq!(
+            commands[5],
+            "ADD_ONION ED25519-V3:c2VjcmV0 Port=12345,127.0.0.1:12345"
+        );
+        fs::remove_file(&key_path).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+/// Bytes that went over a connection, or all of an account's, as the server sees
+/// them: `sent` to the client and `received` from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+/// What the sessions that have ended used, per account, since the server started.
+/// Sessions still going keep count of their own.
+#[derive(Default)]
+pub struct Traffic {
+    accounts: HashMap<String, Usage>,
+}
+
+impl Traffic {
+    /// Adds what a session of `username` used, as it ends.
+    pub fn record(&mut self, username: &str, usage: Usage) {
+        *self.accounts.entry(username.to_string()).or_default() += usage;
+    }
+
+    /// Adds up the ended sessions' usage with `live`, what the sessions still going
+    /// used, heaviest users first.
+    pub fn with(&self, live: impl IntoIterator<Item = (String, Usage)>) -> Vec<(String, Usage)> {
+        let mut accounts = self.accounts.clone();
+        for (username, usage) in live {
+            *accounts.entry(username).or_default() += usage;
+        }
+        let mut accounts: Vec<_> = accounts.into_iter().collect();
+        accounts.sort_by(|(a, a_usage), (b, b_usage)| {
+            b_usage.total().cmp(&a_usage.total()).then_with(|| a.cmp(b))
+        });
+        accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_adds_up_per_account() {
+        let mut traffic = Traffic::default();
+        let usage = |sent, received| Usage { sent, received };
+        traffic.record("alice", usage(10, 5));
+        traffic.record("bob", usage(100, 0));
+        traffic.record("alice", usage(1, 1));
+        let accounts = traffic.with([("alice".to_string(), usage(100, 0))]);
+        assert_eq!(
+            accounts,
+            [
+                ("alice".to_string(), usage(111, 6)),
+                ("bob".to_string(), usage(100, 0))
+            ]
+        );
+    }
+}
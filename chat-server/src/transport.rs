@@ -0,0 +1,182 @@
+use std::io::{self, BufRead, BufReader, IoSlice, Write};
+use std::net::{IpAddr, TcpStream};
+
+use crate::buffers::BUFFERS;
+use crate::poll;
+use crate::stats::Counter;
+
+#[cfg(feature = "noise")]
+use crate::noise;
+#[cfg(feature = "quic")]
+use crate::quic;
+
+/// Called for every client that connects over a transport with a listener of its
+/// own, such as QUIC or long-polling, with the two halves of its connection.
+pub type OnConnection = dyn Fn(Reader, Writer, IpAddr) + Send + Sync;
+
+/// How lines go out to clients, for `/stats` to show what batching saves.
+pub struct Writes {
+    /// Lines written, one at a time or batched.
+    pub lines: Counter,
+    /// Writes it took, i.e. system calls for plain TCP.
+    pub writes: Counter,
+    /// Batches of lines written together with [`Writer::write_lines`].
+    pub batches: Counter,
+    /// Lines in those batches.
+    pub batched_lines: Counter,
+}
+
+/// Shared by every connection, which write from threads of their own.
+pub static WRITES: Writes = Writes {
+    lines: Counter::new(),
+    writes: Counter::new(),
+    batches: Counter::new(),
+    batched_lines: Counter::new(),
+};
+
+impl Writes {
+    /// Average number of lines a batch wrote at once.
+    pub fn batch_size(&self) -> f64 {
+        self.batched_lines.get() as f64 / self.batches.get().max(1) as f64
+    }
+}
+
+/// The sending half of a client connection.
+pub enum Writer {
+    Plain(TcpStream),
+    #[cfg(feature = "noise")]
+    Noise(noise::Writer),
+    #[cfg(feature = "quic")]
+    Quic(quic::Writer),
+    Poll(poll::Writer),
+}
+
+impl Writer {
+    /// Writes `line` and its newline in a single write, so readers never see a
+    /// line split from its terminator.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        WRITES.lines.bump();
+        WRITES.writes.bump();
+        match self {
+            Writer::Plain(stream) => {
+                let mut buffer = BUFFERS.take();
+                buffer.extend_from_slice(line.as_bytes());
+                buffer.push(b'\n');
+                let result = stream.write_all(&buffer);
+                BUFFERS.give_back(buffer);
+                result
+            }
+            #[cfg(feature = "noise")]
+            Writer::Noise(writer) => writer.write_line(line),
+            #[cfg(feature = "quic")]
+            Writer::Quic(writer) => writer.write_line(line),
+            Writer::Poll(writer) => writer.write_line(line),
+        }
+    }
+
+    /// Writes several lines, each with its newline. Over plain TCP they go out
+    /// together in as few `writev` calls as the kernel allows, rather than one write
+    /// each. The other transports frame or queue every line on its own, so they still
+    /// write them one at a time.
+    pub fn write_lines(&mut self, lines: &[String]) -> io::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        WRITES.batches.bump();
+        WRITES.batched_lines.add(lines.len() as u64);
+        match self {
+            Writer::Plain(stream) => {
+                WRITES.lines.add(lines.len() as u64);
+                let mut slices: Vec<IoSlice> = (lines.iter())
+                    .flat_map(|line| [IoSlice::new(line.as_bytes()), IoSlice::new(b"\n")])
+                    .collect();
+                write_all_vectored(stream, &mut slices)
+            }
+            _ => lines.iter().try_for_each(|line| self.write_line(line)),
+        }
+    }
+
+    /// Name of the transport the client came in on.
+    pub fn transport(&self) -> &'static str {
+        match self {
+            Writer::Plain(_) => "tcp",
+            #[cfg(feature = "noise")]
+            Writer::Noise(_) => "noise",
+            #[cfg(feature = "quic")]
+            Writer::Quic(_) => "quic",
+            Writer::Poll(_) => "http-poll",
+        }
+    }
+}
+
+/// Writes all of `slices`, picking up where a short write left off.
+fn write_all_vectored(stream: &mut TcpStream, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                WRITES.writes.bump();
+                IoSlice::advance_slices(&mut slices, n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// The receiving half of a client connection, yielding one line at a time.
+pub enum Reader {
+    Plain(BufReader<TcpStream>),
+    #[cfg(feature = "noise")]
+    Noise(noise::Reader),
+    #[cfg(feature = "quic")]
+    Quic(quic::Reader),
+    Poll(poll::Reader),
+}
+
+impl Iterator for Reader {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Reader::Plain(reader) => {
+                let mut line = String::new();
+                match reader.read_line(&mut line) {
+                    Ok(0) => None,
+                    Ok(_) => {
+                        let len = line.trim_end_matches(['\r', '\n']).len();
+                        line.truncate(len);
+                        Some(Ok(line))
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            #[cfg(feature = "noise")]
+            Reader::Noise(reader) => reader.next(),
+            #[cfg(feature = "quic")]
+            Reader::Quic(reader) => reader.next(),
+            Reader::Poll(reader) => reader.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_lines_are_written_in_a_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let mut writer = Writer::Plain(stream);
+        let lines = ["hello".to_string(), String::new(), "x".repeat(10_000)];
+        writer.write_lines(&lines).unwrap();
+        drop(writer);
+        let reader = Reader::Plain(BufReader::new(accepted));
+        let read: Vec<String> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, lines);
+    }
+}
@@ -0,0 +1,38 @@
+/// The server's version, as released.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The optional features the server was built with.
+pub fn features() -> Vec<&'static str> {
+    [
+        ("noise", cfg!(feature = "noise")),
+        ("quic", cfg!(feature = "quic")),
+        ("mdns", cfg!(feature = "mdns")),
+        ("fun", cfg!(feature = "fun")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+/// The line sent to every client that joins, `/server <version> <features>`, with
+/// the features separated by commas, or `-` if there are none. Clients use it to warn
+/// about incompatible versions.
+pub fn announcement() -> String {
+    let features = features();
+    let features = if features.is_empty() {
+        "-".to_string()
+    } else {
+        features.join(",")
+    };
+    format!("/server {VERSION} {features}")
+}
+
+/// The version and features, for people to read.
+pub fn describe() -> String {
+    let features = features();
+    if features.is_empty() {
+        format!("chat-server {VERSION}, built without optional features")
+    } else {
+        format!("chat-server {VERSION}, built with {}", features.join(", "))
+    }
+}
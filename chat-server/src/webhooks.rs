@@ -0,0 +1,405 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::events::{Event, EventSink};
+use crate::outbound::{self, Url};
+use crate::stats::Counter;
+use crate::token;
+
+/// Deliveries waiting to be sent; any more are dead-lettered rather than slowing the
+/// server.
+const QUEUE: usize = 1024;
+/// How many times a delivery is tried before it is dead-lettered.
+const ATTEMPTS: u32 = 6;
+/// How long to wait before the first retry, doubling for each after it.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// What a webhook may be notified of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// A room message mentioning one of the webhook's keywords.
+    Mention,
+    /// A user joining.
+    Join,
+    /// A user leaving, or their session expiring.
+    Leave,
+    /// A moderator or admin action, as recorded in the audit log.
+    Moderation,
+    /// A report filed against a user.
+    Report,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mention" => Ok(Kind::Mention),
+            "join" => Ok(Kind::Join),
+            "leave" => Ok(Kind::Leave),
+            "moderation" => Ok(Kind::Moderation),
+            "report" => Ok(Kind::Report),
+            _ => Err(format!(
+                "Unknown webhook event {s:?}, expected mention, join, leave, moderation or report"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Mention => "mention",
+            Kind::Join => "join",
+            Kind::Leave => "leave",
+            Kind::Moderation => "moderation",
+            Kind::Report => "report",
+        })
+    }
+}
+
+/// A webhook declared in the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: Url,
+    /// Signs the deliveries, if set, so the endpoint can tell they came from here.
+    pub secret: Option<String>,
+    pub events: Vec<Kind>,
+    /// The words whose mention in a room message is delivered, matched ignoring case.
+    pub keywords: Vec<String>,
+}
+
+impl Webhook {
+    /// What to deliver of `event`, if the webhook wants it.
+    fn payload(&self, event: &Event<'_>) -> Option<(Kind, Value)> {
+        let (kind, data) = match *event {
+            Event::MessageBroadcast {
+                room,
+                from,
+                seq,
+                text,
+            } => {
+                let lowercase = text.to_lowercase();
+                let keyword = (self.keywords.iter())
+                    .find(|keyword| lowercase.contains(&keyword.to_lowercase()))?;
+                let data = json!({
+                    "room": room,
+                    "from": from,
+                    "seq": seq,
+                    "text": text,
+                    "keyword": keyword,
+                });
+                (Kind::Mention, data)
+            }
+            Event::UserJoined { username, sessions } => (
+                Kind::Join,
+                json!({"username": username, "sessions": sessions}),
+            ),
+            Event::UserLeft { username } => {
+                (Kind::Leave, json!({"username": username, "expired": false}))
+            }
+            Event::SessionExpired { username } => {
+                (Kind::Leave, json!({"username": username, "expired": true}))
+            }
+            Event::Moderated {
+                action,
+                by,
+                target,
+                detail,
+                reason,
+            } => {
+                let data = json!({
+                    "action": action.to_string(),
+                    "by": by,
+                    "target": target,
+                    "detail": detail,
+                    "reason": reason,
+                });
+                (Kind::Moderation, data)
+            }
+            Event::ReportFiled {
+                id,
+                user,
+                by,
+                reason,
+            } => {
+                let data = json!({"id": id, "user": user, "by": by, "reason": reason});
+                (Kind::Report, data)
+            }
+            Event::SessionResumed { .. }
+            | Event::ConnectionLost { .. }
+            | Event::PrivateMessage { .. } => return None,
+        };
+        self.events.contains(&kind).then_some((kind, data))
+    }
+}
+
+/// Delivers events to the webhooks in the config file, as JSON posted from a thread
+/// of its own. Failed deliveries are retried with exponential backoff, and those that
+/// fail every time are appended to a dead-letter log.
+pub struct Webhooks {
+    hooks: RwLock<Vec<Webhook>>,
+    dead_letters: PathBuf,
+    sender: OnceLock<SyncSender<Delivery>>,
+    /// Counters for `/stats`, shared with the delivery thread.
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+pub struct Counts {
+    pub delivered: Counter,
+    pub retried: Counter,
+    pub dead: Counter,
+}
+
+/// A payload on its way to a webhook.
+struct Delivery {
+    id: String,
+    url: Url,
+    secret: Option<String>,
+    kind: Kind,
+    body: String,
+    attempts: u32,
+}
+
+impl Webhooks {
+    /// Webhooks whose failed deliveries are appended to `dead_letters`. There are none
+    /// until the config file declares some.
+    pub fn new(dead_letters: &Path) -> Self {
+        Webhooks {
+            hooks: RwLock::new(Vec::new()),
+            dead_letters: dead_letters.to_path_buf(),
+            sender: OnceLock::new(),
+            counts: Arc::default(),
+        }
+    }
+
+    /// Replaces the webhooks, starting the delivery thread the first time there are
+    /// any. Deliveries already queued still go out.
+    pub fn configure(&self, hooks: Vec<Webhook>) {
+        if !hooks.is_empty() && self.sender.get().is_none() {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE);
+            if self.sender.set(sender).is_ok() {
+                let dead_letters = self.dead_letters.clone();
+                let counts = Arc::clone(&self.counts);
+                thread::spawn(move || deliver(&receiver, &dead_letters, &counts));
+            }
+        }
+        *self.hooks.write().unwrap() = hooks;
+    }
+
+    /// How many webhooks there are.
+    pub fn len(&self) -> usize {
+        self.hooks.read().unwrap().len()
+    }
+
+    pub fn counts(&self) -> &Counts {
+        &self.counts
+    }
+}
+
+impl EventSink for Webhooks {
+    fn event(&self, event: &Event<'_>) {
+        let hooks = self.hooks.read().unwrap();
+        let Some(sender) = self.sender.get() else {
+            return;
+        };
+        let at = (SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |since| since.as_secs());
+        for hook in hooks.iter() {
+            let Some((kind, data)) = hook.payload(event) else {
+                continue;
+            };
+            let id = token::generate();
+            let body = json!({"id": id, "event": kind.to_string(), "at": at, "data": data});
+            let delivery = Delivery {
+                id,
+                url: hook.url.clone(),
+                secret: hook.secret.clone(),
+                kind,
+                body: body.to_string(),
+                attempts: 0,
+            };
+            if let Err(e) = sender.try_send(delivery) {
+                let delivery = match e {
+                    mpsc::TrySendError::Full(delivery)
+                    | mpsc::TrySendError::Disconnected(delivery) => delivery,
+                };
+                bury(&self.dead_letters, &delivery, "the delivery queue is full");
+                self.counts.dead.bump();
+            }
+        }
+    }
+}
+
+/// Sends deliveries as they come, and retries those that failed once their backoff
+/// is up.
+fn deliver(receiver: &Receiver<Delivery>, dead_letters: &Path, counts: &Counts) {
+    let mut retries: Vec<(Instant, Delivery)> = Vec::new();
+    loop {
+        let due = retries.iter().map(|(at, _)| *at).min();
+        let wait = due.map_or(Duration::from_secs(3600), |due| {
+            due.saturating_duration_since(Instant::now())
+        });
+        let mut ready = Vec::new();
+        match receiver.recv_timeout(wait) {
+            Ok(delivery) => ready.push(delivery),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = Instant::now();
+        let (due, later): (Vec<_>, Vec<_>) = retries.drain(..).partition(|(at, _)| *at <= now);
+        retries = later;
+        ready.extend(due.into_iter().map(|(_, delivery)| delivery));
+        for mut delivery in ready {
+            delivery.attempts += 1;
+            let error = match post(&delivery) {
+                Ok(()) => {
+                    counts.delivered.bump();
+                    continue;
+                }
+                Err(e) => e,
+            };
+            if !error.retry || delivery.attempts == ATTEMPTS {
+                bury(dead_letters, &delivery, &error.message);
+                counts.dead.bump();
+                continue;
+            }
+            counts.retried.bump();
+            let backoff = BACKOFF * 2u32.pow(delivery.attempts - 1);
+            retries.push((Instant::now() + backoff, delivery));
+        }
+    }
+}
+
+/// Why a delivery failed, and whether trying again might help.
+struct Failure {
+    message: String,
+    retry: bool,
+}
+
+/// Posts a delivery. Server errors, `429` and network errors are worth retrying; any
+/// other answer but `2xx` is not.
+fn post(delivery: &Delivery) -> Result<(), Failure> {
+    let mut headers = vec![
+        ("Content-Type", "application/json".to_string()),
+        ("X-Chat-Event", delivery.kind.to_string()),
+        ("X-Chat-Delivery", delivery.id.clone()),
+    ];
+    if let Some(secret) = &delivery.secret {
+        let signature = hmac_sha256(secret.as_bytes(), delivery.body.as_bytes());
+        headers.push(("X-Chat-Signature", format!("sha256={signature}")));
+    }
+    let status =
+        outbound::post(&delivery.url, &headers, delivery.body.as_bytes()).map_err(|e| Failure {
+            message: e.to_string(),
+            retry: true,
+        })?;
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(Failure {
+            message: format!("The endpoint answered {}", status.line),
+            retry: status.code == 429 || status.code >= 500,
+        }),
+    }
+}
+
+/// Appends a delivery that failed for good to the dead-letter log, as a line of
+/// JSON.
+fn bury(dead_letters: &Path, delivery: &Delivery, error: &str) {
+    let at = (SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |since| since.as_secs());
+    let entry = json!({
+        "at": at,
+        "url": delivery.url.to_string(),
+        "attempts": delivery.attempts,
+        "error": error,
+        "body": delivery.body,
+    });
+    let appended = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letters)
+        .and_then(|mut file| writeln!(file, "{entry}"));
+    if let Err(e) = appended {
+        println!("Failed to dead-letter a delivery to {}: {e}", delivery.url);
+    }
+}
+
+/// HMAC-SHA256 of `message` under `key`, hex encoded.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> String {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize();
+    format!("{outer:x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Action;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_payloads() {
+        let hook = Webhook {
+            url: "http://hooks.local/chat".parse().unwrap(),
+            secret: None,
+            events: vec![Kind::Mention, Kind::Moderation],
+            keywords: vec!["Deploy".to_string()],
+        };
+        let mention = Event::MessageBroadcast {
+            room: "lobby",
+            from: "alice",
+            seq: 7,
+            text: "deploying now",
+        };
+        let (kind, data) = hook.payload(&mention).unwrap();
+        assert_eq!(kind, Kind::Mention);
+        assert_eq!(data["keyword"], "Deploy");
+        let chatter = Event::MessageBroadcast {
+            room: "lobby",
+            from: "alice",
+            seq: 8,
+            text: "lunch?",
+        };
+        assert!(hook.payload(&chatter).is_none());
+        assert!(hook.payload(&Event::UserLeft { username: "bob" }).is_none());
+        let kick = Event::Moderated {
+            action: Action::Kick,
+            by: "admin",
+            target: Some("bob"),
+            detail: "",
+            reason: "spam",
+        };
+        assert_eq!(hook.payload(&kick).unwrap().1["action"], "kick");
+    }
+}